@@ -0,0 +1,400 @@
+//! Integration tests that run the service under Viceroy, the Fastly local development server.
+//!
+//! Each test starts a mock origin on a local port and an instance of Viceroy serving the service,
+//! with a backend named `origin` pointing to the mock and an `origin_authorization` secret, and
+//! then sends requests to it as a client would. The mock origin records every request it gets, so
+//! that tests can check what reached it, and how often.
+//!
+//! These tests need a Viceroy release that supports the HTTP caching API, and are ignored by
+//! default. Run them from this directory with:
+//!
+//! ```sh
+//! cargo test -- --ignored
+//! ```
+//!
+//! The service is built for `wasm32-wasip1` first. Viceroy is looked up on the `PATH`, unless the
+//! `VICEROY` environment variable names another executable.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+/// The credential the service is configured to send to the origin.
+const ORIGIN_CREDENTIAL: &str = "Bearer integration-test";
+
+/// How long Viceroy is given to start serving.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[test]
+#[ignore = "requires Viceroy"]
+fn before_send_injects_the_origin_credential() {
+    let service = Service::start();
+    let resp = service.get("/page", &[("Authorization", "Bearer client")]);
+    assert_eq!(resp.status, 200);
+
+    let requests = service.origin.requests("/page");
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0].get("authorization").map(String::as_str),
+        Some(ORIGIN_CREDENTIAL)
+    );
+}
+
+#[test]
+#[ignore = "requires Viceroy"]
+fn pass_requests_carry_the_origin_credential() {
+    let service = Service::start();
+    let resp = service.request(
+        "POST",
+        "/page?utm_source=mail",
+        &[("Authorization", "Bearer client")],
+    );
+    assert_eq!(resp.status, 200);
+
+    let requests = service.origin.requests("/page");
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0].get("authorization").map(String::as_str),
+        Some(ORIGIN_CREDENTIAL)
+    );
+}
+
+#[test]
+#[ignore = "requires Viceroy"]
+fn ttl_is_chosen_by_content_type() {
+    let service = Service::start();
+    for (path, ttl) in [("/page", 321), ("/logo", 67), ("/notes", 30)] {
+        let resp = service.get(path, &[]);
+        assert_eq!(resp.status, 200, "{path}");
+        let cache_status = resp.header("cache-status").unwrap_or_default();
+        assert!(
+            cache_status.contains(&format!("stored; ttl={ttl}")),
+            "{path}: {cache_status}"
+        );
+    }
+}
+
+#[test]
+#[ignore = "requires Viceroy"]
+fn json_is_stored_as_rendered_html() {
+    let service = Service::start();
+    for _ in 0..2 {
+        let resp = service.get("/person", &[]);
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body, "<div>Ada Lovelace</div>");
+        assert_eq!(
+            resp.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+        // The transform marker is only for the cache, and never reaches clients.
+        assert_eq!(resp.header("x-edge-transformed"), None);
+    }
+    assert_eq!(service.origin.requests("/person").len(), 1);
+}
+
+#[test]
+#[ignore = "requires Viceroy"]
+fn private_responses_are_hit_for_pass() {
+    let service = Service::start();
+    for _ in 0..2 {
+        let resp = service.get("/private", &[]);
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body, "private");
+        let cache_status = resp.header("cache-status").unwrap_or_default();
+        assert!(!cache_status.contains("stored"), "{cache_status}");
+    }
+    assert_eq!(service.origin.requests("/private").len(), 2);
+}
+
+/// A response received from the service.
+struct Response {
+    status: u16,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+/// The headers of a request received by the mock origin, with lowercase names.
+type RecordedRequest = HashMap<String, String>;
+
+/// A mock origin, answering every path with a canned response.
+struct MockOrigin {
+    port: u16,
+    requests: Arc<Mutex<Vec<(String, RecordedRequest)>>>,
+}
+
+impl MockOrigin {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind the mock origin");
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let recorded = recorded.clone();
+                thread::spawn(move || serve_origin(stream, &recorded));
+            }
+        });
+        Self { port, requests }
+    }
+
+    /// Returns the requests received for a path.
+    fn requests(&self, path: &str) -> Vec<RecordedRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(requested, _)| requested == path)
+            .map(|(_, headers)| headers.clone())
+            .collect()
+    }
+}
+
+/// Answers one request to the mock origin.
+fn serve_origin(stream: TcpStream, recorded: &Mutex<Vec<(String, RecordedRequest)>>) {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let headers = read_headers(&mut reader);
+    recorded.lock().unwrap().push((path.clone(), headers));
+
+    let (content_type, extra_headers, body) = match path.as_str() {
+        "/page" => ("text/html", "", "<p>page</p>"),
+        "/logo" => ("image", "", "logo"),
+        "/notes" => ("text/plain", "", "notes"),
+        "/person" => (
+            "application/json",
+            "",
+            r#"{"firstName":"Ada","lastName":"Lovelace"}"#,
+        ),
+        "/private" => ("text/html", "My-Private-Header: 1\r\n", "private"),
+        _ => ("text/plain", "", "not found"),
+    };
+    let status = if body == "not found" {
+        "404 Not Found"
+    } else {
+        "200 OK"
+    };
+    let resp = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         {extra_headers}Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = reader.get_mut().write_all(resp.as_bytes());
+}
+
+/// The service running under Viceroy, in front of its own mock origin.
+struct Service {
+    origin: MockOrigin,
+    port: u16,
+    viceroy: Child,
+    _config: TempFile,
+}
+
+impl Service {
+    fn start() -> Self {
+        let origin = MockOrigin::start();
+        let config = TempFile::new(
+            "fastly.toml",
+            &format!(
+                r#"manifest_version = 3
+name = "integration-tests"
+language = "rust"
+
+[local_server.backends.origin]
+url = "http://127.0.0.1:{}"
+
+[local_server.secret_stores]
+secrets = [{{ key = "origin_authorization", data = "{ORIGIN_CREDENTIAL}" }}]
+"#,
+                origin.port
+            ),
+        );
+        let port = free_port();
+        let viceroy = Command::new(env::var("VICEROY").unwrap_or_else(|_| "viceroy".into()))
+            .arg("serve")
+            .arg("-C")
+            .arg(&config.path)
+            .arg("--addr")
+            .arg(format!("127.0.0.1:{port}"))
+            .arg(service_wasm())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("cannot run Viceroy; install it or set VICEROY to its path");
+        let service = Self {
+            origin,
+            port,
+            viceroy,
+            _config: config,
+        };
+        service.wait_until_ready();
+        service
+    }
+
+    fn wait_until_ready(&self) {
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", self.port)).is_err() {
+            assert!(
+                started.elapsed() < STARTUP_TIMEOUT,
+                "Viceroy did not start serving"
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Sends a `GET` request for a path, with some headers.
+    fn get(&self, path: &str, headers: &[(&str, &str)]) -> Response {
+        self.request("GET", path, headers)
+    }
+
+    /// Sends a request with an empty body for a path, with some headers.
+    fn request(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> Response {
+        let mut stream =
+            TcpStream::connect(("127.0.0.1", self.port)).expect("cannot connect to Viceroy");
+        let mut request = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n");
+        for (name, value) in headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if method != "GET" {
+            request.push_str("Content-Length: 0\r\n");
+        }
+        request.push_str("Connection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line).unwrap();
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .expect("malformed status line");
+        let headers = read_headers(&mut reader);
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+        if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+            body = dechunk(&body);
+        }
+        Response {
+            status,
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        }
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        let _ = self.viceroy.kill();
+        let _ = self.viceroy.wait();
+    }
+}
+
+/// A file removed when it is dropped.
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn new(name: &str, contents: &str) -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let dir = env::temp_dir().join(format!(
+            "viceroy-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        Self { path }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(dir) = self.path.parent() {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Builds the service for Compute once, returning the path of its Wasm module.
+fn service_wasm() -> PathBuf {
+    static WASM: OnceLock<PathBuf> = OnceLock::new();
+    WASM.get_or_init(|| {
+        let service_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        let status = Command::new(env!("CARGO"))
+            .args(["build", "--target", "wasm32-wasip1"])
+            .current_dir(&service_dir)
+            .status()
+            .expect("cannot run cargo");
+        assert!(status.success(), "cannot build the service");
+        service_dir.join("target/wasm32-wasip1/debug/fastly-compute-project.wasm")
+    })
+    .clone()
+}
+
+/// Returns a local port that is free to listen on.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("cannot find a free port")
+}
+
+/// Reads HTTP headers up to the empty line ending them.
+fn read_headers(reader: &mut impl BufRead) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    let mut line = String::new();
+    while reader.read_line(&mut line).is_ok_and(|read| read > 0) {
+        let trimmed = line.trim_end();
+        if trimmed.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = trimmed.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+        line.clear();
+    }
+    headers
+}
+
+/// Decodes a chunked response body.
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some(end) = body.windows(2).position(|window| window == b"\r\n") {
+        let size = std::str::from_utf8(&body[..end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .unwrap_or_default();
+        body = &body[end + 2..];
+        if size == 0 || body.len() < size {
+            break;
+        }
+        out.extend_from_slice(&body[..size]);
+        body = body.get(size + 2..).unwrap_or_default();
+    }
+    out
+}
@@ -0,0 +1,211 @@
+//! Normalization of the `Accept` header to a few canonical values.
+//!
+//! Browsers and HTTP libraries send many different `Accept` strings, so a response that varies on
+//! `Accept` would otherwise be stored once for every one of them. With the `normalize_accept`
+//! setting on, the header is replaced before the cache lookup by the canonical value of the
+//! family the client prefers, so such responses are stored at most four times.
+//!
+//! This module also parses the quality values of `Accept` and its siblings, `Accept-Encoding`
+//! and `Accept-Language`, for every feature that negotiates with the client.
+
+use fastly::http::header;
+use fastly::Request;
+
+/// The canonical `Accept` values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Family {
+    Html,
+    Json,
+    Xml,
+    Any,
+}
+
+impl Family {
+    fn of(media_type: &str) -> Option<Self> {
+        let media_type = media_type.to_ascii_lowercase();
+        match media_type.as_str() {
+            "text/html" | "application/xhtml+xml" => Some(Family::Html),
+            "application/json" => Some(Family::Json),
+            "application/xml" | "text/xml" => Some(Family::Xml),
+            "*/*" => Some(Family::Any),
+            other if other.ends_with("+json") => Some(Family::Json),
+            other if other.ends_with("+xml") => Some(Family::Xml),
+            _ => None,
+        }
+    }
+
+    fn canonical(self) -> &'static str {
+        match self {
+            Family::Html => "text/html",
+            Family::Json => "application/json",
+            Family::Xml => "application/xml",
+            Family::Any => "*/*",
+        }
+    }
+}
+
+/// Replaces the `Accept` header of a request with the canonical value of the family that the
+/// client prefers. Clients that prefer none of them are treated as accepting anything.
+pub fn normalize(req: &mut Request) {
+    let family = preferred_family(req.get_header_str(header::ACCEPT).unwrap_or_default());
+    req.set_header(header::ACCEPT, family.canonical());
+}
+
+/// Returns the family an `Accept` header prefers. Of families with the same quality, the first
+/// listed is preferred.
+fn preferred_family(accept: &str) -> Family {
+    let mut best = (Family::Any, 0.0);
+    for (media_type, quality) in weighted(accept) {
+        if let Some(family) = Family::of(media_type) {
+            if quality > best.1 {
+                best = (family, quality);
+            }
+        }
+    }
+    best.0
+}
+
+/// Parses a list of weighted values, such as the media ranges of an `Accept` header, into each
+/// value and its quality. Values without a `q` parameter have a quality of 1, and values whose `q`
+/// is not a number from 0 to 1 are left out.
+pub fn weighted(header: &str) -> impl Iterator<Item = (&str, f32)> {
+    header.split(',').filter_map(|item| {
+        let mut params = item.split(';');
+        let value = params.next().unwrap_or_default().trim();
+        if value.is_empty() {
+            return None;
+        }
+        let mut quality = 1.0;
+        for (name, q) in params.filter_map(|param| param.split_once('=')) {
+            if name.trim().eq_ignore_ascii_case("q") {
+                quality = q
+                    .trim()
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|q| (0.0..=1.0).contains(q))?;
+            }
+        }
+        Some((value, quality))
+    })
+}
+
+/// Returns whether an `Accept` header prefers a media type to another one, such as
+/// `application/json` to `text/html`. Each is weighted by the most specific range it matches, and
+/// when both have the same quality, the one matched more specifically is preferred. Clients
+/// without an `Accept` header accept anything, and prefer neither.
+pub fn prefers(accept: Option<&str>, media_type: &str, other: &str) -> bool {
+    let (specificity, quality) = weight(accept, media_type);
+    let (other_specificity, other_quality) = weight(accept, other);
+    quality > other_quality
+        || (quality == other_quality && quality > 0.0 && specificity > other_specificity)
+}
+
+/// Returns how specifically an `Accept` header matches a media type, and with which quality.
+fn weight(accept: Option<&str>, media_type: &str) -> (Option<u8>, f32) {
+    let mut best = (None, 0.0);
+    for (range, quality) in weighted(accept.unwrap_or("*/*")) {
+        let specificity = specificity(&range.to_ascii_lowercase(), media_type);
+        if specificity > best.0
+            || (specificity.is_some() && specificity == best.0 && quality > best.1)
+        {
+            best = (specificity, quality);
+        }
+    }
+    best
+}
+
+/// Returns how specifically a media range matches a media type: 2 for the type itself, or a type
+/// with it as structured syntax suffix, such as `application/problem+json` for
+/// `application/json`, 1 for a subtype wildcard, 0 for `*/*`, and `None` if it does not match.
+fn specificity(range: &str, media_type: &str) -> Option<u8> {
+    if range == "*/*" {
+        return Some(0);
+    }
+    let (range_type, range_subtype) = range.split_once('/')?;
+    let (type_, subtype) = media_type.split_once('/')?;
+    if range_type != type_ {
+        return None;
+    }
+    if range_subtype == "*" {
+        Some(1)
+    } else if range_subtype == subtype || range_subtype.ends_with(&format!("+{subtype}")) {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefers_json(accept: Option<&str>) -> bool {
+        prefers(accept, "application/json", "text/html")
+    }
+
+    #[test]
+    fn json_is_preferred_only_when_asked_for() {
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("text/html;q=0.5, application/json")));
+        assert!(prefers_json(Some(
+            "text/html;q=0.5, application/problem+json"
+        )));
+        assert!(!prefers_json(Some("text/html,application/json;q=0.9")));
+        assert!(!prefers_json(Some(
+            "text/html,application/xhtml+xml,*/*;q=0.8"
+        )));
+        assert!(prefers_json(Some("application/json, */*")));
+        assert!(!prefers_json(Some("application/json;q=0.5, */*")));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(!prefers_json(None));
+    }
+
+    #[test]
+    fn quality_values_are_parsed() {
+        assert_eq!(
+            weighted("text/html, application/json;q=0.5, */*; Q=0").collect::<Vec<_>>(),
+            [("text/html", 1.0), ("application/json", 0.5), ("*/*", 0.0)]
+        );
+        assert_eq!(
+            weighted("gzip;level=1;q=0.8, , br").collect::<Vec<_>>(),
+            [("gzip", 0.8), ("br", 1.0)]
+        );
+    }
+
+    #[test]
+    fn malformed_quality_values_leave_the_range_out() {
+        assert_eq!(
+            weighted("a;q=high, b;q=1.5, c;q=-1, d;q=NaN, e;q=0.3").collect::<Vec<_>>(),
+            [("e", 0.3)]
+        );
+        assert_eq!(preferred_family("application/json;q=x"), Family::Any);
+    }
+
+    #[test]
+    fn refused_types_and_wildcards_are_weighed() {
+        assert!(!prefers_json(Some("application/json;q=0, */*")));
+        assert!(!prefers_json(Some("application/json;q=0")));
+        assert!(prefers_json(Some("application/*, text/*;q=0.5")));
+        assert!(prefers_json(Some("text/html;q=0, */*")));
+        assert_eq!(
+            preferred_family("text/html;q=0, application/xml"),
+            Family::Xml
+        );
+        assert_eq!(preferred_family("*/*, image/webp"), Family::Any);
+    }
+
+    #[test]
+    fn ties_go_to_the_first_or_most_specific_range() {
+        assert_eq!(
+            preferred_family("application/json, text/html"),
+            Family::Json
+        );
+        assert_eq!(
+            preferred_family("text/html;q=0.9, application/json;q=0.9"),
+            Family::Html
+        );
+        assert!(!prefers_json(Some("application/json, text/html")));
+        assert!(!prefers_json(Some("text/*, application/*")));
+        assert!(prefers_json(Some("application/json;q=0.8, text/*;q=0.8")));
+    }
+}
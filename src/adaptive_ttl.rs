@@ -0,0 +1,127 @@
+//! TTLs that adapt to how often content actually changes.
+//!
+//! Every time an object is fetched or revalidated from the origin, its validator (the `ETag`, or
+//! else the `Last-Modified` date) is compared with the one seen last time, and the outcome is
+//! recorded in the `validators` KV Store. The TTL chosen by the other caching policies is then
+//! scaled by how often the object changed over its recent fetches: objects that rarely change are
+//! kept for longer, and objects that change on most fetches for less time, within the bounds set
+//! by the `adaptive_ttl_min` and `adaptive_ttl_max` settings.
+
+use crate::config::Settings;
+use fastly::http::{header, CandidateResponse};
+use fastly::kv_store::KVStore;
+use fastly::Request;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// The name of the KV Store holding validator histories.
+const STORE_NAME: &str = "validators";
+
+/// The shortest adapted TTL, unless overridden by the `adaptive_ttl_min` setting (in seconds).
+const DEFAULT_MIN_SECS: u64 = 10;
+
+/// The longest adapted TTL, unless overridden by the `adaptive_ttl_max` setting (in seconds).
+const DEFAULT_MAX_SECS: u64 = 86_400;
+
+/// The number of recent fetches whose outcome is remembered.
+const HISTORY_LEN: usize = 10;
+
+/// The number of fetches needed before the TTL is adapted.
+const MIN_HISTORY_LEN: usize = 3;
+
+/// The adaptive TTL policy for one URL.
+#[derive(Clone)]
+pub struct AdaptiveTtl {
+    min: Duration,
+    max: Duration,
+    key: String,
+}
+
+impl AdaptiveTtl {
+    /// Returns the adaptive TTL policy for a request, or `None` if the `adaptive_ttl` setting is
+    /// off.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        if !settings.get_bool("adaptive_ttl", false) {
+            return None;
+        }
+        let key = Sha256::digest(req.get_url_str().as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        Some(Self {
+            min: Duration::from_secs(settings.get_u64("adaptive_ttl_min", DEFAULT_MIN_SECS)),
+            max: Duration::from_secs(settings.get_u64("adaptive_ttl_max", DEFAULT_MAX_SECS)),
+            key,
+        })
+    }
+
+    /// Records the validator of a response received from the origin, and scales its TTL by how
+    /// often the object has changed.
+    pub fn apply(&self, resp: &mut CandidateResponse) {
+        if !resp.get_status().is_success() || !resp.is_cacheable() {
+            return;
+        }
+        let Some(validator) = resp
+            .get_header_str(header::ETAG)
+            .or_else(|| resp.get_header_str(header::LAST_MODIFIED))
+            .map(str::to_string)
+        else {
+            return;
+        };
+
+        let store = match KVStore::open(STORE_NAME) {
+            Ok(Some(store)) => store,
+            Ok(None) => {
+                log::warn!("KV Store {STORE_NAME} is not linked; not adapting TTL");
+                return;
+            }
+            Err(e) => {
+                log::warn!("cannot open KV Store {STORE_NAME}: {e}");
+                return;
+            }
+        };
+
+        let history: Value = store
+            .lookup(&self.key)
+            .ok()
+            .and_then(|mut found| serde_json::from_slice(&found.take_body_bytes()).ok())
+            .unwrap_or_default();
+        let mut changes: Vec<bool> = history["changes"]
+            .as_array()
+            .map(|changes| changes.iter().filter_map(Value::as_bool).collect())
+            .unwrap_or_default();
+        if let Some(previous) = history["validator"].as_str() {
+            changes.push(previous != validator);
+        }
+        if changes.len() > HISTORY_LEN {
+            changes.drain(..changes.len() - HISTORY_LEN);
+        }
+
+        let record = json!({ "validator": validator, "changes": changes });
+        if let Err(e) = store.insert(&self.key, record.to_string()) {
+            log::warn!("cannot record validator history: {e}");
+        }
+
+        if changes.len() >= MIN_HISTORY_LEN {
+            let change_rate =
+                changes.iter().filter(|changed| **changed).count() as f64 / changes.len() as f64;
+            let ttl = resp
+                .get_ttl()
+                .mul_f64(ttl_factor(change_rate))
+                .clamp(self.min, self.max.max(self.min));
+            resp.set_ttl(ttl);
+        }
+    }
+}
+
+/// The factor applied to the TTL of an object that changed on the given share of its fetches.
+fn ttl_factor(change_rate: f64) -> f64 {
+    match change_rate {
+        r if r <= 0.0 => 4.0,
+        r if r <= 0.25 => 2.0,
+        r if r >= 0.75 => 0.25,
+        r if r >= 0.5 => 0.5,
+        _ => 1.0,
+    }
+}
@@ -0,0 +1,370 @@
+//! The authenticated `/_edge/*` admin API.
+//!
+//! Operators use these routes to inspect and purge the cache, warm it up, read the effective
+//! settings, check the health of the origin and read the edge hit ratio. Every route requires the
+//! `edge_admin_token` secret as a bearer token and, if the `edge_admin_allowed_ips` setting is
+//! present, a client address within one of the listed addresses or CIDR ranges.
+//!
+//! Responses are JSON objects with an `ok` member. Successful responses carry the route's data
+//! alongside it, and failed ones an `error` code and a human-readable `message`. Every call is
+//! logged as a single JSON audit line.
+
+use crate::config::{self, Settings};
+use crate::{capture, metrics, secrets, signing, surrogate_keys};
+use fastly::experimental::{BackendExt, BackendHealth};
+use fastly::http::{header, purge, Method, StatusCode};
+use fastly::{Backend, Request, Response};
+use serde_json::{json, Value};
+use std::net::IpAddr;
+
+/// The path prefix of the admin API.
+const PREFIX: &str = "/_edge/";
+
+/// The name of the secret holding the admin bearer token.
+const TOKEN_SECRET: &str = "edge_admin_token";
+
+/// The backend whose cache and health the admin API reports on.
+const BACKEND: &str = "origin";
+
+/// The largest number of URLs accepted by one warmup call.
+const MAX_WARMUP_URLS: usize = 50;
+
+/// The number of minutes of metrics reported by default.
+const DEFAULT_METRICS_MINUTES: u64 = 60;
+
+/// The largest number of minutes of metrics reported, matching how long they are kept.
+const MAX_METRICS_MINUTES: u64 = 1440;
+
+/// The response headers reported by cache inspection and warmup.
+const CACHE_HEADERS: [&str; 7] = [
+    "age",
+    "cache-control",
+    "etag",
+    "expires",
+    "last-modified",
+    "surrogate-key",
+    "vary",
+];
+
+/// Returns whether a request is for the admin API.
+pub fn is_admin_request(req: &Request) -> bool {
+    req.get_path().starts_with(PREFIX)
+}
+
+/// A failed admin call, reported to the client as JSON.
+struct Failure {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Authenticates and serves an admin API request.
+pub fn handle(mut req: Request, settings: &Settings) -> Response {
+    let route = req.get_path()[PREFIX.len()..].to_string();
+    let method = req.get_method_str().to_string();
+    let client_ip = req.get_client_ip_addr();
+
+    let result = authorize(&req, client_ip, settings).and_then(|()| {
+        match (req.get_method(), route.as_str()) {
+            (&Method::GET, "cache") => inspect(&req),
+            (&Method::POST, "purge") => purge(&mut req, settings),
+            (&Method::POST, "warmup") => warmup(&mut req),
+            (&Method::GET, "config") => Ok(dump_config(settings)),
+            (&Method::GET, "health") => Ok(health()),
+            (&Method::GET, "captures") => captures(),
+            (&Method::GET, "metrics") => hit_ratio_metrics(&req),
+            _ => Err(Failure::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("no admin route {method} {PREFIX}{route}"),
+            )),
+        }
+    });
+
+    let (status, body) = match result {
+        Ok(Value::Object(mut data)) => {
+            data.insert("ok".into(), true.into());
+            (StatusCode::OK, Value::Object(data))
+        }
+        Ok(data) => (StatusCode::OK, json!({ "ok": true, "data": data })),
+        Err(failure) => (
+            failure.status,
+            json!({ "ok": false, "error": failure.code, "message": failure.message }),
+        ),
+    };
+
+    log::info!(
+        "{}",
+        json!({
+            "audit": "edge_admin",
+            "method": method,
+            "route": route,
+            "client_ip": client_ip.map(|ip| ip.to_string()),
+            "status": status.as_u16(),
+            "error": body.get("error"),
+        })
+    );
+
+    Response::from_status(status)
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_body_json(&body)
+        .unwrap_or_else(|_| Response::from_status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// The shared authentication of every admin route.
+fn authorize(req: &Request, client_ip: Option<IpAddr>, settings: &Settings) -> Result<(), Failure> {
+    let allowed = settings.get_list("edge_admin_allowed_ips");
+    if !allowed.is_empty() && !client_ip.is_some_and(|ip| is_allowed_ip(ip, &allowed)) {
+        return Err(Failure::new(
+            StatusCode::FORBIDDEN,
+            "ip_not_allowed",
+            "the client address is not allowed to use the admin API",
+        ));
+    }
+
+    let Some(expected) = secrets::get(TOKEN_SECRET) else {
+        return Err(Failure::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "not_configured",
+            "the admin API token is not configured",
+        ));
+    };
+    let token = req
+        .get_header_str(header::AUTHORIZATION)
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .trim();
+    if token.is_empty() || !signing::constant_time_eq(token.as_bytes(), &expected) {
+        return Err(Failure::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "a valid bearer token is required",
+        ));
+    }
+    Ok(())
+}
+
+/// Returns whether an address is one of the listed addresses, or within one of the listed CIDR
+/// ranges.
+fn is_allowed_ip(ip: IpAddr, allowed: &[String]) -> bool {
+    let ip = to_v6_bits(ip);
+    allowed.iter().any(|entry| {
+        let (address, prefix) = match entry.split_once('/') {
+            Some((address, prefix)) => (address, prefix.parse::<u32>().ok()),
+            None => (entry.as_str(), None),
+        };
+        let Ok(network) = address.parse::<IpAddr>() else {
+            return false;
+        };
+        let prefix = match (network, prefix) {
+            (_, None) => 128,
+            (IpAddr::V4(_), Some(prefix)) if prefix <= 32 => prefix + 96,
+            (IpAddr::V6(_), Some(prefix)) if prefix <= 128 => prefix,
+            _ => return false,
+        };
+        let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+        ip & mask == to_v6_bits(network) & mask
+    })
+}
+
+/// Returns the bits of an address, with IPv4 addresses mapped into IPv6.
+fn to_v6_bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// `GET /_edge/cache?url=<path>`: fetches a path through the cache and reports how it was served.
+///
+/// The Compute platform offers no way to look into the readthrough cache without going through
+/// it, so inspecting a path that is not cached also fills the cache with it.
+fn inspect(req: &Request) -> Result<Value, Failure> {
+    let path = req
+        .get_query_parameter("url")
+        .filter(|path| path.starts_with('/'))
+        .ok_or_else(|| {
+            Failure::new(
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                "the url query parameter must be a path starting with /",
+            )
+        })?
+        .to_string();
+    fetch_through_cache(req, &path)
+}
+
+/// `POST /_edge/purge` with `{"surrogate_key": "...", "soft": false}`: purges a surrogate key,
+/// and all of its shards if it is sharded.
+fn purge(req: &mut Request, settings: &Settings) -> Result<Value, Failure> {
+    let body: Value = read_json(req)?;
+    let Some(key) = body["surrogate_key"].as_str().filter(|key| !key.is_empty()) else {
+        return Err(Failure::new(
+            StatusCode::BAD_REQUEST,
+            "bad_request",
+            "surrogate_key is required",
+        ));
+    };
+    let soft = body["soft"].as_bool().unwrap_or(false);
+    let keys = surrogate_keys::purge_keys(key, settings);
+    for key in &keys {
+        let result = if soft {
+            purge::soft_purge_surrogate_key(key)
+        } else {
+            purge::purge_surrogate_key(key)
+        };
+        result.map_err(|e| {
+            Failure::new(
+                StatusCode::BAD_GATEWAY,
+                "purge_failed",
+                format!("cannot purge {key}: {e}"),
+            )
+        })?;
+    }
+    Ok(json!({ "surrogate_key": key, "soft": soft, "purged": keys }))
+}
+
+/// `POST /_edge/warmup` with `{"urls": ["/path", ...]}`: fetches paths through the cache so that
+/// they are cached before clients ask for them.
+fn warmup(req: &mut Request) -> Result<Value, Failure> {
+    let body: Value = read_json(req)?;
+    let paths: Vec<&str> = body["urls"]
+        .as_array()
+        .map(|urls| urls.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    if paths.is_empty()
+        || paths.len() > MAX_WARMUP_URLS
+        || !paths.iter().all(|p| p.starts_with('/'))
+    {
+        return Err(Failure::new(
+            StatusCode::BAD_REQUEST,
+            "bad_request",
+            format!("urls must list between 1 and {MAX_WARMUP_URLS} paths starting with /"),
+        ));
+    }
+
+    let results: Vec<Value> = paths
+        .iter()
+        .map(|path| {
+            fetch_through_cache(req, path)
+                .unwrap_or_else(|failure| json!({ "url": path, "error": failure.message }))
+        })
+        .collect();
+    Ok(json!({ "results": results }))
+}
+
+/// `GET /_edge/config`: the effective value of every setting. Unset settings are `null`, meaning
+/// that their default applies.
+fn dump_config(settings: &Settings) -> Value {
+    let values: serde_json::Map<String, Value> = config::KEYS
+        .iter()
+        .map(|key| (key.to_string(), settings.get(key).into()))
+        .collect();
+    json!({ "settings": values })
+}
+
+/// `GET /_edge/health`: the health of the origin backend, as seen by its health check.
+fn health() -> Value {
+    let health = Backend::from_name(BACKEND)
+        .ok()
+        .and_then(|backend| backend.is_healthy().ok());
+    let status = match health {
+        Some(BackendHealth::Healthy) => "healthy",
+        Some(BackendHealth::Unhealthy) => "unhealthy",
+        _ => "unknown",
+    };
+    json!({ "backend": BACKEND, "health": status })
+}
+
+/// `GET /_edge/captures`: the request and response pairs recorded in capture mode, newest first.
+fn captures() -> Result<Value, Failure> {
+    let captures = capture::list().map_err(|e| {
+        Failure::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "captures_unavailable",
+            format!("cannot read captures: {e}"),
+        )
+    })?;
+    Ok(json!({ "captures": captures }))
+}
+
+/// `GET /_edge/metrics?minutes=<n>`: the cache hit, miss and pass counts of the last `n` minutes
+/// (60 by default), with their hit ratios.
+fn hit_ratio_metrics(req: &Request) -> Result<Value, Failure> {
+    let minutes = match req
+        .get_url()
+        .query_pairs()
+        .find(|(name, _)| name == "minutes")
+    {
+        Some((_, value)) => value
+            .parse::<u64>()
+            .ok()
+            .filter(|minutes| (1..=MAX_METRICS_MINUTES).contains(minutes))
+            .ok_or_else(|| {
+                Failure::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_minutes",
+                    format!("minutes must be between 1 and {MAX_METRICS_MINUTES}"),
+                )
+            })?,
+        None => DEFAULT_METRICS_MINUTES,
+    };
+    metrics::recent(minutes).map_err(|e| {
+        Failure::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "metrics_unavailable",
+            format!("cannot read metrics: {e}"),
+        )
+    })
+}
+
+/// Sends a `GET` for a path on the admin request's host through the cache, and reports the
+/// response status and caching headers.
+fn fetch_through_cache(admin_req: &Request, path: &str) -> Result<Value, Failure> {
+    let mut url = admin_req.get_url().clone();
+    url.set_query(None);
+    let url = url.join(path).map_err(|e| {
+        Failure::new(
+            StatusCode::BAD_REQUEST,
+            "bad_request",
+            format!("invalid path {path}: {e}"),
+        )
+    })?;
+
+    let resp = Request::get(url).send(BACKEND).map_err(|e| {
+        Failure::new(
+            StatusCode::BAD_GATEWAY,
+            "origin_error",
+            format!("cannot fetch {path}: {e}"),
+        )
+    })?;
+    let headers: serde_json::Map<String, Value> = CACHE_HEADERS
+        .iter()
+        .filter_map(|name| Some((name.to_string(), resp.get_header_str(*name)?.into())))
+        .collect();
+    Ok(json!({
+        "url": path,
+        "status": resp.get_status().as_u16(),
+        "headers": headers,
+    }))
+}
+
+fn read_json(req: &mut Request) -> Result<Value, Failure> {
+    req.take_body_json().map_err(|e| {
+        Failure::new(
+            StatusCode::BAD_REQUEST,
+            "bad_request",
+            format!("the request body must be a JSON object: {e}"),
+        )
+    })
+}
@@ -0,0 +1,165 @@
+//! Graceful degradation of the after-send callback.
+//!
+//! If the caching policy applied in the after-send callback fails, returning the error to the
+//! readthrough cache would fail the whole request with a 500. Instead, the response is delivered
+//! as an uncacheable pass-through, without any body transform, exactly as the origin sent it. The
+//! headers the policy changed before failing, such as a `Content-Type` set for a transform that
+//! no longer runs, are restored to those of the origin.
+
+use fastly::http::{CandidateResponse, HeaderName, HeaderValue};
+use fastly::Error;
+
+/// The operations needed to turn a candidate response into a pass-through.
+pub trait PassThrough {
+    /// Prevents the response from being stored in the cache.
+    fn set_uncacheable(&mut self, record_uncacheable: bool);
+
+    /// Replaces any body transform with one that copies the body unchanged.
+    fn clear_body_transform(&mut self);
+
+    /// Returns the headers of the response.
+    fn headers(&self) -> Vec<(HeaderName, HeaderValue)>;
+
+    /// Replaces the headers of the response.
+    fn set_headers(&mut self, headers: Vec<(HeaderName, HeaderValue)>);
+}
+
+impl PassThrough for CandidateResponse {
+    fn set_uncacheable(&mut self, record_uncacheable: bool) {
+        CandidateResponse::set_uncacheable(self, record_uncacheable);
+    }
+
+    fn clear_body_transform(&mut self) {
+        self.set_body_transform(|body_in, body_out| {
+            body_out.append(body_in);
+            Ok(())
+        });
+    }
+
+    fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+        self.get_headers()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    fn set_headers(&mut self, headers: Vec<(HeaderName, HeaderValue)>) {
+        let names: Vec<HeaderName> = self.get_header_names().cloned().collect();
+        for name in names {
+            self.remove_header(name);
+        }
+        for (name, value) in headers {
+            self.append_header(name, value);
+        }
+    }
+}
+
+/// Runs an after-send policy, turning the response into a pass-through if the policy fails.
+pub fn run_or_pass<R: PassThrough>(resp: &mut R, policy: impl FnOnce(&mut R) -> Result<(), Error>) {
+    let origin_headers = resp.headers();
+    if let Err(e) = policy(resp) {
+        log::warn!("after-send policy failed, passing the response through uncached: {e}");
+        resp.set_headers(origin_headers);
+        resp.clear_body_transform();
+        resp.set_uncacheable(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeResponse {
+        uncacheable: Option<bool>,
+        has_transform: bool,
+        transform_cleared: bool,
+        headers: Vec<(HeaderName, HeaderValue)>,
+    }
+
+    impl PassThrough for FakeResponse {
+        fn set_uncacheable(&mut self, record_uncacheable: bool) {
+            self.uncacheable = Some(record_uncacheable);
+        }
+
+        fn clear_body_transform(&mut self) {
+            self.has_transform = false;
+            self.transform_cleared = true;
+        }
+
+        fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+            self.headers.clone()
+        }
+
+        fn set_headers(&mut self, headers: Vec<(HeaderName, HeaderValue)>) {
+            self.headers = headers;
+        }
+    }
+
+    #[test]
+    fn successful_policy_leaves_response_alone() {
+        let mut resp = FakeResponse::default();
+        run_or_pass(&mut resp, |resp| {
+            resp.has_transform = true;
+            Ok(())
+        });
+        assert_eq!(resp.uncacheable, None);
+        assert!(resp.has_transform);
+        assert!(!resp.transform_cleared);
+    }
+
+    #[test]
+    fn failing_policy_passes_response_through() {
+        let mut resp = FakeResponse::default();
+        run_or_pass(&mut resp, |_| Err(Error::msg("config store unavailable")));
+        assert_eq!(resp.uncacheable, Some(false));
+        assert!(resp.transform_cleared);
+    }
+
+    #[test]
+    fn failure_after_installing_transform_removes_it() {
+        let mut resp = FakeResponse::default();
+        run_or_pass(&mut resp, |resp| {
+            resp.has_transform = true;
+            let _: serde_json::Value = serde_json::from_str("{not json")?;
+            Ok(())
+        });
+        assert_eq!(resp.uncacheable, Some(false));
+        assert!(!resp.has_transform);
+    }
+
+    #[test]
+    fn failure_after_changing_headers_restores_the_origin_headers() {
+        let json = (
+            HeaderName::from_static("content-type"),
+            HeaderValue::from_static("application/json"),
+        );
+        let mut resp = FakeResponse {
+            headers: vec![json.clone()],
+            ..FakeResponse::default()
+        };
+        run_or_pass(&mut resp, |resp| {
+            resp.headers = vec![
+                (
+                    HeaderName::from_static("content-type"),
+                    HeaderValue::from_static("text/html"),
+                ),
+                (
+                    HeaderName::from_static("content-encoding"),
+                    HeaderValue::from_static("br"),
+                ),
+            ];
+            Err(Error::msg("transform failed"))
+        });
+        assert_eq!(resp.headers, [json]);
+
+        let mut resp = FakeResponse::default();
+        run_or_pass(&mut resp, |resp| {
+            resp.headers = vec![(
+                HeaderName::from_static("x-edge-transformed"),
+                HeaderValue::from_static("json-to-html"),
+            )];
+            Ok(())
+        });
+        assert_eq!(resp.headers.len(), 1);
+    }
+}
@@ -0,0 +1,285 @@
+//! JSON Web Token validation at the edge.
+//!
+//! Requests under the path prefixes in the `jwt_path_prefixes` setting must carry a bearer JWT
+//! in their `Authorization` header. The token is validated before the request reaches the cache,
+//! and requests without a valid one are answered with a synthetic `401 Unauthorized`, so that
+//! neither cached objects nor the origin are ever reached without a valid token.
+//!
+//! Tokens must be signed with HS256, under the key held in the `jwt_key` secret. Their `exp`
+//! claim is required and `nbf` is honored, both with a minute of leeway for clock skew. If the
+//! `jwt_audience` setting is set, the `aud` claim must name it.
+//!
+//! The validated identity is sent to the origin in the headers listed in the `jwt_claim_headers`
+//! setting, as `<claim>=<header>` entries such as `sub=x-user-id`. The headers are set in the
+//! before-send callback, so they never take part in the cache lookup, and any copy of them sent
+//! by the client is removed first, so the origin can trust them. Since the cache is shared, the
+//! origin must still mark responses that depend on the identity as private.
+
+use crate::config::Settings;
+use crate::{secrets, signing};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use fastly::http::{header, StatusCode};
+use fastly::{Request, Response};
+use hmac::{Hmac, Mac};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the secret holding the HS256 key of tokens.
+const KEY_SECRET: &str = "jwt_key";
+
+/// The clock skew tolerated when checking the `exp` and `nbf` claims, in seconds.
+const LEEWAY_SECS: u64 = 60;
+
+/// The identity of a client with a validated token, as headers for the origin.
+#[derive(Clone, Default)]
+pub struct Identity {
+    headers: Vec<(String, String)>,
+}
+
+impl Identity {
+    /// Sets the claim headers of the identity on a request to the origin.
+    pub fn apply(&self, req: &mut Request) {
+        for (name, value) in &self.headers {
+            req.set_header(name, value);
+        }
+    }
+}
+
+/// Validates the bearer token of a request under a protected path.
+///
+/// Returns the identity to send to the origin, `None` if the path is not protected, or the
+/// response to send instead if the token is missing or invalid. Client copies of the claim
+/// headers are removed from every request.
+pub fn authenticate(
+    req: &mut Request,
+    settings: &Settings,
+) -> Result<Option<Identity>, Box<Response>> {
+    let claim_headers: Vec<(String, String)> = settings
+        .get_list("jwt_claim_headers")
+        .iter()
+        .filter_map(|entry| {
+            let (claim, header) = entry.split_once('=')?;
+            Some((claim.trim().to_string(), header.trim().to_ascii_lowercase()))
+        })
+        .collect();
+    for (_, header) in &claim_headers {
+        req.remove_header(header);
+    }
+
+    let path = req.get_path();
+    if !settings
+        .get_list("jwt_path_prefixes")
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+    {
+        return Ok(None);
+    }
+
+    let Some(key) = secrets::get(KEY_SECRET) else {
+        return Err(unauthorized("the token key is not configured"));
+    };
+    let token = req
+        .get_header_str(header::AUTHORIZATION)
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .trim();
+    let claims = validate(token, &key, settings.get("jwt_audience").as_deref(), now()).map_err(
+        |reason| {
+            log::info!("rejecting JWT for {path}: {reason}");
+            unauthorized(reason)
+        },
+    )?;
+
+    let headers = claim_headers
+        .into_iter()
+        .filter_map(|(claim, header)| {
+            let value = match claims.get(&claim)? {
+                Value::String(value) => value.clone(),
+                Value::Null => return None,
+                value => value.to_string(),
+            };
+            Some((header, value))
+        })
+        .collect();
+    Ok(Some(Identity { headers }))
+}
+
+/// Validates a compact HS256 token, returning its claims or why it is invalid.
+fn validate(
+    token: &str,
+    key: &[u8],
+    audience: Option<&str>,
+    now: u64,
+) -> Result<Map<String, Value>, &'static str> {
+    if token.is_empty() {
+        return Err("a bearer token is required");
+    }
+    let Some((signed, signature)) = token.rsplit_once('.') else {
+        return Err("the token is malformed");
+    };
+    let Some((header, payload)) = signed.split_once('.').filter(|(_, p)| !p.contains('.')) else {
+        return Err("the token is malformed");
+    };
+
+    let header: Value = decode_part(header).ok_or("the token header is malformed")?;
+    if header["alg"] != "HS256" {
+        return Err("the token must be signed with HS256");
+    }
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| "the token signature is malformed")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(|_| "the token key is invalid")?;
+    mac.update(signed.as_bytes());
+    let expected = mac.finalize().into_bytes();
+    if !signing::constant_time_eq(&expected, &signature) {
+        return Err("the token signature is invalid");
+    }
+
+    let Some(Value::Object(claims)) = decode_part::<Value>(payload) else {
+        return Err("the token claims are malformed");
+    };
+    let Some(expires) = claims.get("exp").and_then(Value::as_u64) else {
+        return Err("the token has no expiry");
+    };
+    if now > expires.saturating_add(LEEWAY_SECS) {
+        return Err("the token has expired");
+    }
+    if let Some(not_before) = claims.get("nbf").and_then(Value::as_u64) {
+        if now.saturating_add(LEEWAY_SECS) < not_before {
+            return Err("the token is not valid yet");
+        }
+    }
+    if let Some(audience) = audience {
+        let matches = match claims.get("aud") {
+            Some(Value::String(aud)) => aud == audience,
+            Some(Value::Array(auds)) => auds.iter().any(|aud| aud == audience),
+            _ => false,
+        };
+        if !matches {
+            return Err("the token is not meant for this audience");
+        }
+    }
+    Ok(claims)
+}
+
+/// Decodes a base64url JSON part of a token.
+fn decode_part<T: serde::de::DeserializeOwned>(part: &str) -> Option<T> {
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).ok()?).ok()
+}
+
+/// Returns the current Unix time, in seconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Returns the response to requests without a valid token.
+fn unauthorized(reason: &str) -> Box<Response> {
+    let resp = Response::from_status(StatusCode::UNAUTHORIZED)
+        .with_header(
+            header::WWW_AUTHENTICATE,
+            format!(r#"Bearer error="invalid_token", error_description="{reason}""#),
+        )
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_body_text_plain(&format!("{reason}\n"));
+    Box::new(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const KEY: &[u8] = b"secret";
+    const NOW: u64 = 1_700_000_000;
+
+    fn token(header: Value, claims: Value, key: &[u8]) -> String {
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(signed.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{signed}.{signature}")
+    }
+
+    fn hs256(claims: Value) -> String {
+        token(json!({ "alg": "HS256", "typ": "JWT" }), claims, KEY)
+    }
+
+    #[test]
+    fn valid_tokens_are_accepted() {
+        let claims = validate(
+            &hs256(json!({ "sub": "user-1", "exp": NOW + 60, "aud": ["web", "api"] })),
+            KEY,
+            Some("api"),
+            NOW,
+        )
+        .unwrap();
+        assert_eq!(claims["sub"], "user-1");
+    }
+
+    #[test]
+    fn forged_tokens_are_rejected() {
+        let forged = token(
+            json!({ "alg": "HS256" }),
+            json!({ "exp": NOW + 60 }),
+            b"other",
+        );
+        assert_eq!(
+            validate(&forged, KEY, None, NOW),
+            Err("the token signature is invalid")
+        );
+        let unsigned = token(json!({ "alg": "none" }), json!({ "exp": NOW + 60 }), KEY);
+        assert_eq!(
+            validate(&unsigned, KEY, None, NOW),
+            Err("the token must be signed with HS256")
+        );
+        assert_eq!(
+            validate("a.b", KEY, None, NOW),
+            Err("the token is malformed")
+        );
+    }
+
+    #[test]
+    fn claims_are_checked() {
+        let expired = hs256(json!({ "exp": NOW - LEEWAY_SECS - 1 }));
+        assert_eq!(
+            validate(&expired, KEY, None, NOW),
+            Err("the token has expired")
+        );
+        let skewed = hs256(json!({ "exp": NOW - 10 }));
+        assert!(validate(&skewed, KEY, None, NOW).is_ok());
+        let early = hs256(json!({ "exp": NOW + 600, "nbf": NOW + 300 }));
+        assert_eq!(
+            validate(&early, KEY, None, NOW),
+            Err("the token is not valid yet")
+        );
+        let no_expiry = hs256(json!({ "sub": "user-1" }));
+        assert_eq!(
+            validate(&no_expiry, KEY, None, NOW),
+            Err("the token has no expiry")
+        );
+        let other_audience = hs256(json!({ "exp": NOW + 60, "aud": "web" }));
+        assert_eq!(
+            validate(&other_audience, KEY, Some("api"), NOW),
+            Err("the token is not meant for this audience")
+        );
+    }
+
+    #[test]
+    fn far_future_claims_do_not_overflow() {
+        let never_expires = hs256(json!({ "exp": u64::MAX }));
+        assert!(validate(&never_expires, KEY, None, NOW).is_ok());
+        let never_valid = hs256(json!({ "exp": u64::MAX, "nbf": u64::MAX }));
+        assert_eq!(
+            validate(&never_valid, KEY, None, NOW),
+            Err("the token is not valid yet")
+        );
+    }
+}
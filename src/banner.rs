@@ -0,0 +1,181 @@
+//! Site-wide breaking-news banner, injected into cached article pages at delivery time.
+//!
+//! The banner is kept out of the cached pages, so editors can switch it on and off with the
+//! `breaking_news_banner` setting, or change its content in the KV Store, without purging any
+//! page.
+//!
+//! Pages compressed with gzip or Brotli, such as those compressed at the edge, are decompressed
+//! before the banner is inserted and compressed again with the same coding. The validators and
+//! length of the origin page are dropped, since they describe the page without the banner.
+
+use crate::compression::{self, Coding};
+use crate::config::Settings;
+use crate::transform;
+use fastly::http::{header, HeaderName};
+use fastly::kv_store::KVStore;
+use fastly::{mime, Request, Response};
+use std::io;
+
+/// The name of the KV Store that holds editorial content.
+const STORE_NAME: &str = "editorial";
+
+/// The KV Store key holding the banner HTML.
+const BANNER_KEY: &str = "breaking_news";
+
+/// The path prefix of article pages when the `article_path_prefix` setting is absent.
+const DEFAULT_ARTICLE_PATH_PREFIX: &str = "/articles/";
+
+/// Returns whether the request is for an article page.
+pub fn is_article_page(req: &Request, settings: &Settings) -> bool {
+    let prefix = settings
+        .get("article_path_prefix")
+        .unwrap_or_else(|| DEFAULT_ARTICLE_PATH_PREFIX.to_string());
+    req.get_path().starts_with(&prefix)
+}
+
+/// Inserts the breaking-news banner at the start of the `<body>` of an HTML article page, if the
+/// `breaking_news_banner` setting is on.
+pub fn inject(resp: Response, settings: &Settings) -> Response {
+    if !settings.get_bool("breaking_news_banner", false)
+        || !resp.get_status().is_success()
+        || !is_insertable_html(&resp)
+    {
+        return resp;
+    }
+
+    let Some(banner) = load_banner() else {
+        return resp;
+    };
+    insert_at_body_start(resp, &banner)
+}
+
+/// Inserts HTML at the start of the `<body>` of an HTML response, which is then re-encoded as
+/// UTF-8, without the validators of the origin page. Other responses, and responses encoded with
+/// a coding other than gzip or Brotli, are returned unchanged.
+pub fn insert_at_body_start(mut resp: Response, html: &str) -> Response {
+    if !is_insertable_html(&resp) {
+        return resp;
+    }
+
+    let content_type = resp
+        .get_header_str(header::CONTENT_TYPE)
+        .unwrap_or("text/html")
+        .to_string();
+    let coding = Coding::of(resp.get_header_str(header::CONTENT_ENCODING));
+    let body = resp.take_body_bytes();
+    match insert_into_page(body.clone(), &content_type, coding, html) {
+        Ok(page) => resp.set_body(page),
+        Err(e) => {
+            log::warn!("cannot insert into an encoded page: {e}");
+            resp.set_body(body);
+            return resp;
+        }
+    }
+    resp.set_header(
+        header::CONTENT_TYPE,
+        transform::with_utf8_charset(&content_type),
+    );
+    let origin_body_headers: Vec<HeaderName> = resp
+        .get_header_names()
+        .filter(|name| describes_origin_body(name))
+        .cloned()
+        .collect();
+    for name in origin_body_headers {
+        resp.remove_header(name);
+    }
+    resp
+}
+
+/// Returns whether a header describes the body as the origin sent it, and no longer holds once
+/// HTML is inserted into it.
+fn describes_origin_body(name: &HeaderName) -> bool {
+    [header::CONTENT_LENGTH, header::ETAG, header::LAST_MODIFIED].contains(name)
+}
+
+/// Inserts HTML at the start of the `<body>` of a page, decoding and encoding it again with its
+/// coding.
+fn insert_into_page(
+    body: Vec<u8>,
+    content_type: &str,
+    coding: Option<Coding>,
+    html: &str,
+) -> io::Result<Vec<u8>> {
+    let body = compression::decode(coding, body)?;
+    let mut page = transform::decode_text(&body, transform::charset(Some(content_type)));
+    if let Some(position) = body_content_start(&page) {
+        page.insert_str(position, html);
+    }
+    compression::encode(coding, page.into_bytes())
+}
+
+fn is_insertable_html(resp: &Response) -> bool {
+    resp.get_content_type()
+        .map(|mime| mime.essence_str().to_string())
+        == Some(mime::TEXT_HTML.essence_str().to_string())
+        && transform::is_transformable_encoding(resp.get_header_str(header::CONTENT_ENCODING))
+}
+
+fn load_banner() -> Option<String> {
+    let store = match KVStore::open(STORE_NAME) {
+        Ok(Some(store)) => store,
+        Ok(None) => {
+            log::warn!("KV Store {STORE_NAME} is not linked");
+            return None;
+        }
+        Err(e) => {
+            log::warn!("cannot open KV Store {STORE_NAME}: {e}");
+            return None;
+        }
+    };
+    let mut found = store.lookup(BANNER_KEY).ok()?;
+    Some(String::from_utf8_lossy(&found.take_body_bytes()).into_owned())
+}
+
+/// Returns the position just after the opening `<body>` tag.
+fn body_content_start(page: &str) -> Option<usize> {
+    let tag_start = page.to_ascii_lowercase().find("<body")?;
+    let tag_end = page[tag_start..].find('>')?;
+    Some(tag_start + tag_end + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_goes_after_the_opening_body_tag() {
+        let page = insert_into_page(
+            b"<html><BODY class=\"article\"><p>Story</p></BODY></html>".to_vec(),
+            "text/html",
+            None,
+            "<aside>News</aside>",
+        )
+        .unwrap();
+        assert_eq!(
+            page,
+            b"<html><BODY class=\"article\"><aside>News</aside><p>Story</p></BODY></html>"
+        );
+        assert_eq!(body_content_start("<p>No body</p>"), None);
+    }
+
+    #[test]
+    fn compressed_pages_keep_their_coding() {
+        for coding in [Coding::Gzip, Coding::Brotli] {
+            let body = compression::encode(Some(coding), b"<body><p>Story</p></body>".to_vec());
+            let page = insert_into_page(body.unwrap(), "text/html", Some(coding), "<aside/>");
+            assert_eq!(
+                compression::decode(Some(coding), page.unwrap()).unwrap(),
+                b"<body><aside/><p>Story</p></body>"
+            );
+        }
+    }
+
+    #[test]
+    fn origin_validators_are_dropped() {
+        assert!(describes_origin_body(&header::ETAG));
+        assert!(describes_origin_body(&header::LAST_MODIFIED));
+        assert!(describes_origin_body(&header::CONTENT_LENGTH));
+        assert!(!describes_origin_body(&header::CONTENT_TYPE));
+        assert!(!describes_origin_body(&header::CACHE_CONTROL));
+    }
+}
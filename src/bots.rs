@@ -0,0 +1,12 @@
+//! Classification of automated clients, based on Fastly Bot Management.
+
+use fastly::Request;
+
+/// Returns whether the request comes from an automated client that is not a verified bot, such
+/// as a price scraper.
+///
+/// Verified bots, like the crawlers of the major search engines, are treated as regular clients.
+/// If bot detection has not been enabled for the service, no client is considered a scraper.
+pub fn is_scraper(req: &Request) -> bool {
+    req.get_bot_detected() && req.get_bot_verified() != Some(true)
+}
@@ -0,0 +1,175 @@
+//! Explicit cache keys for the readthrough cache.
+//!
+//! By default, the readthrough cache derives the cache key from the request itself. Features that
+//! need to control where a response is cached, such as per-tenant partitioning or sharing one
+//! object between the hops of a redirect chain, set an explicit key derived here instead.
+//!
+//! With the `cache_key_normalization` setting on, the URL is also normalized before it is hashed
+//! into the key, so that URLs that only differ in ways the origin ignores share one cached object:
+//! the host is lowercased, the query parameters listed in `cache_key_ignored_params` are left out,
+//! and the remaining ones are sorted. The request sent to the origin is unchanged.
+//!
+//! The case of the path is kept: routes, gates and the origin all see it as it is, so folding it
+//! in the key alone would let `/Admin` be answered from the object cached for `/admin`. Paths are
+//! lowercased for everything at once by `url_normalization`, with `lowercase_paths` on.
+
+use crate::config::Settings;
+use crate::query;
+use fastly::http::Url;
+use sha2::{Digest, Sha256};
+
+/// The normalization of URLs in cache keys.
+pub struct Normalization {
+    /// The names of the query parameters left out of the key. Names ending with `*` match any
+    /// parameter starting with the rest of the name.
+    ignored_params: Vec<String>,
+}
+
+impl Normalization {
+    /// Returns the normalization of cache keys, or `None` if they are not normalized.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        settings
+            .get_bool("cache_key_normalization", false)
+            .then(|| Self {
+                ignored_params: settings.get_list("cache_key_ignored_params"),
+            })
+    }
+
+    /// Returns the normalized form of a URL.
+    pub fn apply(&self, url: &Url) -> String {
+        let mut params: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| !query::is_listed(name, &self.ignored_params))
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        params.sort();
+
+        let mut normalized = format!(
+            "{}://{}",
+            url.scheme(),
+            url.host_str().unwrap_or_default().to_ascii_lowercase()
+        );
+        if let Some(port) = url.port() {
+            normalized.push_str(&format!(":{port}"));
+        }
+        normalized.push_str(url.path());
+        if !params.is_empty() {
+            let mut query = Url::parse("http://localhost/").expect("the URL is valid");
+            query.query_pairs_mut().extend_pairs(params);
+            normalized.push('?');
+            normalized.push_str(query.query().unwrap_or_default());
+        }
+        normalized
+    }
+}
+
+/// Derives a cache key from a request URL, within an optional namespace, and normalized if cache
+/// keys are normalized.
+///
+/// Requests for the same URL in different namespaces never share a cached object.
+pub fn derive(
+    url: &Url,
+    namespace: Option<&str>,
+    normalization: Option<&Normalization>,
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    if let Some(namespace) = namespace {
+        hasher.update(namespace.as_bytes());
+        hasher.update([0]);
+    }
+    match normalization {
+        Some(normalization) => hasher.update(normalization.apply(url).as_bytes()),
+        None => hasher.update(url.as_str().as_bytes()),
+    }
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(url: &str, ignored_params: &[&str]) -> String {
+        let normalization = Normalization {
+            ignored_params: ignored_params.iter().map(|name| name.to_string()).collect(),
+        };
+        normalization.apply(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn query_parameters_are_sorted() {
+        assert_eq!(
+            normalize("https://example.com/search?q=shoes&page=2&color=red", &[]),
+            "https://example.com/search?color=red&page=2&q=shoes"
+        );
+        assert_eq!(
+            normalize("https://example.com/search?page=2&color=red&q=shoes", &[]),
+            normalize("https://example.com/search?q=shoes&color=red&page=2", &[])
+        );
+    }
+
+    #[test]
+    fn repeated_parameters_are_sorted_by_value() {
+        assert_eq!(
+            normalize("https://example.com/?tag=b&tag=a", &[]),
+            normalize("https://example.com/?tag=a&tag=b", &[])
+        );
+    }
+
+    #[test]
+    fn ignored_parameters_are_left_out() {
+        assert_eq!(
+            normalize(
+                "https://example.com/page?utm_source=mail&id=7&utm_medium=x&ref=home",
+                &["utm_*", "ref"]
+            ),
+            "https://example.com/page?id=7"
+        );
+        assert_eq!(
+            normalize("https://example.com/page?ref=home", &["ref"]),
+            "https://example.com/page"
+        );
+        assert_eq!(
+            normalize("https://example.com/page?referrer=home", &["ref"]),
+            "https://example.com/page?referrer=home"
+        );
+    }
+
+    #[test]
+    fn only_the_host_is_lowercased() {
+        assert_eq!(
+            normalize("https://Example.COM/Products/Shoes", &[]),
+            "https://example.com/Products/Shoes"
+        );
+    }
+
+    #[test]
+    fn gated_paths_in_another_case_have_their_own_key() {
+        let normalization = Normalization {
+            ignored_params: Vec::new(),
+        };
+        let key = |url: &str| derive(&Url::parse(url).unwrap(), None, Some(&normalization));
+        assert_ne!(
+            key("https://example.com/Admin/reports"),
+            key("https://example.com/admin/reports")
+        );
+        assert_eq!(
+            key("https://EXAMPLE.com/admin/reports"),
+            key("https://example.com/admin/reports")
+        );
+    }
+
+    #[test]
+    fn port_is_kept() {
+        assert_eq!(
+            normalize("http://example.com:8080/a?b=1", &[]),
+            "http://example.com:8080/a?b=1"
+        );
+    }
+
+    #[test]
+    fn encoding_is_stable() {
+        let once = normalize("https://example.com/?q=a%20b&x=%2F", &[]);
+        assert_eq!(once, "https://example.com/?q=a+b&x=%2F");
+        assert_eq!(normalize(&once, &[]), once);
+    }
+}
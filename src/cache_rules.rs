@@ -0,0 +1,408 @@
+//! A declarative rule engine for caching policies.
+//!
+//! Rules are a JSON array, read from the `cache_rules` setting so that they can be changed
+//! without a redeploy, or else from the `cache_rules.json` file embedded in the service:
+//!
+//! ```json
+//! [
+//!   { "path": "/assets/*", "ttl": 86400, "swr": 3600, "surrogate_keys": ["assets"] },
+//!   { "path": "/search*", "uncacheable": true },
+//!   { "method": ["POST", "PUT"], "path": "/api/*", "pass": true },
+//!   { "path": "/api/*", "status": [404, 410], "ttl": 30 },
+//!   { "path": "/graphql", "operation": "Product*", "ttl": 15 },
+//!   { "content_type": "image/*", "ttl": 604800, "vary": ["Accept"] }
+//! ]
+//! ```
+//!
+//! A rule matches on any of:
+//!
+//! * `path`, a pattern matched against the whole request path, in which `*` matches any run of
+//!   characters, including none;
+//! * `method`, a request method or an array of them;
+//! * `operation`, a pattern matched against the name of a GraphQL query (see the
+//!   [`graphql`](crate::graphql) module). Other requests never match it;
+//! * `status`, a status of the origin response or an array of them. A `304 Not Modified` that
+//!   revalidates a stored object matches as a `200`;
+//! * `content_type`, a pattern matched against the media type of the origin response, such as
+//!   `image/*`.
+//!
+//! Conditions left out match anything. The actions of a rule are:
+//!
+//! * `ttl`, the TTL of responses, in seconds, replacing the one chosen for their content type;
+//! * `swr`, the stale-while-revalidate window, in seconds, replacing the one the origin asks for;
+//! * `uncacheable`, which keeps responses out of the cache;
+//! * `pass`, which sends requests straight to the origin, bypassing the cache;
+//! * `surrogate_keys`, an array of surrogate keys added to those of responses;
+//! * `vary`, an array of request headers the cached responses vary on.
+//!
+//! Rules are evaluated in the after-send callback, where the first rule matching the request and
+//! the origin response applies. Since `pass` has to be decided before the cache lookup, it can
+//! only be used in rules that match on the request alone, and it applies when the first of those
+//! rules to match the request is a pass rule.
+//!
+//! A document that cannot be parsed is logged and ignored as a whole, rather than partially
+//! applied.
+
+use crate::config::Settings;
+use fastly::http::{CandidateResponse, HeaderName, StatusCode};
+use fastly::Request;
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+/// The rules applied unless the `cache_rules` setting holds others.
+const EMBEDDED_RULES: &str = include_str!("../cache_rules.json");
+
+/// The members a rule may have.
+const RULE_MEMBERS: [&str; 11] = [
+    "path",
+    "method",
+    "operation",
+    "status",
+    "content_type",
+    "ttl",
+    "swr",
+    "uncacheable",
+    "pass",
+    "surrogate_keys",
+    "vary",
+];
+
+/// One rule of the rules document.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheRule {
+    path: Option<String>,
+    methods: Vec<String>,
+    operation: Option<String>,
+    statuses: Vec<u16>,
+    content_type: Option<String>,
+    /// The TTL of responses, replacing the one chosen by content type.
+    pub ttl: Option<Duration>,
+    swr: Option<Duration>,
+    uncacheable: bool,
+    pass: bool,
+    surrogate_keys: Vec<String>,
+    vary: Vec<HeaderName>,
+}
+
+/// The rules that can apply to a request.
+#[derive(Clone)]
+pub struct CacheRules {
+    rules: Vec<CacheRule>,
+}
+
+impl CacheRules {
+    /// Returns the rules matching the method and path of a request, and the name of its GraphQL
+    /// operation, if any, or `None` if none does or the rules document is not valid.
+    pub fn for_request(
+        req: &Request,
+        operation: Option<&str>,
+        settings: &Settings,
+    ) -> Option<Self> {
+        let document = settings
+            .get("cache_rules")
+            .unwrap_or_else(|| EMBEDDED_RULES.to_string());
+        let rules = parse(&document)
+            .inspect_err(|e| log::warn!("ignoring invalid cache rules: {e}"))
+            .ok()?;
+        let rules: Vec<CacheRule> = rules
+            .into_iter()
+            .filter(|rule| rule.matches_request(req.get_method_str(), req.get_path(), operation))
+            .collect();
+        (!rules.is_empty()).then_some(Self { rules })
+    }
+
+    /// Returns whether the request should bypass the cache.
+    pub fn pass(&self) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| !rule.matches_on_response())
+            .is_some_and(|rule| rule.pass)
+    }
+
+    /// Returns the first rule matching a response from the origin.
+    pub fn for_response(
+        &self,
+        status: StatusCode,
+        content_type: Option<&str>,
+    ) -> Option<&CacheRule> {
+        let status = if status == StatusCode::NOT_MODIFIED {
+            StatusCode::OK
+        } else {
+            status
+        };
+        self.rules
+            .iter()
+            .find(|rule| rule.matches_response(status.as_u16(), content_type))
+    }
+}
+
+impl CacheRule {
+    /// Applies the stale-while-revalidate window, the uncacheable flag, the surrogate keys and
+    /// the vary headers of the rule. The TTL is applied by the caller, in place of the one chosen
+    /// by content type.
+    pub fn apply(&self, resp: &mut CandidateResponse) {
+        if let Some(swr) = self.swr {
+            resp.set_stale_while_revalidate(swr);
+        }
+        if self.uncacheable {
+            resp.set_uncacheable(false);
+        }
+        if !self.surrogate_keys.is_empty() {
+            let mut keys: Vec<String> = resp.get_surrogate_keys().map(str::to_string).collect();
+            for key in &self.surrogate_keys {
+                if !keys.contains(key) {
+                    keys.push(key.clone());
+                }
+            }
+            resp.set_surrogate_keys(keys.iter().map(String::as_str));
+        }
+        for name in &self.vary {
+            resp.push_vary(name);
+        }
+    }
+
+    /// Returns whether the rule matches a request method and path, and a GraphQL operation name.
+    fn matches_request(&self, method: &str, path: &str, operation: Option<&str>) -> bool {
+        self.operation.as_ref().is_none_or(|pattern| {
+            operation.is_some_and(|operation| matches(pattern.as_bytes(), operation.as_bytes()))
+        }) && self
+            .path
+            .as_ref()
+            .is_none_or(|pattern| matches(pattern.as_bytes(), path.as_bytes()))
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(method)))
+    }
+
+    /// Returns whether the rule has conditions on the response.
+    fn matches_on_response(&self) -> bool {
+        !self.statuses.is_empty() || self.content_type.is_some()
+    }
+
+    /// Returns whether the rule matches the status and content type of a response.
+    fn matches_response(&self, status: u16, content_type: Option<&str>) -> bool {
+        let media_type = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        (self.statuses.is_empty() || self.statuses.contains(&status))
+            && self.content_type.as_ref().is_none_or(|pattern| {
+                matches(
+                    pattern.to_ascii_lowercase().as_bytes(),
+                    media_type.as_bytes(),
+                )
+            })
+    }
+}
+
+/// Parses a rules document into its rules, in order.
+fn parse(document: &str) -> Result<Vec<CacheRule>, String> {
+    let document: Value =
+        serde_json::from_str(document).map_err(|e| format!("not valid JSON: {e}"))?;
+    let rules = document
+        .as_array()
+        .ok_or("the rules must be a JSON array")?;
+    rules
+        .iter()
+        .map(|rule| parse_rule(rule.as_object().ok_or("every rule must be a JSON object")?))
+        .collect()
+}
+
+/// Parses one rule of a rules document.
+fn parse_rule(rule: &Map<String, Value>) -> Result<CacheRule, String> {
+    if let Some(member) = rule
+        .keys()
+        .find(|member| !RULE_MEMBERS.contains(&member.as_str()))
+    {
+        return Err(format!("unknown member {member:?}"));
+    }
+    let string = |member: &str| match rule.get(member) {
+        None => Ok(None),
+        Some(value) => value
+            .as_str()
+            .map(|value| Some(value.to_string()))
+            .ok_or(format!("{member} must be a string")),
+    };
+    let strings = |member: &str| match rule.get(member) {
+        None => Ok(Vec::new()),
+        Some(Value::String(value)) => Ok(vec![value.clone()]),
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|value| value.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(format!("{member} must be a string or an array of strings")),
+        Some(_) => Err(format!("{member} must be a string or an array of strings")),
+    };
+    let secs = |member: &str| match rule.get(member) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .ok_or(format!("{member} must be a number of seconds")),
+    };
+    let flag = |member: &str| match rule.get(member) {
+        None => Ok(false),
+        Some(value) => value.as_bool().ok_or(format!("{member} must be a boolean")),
+    };
+
+    let path = string("path")?;
+    if path.as_ref().is_some_and(|path| !path.starts_with('/')) {
+        return Err("path patterns must start with /".to_string());
+    }
+    let statuses = match rule.get("status") {
+        None => Some(Vec::new()),
+        Some(Value::Array(values)) => values.iter().map(parse_status).collect(),
+        Some(value) => parse_status(value).map(|status| vec![status]),
+    }
+    .ok_or("status must be an HTTP status or an array of them")?;
+    let vary = strings("vary")?
+        .iter()
+        .map(|name| {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("{name:?} is not a header name"))
+        })
+        .collect::<Result<_, _>>()?;
+
+    let rule = CacheRule {
+        path,
+        methods: strings("method")?,
+        operation: string("operation")?,
+        statuses,
+        content_type: string("content_type")?,
+        ttl: secs("ttl")?,
+        swr: secs("swr")?,
+        uncacheable: flag("uncacheable")?,
+        pass: flag("pass")?,
+        surrogate_keys: strings("surrogate_keys")?,
+        vary,
+    };
+    if rule.pass && rule.matches_on_response() {
+        return Err("pass rules cannot match on the response".to_string());
+    }
+    Ok(rule)
+}
+
+fn parse_status(value: &Value) -> Option<u16> {
+    value
+        .as_u64()
+        .and_then(|status| u16::try_from(status).ok())
+        .filter(|status| (100..=599).contains(status))
+}
+
+/// Returns whether a path matches a pattern, in which `*` matches any run of characters.
+pub fn matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((b'*', rest)) => (0..=path.len()).any(|skip| matches(rest, &path[skip..])),
+        Some((c, rest)) => path.first() == Some(c) && matches(rest, &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(document: &str, method: &str, path: &str) -> CacheRules {
+        CacheRules {
+            rules: parse(document)
+                .unwrap()
+                .into_iter()
+                .filter(|rule| rule.matches_request(method, path, None))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn first_rule_matching_the_response_applies() {
+        let document = r#"[
+            { "path": "/api/*", "status": [404, 410], "ttl": 30 },
+            { "path": "/api/*", "content_type": "application/*", "ttl": 300 },
+            { "path": "/api/*", "ttl": 60 }
+        ]"#;
+        let rules = rules(document, "GET", "/api/items");
+        let ttl = |status, content_type| {
+            rules
+                .for_response(StatusCode::from_u16(status).unwrap(), content_type)
+                .and_then(|rule| rule.ttl)
+                .map(|ttl| ttl.as_secs())
+        };
+        assert_eq!(ttl(410, Some("application/json")), Some(30));
+        assert_eq!(ttl(200, Some("Application/JSON; charset=utf-8")), Some(300));
+        assert_eq!(ttl(304, Some("application/json")), Some(300));
+        assert_eq!(ttl(200, Some("text/html")), Some(60));
+    }
+
+    #[test]
+    fn rules_match_on_method_and_path() {
+        let document = r#"[{ "method": ["post", "PUT"], "path": "/api/*", "pass": true }]"#;
+        assert!(rules(document, "POST", "/api/items").pass());
+        assert!(rules(document, "GET", "/api/items").rules.is_empty());
+        assert!(rules(document, "PUT", "/apiv2").rules.is_empty());
+    }
+
+    #[test]
+    fn rules_match_on_graphql_operation() {
+        let document = r#"[
+            { "operation": "Product*", "ttl": 15 },
+            { "path": "/graphql", "ttl": 60 }
+        ]"#;
+        let ttl = |operation| {
+            let rules: Vec<CacheRule> = parse(document)
+                .unwrap()
+                .into_iter()
+                .filter(|rule| rule.matches_request("GET", "/graphql", operation))
+                .collect();
+            rules[0].ttl.map(|ttl| ttl.as_secs())
+        };
+        assert_eq!(ttl(Some("ProductPage")), Some(15));
+        assert_eq!(ttl(Some("Cart")), Some(60));
+        assert_eq!(ttl(None), Some(60));
+    }
+
+    #[test]
+    fn pass_applies_when_it_is_the_first_request_rule() {
+        let document = r#"[
+            { "path": "/api/*", "status": 404, "ttl": 30 },
+            { "path": "/api/private/*", "pass": true },
+            { "path": "/api/*", "ttl": 60 }
+        ]"#;
+        assert!(rules(document, "GET", "/api/private/me").pass());
+        assert!(!rules(document, "GET", "/api/items").pass());
+    }
+
+    #[test]
+    fn actions_are_parsed() {
+        let rule = &parse(
+            r#"[{ "path": "/assets/*", "ttl": 86400, "swr": 3600,
+                  "surrogate_keys": "assets", "vary": ["Accept"] }]"#,
+        )
+        .unwrap()[0];
+        assert_eq!(rule.ttl, Some(Duration::from_secs(86400)));
+        assert_eq!(rule.swr, Some(Duration::from_secs(3600)));
+        assert_eq!(rule.surrogate_keys, vec!["assets"]);
+        assert_eq!(rule.vary, vec![HeaderName::from_static("accept")]);
+    }
+
+    #[test]
+    fn invalid_documents_are_rejected() {
+        for document in [
+            "{}",
+            r#"[{ "path": "assets/*" }]"#,
+            r#"[{ "path": "/*", "ttl": "long" }]"#,
+            r#"[{ "path": "/*", "status": 999 }]"#,
+            r#"[{ "path": "/*", "vary": ["not a header"] }]"#,
+            r#"[{ "path": "/*", "cache": true }]"#,
+            r#"[{ "status": 404, "pass": true }]"#,
+        ] {
+            assert!(parse(document).is_err(), "{document}");
+        }
+    }
+
+    #[test]
+    fn embedded_rules_are_valid() {
+        assert!(parse(EMBEDDED_RULES).is_ok());
+    }
+}
@@ -0,0 +1,209 @@
+//! The RFC 9211 `Cache-Status` response header.
+//!
+//! Every response delivered through the cache gets a `Cache-Status` member describing what the
+//! edge did with the request: whether it was a hit, and otherwise why it was forwarded to the
+//! origin, what status the origin answered with, whether the response was stored, and its
+//! remaining freshness lifetime. The member is appended after any `Cache-Status` members of caches
+//! closer to the origin, and replaces the non-standard `X-Cache` and `X-Cache-Hits` headers.
+//!
+//! The cache is named by the `cache_status_name` setting.
+//!
+//! For debugging, the `x_cache_headers` setting keeps the `X-Cache` and `X-Cache-Hits` headers
+//! instead, rewritten from what this cache saw: `HIT` for responses served from the cache,
+//! including those revalidated with the origin, `MISS` for responses fetched and stored, `PASS`
+//! for responses fetched without being stored, and `STALE` for stale responses served while the
+//! origin failed or was revalidated in the background. Responses served from the cache also get
+//! an `Age` header with the age of the object.
+
+use crate::config::Settings;
+use crate::stale;
+use fastly::http::{header, CandidateResponse, StatusCode};
+use fastly::Response;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The name of the cache in `Cache-Status` members, unless overridden by the `cache_status_name`
+/// setting.
+const DEFAULT_CACHE_NAME: &str = "fastly-edge";
+
+/// The response header carrying the cache status.
+const CACHE_STATUS_HEADER: &str = "cache-status";
+
+/// The non-standard cache header carrying the cache disposition.
+const X_CACHE_HEADER: &str = "x-cache";
+
+/// The non-standard cache header carrying the number of hits of the object.
+const X_CACHE_HITS_HEADER: &str = "x-cache-hits";
+
+/// What the cache did with a request, as reported in the `X-Cache` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disposition {
+    Hit,
+    Miss,
+    Pass,
+    Stale,
+}
+
+impl Disposition {
+    /// Returns the disposition of a request. `stale` is whether the response delivered is a stale
+    /// copy.
+    fn of(bypassed: bool, fetch: Option<Fetch>, stale: bool) -> Self {
+        match fetch {
+            _ if bypassed => Disposition::Pass,
+            _ if stale => Disposition::Stale,
+            Some(fetch) if fetch.status == StatusCode::NOT_MODIFIED.as_u16() => Disposition::Hit,
+            Some(fetch) if !fetch.stored => Disposition::Pass,
+            Some(_) => Disposition::Miss,
+            None => Disposition::Hit,
+        }
+    }
+
+    /// Returns the disposition as it appears in the `X-Cache` header.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Disposition::Hit => "HIT",
+            Disposition::Miss => "MISS",
+            Disposition::Pass => "PASS",
+            Disposition::Stale => "STALE",
+        }
+    }
+}
+
+/// What was decided about the response fetched from the origin.
+#[derive(Clone, Copy)]
+struct Fetch {
+    status: u16,
+    stored: bool,
+    ttl_secs: u64,
+}
+
+/// The cache status of one request.
+#[derive(Clone)]
+pub struct CacheStatus {
+    name: Arc<String>,
+    bypassed: bool,
+    x_cache: bool,
+    fetch: Arc<Mutex<Option<Fetch>>>,
+}
+
+impl CacheStatus {
+    /// Starts tracking the cache status of a request. `bypassed` is whether the client asked to
+    /// bypass the cache, and was allowed to.
+    pub fn for_request(settings: &Settings, bypassed: bool) -> Self {
+        Self {
+            name: Arc::new(
+                settings
+                    .get("cache_status_name")
+                    .unwrap_or_else(|| DEFAULT_CACHE_NAME.to_string()),
+            ),
+            bypassed,
+            x_cache: settings.get_bool("x_cache_headers", false),
+            fetch: Arc::default(),
+        }
+    }
+
+    /// Notes what was decided about a response received from the origin.
+    pub fn note_fetch(&self, resp: &CandidateResponse) {
+        let fetch = Fetch {
+            status: resp.get_status().as_u16(),
+            stored: resp.is_cacheable(),
+            ttl_secs: resp.get_ttl().as_secs(),
+        };
+        if let Ok(mut slot) = self.fetch.lock() {
+            *slot = Some(fetch);
+        }
+    }
+
+    /// Adds the `Cache-Status` member of this cache to the response, and rewrites or removes the
+    /// non-standard cache headers.
+    ///
+    /// This must be called on the response returned by the cache, before it is replaced by any
+    /// other response, except by a stale copy. Returns what the cache did with the request.
+    pub fn apply(&self, resp: &mut Response) -> Disposition {
+        let hits = resp.remove_header_str(X_CACHE_HITS_HEADER);
+        resp.remove_header(X_CACHE_HEADER);
+
+        let fetch = self.fetch.lock().ok().and_then(|slot| *slot);
+        let stale = resp.contains_header(stale::STALE_HEADER)
+            || resp.get_masked_error().is_some()
+            || (fetch.is_none() && resp.get_ttl() == Some(Duration::ZERO));
+        let disposition = Disposition::of(self.bypassed, fetch, stale);
+        if self.x_cache {
+            let hits = match disposition {
+                Disposition::Hit | Disposition::Stale => hits
+                    .and_then(|hits| hits.trim().parse::<u64>().ok())
+                    .unwrap_or_default(),
+                Disposition::Miss | Disposition::Pass => 0,
+            };
+            resp.set_header(X_CACHE_HEADER, disposition.as_str());
+            resp.set_header(X_CACHE_HITS_HEADER, hits.to_string());
+            if fetch.is_none() && !resp.contains_header(header::AGE) {
+                if let Some(age) = resp.get_age() {
+                    resp.set_header(header::AGE, age.as_secs().to_string());
+                }
+            }
+        }
+
+        let mut member = self.name.to_string();
+        match fetch {
+            _ if self.bypassed => member.push_str("; fwd=request"),
+            Some(fetch) => {
+                let fwd = if fetch.status == StatusCode::NOT_MODIFIED.as_u16() {
+                    "stale"
+                } else {
+                    "uri-miss"
+                };
+                member.push_str(&format!("; fwd={fwd}; fwd-status={}", fetch.status));
+                if fetch.stored {
+                    member.push_str(&format!("; stored; ttl={}", fetch.ttl_secs));
+                }
+            }
+            None => {
+                member.push_str("; hit");
+                if let Some(ttl) = resp.get_ttl() {
+                    member.push_str(&format!("; ttl={}", ttl.as_secs()));
+                }
+            }
+        }
+        resp.append_header(CACHE_STATUS_HEADER, member);
+        disposition
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetch(status: u16, stored: bool) -> Option<Fetch> {
+        Some(Fetch {
+            status,
+            stored,
+            ttl_secs: 60,
+        })
+    }
+
+    #[test]
+    fn dispositions_follow_what_the_cache_did() {
+        assert_eq!(Disposition::of(false, None, false), Disposition::Hit);
+        assert_eq!(
+            Disposition::of(false, fetch(200, true), false),
+            Disposition::Miss
+        );
+        assert_eq!(
+            Disposition::of(false, fetch(200, false), false),
+            Disposition::Pass
+        );
+        assert_eq!(
+            Disposition::of(false, fetch(304, true), false),
+            Disposition::Hit
+        );
+        assert_eq!(
+            Disposition::of(false, fetch(503, false), true),
+            Disposition::Stale
+        );
+        assert_eq!(
+            Disposition::of(true, fetch(200, false), false),
+            Disposition::Pass
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Responses that a shared cache must not store, according to RFC 9111.
+//!
+//! The caching policies of this service set TTLs by path and content type. This check runs before
+//! them, and keeps responses that the HTTP caching specification forbids a shared cache to store
+//! out of the cache whatever those policies decide. Every refusal is logged with a reason code.
+//!
+//! Responses that set cookies are also kept out of the cache, since a shared cache would replay
+//! one user's cookies to everyone else. They still reach the client whose request fetched them,
+//! cookies included. Harmless cookies are stripped from responses before this check, so that
+//! the responses setting only those can be cached.
+
+use fastly::http::{header, CandidateResponse};
+use fastly::Request;
+use std::fmt;
+
+/// Why a response must not be stored in a shared cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    /// The response varies on `*`, so no later request can ever be known to match it.
+    VaryStar,
+    /// The response carries `Cache-Control: no-store`.
+    NoStore,
+    /// The response carries `Cache-Control: private`, and is meant for a single user.
+    Private,
+    /// The request carried `Authorization`, and the response does not explicitly allow shared
+    /// caching with `public`, `s-maxage` or `must-revalidate`.
+    Authorization,
+    /// The request carried `Cache-Control: no-store`.
+    RequestNoStore,
+    /// The response carries `Set-Cookie`.
+    SetCookie,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reason::VaryStar => "vary-star",
+            Reason::NoStore => "no-store",
+            Reason::Private => "private",
+            Reason::Authorization => "authorization",
+            Reason::RequestNoStore => "request-no-store",
+            Reason::SetCookie => "set-cookie",
+        })
+    }
+}
+
+/// The properties of the client request that affect whether its response may be stored.
+#[derive(Clone, Copy, Default)]
+pub struct RequestFacts {
+    /// Whether the client sent an `Authorization` header. Credentials that the service itself adds
+    /// for the origin do not count, because they do not scope the response to a user.
+    pub authorized: bool,
+    /// Whether the client sent `Cache-Control: no-store`.
+    pub no_store: bool,
+}
+
+impl RequestFacts {
+    /// Records the relevant properties of a client request.
+    pub fn from_request(req: &Request) -> Self {
+        Self {
+            authorized: req.contains_header(header::AUTHORIZATION),
+            no_store: has_directive(req.get_header_str(header::CACHE_CONTROL), &["no-store"]),
+        }
+    }
+}
+
+/// Returns why a response must not be stored, or `None` if the specification allows storing it.
+pub fn check(request: RequestFacts, resp: &CandidateResponse) -> Option<Reason> {
+    reason(
+        request,
+        &resp.get_header_all_str(header::VARY),
+        resp.get_header_str(header::CACHE_CONTROL),
+        resp.contains_header(header::SET_COOKIE),
+    )
+}
+
+/// Returns why a response with the given `Vary` and `Cache-Control` headers, and setting cookies
+/// or not, must not be stored.
+fn reason(
+    request: RequestFacts,
+    vary: &[&str],
+    cache_control: Option<&str>,
+    sets_cookies: bool,
+) -> Option<Reason> {
+    let vary_star = vary
+        .iter()
+        .flat_map(|value| value.split(','))
+        .any(|field| field.trim() == "*");
+
+    if vary_star {
+        Some(Reason::VaryStar)
+    } else if has_directive(cache_control, &["no-store"]) {
+        Some(Reason::NoStore)
+    } else if has_directive(cache_control, &["private"]) {
+        Some(Reason::Private)
+    } else if request.authorized
+        && !has_directive(cache_control, &["public", "s-maxage", "must-revalidate"])
+    {
+        Some(Reason::Authorization)
+    } else if request.no_store {
+        Some(Reason::RequestNoStore)
+    } else if sets_cookies {
+        Some(Reason::SetCookie)
+    } else {
+        None
+    }
+}
+
+/// Returns whether a `Cache-Control` header value contains any of the given directives.
+pub fn has_directive(cache_control: Option<&str>, names: &[&str]) -> bool {
+    cache_control.is_some_and(|value| {
+        value.split(',').any(|directive| {
+            let name = directive.split('=').next().unwrap_or_default().trim();
+            names.iter().any(|wanted| name.eq_ignore_ascii_case(wanted))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANONYMOUS: RequestFacts = RequestFacts {
+        authorized: false,
+        no_store: false,
+    };
+
+    const AUTHORIZED: RequestFacts = RequestFacts {
+        authorized: true,
+        no_store: false,
+    };
+
+    #[test]
+    fn responses_forbidding_storage_are_refused() {
+        assert_eq!(reason(ANONYMOUS, &[], Some("max-age=60"), false), None);
+        assert_eq!(
+            reason(ANONYMOUS, &["Accept-Encoding", "Origin, *"], None, false),
+            Some(Reason::VaryStar)
+        );
+        assert_eq!(
+            reason(ANONYMOUS, &[], Some("max-age=60, No-Store"), false),
+            Some(Reason::NoStore)
+        );
+        assert_eq!(
+            reason(ANONYMOUS, &[], Some("private=\"set-cookie\""), false),
+            Some(Reason::Private)
+        );
+        assert_eq!(reason(ANONYMOUS, &[], None, true), Some(Reason::SetCookie));
+        let no_store = RequestFacts {
+            authorized: false,
+            no_store: true,
+        };
+        assert_eq!(
+            reason(no_store, &[], None, false),
+            Some(Reason::RequestNoStore)
+        );
+    }
+
+    #[test]
+    fn authorized_responses_need_explicit_permission() {
+        assert_eq!(
+            reason(AUTHORIZED, &[], Some("max-age=60"), false),
+            Some(Reason::Authorization)
+        );
+        for cache_control in ["public", "s-maxage=60", "max-age=0, must-revalidate"] {
+            assert_eq!(reason(AUTHORIZED, &[], Some(cache_control), false), None);
+        }
+        assert_eq!(
+            reason(AUTHORIZED, &[], Some("public, private"), false),
+            Some(Reason::Private)
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! Capture of request and response pairs, for debugging cache behavior.
+//!
+//! On the path prefixes listed in the `capture_path_prefixes` setting, every exchange is recorded
+//! in the `captures` KV Store: the request and response headers, the cache decision taken after
+//! the response was received from the origin, and the start of both bodies. Credentials, cookies
+//! and the signatures of signed URLs are redacted before anything is stored. Only the start of a
+//! body is read for its capture, and the capture is stored once the response has been sent to the
+//! client, so that capturing neither buffers whole bodies nor delays responses.
+//!
+//! Captures are kept in a ring buffer of `capture_slots` entries, so that the newest captures
+//! replace the oldest ones. Concurrent requests may occasionally claim the same slot, which loses
+//! one of the two captures; this is acceptable for a debugging aid. The captures can be read back
+//! through the `/_edge/captures` admin route.
+
+use crate::config::Settings;
+use fastly::http::{header, CandidateResponse, HeaderName, HeaderValue, Url};
+use fastly::kv_store::KVStore;
+use fastly::{Body, Request, Response};
+use serde_json::{json, Map, Value};
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the KV Store holding the captures.
+const STORE_NAME: &str = "captures";
+
+/// The KV Store key holding the index of the next slot to write.
+const HEAD_KEY: &str = "head";
+
+/// The number of slots in the ring buffer, unless overridden by the `capture_slots` setting.
+const DEFAULT_SLOTS: u64 = 50;
+
+/// The number of bytes of each body that are captured.
+const MAX_BODY_BYTES: usize = 1024;
+
+/// Headers whose values are never captured.
+const REDACTED_HEADERS: [&str; 6] = [
+    "authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "idempotency-key",
+    "proxy-authorization",
+];
+
+/// Query parameters whose values are never captured.
+const REDACTED_PARAMS: [&str; 4] = ["token", "callback", "signature", "expires"];
+
+/// Records one request and response pair.
+#[derive(Clone)]
+pub struct Recorder {
+    slots: u64,
+    request: Arc<Value>,
+    decision: Arc<Mutex<Option<Value>>>,
+}
+
+impl Recorder {
+    /// Starts recording a request, if its path is under one of the capture path prefixes.
+    pub fn for_request(req: &mut Request, settings: &Settings) -> Option<Self> {
+        let path = req.get_path();
+        if !settings
+            .get_list("capture_path_prefixes")
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+
+        let mut url = req.get_url().clone();
+        redact_query(&mut url);
+
+        let length = content_length(req.get_header_str(header::CONTENT_LENGTH));
+        let (body, whole) = read_start(req.take_body());
+        req.set_body(whole);
+        let request = json!({
+            "method": req.get_method_str(),
+            "url": url.as_str(),
+            "headers": sanitize(req.get_headers()),
+            "body": truncate(&body, length),
+        });
+
+        Some(Self {
+            slots: settings.get_u64("capture_slots", DEFAULT_SLOTS).max(1),
+            request: Arc::new(request),
+            decision: Arc::default(),
+        })
+    }
+
+    /// Records the cache decision taken for a response received from the origin.
+    pub fn record_decision(&self, resp: &CandidateResponse) {
+        let decision = json!({
+            "status": resp.get_status().as_u16(),
+            "cacheable": resp.is_cacheable(),
+            "ttl_secs": resp.get_ttl().as_secs(),
+            "stale_while_revalidate_secs": resp.get_stale_while_revalidate().as_secs(),
+            "headers": sanitize(resp.get_headers()),
+        });
+        if let Ok(mut slot) = self.decision.lock() {
+            *slot = Some(decision);
+        }
+    }
+
+    /// Records the response delivered to the client, leaving the capture to be stored once the
+    /// response has been sent.
+    ///
+    /// A response with no recorded cache decision was served from the cache.
+    pub fn finish(&self, resp: &mut Response, pending: &Pending) {
+        let length = content_length(resp.get_header_str(header::CONTENT_LENGTH));
+        let (body, whole) = read_start(resp.take_body());
+        resp.set_body(whole);
+        let decision = self.decision.lock().ok().and_then(|mut slot| slot.take());
+        let capture = json!({
+            "captured_at": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            "request": *self.request,
+            "cache": if decision.is_some() { "miss" } else { "hit" },
+            "origin_response": decision,
+            "response": {
+                "status": resp.get_status().as_u16(),
+                "headers": sanitize(resp.get_headers()),
+                "body": truncate(&body, length),
+            },
+        });
+        if let Ok(mut slot) = pending.0.lock() {
+            *slot = Some((capture, self.slots));
+        }
+    }
+}
+
+/// A capture waiting for its response to be sent to the client.
+#[derive(Default)]
+pub struct Pending(Mutex<Option<(Value, u64)>>);
+
+impl Pending {
+    /// Stores the capture waiting, if any.
+    pub fn store(&self) {
+        let Some((capture, slots)) = self.0.lock().ok().and_then(|mut slot| slot.take()) else {
+            return;
+        };
+        if let Err(e) = store(&capture, slots) {
+            log::warn!("cannot store capture: {e}");
+        }
+    }
+}
+
+/// Returns the stored captures, newest first.
+pub fn list() -> Result<Vec<Value>, fastly::Error> {
+    let Some(store) = KVStore::open(STORE_NAME)? else {
+        return Ok(Vec::new());
+    };
+    let mut captures: Vec<Value> = Vec::new();
+    for key in store.build_list().prefix("slot/").iter() {
+        for key in key?.into_keys() {
+            if let Ok(mut found) = store.lookup(&key) {
+                if let Ok(capture) = serde_json::from_slice(&found.take_body_bytes()) {
+                    captures.push(capture);
+                }
+            }
+        }
+    }
+    captures.sort_by_key(|capture: &Value| std::cmp::Reverse(capture["captured_at"].as_u64()));
+    Ok(captures)
+}
+
+fn store(capture: &Value, slots: u64) -> Result<(), fastly::Error> {
+    let store = KVStore::open(STORE_NAME)?
+        .ok_or_else(|| fastly::Error::msg(format!("KV Store {STORE_NAME} is not linked")))?;
+    let head = store
+        .lookup(HEAD_KEY)
+        .ok()
+        .and_then(|mut found| String::from_utf8(found.take_body_bytes()).ok())
+        .and_then(|head| head.parse::<u64>().ok())
+        .unwrap_or(0)
+        % slots;
+    store.insert(HEAD_KEY, ((head + 1) % slots).to_string())?;
+    store.insert(&format!("slot/{head}"), capture.to_string())?;
+    Ok(())
+}
+
+/// Returns headers as a JSON object, with the values of sensitive headers redacted.
+fn sanitize<'a>(headers: impl Iterator<Item = (&'a HeaderName, &'a HeaderValue)>) -> Value {
+    let mut map = Map::new();
+    for (name, value) in headers {
+        let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+            "[redacted]".to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        match map.get_mut(name.as_str()) {
+            Some(Value::Array(values)) => values.push(value.into()),
+            Some(existing) => *existing = json!([existing.take(), value]),
+            None => {
+                map.insert(name.to_string(), value.into());
+            }
+        }
+    }
+    Value::Object(map)
+}
+
+/// Replaces the values of the sensitive query parameters of a URL.
+fn redact_query(url: &mut Url) {
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            let redacted = REDACTED_PARAMS
+                .iter()
+                .any(|param| param.eq_ignore_ascii_case(&name));
+            let value = if redacted {
+                "[redacted]".into()
+            } else {
+                value.into_owned()
+            };
+            (name.into_owned(), value)
+        })
+        .collect();
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+}
+
+/// Reads the start of a body, one byte more than is captured so that truncation can be told, and
+/// returns it along with the whole body, the start put back in front of the rest.
+fn read_start(mut body: Body) -> (Vec<u8>, Body) {
+    let start = read_prefix(&mut body).unwrap_or_else(|e| {
+        log::warn!("cannot read the body to capture: {e}");
+        Vec::new()
+    });
+    let mut whole = Body::from(start.clone());
+    whole.append(body);
+    (start, whole)
+}
+
+fn read_prefix(body: impl Read) -> io::Result<Vec<u8>> {
+    let mut start = Vec::new();
+    body.take(MAX_BODY_BYTES as u64 + 1)
+        .read_to_end(&mut start)?;
+    Ok(start)
+}
+
+fn content_length(value: Option<&str>) -> Option<u64> {
+    value?.trim().parse().ok()
+}
+
+/// Returns the start of a body as text, noting how much was left out. `length` is the length of
+/// the whole body, if it is declared.
+fn truncate(start: &[u8], length: Option<u64>) -> Value {
+    if start.is_empty() {
+        return Value::Null;
+    }
+    let shown = &start[..start.len().min(MAX_BODY_BYTES)];
+    let truncated = start.len() > shown.len();
+    json!({
+        "text": String::from_utf8_lossy(shown),
+        "length": length.or_else(|| (!truncated).then_some(shown.len() as u64)),
+        "truncated": truncated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_and_signatures_are_redacted_from_urls() {
+        let mut url =
+            Url::parse("https://example.com/a?id=7&Signature=abc&expires=99&token=t&callback=cb")
+                .unwrap();
+        redact_query(&mut url);
+        assert_eq!(
+            url.as_str(),
+            "https://example.com/a?id=7&Signature=%5Bredacted%5D&expires=%5Bredacted%5D\
+             &token=%5Bredacted%5D&callback=%5Bredacted%5D"
+        );
+
+        let mut url = Url::parse("https://example.com/a?").unwrap();
+        redact_query(&mut url);
+        assert_eq!(url.as_str(), "https://example.com/a");
+    }
+
+    #[test]
+    fn only_the_start_of_bodies_is_read() {
+        let body = vec![b'a'; 10 * MAX_BODY_BYTES];
+        let start = read_prefix(body.as_slice()).unwrap();
+        assert_eq!(start.len(), MAX_BODY_BYTES + 1);
+        let captured = truncate(&start, Some(body.len() as u64));
+        assert_eq!(captured["truncated"], true);
+        assert_eq!(captured["length"], body.len());
+        assert_eq!(captured["text"].as_str().unwrap().len(), MAX_BODY_BYTES);
+
+        assert_eq!(truncate(&start, None)["length"], Value::Null);
+        let captured = truncate(b"short", None);
+        assert_eq!(captured["truncated"], false);
+        assert_eq!(captured["length"], 5);
+        assert_eq!(truncate(b"", Some(0)), Value::Null);
+    }
+}
@@ -0,0 +1,96 @@
+//! Fault injection, for exercising failure handling in non-production environments.
+//!
+//! Chaos mode only applies to requests for the hosts listed in the `chaos_hosts` setting, so that
+//! it can be enabled on a staging host without any risk to production traffic. For those hosts,
+//! origin fetches are randomly delayed, origin responses are randomly replaced with a 503, and
+//! the after-send policy randomly fails, each at the rate given by its own setting.
+
+use crate::config::Settings;
+use fastly::http::{CandidateResponse, StatusCode};
+use fastly::{Error, Request};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// The response header marking an injected fault.
+const CHAOS_HEADER: &str = "x-chaos";
+
+/// The delay added to origin fetches, unless overridden by the `chaos_latency_ms` setting.
+const DEFAULT_LATENCY_MS: u64 = 500;
+
+/// The faults injected for a request, each with the probability that it happens.
+#[derive(Clone, Copy)]
+pub struct Chaos {
+    latency_rate: f64,
+    latency: Duration,
+    error_rate: f64,
+    transform_failure_rate: f64,
+}
+
+impl Chaos {
+    /// Returns the faults to inject for a request, or `None` if its host is not a chaos host.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        let host = req.get_url().host_str()?;
+        if !settings
+            .get_list("chaos_hosts")
+            .iter()
+            .any(|chaos_host| chaos_host.eq_ignore_ascii_case(host))
+        {
+            return None;
+        }
+        let rate = |key| {
+            let rate = settings.get_f64(key, 0.0);
+            if rate.is_finite() {
+                rate.clamp(0.0, 1.0)
+            } else {
+                0.0
+            }
+        };
+        Some(Self {
+            latency_rate: rate("chaos_latency_rate"),
+            latency: Duration::from_millis(
+                settings.get_u64("chaos_latency_ms", DEFAULT_LATENCY_MS),
+            ),
+            error_rate: rate("chaos_error_rate"),
+            transform_failure_rate: rate("chaos_transform_failure_rate"),
+        })
+    }
+
+    /// Delays a request about to be sent to the origin.
+    pub fn delay_origin(&self) {
+        if roll(self.latency_rate) {
+            log::info!("chaos: delaying origin fetch by {:?}", self.latency);
+            std::thread::sleep(self.latency);
+        }
+    }
+
+    /// Replaces an origin response with an uncacheable 503, returning whether it did.
+    pub fn inject_origin_error(&self, resp: &mut CandidateResponse) -> bool {
+        if !roll(self.error_rate) {
+            return false;
+        }
+        log::info!("chaos: replacing origin response with a 503");
+        resp.set_status(StatusCode::SERVICE_UNAVAILABLE);
+        resp.set_header(CHAOS_HEADER, "origin-error");
+        resp.set_uncacheable(false);
+        true
+    }
+
+    /// Fails the after-send policy.
+    pub fn fail_transform(&self) -> Result<(), Error> {
+        if roll(self.transform_failure_rate) {
+            return Err(Error::msg("chaos: injected transform failure"));
+        }
+        Ok(())
+    }
+}
+
+/// Returns `true` with the given probability.
+fn roll(rate: f64) -> bool {
+    if rate <= 0.0 {
+        return false;
+    }
+    // Each `RandomState` is seeded from the platform's random number generator.
+    let sample = RandomState::new().build_hasher().finish();
+    (sample as f64 / u64::MAX as f64) < rate
+}
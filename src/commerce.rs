@@ -0,0 +1,105 @@
+//! Caching policies for commerce pages.
+
+use crate::bots;
+use crate::config::Settings;
+use fastly::http::{CandidateResponse, HeaderName};
+use fastly::Request;
+use std::time::Duration;
+
+/// The request header that selects the price variant of a product page.
+///
+/// The origin renders product pages without prices when this header is `masked`, and the cache
+/// varies on it, so scrapers and human visitors are each served their own cached variant.
+pub const PRICE_VARIANT_HEADER: HeaderName = HeaderName::from_static("x-price-variant");
+
+/// The path prefix of product pages when the `product_path_prefix` setting is absent.
+const DEFAULT_PRODUCT_PATH_PREFIX: &str = "/products/";
+
+/// The TTL of product pages for items that are almost sold out, unless overridden by the
+/// `low_stock_ttl` setting (in seconds).
+const DEFAULT_LOW_STOCK_TTL_SECS: u64 = 10;
+
+/// The stale-while-revalidate window of product pages for items that are almost sold out, unless
+/// overridden by the `low_stock_swr` setting (in seconds).
+const DEFAULT_LOW_STOCK_SWR_SECS: u64 = 5;
+
+/// The paths that always bypass the cache when the `pass_path_prefixes` setting is absent.
+const DEFAULT_PASS_PATH_PREFIXES: [&str; 3] = ["/cart", "/checkout", "/account"];
+
+/// Returns whether the request belongs to a personalized commerce flow, such as the cart or the
+/// checkout, which must never be cached or transformed.
+///
+/// The paths are listed in the comma-separated `pass_path_prefixes` setting. Each prefix matches
+/// the path itself and everything below it, so `/cart` matches `/cart` and `/cart/items` but not
+/// `/cartoons`.
+pub fn is_always_pass(req: &Request, settings: &Settings) -> bool {
+    let mut prefixes = settings.get_list("pass_path_prefixes");
+    if prefixes.is_empty() {
+        prefixes = DEFAULT_PASS_PATH_PREFIXES.map(String::from).to_vec();
+    }
+
+    let path = req.get_path();
+    prefixes.iter().any(|prefix| {
+        let prefix = prefix.trim_end_matches('/');
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Returns whether the request is for a product page.
+pub fn is_product_page(req: &Request, settings: &Settings) -> bool {
+    let prefix = settings
+        .get("product_path_prefix")
+        .unwrap_or_else(|| DEFAULT_PRODUCT_PATH_PREFIX.to_string());
+    req.get_path().starts_with(&prefix)
+}
+
+/// Selects the price variant of a product page request: `masked` for scrapers, `full` for
+/// everyone else.
+///
+/// Any value sent by the client is overwritten, so clients cannot choose their own variant.
+pub fn set_price_variant(req: &mut Request) {
+    let variant = if bots::is_scraper(req) {
+        "masked"
+    } else {
+        "full"
+    };
+    req.set_header(PRICE_VARIANT_HEADER, variant);
+}
+
+/// Cache lifetimes for product pages, based on the stock level reported by the origin.
+#[derive(Clone, Copy)]
+pub struct StockPolicy {
+    low_stock_ttl: Duration,
+    low_stock_swr: Duration,
+}
+
+impl StockPolicy {
+    /// Reads the low-stock cache lifetimes from the settings.
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            low_stock_ttl: Duration::from_secs(
+                settings.get_u64("low_stock_ttl", DEFAULT_LOW_STOCK_TTL_SECS),
+            ),
+            low_stock_swr: Duration::from_secs(
+                settings.get_u64("low_stock_swr", DEFAULT_LOW_STOCK_SWR_SECS),
+            ),
+        }
+    }
+
+    /// Shortens the TTL and stale-while-revalidate window of a product page when the origin
+    /// reports `X-Stock-Level: low`, so that near-sellout items are refreshed quickly.
+    ///
+    /// The lifetimes are only ever shortened, never extended.
+    pub fn apply(&self, resp: &mut CandidateResponse) {
+        let is_low_stock = resp
+            .get_header_str("x-stock-level")
+            .is_some_and(|level| level.trim().eq_ignore_ascii_case("low"));
+        if !is_low_stock {
+            return;
+        }
+
+        resp.set_ttl(resp.get_ttl().min(self.low_stock_ttl));
+        resp.set_stale_while_revalidate(resp.get_stale_while_revalidate().min(self.low_stock_swr));
+    }
+}
@@ -0,0 +1,336 @@
+//! Compressed fetches from the origin, and compressed bodies in transforms.
+//!
+//! With the `compressed_origin_fetch` setting on, the origin is always asked for a gzip-encoded
+//! body, whatever the client accepts. Only the compressed representation is stored in the cache,
+//! which cuts both origin egress and cache storage. The few clients that do not accept gzip get
+//! the body decompressed at delivery time.
+//!
+//! With the `edge_compression` setting on, uncompressed text responses are compressed at the edge
+//! instead. The client's `Accept-Encoding` is normalized before the cache lookup to `br`, `gzip`
+//! or nothing, whichever is the best coding the client accepts, and the body is compressed with
+//! that coding as it is stored. The cache varies on the normalized header, so each text object is
+//! stored at most three times, and most clients are served a compressed body straight from the
+//! cache.
+//!
+//! Body transforms applied before a response is stored handle gzip and Brotli bodies: the body is
+//! decompressed before it is transformed, and the transformed body is compressed again with the
+//! same coding, so that the cached object keeps its `Content-Encoding`. Bodies with other codings
+//! are cached as they are.
+//!
+//! The rewrites applied at delivery, such as JSONP wrapping, format conversion and the news banner,
+//! handle gzip and Brotli bodies the same way, so they keep working on edge-compressed objects.
+
+use crate::accept;
+use crate::integrity::{self, ExpectedDigest};
+use crate::metrics::TransformMetrics;
+use crate::trailers::TrailerPolicy;
+use fastly::http::{header, CandidateResponse};
+use fastly::{Request, Response};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+
+/// The size of the buffers used by Brotli streams.
+const BROTLI_BUFFER_SIZE: usize = 16 * 1024;
+
+/// The Brotli quality of recompressed bodies, trading some ratio for speed.
+const BROTLI_QUALITY: u32 = 5;
+
+/// The base-2 logarithm of the Brotli window size.
+const BROTLI_WINDOW: u32 = 22;
+
+/// A content coding that body transforms can decompress and compress again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    Brotli,
+}
+
+impl Coding {
+    /// Returns the coding of a body with the given `Content-Encoding`, or `None` if the body is
+    /// not encoded, or encoded with a coding that is not supported.
+    pub fn of(content_encoding: Option<&str>) -> Option<Self> {
+        match content_encoding?.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "br" => Some(Coding::Brotli),
+            _ => None,
+        }
+    }
+
+    /// Returns the `Content-Encoding` value of the coding.
+    pub fn name(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Brotli => "br",
+        }
+    }
+
+    /// Wraps a reader of an encoded body into a reader of the decoded body.
+    pub fn decoder<'a>(self, encoded: impl Read + 'a) -> Box<dyn Read + 'a> {
+        match self {
+            Coding::Gzip => Box::new(GzDecoder::new(encoded)),
+            Coding::Brotli => Box::new(brotli::Decompressor::new(encoded, BROTLI_BUFFER_SIZE)),
+        }
+    }
+
+    /// Wraps a writer of an encoded body into a writer of the decoded body. The encoder must be
+    /// finished once everything has been written.
+    pub fn encoder<W: Write>(self, encoded: W) -> Encoder<W> {
+        match self {
+            Coding::Gzip => Encoder::Gzip(GzEncoder::new(encoded, Compression::default())),
+            Coding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                encoded,
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+        }
+    }
+}
+
+/// A writer that compresses what it is given with a [`Coding`].
+pub enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Brotli(Box<brotli::CompressorWriter<W>>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes the end of the compressed stream.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish().map(drop),
+            // Brotli reports errors when flushing, but not when writing the end of the stream.
+            Encoder::Brotli(mut encoder) => {
+                encoder.flush()?;
+                encoder.into_inner();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Brotli(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Decompresses a whole body, if it is encoded.
+pub fn decode(coding: Option<Coding>, body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let Some(coding) = coding else {
+        return Ok(body);
+    };
+    let mut decoded = Vec::new();
+    coding.decoder(body.as_slice()).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// Compresses a whole body, if it is to be encoded.
+pub fn encode(coding: Option<Coding>, body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let Some(coding) = coding else {
+        return Ok(body);
+    };
+    let mut encoded = Vec::new();
+    let mut encoder = coding.encoder(&mut encoded);
+    encoder.write_all(&body)?;
+    encoder.finish()?;
+    Ok(encoded)
+}
+
+/// Asks the origin for a gzip-encoded body.
+pub fn request_gzip(req: &mut Request) {
+    req.set_header(header::ACCEPT_ENCODING, "gzip");
+}
+
+/// Returns whether a client accepts gzip-encoded responses, according to its `Accept-Encoding`
+/// header.
+pub fn accepts_gzip(req: &Request) -> bool {
+    accepts(req, &["gzip", "x-gzip"])
+}
+
+/// Returns whether a client's `Accept-Encoding` header accepts any of the given codings.
+fn accepts(req: &Request, names: &[&str]) -> bool {
+    accept::weighted(
+        req.get_header_str(header::ACCEPT_ENCODING)
+            .unwrap_or_default(),
+    )
+    .any(|(name, quality)| {
+        quality > 0.0
+            && (names.iter().any(|wanted| name.eq_ignore_ascii_case(wanted)) || name == "*")
+    })
+}
+
+/// Replaces the `Accept-Encoding` header of a request with the best coding the client accepts,
+/// and returns that coding. The header is removed if the client accepts neither Brotli nor gzip.
+pub fn normalize_accept_encoding(req: &mut Request) -> Option<Coding> {
+    let coding = if accepts(req, &["br"]) {
+        Some(Coding::Brotli)
+    } else if accepts_gzip(req) {
+        Some(Coding::Gzip)
+    } else {
+        None
+    };
+    match coding {
+        Some(coding) => req.set_header(header::ACCEPT_ENCODING, coding.name()),
+        None => {
+            req.remove_header(header::ACCEPT_ENCODING);
+        }
+    }
+    coding
+}
+
+/// Returns whether a body is worth compressing at the edge: text that is not already encoded.
+pub fn is_compressible(content_type: Option<&str>, content_encoding: Option<&str>) -> bool {
+    let essence = content_type
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let is_text = essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        );
+    let is_unencoded = content_encoding.is_none_or(|encoding| {
+        let encoding = encoding.trim();
+        encoding.is_empty() || encoding.eq_ignore_ascii_case("identity")
+    });
+    is_text && is_unencoded
+}
+
+/// Installs a body transform that compresses an uncompressed body with a coding as it is stored,
+/// and marks the response as encoded with it. The body is checked against the digest the origin
+/// sent with it, if any.
+pub fn install_edge_compression(
+    coding: Coding,
+    trailer_policy: TrailerPolicy,
+    transform_metrics: Option<TransformMetrics>,
+    resp: &mut CandidateResponse,
+) {
+    let content_type = resp
+        .get_header_str(header::CONTENT_TYPE)
+        .map(str::to_string);
+    let digest = ExpectedDigest::take_from_response(resp);
+    mark_encoded(coding, resp);
+    resp.set_body_transform(move |body_in, body_out| {
+        let started = Instant::now();
+        let body = trailer_policy.read_body(body_in, body_out);
+        integrity::verify(digest.as_ref(), &body)?;
+        let body_len = body.len();
+        let compressed = encode(Some(coding), body)?;
+        if let Some(metrics) = transform_metrics {
+            metrics.record(
+                coding.name(),
+                content_type.as_deref(),
+                body_len,
+                compressed.len(),
+                started.elapsed(),
+            );
+        }
+        body_out.write_all(&compressed)?;
+        Ok(())
+    });
+}
+
+/// Marks a response whose body is compressed at the edge as encoded with a coding.
+pub fn mark_encoded(coding: Coding, resp: &mut CandidateResponse) {
+    resp.set_header(header::CONTENT_ENCODING, coding.name());
+    resp.remove_header(header::CONTENT_LENGTH);
+}
+
+/// Prepares a response for delivery to a client, decompressing a gzip-encoded body if the client
+/// does not accept it.
+pub fn deliver(
+    mut resp: Response,
+    client_accepts_gzip: bool,
+    transform_metrics: Option<TransformMetrics>,
+) -> Response {
+    let is_gzip = resp
+        .get_header_str(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("gzip"));
+    if !is_gzip {
+        return resp;
+    }
+    resp.append_header(header::VARY, "Accept-Encoding");
+    if client_accepts_gzip {
+        return resp;
+    }
+
+    let started = Instant::now();
+    let compressed = resp.take_body_bytes();
+    let mut body = Vec::new();
+    if let Err(e) = GzDecoder::new(compressed.as_slice()).read_to_end(&mut body) {
+        log::warn!("cannot decompress gzip response: {e}");
+        resp.set_body(compressed);
+        return resp;
+    }
+    if let Some(metrics) = transform_metrics {
+        metrics.record(
+            "gunzip",
+            resp.get_header_str(header::CONTENT_TYPE),
+            compressed.len(),
+            body.len(),
+            started.elapsed(),
+        );
+    }
+    resp.set_body(body);
+    resp.remove_header(header::CONTENT_ENCODING);
+    resp.remove_header(header::CONTENT_LENGTH);
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_codings_are_recognized() {
+        assert_eq!(Coding::of(Some("gzip")), Some(Coding::Gzip));
+        assert_eq!(Coding::of(Some(" X-Gzip ")), Some(Coding::Gzip));
+        assert_eq!(Coding::of(Some("br")), Some(Coding::Brotli));
+        assert_eq!(Coding::of(Some("zstd")), None);
+        assert_eq!(Coding::of(Some("identity")), None);
+        assert_eq!(Coding::of(None), None);
+    }
+
+    #[test]
+    fn bodies_survive_recompression() {
+        let body = br#"{"firstName":"Ada","lastName":"Lovelace"}"#.repeat(100);
+        for coding in [Coding::Gzip, Coding::Brotli] {
+            let encoded = encode(Some(coding), body.clone()).unwrap();
+            assert!(encoded.len() < body.len());
+            assert_eq!(decode(Some(coding), encoded).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn text_is_compressible() {
+        assert!(is_compressible(Some("text/html; charset=utf-8"), None));
+        assert!(is_compressible(Some("application/json"), Some("identity")));
+        assert!(is_compressible(Some("application/ld+json"), None));
+        assert!(is_compressible(Some("image/svg+xml"), None));
+        assert!(!is_compressible(Some("image/png"), None));
+        assert!(!is_compressible(Some("text/css"), Some("gzip")));
+        assert!(!is_compressible(None, None));
+    }
+
+    #[test]
+    fn unencoded_bodies_are_left_alone() {
+        assert_eq!(encode(None, b"plain".to_vec()).unwrap(), b"plain");
+        assert_eq!(decode(None, b"plain".to_vec()).unwrap(), b"plain");
+    }
+}
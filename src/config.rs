@@ -0,0 +1,212 @@
+//! Runtime settings, read from a Config Store.
+//!
+//! Settings let operators change the behavior of the service without a redeploy. Every setting
+//! has a default that applies when the Config Store, or the key within it, is absent.
+//!
+//! With host policies, the settings of a host can also be overridden by its policy document.
+
+use fastly::ConfigStore;
+use std::collections::HashMap;
+
+/// The name of the Config Store that holds the service settings.
+const STORE_NAME: &str = "settings";
+
+/// Every setting read by the service, in the order they are documented in the README.
+pub const KEYS: &[&str] = &[
+    "feature_flags",
+    "pass_origin_errors",
+    "error_pages_ttl",
+    "error_brand",
+    "error_page_ttl",
+    "negative_ttl",
+    "permanent_redirect_ttl",
+    "pass_path_prefixes",
+    "product_path_prefix",
+    "low_stock_ttl",
+    "low_stock_swr",
+    "media_live_manifest_ttl",
+    "media_vod_manifest_ttl",
+    "media_segment_ttl",
+    "media_token_auth",
+    "signed_url_prefixes",
+    "event_mode",
+    "event_mode_factor",
+    "breaking_news_banner",
+    "article_path_prefix",
+    "tenant_partitioning",
+    "public_api_prefixes",
+    "refetch_partial_content",
+    "preserve_trailers_prefixes",
+    "edge_etags",
+    "scrubbed_headers",
+    "scrubbed_delivery_headers",
+    "scrubbed_headers_allowlist",
+    "request_filtering",
+    "cors_allowed_origins",
+    "cors_allowed_methods",
+    "cors_allowed_headers",
+    "cors_max_age",
+    "security_headers",
+    "content_security_policy",
+    "security_header_overrides",
+    "follow_redirects",
+    "redirect_allowed_hosts",
+    "redirect_max_hops",
+    "harmless_cookies",
+    "strip_request_cookies",
+    "allowed_request_cookies",
+    "idempotency_path_prefixes",
+    "idempotency_window",
+    "edge_admin_allowed_ips",
+    "capture_path_prefixes",
+    "capture_slots",
+    "chaos_hosts",
+    "chaos_latency_rate",
+    "chaos_latency_ms",
+    "chaos_error_rate",
+    "chaos_transform_failure_rate",
+    "load_shed_latency_ms",
+    "load_shed_cooldown",
+    "load_shed_stale",
+    "rate_limit",
+    "rate_limit_window",
+    "rate_limit_penalty",
+    "rate_limit_by",
+    "adaptive_ttl",
+    "adaptive_ttl_min",
+    "adaptive_ttl_max",
+    "surrogate_key_generation",
+    "sharded_surrogate_key_prefixes",
+    "surrogate_key_shards",
+    "snapshot_path_prefixes",
+    "snapshot_interval",
+    "disaster_mode",
+    "fallback_copies",
+    "failover_backends",
+    "serve_stale_on_error",
+    "stale_on_error_window",
+    "compressed_origin_fetch",
+    "edge_compression",
+    "normalize_accept",
+    "device_variants",
+    "experiments",
+    "experiment_cookie",
+    "geo_variants",
+    "geo_regions",
+    "supported_locales",
+    "enrichment_path_prefixes",
+    "enrichment_key_member",
+    "json_projection",
+    "origin_warmup",
+    "warmup_backends",
+    "hit_ratio_metrics",
+    "transform_metrics",
+    "memoization_ttl",
+    "esi_path_prefixes",
+    "esi_backends",
+    "html_rewrites",
+    "shell_path_prefixes",
+    "fragment_path",
+    "personalized_path_prefixes",
+    "session_cookie",
+    "delivery_removed_headers",
+    "cache_status_name",
+    "x_cache_headers",
+    "server_timing",
+    "log_endpoint",
+    "early_hints",
+    "early_hints_ttl",
+    "host_policies",
+    "routes",
+    "cache_rules",
+    "core_cache_path_prefixes",
+    "graphql_path_prefixes",
+    "purge_api",
+    "jwt_path_prefixes",
+    "jwt_audience",
+    "jwt_claim_headers",
+    "s3_backends",
+    "s3_host",
+    "s3_region",
+    "s3_service",
+    "url_normalization",
+    "trailing_slash",
+    "lowercase_paths",
+    "canonical_redirects",
+    "cache_key_normalization",
+    "cache_key_ignored_params",
+    "strip_tracking_params",
+    "tracking_params",
+];
+
+/// A handle to the service settings.
+pub struct Settings {
+    store: Option<ConfigStore>,
+    overrides: HashMap<String, String>,
+}
+
+impl Settings {
+    /// Opens the settings Config Store, falling back to defaults if it is not linked to the
+    /// service.
+    pub fn open() -> Self {
+        Self {
+            store: ConfigStore::try_open(STORE_NAME).ok(),
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// Returns these settings with some of their values replaced.
+    pub fn with_overrides(self, overrides: HashMap<String, String>) -> Self {
+        Self { overrides, ..self }
+    }
+
+    /// Returns the raw value of a setting.
+    pub fn get(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.overrides.get(key) {
+            return Some(value.clone());
+        }
+        self.store
+            .as_ref()
+            .and_then(|store| store.try_get(key).ok().flatten())
+    }
+
+    /// Returns a boolean setting. The values `on`, `true`, `yes` and `1` are treated as enabled.
+    pub fn get_bool(&self, key: &str, default: bool) -> bool {
+        match self.get(key) {
+            Some(value) => matches!(
+                value.trim().to_ascii_lowercase().as_str(),
+                "on" | "true" | "yes" | "1"
+            ),
+            None => default,
+        }
+    }
+
+    /// Returns a comma-separated list setting, with surrounding whitespace and empty entries
+    /// removed.
+    pub fn get_list(&self, key: &str) -> Vec<String> {
+        self.get(key)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns a floating-point setting, or `default` if it is missing or malformed.
+    pub fn get_f64(&self, key: &str, default: f64) -> f64 {
+        self.get(key)
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(default)
+    }
+
+    /// Returns an unsigned integer setting, or `default` if it is missing or malformed.
+    pub fn get_u64(&self, key: &str, default: u64) -> u64 {
+        self.get(key)
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(default)
+    }
+}
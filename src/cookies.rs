@@ -0,0 +1,148 @@
+//! Request cookies, and caching responses that set harmless cookies.
+//!
+//! With the `strip_request_cookies` setting on, the cookies of a request are removed before the
+//! cache lookup, except those listed in `allowed_request_cookies`, so that the origin cannot
+//! personalize a response that is then cached for everyone.
+//!
+//! Responses with a `Set-Cookie` header are not cached by default, because a shared cache would
+//! replay one user's cookie to everyone else. Some cookies are harmless to share the response
+//! with, though, such as the affinity cookies of a load balancer in front of the origin. When every
+//! cookie a response sets is listed in the `harmless_cookies` setting, the cookies are removed
+//! from the object stored in the cache, which then becomes cacheable, and they are only delivered
+//! to the client whose request fetched it from the origin. Responses that would not be cached
+//! without their cookies either, because of their status or their `Cache-Control` header, are
+//! still not cached.
+
+use crate::cacheability;
+use crate::config::Settings;
+use fastly::http::{header, CandidateResponse, HeaderValue, StatusCode};
+use fastly::{Request, Response};
+use std::sync::{Arc, Mutex};
+
+/// The cookies stripped from a cached response, kept for the response delivered on the miss.
+#[derive(Clone)]
+pub struct CookieStash {
+    harmless: Arc<Vec<String>>,
+    stripped: Arc<Mutex<Vec<HeaderValue>>>,
+}
+
+impl CookieStash {
+    /// Returns a stash for the cookies named in the `harmless_cookies` setting, or `None` if no
+    /// cookie is harmless.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        let harmless = settings.get_list("harmless_cookies");
+        if harmless.is_empty() {
+            return None;
+        }
+        Some(Self {
+            harmless: Arc::new(harmless),
+            stripped: Arc::default(),
+        })
+    }
+
+    /// Removes the `Set-Cookie` headers of a response about to be cached, if every cookie it sets
+    /// is harmless, and makes it cacheable if its cookies were all that kept it out of the cache.
+    pub fn strip(&self, resp: &mut CandidateResponse) {
+        let cookies: Vec<HeaderValue> = resp.get_header_all(header::SET_COOKIE).cloned().collect();
+        if cookies.is_empty() || !cookies.iter().all(|cookie| self.is_harmless(cookie)) {
+            return;
+        }
+        resp.remove_header(header::SET_COOKIE);
+        if !resp.is_cacheable()
+            && is_cacheable_without_cookies(
+                resp.get_status(),
+                resp.get_header_str(header::CACHE_CONTROL),
+            )
+        {
+            resp.set_cacheable();
+        }
+        if let Ok(mut stripped) = self.stripped.lock() {
+            *stripped = cookies;
+        }
+    }
+
+    /// Adds the cookies stripped from the cached object back to the response delivered on the miss
+    /// that fetched it. Responses served from the cache have nothing to restore.
+    pub fn restore(&self, resp: &mut Response) {
+        let Ok(mut stripped) = self.stripped.lock() else {
+            return;
+        };
+        for cookie in stripped.drain(..) {
+            resp.append_header(header::SET_COOKIE, cookie);
+        }
+    }
+
+    fn is_harmless(&self, cookie: &HeaderValue) -> bool {
+        let name = cookie
+            .to_str()
+            .ok()
+            .and_then(|cookie| cookie.split_once('='))
+            .map(|(name, _)| name.trim());
+        name.is_some_and(|name| self.harmless.iter().any(|harmless| harmless == name))
+    }
+}
+
+/// Returns whether a response with a status and a `Cache-Control` header would be cached by
+/// default if it set no cookie.
+fn is_cacheable_without_cookies(status: StatusCode, cache_control: Option<&str>) -> bool {
+    matches!(status.as_u16(), 200 | 203 | 300 | 301 | 302 | 404 | 410)
+        && !cacheability::has_directive(cache_control, &["no-store", "private"])
+}
+
+/// Removes the cookies of a request that are not allowed through to the origin, if request
+/// cookies are stripped.
+pub fn strip_request_cookies(req: &mut Request, settings: &Settings) {
+    if !settings.get_bool("strip_request_cookies", false) {
+        return;
+    }
+    let allowed = settings.get_list("allowed_request_cookies");
+    let kept: Vec<String> = req
+        .get_header_all_str(header::COOKIE)
+        .iter()
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|cookie| {
+            let name = cookie
+                .split_once('=')
+                .map_or(*cookie, |(name, _)| name)
+                .trim();
+            !cookie.is_empty() && allowed.iter().any(|allowed| allowed == name)
+        })
+        .map(str::to_string)
+        .collect();
+    if kept.is_empty() {
+        req.remove_header(header::COOKIE);
+    } else {
+        req.set_header(header::COOKIE, kept.join("; "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies_alone_keep_cacheable_responses_out() {
+        assert!(is_cacheable_without_cookies(StatusCode::OK, None));
+        assert!(is_cacheable_without_cookies(
+            StatusCode::NOT_FOUND,
+            Some("public, max-age=60")
+        ));
+    }
+
+    #[test]
+    fn other_reasons_keep_responses_out_without_their_cookies() {
+        assert!(!is_cacheable_without_cookies(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            None
+        ));
+        assert!(!is_cacheable_without_cookies(
+            StatusCode::OK,
+            Some("max-age=60, No-Store")
+        ));
+        assert!(!is_cacheable_without_cookies(
+            StatusCode::OK,
+            Some("private")
+        ));
+    }
+}
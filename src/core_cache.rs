@@ -0,0 +1,240 @@
+//! A second caching code path, built on the Core Cache API instead of the readthrough cache.
+//!
+//! `GET` requests under the path prefixes of the `core_cache_path_prefixes` setting are cached
+//! with [`fastly::cache::core`], doing by hand what the readthrough cache does behind the
+//! before-send and after-send callbacks, so that the two can be compared side by side:
+//!
+//! * A transactional lookup finds the cached object. When it is missing, concurrent lookups of the
+//!   same object wait for this one to insert it, so that the origin is only asked once: requests
+//!   are collapsed by the transaction itself.
+//! * A missing object is fetched from the origin and inserted while its body is streamed into the
+//!   cache, and the response is streamed back from the object being inserted.
+//! * A stale object is revalidated with a conditional request carrying its `ETag` and
+//!   `Last-Modified` validators. A `304 Not Modified` only updates the freshness of the object,
+//!   while a new body replaces it. Other clients are served the stale object in the meantime, and
+//!   it is also served if the origin cannot be reached.
+//!
+//! Cache lifetimes come from the `max-age` and `s-maxage` directives of the origin response, and
+//! otherwise from its content type, as chosen by the [`policy`](crate::policy) module. Responses
+//! marked `private` or `no-store`, and responses other than `200 OK`, are passed to the client
+//! without being stored. Responses carry an `X-Core-Cache` header, with `HIT`, `MISS`,
+//! `REVALIDATED`, `STALE` or `PASS`.
+
+use crate::config::Settings;
+use crate::origin_auth;
+use crate::policy::{self, Storage};
+use fastly::cache::core::{CacheKey, Found, Transaction};
+use fastly::http::{header, HeaderName, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// The response header telling what the Core Cache did with a request.
+const CORE_CACHE_HEADER: &str = "x-core-cache";
+
+/// The prefix of the Core Cache keys of objects cached by this module.
+const KEY_PREFIX: &str = "core/";
+
+/// How long stale objects are kept to be served while they are revalidated.
+const REVALIDATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Response headers that are not stored with an object.
+const DROPPED_HEADERS: [HeaderName; 5] = [
+    header::AGE,
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::SET_COOKIE,
+    header::TRANSFER_ENCODING,
+];
+
+/// Returns whether a request is cached through the Core Cache.
+pub fn applies(req: &Request, settings: &Settings) -> bool {
+    req.get_method() == Method::GET
+        && settings
+            .get_list("core_cache_path_prefixes")
+            .iter()
+            .any(|prefix| req.get_path().starts_with(prefix.as_str()))
+}
+
+/// Serves a request from the Core Cache, fetching it from `backend` when it is missing or stale.
+pub fn handle(req: Request, backend: &str) -> Result<Response, Error> {
+    let key = CacheKey::from(format!("{KEY_PREFIX}{}", req.get_url_str()));
+    let transaction = Transaction::lookup(key).execute()?;
+    let found = transaction.found();
+
+    if !transaction.must_insert_or_update() {
+        return match found {
+            Some(found) => serve(&found, "HIT"),
+            // Without an object or the obligation to insert one, the request is simply passed.
+            None => fetch(req, backend, None).map(|resp| mark(resp, "PASS")),
+        };
+    }
+
+    let resp = match fetch(req, backend, found.as_ref()) {
+        Ok(resp) => resp,
+        Err(e) => {
+            transaction.cancel_insert_or_update()?;
+            return match found {
+                Some(found) => {
+                    log::warn!("serving a stale object while the origin fails: {e}");
+                    serve(&found, "STALE")
+                }
+                None => Err(e),
+            };
+        }
+    };
+
+    // A stale object whose validators still match the origin's only has its freshness updated.
+    if let (StatusCode::NOT_MODIFIED, Some(found)) = (resp.get_status(), &found) {
+        let metadata = found.user_metadata();
+        let ttl = stored_lifetime(&resp).unwrap_or_else(|| found.max_age());
+        transaction
+            .update(ttl)
+            .stale_while_revalidate(REVALIDATION_WINDOW)
+            .user_metadata(metadata)
+            .execute()?;
+        return serve(found, "REVALIDATED");
+    }
+
+    match stored_lifetime(&resp).filter(|_| resp.get_status() == StatusCode::OK) {
+        Some(ttl) => insert(transaction, resp, ttl),
+        None => {
+            transaction.cancel_insert_or_update()?;
+            Ok(mark(resp, "PASS"))
+        }
+    }
+}
+
+/// Fetches a request from the origin, as a conditional request if a stale object is revalidated.
+fn fetch(mut req: Request, backend: &str, stale: Option<&Found>) -> Result<Response, Error> {
+    // The validators of the client are not forwarded, so that the origin sends a body to store.
+    req.remove_header(header::IF_NONE_MATCH);
+    req.remove_header(header::IF_MODIFIED_SINCE);
+    let metadata: Option<Value> =
+        stale.and_then(|found| serde_json::from_slice(&found.user_metadata()).ok());
+    if let Some(metadata) = &metadata {
+        if let Some(etag) = stored_header(metadata, header::ETAG.as_str()) {
+            req.set_header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = stored_header(metadata, header::LAST_MODIFIED.as_str()) {
+            req.set_header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    origin_auth::apply(&mut req);
+    req.set_pass(true);
+    Ok(req.send(backend)?)
+}
+
+/// Inserts a response from the origin, streaming its body into the cache, and returns the
+/// response streamed back from the new object.
+fn insert(transaction: Transaction, mut resp: Response, ttl: Duration) -> Result<Response, Error> {
+    let headers: Vec<(&str, &str)> = resp
+        .get_headers()
+        .filter(|(name, _)| !DROPPED_HEADERS.contains(name))
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect();
+    let metadata = json!({ "status": resp.get_status().as_u16(), "headers": headers });
+    let mut insert = transaction
+        .insert(ttl)
+        .stale_while_revalidate(REVALIDATION_WINDOW)
+        .user_metadata(metadata.to_string().into());
+    if let Some(length) = resp.get_content_length() {
+        insert = insert.known_length(length as u64);
+    }
+    let (mut body, found) = insert.execute_and_stream_back()?;
+    body.append(resp.take_body());
+    body.finish()?;
+    serve(&found, "MISS")
+}
+
+/// Returns the response stored in a cached object.
+fn serve(found: &Found, disposition: &str) -> Result<Response, Error> {
+    let metadata: Value = serde_json::from_slice(&found.user_metadata())?;
+    let status = metadata["status"]
+        .as_u64()
+        .and_then(|status| StatusCode::from_u16(u16::try_from(status).ok()?).ok())
+        .unwrap_or(StatusCode::OK);
+    let mut resp = Response::from_body(found.to_stream()?).with_status(status);
+    for header in metadata["headers"].as_array().into_iter().flatten() {
+        if let (Some(name), Some(value)) = (header[0].as_str(), header[1].as_str()) {
+            resp.append_header(name, value);
+        }
+    }
+    resp.set_header(header::AGE, found.age().as_secs().to_string());
+    Ok(mark(resp, disposition))
+}
+
+fn mark(resp: Response, disposition: &str) -> Response {
+    resp.with_header(CORE_CACHE_HEADER, disposition)
+}
+
+/// Returns the first value of a header stored in the metadata of an object.
+fn stored_header<'a>(metadata: &'a Value, name: &str) -> Option<&'a str> {
+    metadata["headers"]
+        .as_array()?
+        .iter()
+        .find(|header| header[0].as_str() == Some(name))
+        .and_then(|header| header[1].as_str())
+}
+
+/// Returns how long a response from the origin is stored, or `None` if it is not.
+fn stored_lifetime(resp: &Response) -> Option<Duration> {
+    lifetime(
+        resp.get_header_str(header::CACHE_CONTROL),
+        resp.get_header_str(header::CONTENT_TYPE),
+    )
+}
+
+/// Returns the cache lifetime of a response with a `Cache-Control` header and a content type, or
+/// `None` if it is not stored.
+fn lifetime(cache_control: Option<&str>, content_type: Option<&str>) -> Option<Duration> {
+    let mut max_age = None;
+    for directive in cache_control.unwrap_or_default().split(',') {
+        let (name, value) = directive
+            .split_once('=')
+            .map_or((directive, None), |(name, value)| (name, Some(value)));
+        let secs = value.and_then(|value| value.trim().trim_matches('"').parse().ok());
+        match name.trim().to_ascii_lowercase().as_str() {
+            "no-store" | "private" => return None,
+            "s-maxage" => max_age = secs.map(Duration::from_secs).or(max_age),
+            "max-age" if max_age.is_none() => max_age = secs.map(Duration::from_secs),
+            _ => {}
+        }
+    }
+    match max_age {
+        Some(max_age) => Some(max_age),
+        None => match policy::storage(None, content_type, None) {
+            Storage::Ttl(ttl) => Some(ttl),
+            Storage::Uncacheable => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifetime_comes_from_cache_control_first() {
+        let secs = |cache_control, content_type| {
+            lifetime(cache_control, content_type).map(|ttl| ttl.as_secs())
+        };
+        assert_eq!(
+            secs(Some("public, max-age=600"), Some("text/html")),
+            Some(600)
+        );
+        assert_eq!(secs(Some("s-maxage=60, max-age=600"), None), Some(60));
+        assert_eq!(secs(Some("max-age=600, s-maxage=60"), None), Some(60));
+        assert_eq!(secs(None, Some("text/html")), Some(321));
+        assert_eq!(secs(Some("private, max-age=600"), None), None);
+        assert_eq!(secs(Some("no-store"), Some("text/html")), None);
+        assert_eq!(secs(None, Some("application/xml")), None);
+    }
+
+    #[test]
+    fn validators_are_read_from_metadata() {
+        let metadata = json!({ "status": 200, "headers": [["etag", "\"v1\""], ["vary", "a"]] });
+        assert_eq!(stored_header(&metadata, "etag"), Some("\"v1\""));
+        assert_eq!(stored_header(&metadata, "last-modified"), None);
+    }
+}
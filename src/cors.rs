@@ -0,0 +1,213 @@
+//! Cross-origin resource sharing, handled at the edge.
+//!
+//! With origins listed in the `cors_allowed_origins` setting, such as
+//! `https://app.example.com,https://admin.example.com` or `*` for any origin:
+//!
+//! * `OPTIONS` preflight requests are answered at the edge, without reaching the origin, with the
+//!   methods of the `cors_allowed_methods` setting, the request headers of the
+//!   `cors_allowed_headers` setting, and a `Access-Control-Max-Age` of `cors_max_age` seconds.
+//!   Preflights from other origins, or for other methods or headers, are refused with a 403.
+//! * Every response to an allowed origin gets it back in an `Access-Control-Allow-Origin` header
+//!   after the cache, so the header is never stored with an object shared by every origin.
+//! * Cached objects vary on the `Origin` header, so that an origin that sends CORS headers of its
+//!   own never has them served to another origin. To keep the variants few, the `Origin` of the
+//!   lookup is removed unless it is one of the listed origins, and always when any origin is
+//!   allowed, since the response is then the same for all of them.
+
+use crate::config::Settings;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Request, Response};
+
+/// The methods allowed unless overridden by the `cors_allowed_methods` setting.
+const DEFAULT_METHODS: &str = "GET,HEAD,POST";
+
+/// How long browsers keep preflight results, unless overridden by the `cors_max_age` setting (in
+/// seconds).
+const DEFAULT_MAX_AGE_SECS: u64 = 600;
+
+/// The CORS policy of the service.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    origins: Vec<String>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    max_age: u64,
+}
+
+impl Cors {
+    /// Returns the CORS policy of the settings, or `None` if no origin is allowed.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        let origins = settings.get_list("cors_allowed_origins");
+        if origins.is_empty() {
+            return None;
+        }
+        let mut methods = settings.get_list("cors_allowed_methods");
+        if methods.is_empty() {
+            methods = DEFAULT_METHODS.split(',').map(str::to_string).collect();
+        }
+        Some(Self {
+            origins,
+            methods: methods
+                .iter()
+                .map(|method| method.to_ascii_uppercase())
+                .collect(),
+            headers: settings
+                .get_list("cors_allowed_headers")
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            max_age: settings.get_u64("cors_max_age", DEFAULT_MAX_AGE_SECS),
+        })
+    }
+
+    /// Returns whether a request is a CORS preflight.
+    pub fn is_preflight(req: &Request) -> bool {
+        req.get_method() == Method::OPTIONS
+            && req.contains_header(header::ORIGIN)
+            && req.contains_header(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// Answers a preflight request.
+    pub fn preflight(&self, req: &Request) -> Response {
+        let allowed_origin = req
+            .get_header_str(header::ORIGIN)
+            .and_then(|origin| self.allowed_origin(origin));
+        let method = req
+            .get_header_str(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .unwrap_or_default();
+        let requested_headers = req
+            .get_header_str(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .unwrap_or_default();
+        let resp = Response::from_status(StatusCode::NO_CONTENT).with_header(
+            header::VARY,
+            "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+        );
+        let Some(allowed_origin) = allowed_origin
+            .filter(|_| self.allows_method(method) && self.allows_headers(requested_headers))
+        else {
+            return resp.with_status(StatusCode::FORBIDDEN);
+        };
+        let allowed_headers = if self.headers.is_empty() {
+            requested_headers.to_string()
+        } else {
+            self.headers.join(", ")
+        };
+        let mut resp = resp
+            .with_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin)
+            .with_header(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                self.methods.join(", "),
+            )
+            .with_header(header::ACCESS_CONTROL_MAX_AGE, self.max_age.to_string());
+        if !allowed_headers.is_empty() {
+            resp.set_header(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        resp
+    }
+
+    /// Replaces the `Origin` header of a request with the one cached objects vary on: the listed
+    /// origin it comes from, or none.
+    pub fn normalize_origin(&self, req: &mut Request) {
+        let keep = req
+            .get_header_str(header::ORIGIN)
+            .is_some_and(|origin| self.is_listed(origin));
+        if !keep {
+            req.remove_header(header::ORIGIN);
+        }
+    }
+
+    /// Adds the CORS headers of the client's origin to a response as it is delivered.
+    pub fn apply(&self, origin: Option<&str>, resp: &mut Response) {
+        let varies_on_origin = resp
+            .get_header_all_str(header::VARY)
+            .iter()
+            .flat_map(|value| value.split(','))
+            .any(|name| name.trim().eq_ignore_ascii_case("origin"));
+        if !varies_on_origin {
+            resp.append_header(header::VARY, "Origin");
+        }
+        if let Some(allowed_origin) = origin.and_then(|origin| self.allowed_origin(origin)) {
+            resp.set_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+        }
+    }
+
+    /// Returns the `Access-Control-Allow-Origin` value for a request origin: the origin itself,
+    /// so that credentialed requests are allowed too, or `None` if the origin is not allowed.
+    fn allowed_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        (self.origins.iter().any(|allowed| allowed == "*") || self.is_listed(origin))
+            .then_some(origin)
+    }
+
+    /// Returns whether an origin is listed by name in the `cors_allowed_origins` setting.
+    fn is_listed(&self, origin: &str) -> bool {
+        self.origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    /// Returns whether every header of an `Access-Control-Request-Headers` value is allowed. Any
+    /// header is allowed when the `cors_allowed_headers` setting is empty.
+    fn allows_headers(&self, requested: &str) -> bool {
+        self.headers.is_empty()
+            || requested
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .all(|name| self.headers.contains(&name.to_ascii_lowercase()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &str, headers: &str) -> Cors {
+        let list = |value: &str| {
+            value
+                .split(',')
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        Cors {
+            origins: list(origins),
+            methods: list("GET,HEAD,POST"),
+            headers: list(headers),
+            max_age: 600,
+        }
+    }
+
+    #[test]
+    fn listed_origins_are_allowed() {
+        let cors = cors("https://app.example.com,https://admin.example.com", "");
+        assert_eq!(
+            cors.allowed_origin("https://APP.example.com"),
+            Some("https://APP.example.com")
+        );
+        assert_eq!(cors.allowed_origin("https://evil.example"), None);
+
+        let any = self::cors("*", "");
+        assert_eq!(
+            any.allowed_origin("https://evil.example"),
+            Some("https://evil.example")
+        );
+        assert!(!any.is_listed("https://evil.example"));
+    }
+
+    #[test]
+    fn preflight_methods_and_headers_are_checked() {
+        let cors = cors("*", "content-type,authorization");
+        assert!(cors.allows_method("post"));
+        assert!(!cors.allows_method("DELETE"));
+        assert!(cors.allows_headers("Content-Type, Authorization"));
+        assert!(cors.allows_headers(""));
+        assert!(!cors.allows_headers("content-type, x-secret"));
+        assert!(self::cors("*", "").allows_headers("x-anything"));
+    }
+}
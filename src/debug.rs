@@ -0,0 +1,57 @@
+//! Debugging controls for operators.
+//!
+//! Operators identify themselves with the `debug_token` secret in the `X-Debug-Token` header.
+//! Requests carrying it may refresh the cached object with `Cache-Control: no-cache` (see the
+//! `no_cache` module), and may override the TTL of the response they fetch with an `X-Edge-Override-TTL`
+//! header, so that cache policy changes can be tried against production origins without affecting
+//! other objects. The debugging headers are removed from every request before it is sent on, and
+//! are ignored on requests from anyone else.
+
+use crate::{secrets, signing};
+use fastly::Request;
+use serde_json::json;
+use std::time::Duration;
+
+/// The request header carrying the operator debug token.
+const DEBUG_TOKEN_HEADER: &str = "x-debug-token";
+
+/// The name of the secret holding the operator debug token.
+const DEBUG_TOKEN_SECRET: &str = "debug_token";
+
+/// The request header carrying a TTL override, in seconds.
+const OVERRIDE_TTL_HEADER: &str = "x-edge-override-ttl";
+
+/// Removes the debug token from a request, returning whether it is valid.
+pub fn take_debug_token(req: &mut Request) -> bool {
+    req.remove_header_str(DEBUG_TOKEN_HEADER)
+        .is_some_and(|token| {
+            secrets::get(DEBUG_TOKEN_SECRET).is_some_and(|expected| {
+                signing::constant_time_eq(token.trim().as_bytes(), &expected)
+            })
+        })
+}
+
+/// Removes the TTL override from a request, returning it if the request is from an operator.
+///
+/// Every override that is honored is logged as a JSON audit line.
+pub fn take_ttl_override(req: &mut Request, is_operator: bool) -> Option<Duration> {
+    let value = req.remove_header_str(OVERRIDE_TTL_HEADER)?;
+    if !is_operator {
+        log::info!("ignoring TTL override from a client without a debug token");
+        return None;
+    }
+    let Ok(secs) = value.trim().parse::<u64>() else {
+        log::warn!("ignoring invalid TTL override: {value}");
+        return None;
+    };
+    log::info!(
+        "{}",
+        json!({
+            "audit": "ttl_override",
+            "url": req.get_url_str(),
+            "client_ip": req.get_client_ip_addr().map(|ip| ip.to_string()),
+            "ttl_secs": secs,
+        })
+    );
+    Some(Duration::from_secs(secs))
+}
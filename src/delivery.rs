@@ -0,0 +1,44 @@
+//! Per-client header changes, made to the response as it is delivered.
+//!
+//! The after-send callback shapes the object stored in the shared cache, so anything it writes is
+//! replayed to every client. Headers that depend on the client or on the moment of delivery are
+//! set here instead, after the response has come out of the cache:
+//!
+//! * internal marker headers and the edge-only `Surrogate-Control` are removed;
+//! * harmless cookies stashed on a miss are given back to the client whose request fetched them;
+//! * the headers listed in the `delivery_removed_headers` setting are removed.
+
+use crate::config::Settings;
+use crate::cookies::CookieStash;
+use crate::{freshness, transform};
+use fastly::Response;
+
+/// The delivery-time header changes for one request.
+pub struct DeliveryHook {
+    cookie_stash: Option<CookieStash>,
+    removed_headers: Vec<String>,
+}
+
+impl DeliveryHook {
+    /// Returns the delivery-time header changes of the settings, which give back the cookies held
+    /// by `cookie_stash`, if any.
+    pub fn from_settings(settings: &Settings, cookie_stash: Option<CookieStash>) -> Self {
+        Self {
+            cookie_stash,
+            removed_headers: settings.get_list("delivery_removed_headers"),
+        }
+    }
+
+    /// Applies the header changes to the response delivered to this client.
+    pub fn apply(&self, resp: &mut Response) {
+        resp.remove_header(transform::TRANSFORMED_HEADER);
+        resp.remove_header(freshness::SURROGATE_CONTROL);
+        for name in &self.removed_headers {
+            resp.remove_header(name.as_str());
+        }
+
+        if let Some(stash) = &self.cookie_stash {
+            stash.restore(resp);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Device-class variants of cached pages.
+//!
+//! With the `device_variants` setting on, every request is classified as `desktop`, `mobile` or
+//! `tablet` from its Client Hints and `User-Agent`, and the class is sent to the origin in an
+//! `X-Device-Class` header. The cache varies on that header, so each device class gets its own
+//! cached variant, rather than one per `User-Agent` string.
+//!
+//! The header is set before the cache lookup rather than in the before-send callback, since the
+//! cache matches variants against the headers of the request being looked up.
+
+use fastly::http::{header, HeaderName};
+use fastly::Request;
+
+/// The request header carrying the device class, which the cache varies on.
+pub const DEVICE_CLASS_HEADER: HeaderName = HeaderName::from_static("x-device-class");
+
+/// The Client Hint telling whether the browser runs on a mobile device.
+const MOBILE_HINT: &str = "sec-ch-ua-mobile";
+
+/// The broad class of a client device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DeviceClass {
+    Desktop,
+    Mobile,
+    Tablet,
+}
+
+impl DeviceClass {
+    /// Classifies the device a request comes from.
+    fn of(req: &Request) -> Self {
+        Self::classify(
+            req.get_header_str(header::USER_AGENT),
+            req.get_header_str(MOBILE_HINT),
+        )
+    }
+
+    /// Classifies a device from its `User-Agent` and its mobile Client Hint.
+    fn classify(user_agent: Option<&str>, mobile_hint: Option<&str>) -> Self {
+        let user_agent = user_agent.unwrap_or_default().to_ascii_lowercase();
+        // Tablets report themselves as non-mobile in Client Hints, so they are recognized by
+        // their `User-Agent` first. Android tablets leave `Mobile` out of it.
+        let is_tablet = user_agent.contains("ipad")
+            || user_agent.contains("tablet")
+            || (user_agent.contains("android") && !user_agent.contains("mobile"));
+        if is_tablet {
+            return DeviceClass::Tablet;
+        }
+        match mobile_hint.map(str::trim) {
+            Some("?1") => DeviceClass::Mobile,
+            Some("?0") => DeviceClass::Desktop,
+            _ if ["mobile", "iphone", "ipod", "windows phone"]
+                .iter()
+                .any(|token| user_agent.contains(token)) =>
+            {
+                DeviceClass::Mobile
+            }
+            _ => DeviceClass::Desktop,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            DeviceClass::Desktop => "desktop",
+            DeviceClass::Mobile => "mobile",
+            DeviceClass::Tablet => "tablet",
+        }
+    }
+}
+
+/// Sets the device class header of a request, replacing any sent by the client.
+pub fn set_device_class(req: &mut Request) {
+    let class = DeviceClass::of(req);
+    req.set_header(DEVICE_CLASS_HEADER, class.as_str());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_are_classified() {
+        let cases = [
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) Mobile/15E148",
+                None,
+                DeviceClass::Mobile,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) Chrome/120.0 Mobile Safari/537.36",
+                None,
+                DeviceClass::Mobile,
+            ),
+            (
+                "Mozilla/5.0 (iPad; CPU OS 17_0 like Mac OS X) Safari/604.1",
+                None,
+                DeviceClass::Tablet,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; SM-X710) Chrome/120.0 Safari/537.36",
+                Some("?0"),
+                DeviceClass::Tablet,
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/120.0 Safari/537.36",
+                None,
+                DeviceClass::Desktop,
+            ),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64)",
+                Some("?1"),
+                DeviceClass::Mobile,
+            ),
+            ("Mozilla/5.0 Mobile", Some("?0"), DeviceClass::Desktop),
+        ];
+        for (user_agent, mobile_hint, class) in cases {
+            assert_eq!(
+                DeviceClass::classify(Some(user_agent), mobile_hint),
+                class,
+                "{user_agent}"
+            );
+        }
+    }
+
+    #[test]
+    fn clients_without_a_user_agent_are_desktops_unless_hinted() {
+        assert_eq!(DeviceClass::classify(None, None), DeviceClass::Desktop);
+        assert_eq!(DeviceClass::classify(None, Some("?1")), DeviceClass::Mobile);
+        assert_eq!(DeviceClass::classify(Some(""), None).as_str(), "desktop");
+    }
+}
@@ -0,0 +1,122 @@
+//! `103 Early Hints` for pages whose subresources are known in advance.
+//!
+//! The Compute platform does not hand the informational responses of a backend to the program, so
+//! `103` responses sent by the origin cannot be forwarded as they are. Instead, with the
+//! `early_hints` setting on, the `preload` and `preconnect` links of every page fetched from the
+//! origin are remembered in the simple cache, alongside the cached page, for `early_hints_ttl`
+//! seconds. Later requests for the page get them in a `103 Early Hints` response as soon as they
+//! arrive, before the cache is even looked up, so that the browser can start fetching the
+//! subresources while the page itself is served.
+//!
+//! Early hints are only sent to clients using HTTP/2 or later, since some HTTP/1.1 clients do not
+//! handle informational responses.
+
+use crate::config::Settings;
+use fastly::cache::simple::{self, CacheEntry};
+use fastly::http::{header, CandidateResponse, Method, StatusCode, Version};
+use fastly::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// How long the links of a page are remembered, unless overridden by the `early_hints_ttl`
+/// setting (in seconds).
+const DEFAULT_TTL_SECS: u64 = 86_400;
+
+/// The link relations worth hinting.
+const HINTED_RELATIONS: [&str; 2] = ["preload", "preconnect"];
+
+/// The early hints of one page.
+#[derive(Clone)]
+pub struct EarlyHints {
+    key: String,
+    ttl: Duration,
+    client_supports_hints: bool,
+}
+
+impl EarlyHints {
+    /// Returns the early hints of the page a request is for, or `None` if early hints are off or
+    /// the request is not for a page.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        if !settings.get_bool("early_hints", false) || req.get_method() != Method::GET {
+            return None;
+        }
+        let digest: String = Sha256::digest(req.get_url_str().as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        Some(Self {
+            key: format!("early-hints/{digest}"),
+            ttl: Duration::from_secs(settings.get_u64("early_hints_ttl", DEFAULT_TTL_SECS)),
+            client_supports_hints: !matches!(
+                req.get_version(),
+                Version::HTTP_09 | Version::HTTP_10 | Version::HTTP_11
+            ),
+        })
+    }
+
+    /// Sends the remembered links of the page to the client in a `103 Early Hints` response.
+    pub fn send(&self) {
+        if !self.client_supports_hints {
+            return;
+        }
+        let Ok(Some(links)) = simple::get(self.key.clone()) else {
+            return;
+        };
+        let links = links.into_string();
+        let mut hints = Response::from_status(StatusCode::EARLY_HINTS);
+        for link in links.lines().filter(|link| !link.is_empty()) {
+            hints.append_header(header::LINK, link);
+        }
+        hints.send_to_client();
+    }
+
+    /// Remembers the `preload` and `preconnect` links of a page fetched from the origin, unless
+    /// links are already remembered for it.
+    pub fn remember(&self, resp: &CandidateResponse) {
+        if !resp.get_status().is_success() {
+            return;
+        }
+        let links: Vec<&str> = resp
+            .get_header_all_str(header::LINK)
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|link| is_hinted(link))
+            .collect();
+        if links.is_empty() {
+            return;
+        }
+
+        // Links already remembered are kept until they expire, so that they are not written on
+        // every fetch.
+        let links = links.join("\n");
+        let ttl = self.ttl;
+        let result = simple::get_or_set_with(self.key.clone(), || {
+            Ok(CacheEntry {
+                value: links.into(),
+                ttl,
+            })
+        });
+        if let Err(e) = result {
+            log::warn!("cannot remember early hints: {e}");
+        }
+    }
+}
+
+/// Returns whether a `Link` value has one of the hinted relations.
+fn is_hinted(link: &str) -> bool {
+    link.split(';').skip(1).any(|param| {
+        param.split_once('=').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("rel")
+                && value
+                    .trim()
+                    .trim_matches('"')
+                    .split_whitespace()
+                    .any(|rel| {
+                        HINTED_RELATIONS
+                            .iter()
+                            .any(|hinted| rel.eq_ignore_ascii_case(hinted))
+                    })
+        })
+    })
+}
@@ -0,0 +1,194 @@
+//! Enrichment of origin JSON with edge data from a KV Store, before it is cached.
+//!
+//! Data that changes independently of the origin content, such as the inventory of a product or
+//! a price override, can be kept in the `enrichment` KV Store and merged into the origin JSON of
+//! the routes under the path prefixes of the `enrichment_path_prefixes` setting. Every JSON
+//! object of the document holding the member named by the `enrichment_key_member` setting (`sku`
+//! by default) is looked up in the KV Store under the value of that member, and the members of
+//! the JSON object stored there are added to it, replacing those of the origin:
+//!
+//! ```text
+//! origin:   {"items": [{"sku": "A-1", "name": "Lamp", "price": 30}]}
+//! KV "A-1": {"price": 25, "inventory": 3}
+//! cached:   {"items": [{"sku": "A-1", "name": "Lamp", "price": 25, "inventory": 3}]}
+//! ```
+//!
+//! The lookups happen in the body transform, so the cached object reflects the edge data as it was
+//! when the object was fetched, and every hit is served without any lookup. Objects must be
+//! purged, or given short TTLs, for changes in the KV Store to show. At most
+//! [`MAX_LOOKUPS`] keys are looked up per document, and keys missing from the store leave their
+//! object as it is.
+
+use crate::config::Settings;
+use crate::transform;
+use fastly::kv_store::KVStore;
+use fastly::Request;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// The name of the KV Store holding the edge data.
+const STORE_NAME: &str = "enrichment";
+
+/// The member holding the KV Store key of an object, unless overridden by the
+/// `enrichment_key_member` setting.
+const DEFAULT_KEY_MEMBER: &str = "sku";
+
+/// The most keys looked up for one document.
+pub const MAX_LOOKUPS: usize = 50;
+
+/// The enrichment of the JSON objects of one request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Enrichment {
+    key_member: String,
+}
+
+impl Enrichment {
+    /// Returns the enrichment of a request, or `None` if its path is not enriched.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        let path = req.get_path();
+        settings
+            .get_list("enrichment_path_prefixes")
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+            .then(|| Self {
+                key_member: settings
+                    .get("enrichment_key_member")
+                    .unwrap_or_else(|| DEFAULT_KEY_MEMBER.to_string()),
+            })
+    }
+
+    /// Returns whether a response body can be enriched: uncompressed JSON only.
+    pub fn applies(content_type: Option<&str>, content_encoding: Option<&str>) -> bool {
+        transform::is_json(content_type) && transform::is_transformable_encoding(content_encoding)
+    }
+
+    /// Returns a JSON body with the edge data of its objects merged into them. Other bodies, and
+    /// every body if the KV Store is not linked, are returned as they are.
+    pub fn apply(&self, body: &[u8]) -> Vec<u8> {
+        let store = match KVStore::open(STORE_NAME) {
+            Ok(Some(store)) => store,
+            Ok(None) => {
+                log::warn!("KV Store {STORE_NAME} is not linked; not enriching");
+                return body.to_vec();
+            }
+            Err(e) => {
+                log::warn!("cannot open KV Store {STORE_NAME}: {e}");
+                return body.to_vec();
+            }
+        };
+        self.enrich(body, |key| {
+            let mut found = store.lookup(key).ok()?;
+            match serde_json::from_slice(&found.take_body_bytes()) {
+                Ok(Value::Object(data)) => Some(data),
+                _ => {
+                    log::warn!("ignoring enrichment data of {key:?}: not a JSON object");
+                    None
+                }
+            }
+        })
+    }
+
+    /// Returns a JSON body with the data found by `lookup` merged into its objects.
+    fn enrich(
+        &self,
+        body: &[u8],
+        mut lookup: impl FnMut(&str) -> Option<Map<String, Value>>,
+    ) -> Vec<u8> {
+        let Ok(mut document) = serde_json::from_slice::<Value>(body) else {
+            return body.to_vec();
+        };
+        let mut found = HashMap::new();
+        self.merge(&mut document, &mut |key: &str| {
+            if !found.contains_key(key) {
+                if found.len() == MAX_LOOKUPS {
+                    return None;
+                }
+                found.insert(key.to_string(), lookup(key));
+            }
+            found[key].clone()
+        });
+        serde_json::to_vec(&document).unwrap_or_else(|_| body.to_vec())
+    }
+
+    fn merge(
+        &self,
+        value: &mut Value,
+        lookup: &mut impl FnMut(&str) -> Option<Map<String, Value>>,
+    ) {
+        match value {
+            Value::Object(object) => {
+                object
+                    .values_mut()
+                    .for_each(|member| self.merge(member, lookup));
+                let data = match object.get(&self.key_member) {
+                    Some(Value::String(key)) => lookup(key),
+                    Some(Value::Number(key)) => lookup(&key.to_string()),
+                    _ => None,
+                };
+                if let Some(data) = data {
+                    object.extend(data);
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.merge(item, lookup)),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn enrich(body: Value, store: Value) -> (Value, Vec<String>) {
+        let enrichment = Enrichment {
+            key_member: "sku".to_string(),
+        };
+        let mut lookups = Vec::new();
+        let enriched = enrichment.enrich(body.to_string().as_bytes(), |key| {
+            lookups.push(key.to_string());
+            store.get(key)?.as_object().cloned()
+        });
+        (serde_json::from_slice(&enriched).unwrap(), lookups)
+    }
+
+    #[test]
+    fn edge_data_is_merged_into_objects() {
+        let (enriched, mut lookups) = enrich(
+            json!({
+                "items": [
+                    { "sku": "A-1", "name": "Lamp", "price": 30 },
+                    { "sku": "B-2", "name": "Desk", "price": 120 },
+                    { "sku": "A-1", "name": "Lamp", "price": 30 },
+                ],
+                "featured": { "sku": 7 },
+            }),
+            json!({
+                "A-1": { "price": 25, "inventory": 3 },
+                "7": { "inventory": 0 },
+            }),
+        );
+        assert_eq!(
+            enriched,
+            json!({
+                "items": [
+                    { "sku": "A-1", "name": "Lamp", "price": 25, "inventory": 3 },
+                    { "sku": "B-2", "name": "Desk", "price": 120 },
+                    { "sku": "A-1", "name": "Lamp", "price": 25, "inventory": 3 },
+                ],
+                "featured": { "sku": 7, "inventory": 0 },
+            })
+        );
+        lookups.sort();
+        assert_eq!(lookups, ["7", "A-1", "B-2"]);
+    }
+
+    #[test]
+    fn lookups_are_bounded() {
+        let items: Vec<Value> = (0..MAX_LOOKUPS + 10)
+            .map(|i| json!({ "sku": i.to_string() }))
+            .collect();
+        let (_, lookups) = enrich(json!(items), json!({}));
+        assert_eq!(lookups.len(), MAX_LOOKUPS);
+    }
+}
@@ -0,0 +1,145 @@
+//! Custom error documents that replace origin error pages.
+//!
+//! Documents are stored in a KV Store under `<status>/<locale>` keys, for example `404/en` or
+//! `503/de`. Once loaded, a document is kept in the Simple Cache so that the KV Store is only
+//! consulted occasionally.
+//!
+//! Custom documents take precedence over the synthetic error pages of `errors`, whose headers they
+//! keep. A document carries the request ID wherever it holds the `{{request_id}}` placeholder.
+
+use crate::config::Settings;
+use crate::transform;
+use fastly::cache::simple::{self, CacheEntry};
+use fastly::http::{header, StatusCode};
+use fastly::kv_store::KVStore;
+use fastly::{mime, Error, Request, Response};
+use std::time::Duration;
+
+/// The name of the KV Store that holds the error documents.
+const STORE_NAME: &str = "error_pages";
+
+/// The statuses that have custom error documents.
+const CUSTOM_STATUSES: [StatusCode; 3] = [
+    StatusCode::NOT_FOUND,
+    StatusCode::INTERNAL_SERVER_ERROR,
+    StatusCode::SERVICE_UNAVAILABLE,
+];
+
+/// The placeholder replaced with the request ID in error documents.
+const REQUEST_ID_PLACEHOLDER: &str = "{{request_id}}";
+
+/// The locale used when no document exists for the client's preferred language.
+const DEFAULT_LOCALE: &str = "en";
+
+/// How long a loaded document is kept in the Simple Cache, unless overridden by the
+/// `error_pages_ttl` setting (in seconds).
+const DEFAULT_TTL_SECS: u64 = 300;
+
+/// Returns the client's preferred language from the `Accept-Language` header, such as `en` for
+/// `en-US,en;q=0.9`.
+pub fn locale(req: &Request) -> String {
+    req.get_header_str(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.split(',').next())
+        .and_then(|tag| tag.split(';').next())
+        .and_then(|tag| tag.trim().split('-').next())
+        .filter(|lang| {
+            (2..=3).contains(&lang.len()) && lang.chars().all(|c| c.is_ascii_alphabetic())
+        })
+        .map(|lang| lang.to_ascii_lowercase())
+        .unwrap_or_else(|| DEFAULT_LOCALE.to_string())
+}
+
+/// Replaces the body of an error response with the matching custom error document, filled in with
+/// the request ID.
+///
+/// The response is returned unchanged if the `pass_origin_errors` setting is enabled, if the
+/// status has no custom document, or if the response is a JSON error body meant for API clients.
+pub fn apply(mut resp: Response, locale: &str, request_id: &str, settings: &Settings) -> Response {
+    if settings.get_bool("pass_origin_errors", false)
+        || !has_document(resp.get_status(), resp.get_header_str(header::CONTENT_TYPE))
+    {
+        return resp;
+    }
+
+    let ttl = Duration::from_secs(settings.get_u64("error_pages_ttl", DEFAULT_TTL_SECS));
+    let status = resp.get_status().as_u16();
+    let cache_key = format!("error-page/{status}/{locale}");
+    let document = simple::get_or_set_with(cache_key, || {
+        let body = load_document(status, locale)?;
+        Ok(CacheEntry {
+            value: body.into(),
+            ttl,
+        })
+    });
+
+    match document {
+        Ok(Some(body)) => {
+            resp.set_body(fill(&body.into_bytes(), request_id));
+            resp.set_content_type(mime::TEXT_HTML_UTF_8);
+            for name in [
+                header::CONTENT_LENGTH,
+                header::CONTENT_ENCODING,
+                header::ETAG,
+                header::LAST_MODIFIED,
+            ] {
+                resp.remove_header(name);
+            }
+            resp
+        }
+        Ok(None) => resp,
+        Err(e) => {
+            log::warn!("error page for {status}/{locale} unavailable: {e}");
+            resp
+        }
+    }
+}
+
+/// Reads the error document for `status` from the KV Store, preferring the given locale.
+fn load_document(status: u16, locale: &str) -> Result<Vec<u8>, Error> {
+    let store = KVStore::open(STORE_NAME)?
+        .ok_or_else(|| Error::msg(format!("KV Store {STORE_NAME} is not linked")))?;
+
+    for candidate in [locale, DEFAULT_LOCALE] {
+        if let Ok(mut found) = store.lookup(&format!("{status}/{candidate}")) {
+            return Ok(found.take_body_bytes());
+        }
+    }
+    Err(Error::msg("no document found"))
+}
+
+/// Returns whether an error response with the given status and content type is replaced with a
+/// custom document.
+fn has_document(status: StatusCode, content_type: Option<&str>) -> bool {
+    CUSTOM_STATUSES.contains(&status) && !transform::is_json(content_type)
+}
+
+/// Fills the request ID into an error document.
+fn fill(document: &[u8], request_id: &str) -> String {
+    String::from_utf8_lossy(document).replace(REQUEST_ID_PLACEHOLDER, request_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_error_pages_are_replaced_unless_json() {
+        let html = Some("text/html; charset=utf-8");
+        assert!(has_document(StatusCode::SERVICE_UNAVAILABLE, html));
+        assert!(has_document(StatusCode::INTERNAL_SERVER_ERROR, html));
+        assert!(!has_document(StatusCode::BAD_GATEWAY, html));
+        assert!(!has_document(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some("application/json")
+        ));
+    }
+
+    #[test]
+    fn documents_carry_the_request_id() {
+        assert_eq!(
+            fill(b"<p>Request ID: {{request_id}}</p>", "abc123"),
+            "<p>Request ID: abc123</p>"
+        );
+        assert_eq!(fill(b"<p>Sorry</p>", "abc123"), "<p>Sorry</p>");
+    }
+}
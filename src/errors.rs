@@ -0,0 +1,125 @@
+//! Synthetic error pages for origin and internal failures.
+//!
+//! Origin 5xx responses, and failures of the service itself, are delivered as an error page
+//! branded with the `error_brand` setting instead of the raw origin body or error message. API
+//! clients, identified by their path or by an `Accept` header that prefers JSON, get a JSON error
+//! object instead. Both carry the request ID, in the page and in an `X-Request-Id` header, so a
+//! failure reported by a visitor can be found in the logs.
+//!
+//! Error pages can be cached downstream for `error_page_ttl` seconds, so that a failing origin is
+//! not hit again by every reload, while recovering quickly once it is back.
+//!
+//! With the `pass_origin_errors` setting on, origin 5xx responses are delivered as they are.
+
+use crate::accept;
+use crate::config::Settings;
+use crate::logging;
+use fastly::http::{header, HeaderName, StatusCode};
+use fastly::{mime, Error, Request, Response};
+use serde_json::json;
+
+/// The response header carrying the request ID.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The brand named on error pages, unless overridden by the `error_brand` setting.
+const DEFAULT_BRAND: &str = "This site";
+
+/// How long error pages can be cached, unless overridden by the `error_page_ttl` setting (in
+/// seconds).
+const DEFAULT_TTL_SECS: u64 = 5;
+
+/// How long clients are asked to wait before retrying when the origin is unreachable, in seconds.
+const RETRY_AFTER_SECS: u64 = 30;
+
+/// The synthetic error pages of a request.
+pub struct SyntheticErrors {
+    request_id: String,
+    json: bool,
+    brand: String,
+    ttl: u64,
+    pass_origin_errors: bool,
+}
+
+impl SyntheticErrors {
+    /// Returns the synthetic error pages of a client request.
+    pub fn for_request(req: &Request, settings: &Settings) -> Self {
+        Self {
+            request_id: logging::request_id(req),
+            json: req.get_path().starts_with("/api/")
+                || accept::prefers(
+                    req.get_header_str(header::ACCEPT),
+                    "application/json",
+                    "text/html",
+                ),
+            brand: settings
+                .get("error_brand")
+                .unwrap_or_else(|| DEFAULT_BRAND.to_string()),
+            ttl: settings.get_u64("error_page_ttl", DEFAULT_TTL_SECS),
+            pass_origin_errors: settings.get_bool("pass_origin_errors", false),
+        }
+    }
+
+    /// Returns the ID of the request, as shown on its error pages.
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// Replaces an origin 5xx response with an error page. Other responses are returned as they
+    /// are.
+    pub fn replace(&self, resp: Response) -> Response {
+        if self.pass_origin_errors || !resp.get_status().is_server_error() {
+            return resp;
+        }
+        let mut page = self.render(resp.get_status());
+        if let Some(retry_after) = resp.get_header(header::RETRY_AFTER) {
+            page.set_header(header::RETRY_AFTER, retry_after.clone());
+        }
+        page
+    }
+
+    /// Returns the error page of a failure of the service itself.
+    pub fn internal(&self, e: &Error) -> Response {
+        log::error!("request {} failed: {e}", self.request_id);
+        self.render(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// Returns the error page of a request whose origin cannot be reached.
+    pub fn outage(&self) -> Response {
+        self.render(StatusCode::SERVICE_UNAVAILABLE)
+            .with_header(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())
+    }
+
+    /// Returns the error page of a status.
+    fn render(&self, status: StatusCode) -> Response {
+        let reason = status.canonical_reason().unwrap_or("Error");
+        let resp = Response::from_status(status)
+            .with_header(header::CACHE_CONTROL, format!("max-age={}", self.ttl))
+            .with_header(REQUEST_ID_HEADER, &self.request_id);
+        if self.json {
+            return resp
+                .with_body_json(&json!({
+                    "error": {
+                        "status": status.as_u16(),
+                        "message": reason,
+                        "request_id": self.request_id,
+                    }
+                }))
+                .unwrap_or_else(|_| Response::from_status(status));
+        }
+        resp.with_content_type(mime::TEXT_HTML_UTF_8)
+            .with_body(format!(
+                "<!DOCTYPE html>\n<title>{reason} | {brand}</title>\n<h1>{reason}</h1>\n<p>{brand} \
+                 cannot show this page right now. Please try again in a moment.</p>\n<p><small>\
+                 Request ID: <code>{id}</code></small></p>\n",
+                brand = escape_html(&self.brand),
+                id = escape_html(&self.request_id),
+            ))
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,240 @@
+//! Edge Side Includes in cached HTML pages.
+//!
+//! HTML pages under the path prefixes in the `esi_path_prefixes` setting are assembled at the
+//! edge before they are cached: every `<esi:include src="..."/>` tag is replaced with the body of
+//! the fragment it points to, and `<esi:remove>...</esi:remove>` blocks, which hold the fallback
+//! content for clients without ESI, are dropped. Tags are matched whatever their case, such as
+//! `<ESI:Include>`. The assembled page is what the cache stores, so fragments are fetched once per
+//! page fetch rather than once per client.
+//!
+//! Relative `src` URLs are resolved against the page URL and fetched from the page's backend.
+//! Absolute URLs are fetched from the backend of their host, listed in the `esi_backends` setting
+//! as `<host>=<backend>` entries. Includes of other hosts are never fetched. Fragments are fetched
+//! through the cache, and are not processed for includes of their own.
+//!
+//! Includes that cannot be fetched are left empty and logged, so that a failing fragment does not
+//! take the page down with it. Gzip and Brotli pages are decompressed to be processed, and
+//! compressed again, while fragments are included as they are fetched, so they must not be
+//! compressed.
+
+use crate::config::Settings;
+use crate::transform;
+use fastly::http::{StatusCode, Url};
+use fastly::{Body, Request};
+use std::io::{self, Read, Write};
+
+/// The most includes processed in one page. Further include tags are dropped.
+const MAX_INCLUDES: usize = 32;
+
+/// The opening of an include tag, and the closing tag of includes written as an element.
+const INCLUDE_START: &[u8] = b"<esi:include";
+const INCLUDE_END: &[u8] = b"</esi:include>";
+
+/// The opening and closing tags of a removed block.
+const REMOVE_START: &[u8] = b"<esi:remove>";
+const REMOVE_END: &[u8] = b"</esi:remove>";
+
+/// The ESI processing of one page.
+#[derive(Clone)]
+pub struct EsiProcessor {
+    base: Url,
+    backend: String,
+    host_backends: Vec<(String, String)>,
+}
+
+impl EsiProcessor {
+    /// Returns the ESI processing of a page fetched from `backend`, or `None` if the page is not
+    /// under an ESI path prefix.
+    pub fn for_request(req: &Request, backend: &str, settings: &Settings) -> Option<Self> {
+        let path = req.get_path();
+        if !settings
+            .get_list("esi_path_prefixes")
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+        let host_backends = settings
+            .get_list("esi_backends")
+            .iter()
+            .filter_map(|entry| {
+                let (host, backend) = entry.split_once('=')?;
+                Some((host.trim().to_ascii_lowercase(), backend.trim().to_string()))
+            })
+            .collect();
+        Some(Self {
+            base: req.get_url().clone(),
+            backend: backend.to_string(),
+            host_backends,
+        })
+    }
+
+    /// Returns whether a response can be processed: HTML, either uncompressed or compressed with
+    /// gzip or Brotli. Its ESI tags are then matched whatever their case.
+    pub fn applies(content_type: Option<&str>, content_encoding: Option<&str>) -> bool {
+        transform::is_html(content_type) && transform::is_transformable_encoding(content_encoding)
+    }
+
+    /// Writes a page to `body_out` with its includes replaced by their fragments, streaming each
+    /// fragment body through as it is fetched.
+    pub fn assemble(&self, page: &[u8], body_out: &mut impl Write) -> io::Result<()> {
+        assemble_with(page, body_out, |src| self.fetch(src))
+    }
+
+    /// Fetches the body of a fragment through the cache.
+    fn fetch(&self, src: &str) -> Result<Body, String> {
+        let url = self.base.join(src).map_err(|e| e.to_string())?;
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        let backend = if url.host_str() == self.base.host_str() {
+            self.backend.as_str()
+        } else {
+            self.host_backends
+                .iter()
+                .find(|(listed, _)| *listed == host)
+                .map(|(_, backend)| backend.as_str())
+                .ok_or_else(|| format!("no backend for host {host}"))?
+        };
+        let mut resp = Request::get(url).send(backend).map_err(|e| e.to_string())?;
+        if resp.get_status() != StatusCode::OK {
+            return Err(format!("status {}", resp.get_status()));
+        }
+        Ok(resp.take_body())
+    }
+}
+
+/// Writes a page to `body_out` with its includes replaced by the fragments returned by `fetch`.
+fn assemble_with<R: Read>(
+    page: &[u8],
+    body_out: &mut impl Write,
+    mut fetch: impl FnMut(&str) -> Result<R, String>,
+) -> io::Result<()> {
+    let mut rest = page;
+    let mut includes = 0;
+    loop {
+        let next_include = find(rest, INCLUDE_START);
+        let next_remove = find(rest, REMOVE_START);
+        let (start, is_include) = match (next_include, next_remove) {
+            (Some(include), Some(remove)) if remove < include => (remove, false),
+            (Some(include), _) => (include, true),
+            (None, Some(remove)) => (remove, false),
+            (None, None) => return body_out.write_all(rest),
+        };
+        body_out.write_all(&rest[..start])?;
+        rest = &rest[start..];
+
+        if !is_include {
+            // An unterminated block removes the rest of the page, as it would in the browser
+            // fallback it is meant for.
+            rest = match find(rest, REMOVE_END) {
+                Some(end) => &rest[end + REMOVE_END.len()..],
+                None => &[],
+            };
+            continue;
+        }
+
+        let Some(tag_end) = find(rest, b">") else {
+            return Ok(());
+        };
+        let tag = &rest[..tag_end];
+        rest = &rest[tag_end + 1..];
+        // Includes written as an element rather than an empty tag have a closing tag.
+        let has_end_tag = rest
+            .get(..INCLUDE_END.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(INCLUDE_END));
+        if !tag.ends_with(b"/") && has_end_tag {
+            rest = &rest[INCLUDE_END.len()..];
+        }
+
+        includes += 1;
+        if includes > MAX_INCLUDES {
+            log::warn!("dropping ESI include beyond the first {MAX_INCLUDES}");
+            continue;
+        }
+        let Some(src) = attribute(tag, "src") else {
+            log::warn!("dropping ESI include without src");
+            continue;
+        };
+        match fetch(&src) {
+            Ok(mut fragment) => {
+                io::copy(&mut fragment, body_out)?;
+            }
+            Err(e) => log::warn!("cannot include {src}: {e}"),
+        }
+    }
+}
+
+/// Returns the position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
+
+/// Returns the value of an attribute of a tag, quoted with double or single quotes.
+fn attribute(tag: &[u8], name: &str) -> Option<String> {
+    let tag = std::str::from_utf8(tag).ok()?;
+    let mut rest = tag;
+    while let Some(position) = rest.find(name) {
+        let before = rest[..position].chars().last();
+        let after = rest[position + name.len()..].trim_start();
+        rest = &rest[position + name.len()..];
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        let Some(value) = after.strip_prefix('=').map(str::trim_start) else {
+            continue;
+        };
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| value[..end].to_string());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(page: &str) -> String {
+        let mut out = Vec::new();
+        assemble_with(page.as_bytes(), &mut out, |src| match src {
+            "/nav" => Ok(&b"<nav>Home</nav>"[..]),
+            other => Err(format!("no fragment {other}")),
+        })
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn includes_are_replaced_and_removed_blocks_dropped() {
+        assert_eq!(assemble("<p>No includes</p>"), "<p>No includes</p>");
+        assert_eq!(
+            assemble(r#"<body><esi:include src="/nav"/><p>Story</p></body>"#),
+            "<body><nav>Home</nav><p>Story</p></body>"
+        );
+        assert_eq!(
+            assemble(
+                r#"<ESI:INCLUDE src="/nav"></ESI:Include><esi:remove><a href="/nav">Nav</a></esi:remove>!"#
+            ),
+            "<nav>Home</nav>!"
+        );
+        assert_eq!(
+            assemble(r#"<esi:include src="/gone"/><esi:include/>ok<esi:remove>rest"#),
+            "ok"
+        );
+    }
+
+    #[test]
+    fn attributes_are_read_from_quoted_values() {
+        assert_eq!(
+            attribute(br#"<esi:include src="/nav""#, "src").as_deref(),
+            Some("/nav")
+        );
+        assert_eq!(
+            attribute(br#"<esi:include data-src="/a" src = '/b'"#, "src").as_deref(),
+            Some("/b")
+        );
+        assert_eq!(attribute(b"<esi:include src=/nav", "src"), None);
+        assert_eq!(attribute(b"<esi:include", "src"), None);
+    }
+}
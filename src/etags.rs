@@ -0,0 +1,180 @@
+//! Strong ETags generated at the edge for transformed bodies, and conditional requests answered
+//! from them.
+//!
+//! The validators of the origin describe the origin body, not the body a transform stores in its
+//! place. They are kept on the cached object, since the cache revalidates the object with them
+//! and the transformed body only changes when the origin body does, but they cannot be handed to
+//! clients. With the `edge_etags` setting on, every transform hashes the body it writes to the
+//! cache, and stores a strong ETag computed from the hash in an `ETag` trailer of the object,
+//! since its headers are already settled by the time the body is written. Transformed objects
+//! carry an `X-Edge-ETag` marker header telling that the trailer is there.
+//!
+//! The client's `If-None-Match` header is taken off the request before the lookup, and answered
+//! at the edge instead: when the object is delivered, the ETag of a transformed object is read
+//! from its trailer into the `ETag` header, and clients holding the current ETag get a
+//! `304 Not Modified` without a body. Transformed objects are read into memory to find their
+//! trailer, rather than being streamed to the client.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use fastly::experimental::{BodyExt, StreamingBodyExt};
+use fastly::http::body::StreamingBody;
+use fastly::http::{header, CandidateResponse, StatusCode};
+use fastly::{Body, Request, Response};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+
+/// The header marking objects whose ETag is stored in a trailer.
+const MARKER_HEADER: &str = "x-edge-etag";
+
+/// The number of bytes of the SHA-256 hash of a body that its ETag is made of.
+const ETAG_BYTES: usize = 16;
+
+/// A writer that hashes the transformed body written through it, to compute its ETag.
+pub struct EtagWriter<W> {
+    inner: W,
+    hasher: Option<Sha256>,
+}
+
+impl<W: Write> EtagWriter<W> {
+    /// Wraps the output of a transform. Nothing is hashed unless `enabled`.
+    pub fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            hasher: enabled.then(Sha256::new),
+        }
+    }
+
+    /// Returns the strong ETag of the body written, or `None` if nothing was hashed.
+    pub fn finish(self) -> Option<String> {
+        etag(self.hasher)
+    }
+}
+
+impl EtagWriter<&mut StreamingBody> {
+    /// Stores the ETag of the body written in a trailer of the object, if one was computed.
+    pub fn append_trailer(self) {
+        if let Some(etag) = etag(self.hasher) {
+            self.inner.append_trailer(header::ETAG, etag);
+        }
+    }
+}
+
+impl<W: Write> Write for EtagWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Marks a candidate response whose body is being transformed as carrying its ETag in a trailer.
+pub fn mark(resp: &mut CandidateResponse) {
+    resp.set_header(MARKER_HEADER, "trailer");
+}
+
+/// Removes the client's `If-None-Match` header from a request, returning it.
+pub fn take_if_none_match(req: &mut Request) -> Option<String> {
+    let if_none_match = req
+        .get_header_str(header::IF_NONE_MATCH)
+        .map(str::to_string);
+    req.remove_header(header::IF_NONE_MATCH);
+    if_none_match
+}
+
+/// Sets the `ETag` of a delivered transformed object from its trailer, and answers the client's
+/// `If-None-Match` with a `304 Not Modified` when it holds the current ETag.
+///
+/// Transformed objects whose trailer was lost, because the body was rebuilt for delivery, are
+/// delivered without an `ETag`.
+pub fn apply(mut resp: Response, if_none_match: Option<&str>) -> Response {
+    if resp.remove_header(MARKER_HEADER).is_some() {
+        resp.remove_header(header::ETAG);
+        let mut body = resp.take_body();
+        let mut bytes = Vec::new();
+        if let Err(e) = body.read_to_end(&mut bytes) {
+            log::warn!("cannot read transformed body for its ETag: {e}");
+        }
+        let mut trailers = body.get_trailers().unwrap_or_default();
+        let etag = trailers.remove(header::ETAG);
+        let mut body = Body::from(bytes);
+        for (name, value) in &trailers {
+            body.append_trailer(name, value);
+        }
+        resp.set_body(body);
+        if let Some(etag) = etag {
+            resp.set_header(header::ETAG, etag);
+        }
+    }
+
+    let etag = resp.get_header_str(header::ETAG);
+    let is_not_modified = resp.get_status() == StatusCode::OK
+        && if_none_match
+            .zip(etag)
+            .is_some_and(|(if_none_match, etag)| matches(if_none_match, etag));
+    if is_not_modified {
+        resp.set_status(StatusCode::NOT_MODIFIED);
+        resp.set_body(Body::new());
+        resp.remove_header(header::CONTENT_LENGTH);
+    }
+    resp
+}
+
+/// Returns the strong ETag of a body from its hash.
+fn etag(hasher: Option<Sha256>) -> Option<String> {
+    let hash = hasher?.finalize();
+    Some(format!(
+        "\"{}\"",
+        URL_SAFE_NO_PAD.encode(&hash[..ETAG_BYTES])
+    ))
+}
+
+/// Returns whether an `If-None-Match` header matches an ETag, with the weak comparison of
+/// RFC 9110.
+fn matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    if_none_match
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || opaque(candidate) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn etag_is_computed_from_the_body_written() {
+        let mut writer = EtagWriter::new(Vec::new(), true);
+        writer.write_all(b"<div>Ada ").unwrap();
+        writer.write_all(b"Lovelace</div>").unwrap();
+        let etag = writer.finish().unwrap();
+
+        let mut again = EtagWriter::new(Vec::new(), true);
+        again.write_all(b"<div>Ada Lovelace</div>").unwrap();
+        assert_eq!(again.finish(), Some(etag.clone()));
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(etag.len(), 24);
+
+        let mut other = EtagWriter::new(Vec::new(), true);
+        other.write_all(b"<div>Grace Hopper</div>").unwrap();
+        assert_ne!(other.finish(), Some(etag));
+
+        assert_eq!(EtagWriter::new(Vec::new(), false).finish(), None);
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        assert!(matches("\"a\"", "\"a\""));
+        assert!(matches("\"b\", W/\"a\"", "\"a\""));
+        assert!(matches("\"a\"", "W/\"a\""));
+        assert!(matches("*", "\"a\""));
+        assert!(!matches("\"b\"", "\"a\""));
+    }
+}
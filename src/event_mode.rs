@@ -0,0 +1,48 @@
+//! Live-event mode, which makes the whole edge more reactive during breaking events.
+//!
+//! While the `event_mode` setting is on, every TTL and stale-while-revalidate window computed for
+//! a response is multiplied by the `event_mode_factor` setting, and error responses are not cached
+//! at all, so that a fix at the origin is visible immediately.
+
+use crate::config::Settings;
+use fastly::http::CandidateResponse;
+
+/// The factor applied to cache lifetimes when the `event_mode_factor` setting is absent.
+const DEFAULT_FACTOR: f64 = 0.1;
+
+/// The live-event mode cache policy.
+#[derive(Clone, Copy)]
+pub struct EventMode {
+    factor: f64,
+}
+
+impl EventMode {
+    /// Returns the live-event mode policy, or `None` if live-event mode is off.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if !settings.get_bool("event_mode", false) {
+            return None;
+        }
+        let factor = settings.get_f64("event_mode_factor", DEFAULT_FACTOR);
+        Some(Self {
+            factor: if factor.is_finite() {
+                factor.clamp(0.0, 1.0)
+            } else {
+                DEFAULT_FACTOR
+            },
+        })
+    }
+
+    /// Scales the cache lifetimes of a response, and prevents error responses from being cached.
+    ///
+    /// This must run after every other policy, so that it applies to the final cache lifetimes.
+    pub fn apply(&self, resp: &mut CandidateResponse) {
+        let status = resp.get_status();
+        if status.is_client_error() || status.is_server_error() {
+            resp.set_uncacheable(false);
+            return;
+        }
+
+        resp.set_ttl(resp.get_ttl().mul_f64(self.factor));
+        resp.set_stale_while_revalidate(resp.get_stale_while_revalidate().mul_f64(self.factor));
+    }
+}
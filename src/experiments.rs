@@ -0,0 +1,204 @@
+//! A/B experiments, with one cached variant per bucket.
+//!
+//! The `experiments` setting defines experiments as comma-separated entries of the form
+//! `<name> <path prefix> <variant>=<weight>...`, such as `hero /products/ control=50 compact=50`.
+//! The first experiment whose prefix a request path starts with applies to it.
+//!
+//! Visitors are identified by the cookie named by the `experiment_cookie` setting, which is set
+//! on the response of visitors who do not have one yet. A visitor is bucketed by hashing the name
+//! of the experiment with their ID, so that they always see the same variant of an experiment,
+//! without any state kept at the edge, and different experiments bucket visitors independently.
+//!
+//! The variant is sent to the origin in an `X-Experiment` header of the form
+//! `<name>=<variant>`, which the cache varies on: each bucket has its own cached variant, however
+//! many visitors there are. Transforms see the bucket too: as an example, HTML pages under an
+//! experiment are stored with `data-experiment` and `data-variant` attributes on their `<html>`
+//! element, for the page scripts and styles of each variant to key on.
+
+use crate::config::Settings;
+use crate::html_rewrite::Rewrites;
+use fastly::http::{header, HeaderName};
+use fastly::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// The request header carrying the experiment variant, which the cache varies on.
+pub const EXPERIMENT_HEADER: HeaderName = HeaderName::from_static("x-experiment");
+
+/// The name of the visitor ID cookie, unless overridden by the `experiment_cookie` setting.
+const DEFAULT_COOKIE: &str = "edge_visitor";
+
+/// How long the visitor ID cookie lasts, in seconds: one year.
+const COOKIE_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// An experiment definition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Experiment {
+    name: String,
+    prefix: String,
+    variants: Vec<(String, u64)>,
+}
+
+/// The variant of an experiment that a request is bucketed into.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assignment {
+    /// The name of the experiment.
+    pub experiment: String,
+    /// The variant of the experiment.
+    pub variant: String,
+    /// The visitor ID cookie to set, for visitors who did not have one.
+    new_cookie: Option<String>,
+}
+
+impl Assignment {
+    /// Buckets a request into the experiment of its path, and sets its `X-Experiment` header,
+    /// replacing any sent by the client. Returns `None` if no experiment applies.
+    pub fn for_request(req: &mut Request, settings: &Settings) -> Option<Self> {
+        req.remove_header(EXPERIMENT_HEADER);
+        let path = req.get_path();
+        let experiment = settings
+            .get_list("experiments")
+            .iter()
+            .filter_map(|entry| {
+                let experiment = parse(entry);
+                if experiment.is_none() {
+                    log::warn!("ignoring invalid experiment {entry:?}");
+                }
+                experiment
+            })
+            .find(|experiment| path.starts_with(experiment.prefix.as_str()))?;
+
+        let cookie_name = settings
+            .get("experiment_cookie")
+            .unwrap_or_else(|| DEFAULT_COOKIE.to_string());
+        let (visitor, new_cookie) = match cookie(req, &cookie_name) {
+            Some(visitor) => (visitor, None),
+            None => {
+                let visitor = new_visitor_id();
+                let cookie = format!(
+                    "{cookie_name}={visitor}; Path=/; Max-Age={COOKIE_MAX_AGE_SECS}; Secure; \
+                     HttpOnly; SameSite=Lax"
+                );
+                (visitor, Some(cookie))
+            }
+        };
+        let variant = bucket(&experiment, &visitor).to_string();
+        req.set_header(EXPERIMENT_HEADER, format!("{}={variant}", experiment.name));
+        Some(Self {
+            experiment: experiment.name,
+            variant,
+            new_cookie,
+        })
+    }
+
+    /// Returns the rewrites tagging the HTML pages of this variant.
+    pub fn rewrites(&self) -> Rewrites {
+        let (experiment, variant) = (self.experiment.clone(), self.variant.clone());
+        Rewrites::new().on_element("html", move |el| {
+            el.set_attribute("data-experiment", &experiment)?;
+            el.set_attribute("data-variant", &variant)?;
+            Ok(())
+        })
+    }
+
+    /// Gives visitors without an ID the cookie holding the one they were bucketed with.
+    pub fn apply(&self, resp: &mut Response) {
+        if let Some(cookie) = &self.new_cookie {
+            resp.append_header(header::SET_COOKIE, cookie);
+        }
+    }
+}
+
+/// Parses an experiment entry.
+fn parse(entry: &str) -> Option<Experiment> {
+    let mut fields = entry.split_whitespace();
+    let name = fields.next()?.to_string();
+    let prefix = fields.next().filter(|prefix| prefix.starts_with('/'))?;
+    let variants = fields
+        .map(|field| {
+            let (variant, weight) = field.split_once('=')?;
+            Some((variant.to_string(), weight.parse().ok()?))
+        })
+        .collect::<Option<Vec<(String, u64)>>>()?;
+    if variants.iter().map(|(_, weight)| weight).sum::<u64>() == 0 {
+        return None;
+    }
+    Some(Experiment {
+        name,
+        prefix: prefix.to_string(),
+        variants,
+    })
+}
+
+/// Returns the variant of an experiment that a visitor is bucketed into.
+fn bucket<'a>(experiment: &'a Experiment, visitor: &str) -> &'a str {
+    let total: u64 = experiment.variants.iter().map(|(_, weight)| weight).sum();
+    let hash = Sha256::digest(format!("{}:{visitor}", experiment.name));
+    let mut point =
+        u64::from_be_bytes(hash[..8].try_into().expect("the hash is long enough")) % total;
+    for (variant, weight) in &experiment.variants {
+        if point < *weight {
+            return variant;
+        }
+        point -= weight;
+    }
+    unreachable!("the point is below the total weight")
+}
+
+/// Returns the value of a request cookie.
+fn cookie(req: &Request, name: &str) -> Option<String> {
+    req.get_header_all_str(header::COOKIE)
+        .iter()
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, value)| *cookie_name == name && !value.is_empty())
+        .map(|(_, value)| value.to_string())
+}
+
+/// Returns a new random visitor ID.
+fn new_visitor_id() -> String {
+    // Each `RandomState` is seeded from the platform's random number generator.
+    let random = || RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", random(), random())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_parsed() {
+        assert_eq!(
+            parse("hero /products/ control=50 compact=50"),
+            Some(Experiment {
+                name: "hero".to_string(),
+                prefix: "/products/".to_string(),
+                variants: vec![("control".to_string(), 50), ("compact".to_string(), 50)],
+            })
+        );
+        assert!(parse("hero products/ control=50").is_none());
+        assert!(parse("hero /products/ control").is_none());
+        assert!(parse("hero /products/ control=0").is_none());
+        assert!(parse("hero /products/").is_none());
+    }
+
+    #[test]
+    fn visitors_are_bucketed_deterministically_by_weight() {
+        let experiment = parse("hero / control=90 compact=10").unwrap();
+        assert_eq!(
+            bucket(&experiment, "visitor-1"),
+            bucket(&experiment, "visitor-1")
+        );
+        let compact = (0..1000)
+            .filter(|i| bucket(&experiment, &format!("visitor-{i}")) == "compact")
+            .count();
+        assert!(
+            (50..150).contains(&compact),
+            "{compact} visitors in compact"
+        );
+
+        let only = parse("hero / control=0 compact=1").unwrap();
+        assert_eq!(bucket(&only, "visitor-1"), "compact");
+    }
+}
@@ -0,0 +1,86 @@
+//! Failover to secondary backends when the origin fails.
+//!
+//! The `failover_backends` setting lists secondary backends, in the order they are tried. When
+//! the backend of a `GET` or `HEAD` request cannot be reached or answers with a 5xx, the request
+//! is sent again to the next secondary backend, until one answers without failing or none is
+//! left, in which case the last failure is delivered.
+//!
+//! Every attempt goes through the readthrough cache with the cache key and after-send callback of
+//! the original request, so a good response from a secondary backend is cached where the next
+//! client will look for it. Failed responses are kept out of the cache while failover is on, so
+//! that the next attempt does not find them there. Each attempt gets its own before-send hook,
+//! which knows the backend it is sent to, so that credentials and signatures match that backend.
+//!
+//! Responses carry an `X-Origin-Backend` header naming the backend whose attempt answered.
+
+use crate::config::Settings;
+use fastly::http::request::{SendError, SendErrorCause};
+use fastly::http::{HeaderName, Method, StatusCode};
+use fastly::{Request, Response};
+use std::sync::Arc;
+
+/// The response header naming the backend whose attempt answered.
+pub const ORIGIN_HEADER: HeaderName = HeaderName::from_static("x-origin-backend");
+
+/// A before-send hook, called with the request about to be sent and the name of its backend.
+pub type BeforeSend = Arc<dyn Fn(&mut Request, &str) -> Result<(), SendErrorCause> + Send + Sync>;
+
+/// The secondary backends of a request.
+#[derive(Clone)]
+pub struct Failover {
+    backends: Vec<String>,
+}
+
+impl Failover {
+    /// Returns the failover of a request, or `None` if it is not a `GET` or `HEAD` request, or no
+    /// secondary backend is set.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        if !matches!(*req.get_method(), Method::GET | Method::HEAD) {
+            return None;
+        }
+        let backends = settings.get_list("failover_backends");
+        (!backends.is_empty()).then_some(Self { backends })
+    }
+
+    /// Returns the names of the secondary backends, in the order they are tried.
+    pub fn backends(&self) -> &[String] {
+        &self.backends
+    }
+
+    /// Sends `template` to the secondary backends in turn while the previous attempt failed,
+    /// starting from the result of the attempt on `primary`.
+    ///
+    /// `template` must be a copy of the original request, carrying its cache key and callbacks.
+    /// Returns the result of the last attempt, along with the backend it was sent to.
+    pub fn retry(
+        &self,
+        template: &Request,
+        mut result: Result<Response, SendError>,
+        primary: &str,
+        before_send: &BeforeSend,
+    ) -> (Result<Response, SendError>, String) {
+        let mut backend = primary.to_string();
+        for secondary in &self.backends {
+            match &result {
+                Ok(resp) if !is_failure(resp.get_status()) => break,
+                Ok(resp) => log::warn!(
+                    "{backend} answered with {}, failing over to {secondary}",
+                    resp.get_status()
+                ),
+                Err(e) => log::warn!("{backend} failed: {e}, failing over to {secondary}"),
+            }
+            let mut attempt = template.clone_without_body();
+            let hook = before_send.clone();
+            let name = secondary.clone();
+            attempt.set_before_send(move |req| hook(req, &name));
+            result = attempt.send(secondary.as_str());
+            backend = secondary.clone();
+        }
+        (result, backend)
+    }
+}
+
+/// Returns whether a response status is a failure that another backend may not repeat.
+pub fn is_failure(status: StatusCode) -> bool {
+    status.is_server_error()
+}
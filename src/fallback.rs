@@ -0,0 +1,117 @@
+//! Fallback copies of pages, uploaded ahead of time to a KV Store.
+//!
+//! With the `fallback_copies` setting on, a `GET` or `HEAD` request that the origin cannot answer,
+//! and that has no stale copy or snapshot to serve instead, is answered with an object from the
+//! `fallback` KV Store, such as a static export of the page or a maintenance notice. Objects are
+//! keyed by path, and the most specific one wins: a request for `/shop/shoes/red` is answered with
+//! the object keyed `/shop/shoes/red`, or else `/shop/shoes/*`, `/shop/*` and finally `/*`.
+//!
+//! The metadata of an object may be a JSON object with a `content_type` member, which becomes the
+//! `Content-Type` of the response. Fallback copies carry an `X-Fallback-Copy` header holding the
+//! key of the object served, and are never stored by the edge or by clients.
+
+use crate::config::Settings;
+use fastly::http::{header, Method};
+use fastly::kv_store::KVStore;
+use fastly::{Body, Request, Response};
+use serde_json::Value;
+
+/// The name of the KV Store holding the fallback copies.
+const STORE_NAME: &str = "fallback";
+
+/// The response header carrying the key of a served fallback copy.
+const FALLBACK_HEADER: &str = "x-fallback-copy";
+
+/// The fallback copies of one page.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FallbackCopy {
+    keys: Vec<String>,
+}
+
+impl FallbackCopy {
+    /// Returns the fallback copies of a request, or `None` if it cannot get one.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        if !settings.get_bool("fallback_copies", false)
+            || !matches!(*req.get_method(), Method::GET | Method::HEAD)
+        {
+            return None;
+        }
+        Some(Self {
+            keys: candidate_keys(req.get_path()),
+        })
+    }
+
+    /// Returns the most specific fallback copy of the page, if one was uploaded.
+    pub fn serve(&self) -> Option<Response> {
+        let store = match KVStore::open(STORE_NAME) {
+            Ok(Some(store)) => store,
+            Ok(None) => {
+                log::warn!("KV Store {STORE_NAME} is not linked; no fallback copy");
+                return None;
+            }
+            Err(e) => {
+                log::warn!("cannot open KV Store {STORE_NAME}: {e}");
+                return None;
+            }
+        };
+        self.keys.iter().find_map(|key| {
+            let mut found = store.lookup(key).ok()?;
+            let metadata = found.metadata();
+            Some(response(key, found.take_body(), metadata.as_deref()))
+        })
+    }
+}
+
+/// Returns the keys of the fallback copies of a path, from the most specific to the least.
+fn candidate_keys(path: &str) -> Vec<String> {
+    let mut keys = vec![path.to_string()];
+    let mut dir = path;
+    while let Some((parent, _)) = dir.rsplit_once('/') {
+        keys.push(format!("{parent}/*"));
+        dir = parent;
+    }
+    keys.dedup();
+    keys
+}
+
+/// Returns the response serving a fallback copy.
+fn response(key: &str, body: Body, metadata: Option<&[u8]>) -> Response {
+    let mut resp = Response::from_body(body)
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_header(FALLBACK_HEADER, key);
+    if let Some(content_type) = metadata.and_then(content_type) {
+        resp.set_header(header::CONTENT_TYPE, content_type);
+    }
+    resp
+}
+
+/// Returns the content type recorded in the metadata of a fallback copy.
+fn content_type(metadata: &[u8]) -> Option<String> {
+    let metadata: Value = serde_json::from_slice(metadata).ok()?;
+    Some(metadata["content_type"].as_str()?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specific_copies_come_first() {
+        assert_eq!(
+            candidate_keys("/shop/shoes/red"),
+            ["/shop/shoes/red", "/shop/shoes/*", "/shop/*", "/*"]
+        );
+        assert_eq!(candidate_keys("/shop/"), ["/shop/", "/shop/*", "/*"]);
+        assert_eq!(candidate_keys("/"), ["/", "/*"]);
+    }
+
+    #[test]
+    fn content_types_are_read_from_metadata() {
+        assert_eq!(
+            content_type(br#"{"content_type": "text/html; charset=utf-8"}"#).as_deref(),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(content_type(br#"{"content_type": 1}"#), None);
+        assert_eq!(content_type(b"not json"), None);
+    }
+}
@@ -0,0 +1,151 @@
+//! Feature flags, turning individual behaviors off at runtime.
+//!
+//! The settings of a behavior configure it, and the `feature_flags` setting can switch it off
+//! without touching its configuration, so that a misbehaving transform is rolled back in the
+//! time it takes the Config Store to propagate, and rolled forward again as quickly. The setting
+//! lists comma-separated `<feature>=on|off` entries, such as `json-to-html=off,edge-compression=off`.
+//! Every feature is on unless listed as off:
+//!
+//! * `json-to-html`, the JSON to HTML example transform, whether run as objects are cached or as
+//!   they are delivered;
+//! * `html-rewrites`, the streaming rewrites of HTML pages;
+//! * `edge-compression`, the compression of text bodies at the edge;
+//! * `security-headers`, the security headers added to delivered responses.
+//!
+//! Flags are read for every request. Turning off a feature that shapes the stored body also moves
+//! the request to a cache namespace of its own, so that objects transformed before the flag
+//! changed are not served in the meantime, and are found again when it is turned back on.
+
+use crate::config::Settings;
+
+/// A behavior that can be turned off with a feature flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    JsonToHtml,
+    HtmlRewrites,
+    EdgeCompression,
+    SecurityHeaders,
+}
+
+impl Feature {
+    const ALL: [Feature; 4] = [
+        Feature::JsonToHtml,
+        Feature::HtmlRewrites,
+        Feature::EdgeCompression,
+        Feature::SecurityHeaders,
+    ];
+
+    /// Returns the name of the feature in the `feature_flags` setting.
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::JsonToHtml => "json-to-html",
+            Feature::HtmlRewrites => "html-rewrites",
+            Feature::EdgeCompression => "edge-compression",
+            Feature::SecurityHeaders => "security-headers",
+        }
+    }
+
+    /// Returns whether the feature changes the body stored in the cache.
+    fn shapes_stored_body(self) -> bool {
+        !matches!(self, Feature::SecurityHeaders)
+    }
+}
+
+/// The features turned off for a request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Features {
+    disabled: Vec<Feature>,
+}
+
+impl Features {
+    /// Reads the feature flags from the `feature_flags` setting.
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self::parse(&settings.get_list("feature_flags"))
+    }
+
+    /// Returns whether a feature is on.
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        !self.disabled.contains(&feature)
+    }
+
+    /// Returns the cache namespace of a request, within an optional namespace, given the features
+    /// turned off for it. Returns the enclosing namespace if no feature shaping the stored body is
+    /// off.
+    pub fn cache_namespace(&self, within: Option<&str>) -> Option<String> {
+        let disabled: Vec<&str> = Feature::ALL
+            .into_iter()
+            .filter(|feature| feature.shapes_stored_body() && !self.is_enabled(*feature))
+            .map(Feature::name)
+            .collect();
+        if disabled.is_empty() {
+            return within.map(str::to_string);
+        }
+        let namespace = format!("features-off={}", disabled.join(","));
+        Some(match within {
+            Some(within) => format!("{within} {namespace}"),
+            None => namespace,
+        })
+    }
+
+    fn parse(entries: &[String]) -> Self {
+        let mut disabled = Vec::new();
+        for entry in entries {
+            let parsed = entry.split_once('=').and_then(|(name, state)| {
+                let feature = Feature::ALL
+                    .into_iter()
+                    .find(|feature| feature.name().eq_ignore_ascii_case(name.trim()))?;
+                match state.trim().to_ascii_lowercase().as_str() {
+                    "on" => Some((feature, true)),
+                    "off" => Some((feature, false)),
+                    _ => None,
+                }
+            });
+            match parsed {
+                Some((feature, enabled)) => {
+                    disabled.retain(|disabled| *disabled != feature);
+                    if !enabled {
+                        disabled.push(feature);
+                    }
+                }
+                None => log::warn!("ignoring invalid feature flag {entry:?}"),
+            }
+        }
+        Self { disabled }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(flags: &str) -> Features {
+        Features::parse(&flags.split(',').map(str::to_string).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn features_are_on_unless_turned_off() {
+        let features =
+            features("json-to-html=off,Edge-Compression=OFF,edge-compression=on,esi=off");
+        assert!(!features.is_enabled(Feature::JsonToHtml));
+        assert!(features.is_enabled(Feature::EdgeCompression));
+        assert!(features.is_enabled(Feature::HtmlRewrites));
+        assert!(Features::default().is_enabled(Feature::SecurityHeaders));
+    }
+
+    #[test]
+    fn features_shaping_stored_bodies_have_their_own_namespace() {
+        assert_eq!(
+            features("html-rewrites=off,json-to-html=off").cache_namespace(None),
+            Some("features-off=json-to-html,html-rewrites".to_string())
+        );
+        assert_eq!(
+            features("json-to-html=off").cache_namespace(Some("tenant:a")),
+            Some("tenant:a features-off=json-to-html".to_string())
+        );
+        assert_eq!(
+            features("security-headers=off").cache_namespace(Some("tenant:a")),
+            Some("tenant:a".to_string())
+        );
+        assert_eq!(Features::default().cache_namespace(None), None);
+    }
+}
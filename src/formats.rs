@@ -0,0 +1,304 @@
+//! Content negotiation of structured data formats for JSON API routes.
+//!
+//! The origin is always asked for JSON, and only the JSON representation is cached. The format
+//! requested in the client's `Accept` header is produced from it at delivery time, so supporting
+//! more formats does not multiply the number of cached objects.
+//!
+//! Gzip and Brotli bodies, such as those compressed at the edge, are decompressed before they are
+//! converted, and the converted body is compressed again with the same coding. Bodies with other
+//! codings are delivered as JSON.
+
+use crate::accept;
+use crate::compression::{self, Coding};
+use crate::metrics::TransformMetrics;
+use crate::transform;
+use fastly::http::header;
+use fastly::{mime, Request, Response};
+use serde_json::{Map, Value};
+use std::time::Instant;
+
+/// A representation of a JSON API response that can be delivered to clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Csv,
+}
+
+impl Format {
+    /// Chooses the format the client prefers, based on the `Accept` header of the request.
+    ///
+    /// JSON is used when the client has no preference among the supported formats.
+    pub fn negotiate(req: &Request) -> Self {
+        let Some(accept) = req.get_header_str(header::ACCEPT) else {
+            return Format::Json;
+        };
+
+        let mut best = (Format::Json, 0.0);
+        for (media_type, quality) in accept::weighted(accept) {
+            let format = match media_type.to_ascii_lowercase().as_str() {
+                "application/json" | "application/*" | "*/*" => Format::Json,
+                "application/yaml" | "application/x-yaml" | "text/yaml" => Format::Yaml,
+                "text/csv" => Format::Csv,
+                _ => continue,
+            };
+            if quality > best.1 {
+                best = (format, quality);
+            }
+        }
+        best.0
+    }
+
+    /// The `Content-Type` of a response in this format.
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Yaml => "application/yaml; charset=utf-8",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+/// Prepares a request so that the origin, and therefore the cache, only ever sees a request for
+/// JSON, whatever the client asked for.
+pub fn request_canonical_json(req: &mut Request) {
+    req.set_header(header::ACCEPT, mime::APPLICATION_JSON.as_ref());
+}
+
+/// Converts a successful JSON response into the given format.
+///
+/// Responses that are not successful JSON responses, whose body is encoded with a coding other
+/// than gzip or Brotli, or whose body is not valid JSON, are returned unchanged.
+pub fn convert(
+    mut resp: Response,
+    format: Format,
+    transform_metrics: Option<TransformMetrics>,
+) -> Response {
+    resp.append_header(header::VARY, "Accept");
+    if !resp.get_status().is_success()
+        || !transform::is_json(resp.get_header_str(header::CONTENT_TYPE))
+    {
+        return resp;
+    }
+    let (stage, render): (&str, fn(&Value) -> String) = match format {
+        Format::Json => return resp,
+        Format::Yaml => ("json-to-yaml", to_yaml),
+        Format::Csv => ("json-to-csv", to_csv),
+    };
+
+    let content_encoding = resp.get_header_str(header::CONTENT_ENCODING);
+    if !transform::is_transformable_encoding(content_encoding) {
+        log::warn!(
+            "cannot convert a JSON response encoded with {}; delivering JSON",
+            content_encoding.unwrap_or_default()
+        );
+        return resp;
+    }
+    let coding = Coding::of(content_encoding);
+
+    let started = Instant::now();
+    let body = resp.take_body_bytes();
+    let value: Value = match compression::decode(coding, body.clone())
+        .map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_slice(&json).map_err(|e| e.to_string()))
+    {
+        Ok(value) => value,
+        Err(e) => {
+            log::warn!("cannot convert invalid JSON response: {e}; delivering JSON");
+            resp.set_body(body);
+            return resp;
+        }
+    };
+
+    let converted = match compression::encode(coding, render(&value).into_bytes()) {
+        Ok(converted) => converted,
+        Err(e) => {
+            log::warn!("cannot compress the converted response: {e}; delivering JSON");
+            resp.set_body(body);
+            return resp;
+        }
+    };
+    if let Some(metrics) = transform_metrics {
+        metrics.record(
+            stage,
+            resp.get_header_str(header::CONTENT_TYPE),
+            body.len(),
+            converted.len(),
+            started.elapsed(),
+        );
+    }
+    resp.set_body(converted);
+    resp.remove_header(header::CONTENT_LENGTH);
+    resp.set_header(header::CONTENT_TYPE, format.content_type());
+    resp
+}
+
+/// Renders a JSON value as a YAML document.
+fn to_yaml(value: &Value) -> String {
+    let mut out = String::new();
+    match value {
+        Value::Object(map) if !map.is_empty() => write_yaml_map(map, 0, &mut out),
+        Value::Array(items) if !items.is_empty() => write_yaml_seq(items, 0, &mut out),
+        scalar => {
+            out.push_str(&yaml_scalar(scalar));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn write_yaml_map(map: &Map<String, Value>, indent: usize, out: &mut String) {
+    for (key, value) in map {
+        out.push_str(&" ".repeat(indent));
+        out.push_str(&Value::from(key.as_str()).to_string());
+        out.push(':');
+        write_yaml_value(value, indent, out);
+    }
+}
+
+fn write_yaml_seq(items: &[Value], indent: usize, out: &mut String) {
+    for item in items {
+        out.push_str(&" ".repeat(indent));
+        out.push('-');
+        write_yaml_value(item, indent, out);
+    }
+}
+
+/// Writes the value that follows a mapping key or sequence dash, nesting collections on the
+/// following lines.
+fn write_yaml_value(value: &Value, indent: usize, out: &mut String) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            out.push('\n');
+            write_yaml_map(map, indent + 2, out);
+        }
+        Value::Array(items) if !items.is_empty() => {
+            out.push('\n');
+            write_yaml_seq(items, indent + 2, out);
+        }
+        scalar => {
+            out.push(' ');
+            out.push_str(&yaml_scalar(scalar));
+            out.push('\n');
+        }
+    }
+}
+
+/// Renders a scalar or empty collection. JSON string syntax is valid YAML double-quoted syntax,
+/// so strings never need YAML-specific escaping.
+fn yaml_scalar(value: &Value) -> String {
+    match value {
+        Value::Object(_) => "{}".to_string(),
+        Value::Array(_) => "[]".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Renders a JSON value as CSV.
+///
+/// An array of objects becomes one row per object, with a column for every key seen. A single
+/// object becomes a single row, and anything else a single `value` column. Nested values are
+/// written as JSON text.
+fn to_csv(value: &Value) -> String {
+    let rows: Vec<Map<String, Value>> = match value {
+        Value::Array(items) => items.iter().map(csv_row).collect(),
+        other => vec![csv_row(other)],
+    };
+
+    let mut columns: Vec<&str> = Vec::new();
+    for row in &rows {
+        for key in row.keys() {
+            if !columns.contains(&key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    write_csv_line(columns.iter().map(|column| column.to_string()), &mut out);
+    for row in &rows {
+        write_csv_line(
+            columns.iter().map(|column| match row.get(*column) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => s.clone(),
+                Some(other) => other.to_string(),
+            }),
+            &mut out,
+        );
+    }
+    out
+}
+
+fn csv_row(value: &Value) -> Map<String, Value> {
+    match value {
+        Value::Object(map) => map.clone(),
+        other => Map::from_iter([("value".to_string(), other.clone())]),
+    }
+}
+
+fn write_csv_line(fields: impl Iterator<Item = String>, out: &mut String) {
+    let line: Vec<String> = fields
+        .map(|field| {
+            if field.contains([',', '"', '\r', '\n']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field
+            }
+        })
+        .collect();
+    out.push_str(&line.join(","));
+    out.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn yaml_nests_collections_and_quotes_strings() {
+        let value = json!({
+            "name": "Ada: \"Countess\"",
+            "tags": ["math", {"lang": null}],
+            "empty": [],
+            "meta": {"born": 1815, "notes": {}}
+        });
+        assert_eq!(
+            to_yaml(&value),
+            concat!(
+                "\"empty\": []\n",
+                "\"meta\":\n",
+                "  \"born\": 1815\n",
+                "  \"notes\": {}\n",
+                "\"name\": \"Ada: \\\"Countess\\\"\"\n",
+                "\"tags\":\n",
+                "  - \"math\"\n",
+                "  -\n",
+                "    \"lang\": null\n",
+            )
+        );
+        assert_eq!(to_yaml(&json!(true)), "true\n");
+    }
+
+    #[test]
+    fn csv_has_a_column_for_every_key_and_escapes_fields() {
+        let value = json!([
+            {"id": 1, "name": "Lovelace, Ada"},
+            {"id": 2, "quote": "say \"hi\"", "tags": ["a", "b"]},
+            {"id": 3, "name": null}
+        ]);
+        assert_eq!(
+            to_csv(&value),
+            concat!(
+                "id,name,quote,tags\r\n",
+                "1,\"Lovelace, Ada\",,\r\n",
+                "2,,\"say \"\"hi\"\"\",\"[\"\"a\"\",\"\"b\"\"]\"\r\n",
+                "3,,,\r\n",
+            )
+        );
+        assert_eq!(
+            to_csv(&json!("line\nbreak")),
+            "value\r\n\"line\nbreak\"\r\n"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Personalized fragments merged into publicly cached HTML shells.
+//!
+//! Pages under the path prefixes in the `shell_path_prefixes` setting are shells: the origin is
+//! asked for them without the client's credentials, so that one copy is cached for everyone. At
+//! the same time, a small JSON fragment is fetched for the client from the `fragment_path` of the
+//! origin, with the client's cookies and `Authorization` header, bypassing the cache.
+//!
+//! When the shell is delivered, every slot marker of the form `<!--edge-slot:name-->` is replaced
+//! with the HTML-escaped value of `name` in the fragment, where dotted names such as `user.name`
+//! look up nested members. Slots without a value are left empty. The shell is streamed to the
+//! client as it is merged, and the merged page is never cached downstream.
+//!
+//! Only uncompressed HTML shells are merged. Other responses are delivered as they are, and their
+//! slot markers stay in place as HTML comments.
+
+use crate::config::Settings;
+use fastly::http::request::PendingRequest;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{mime, Error, Request, Response};
+use serde_json::Value;
+use std::io::{BufRead, Write};
+
+/// The origin path of the fragment, unless overridden by the `fragment_path` setting.
+const DEFAULT_FRAGMENT_PATH: &str = "/fragment";
+
+/// The opening of a slot marker, followed by the slot name.
+const SLOT_START: &[u8] = b"<!--edge-slot:";
+
+/// The end of a slot marker.
+const SLOT_END: &[u8] = b"-->";
+
+/// The client request headers that authenticate the fragment request, and that are removed from
+/// the shell request.
+const CREDENTIAL_HEADERS: [header::HeaderName; 2] = [header::COOKIE, header::AUTHORIZATION];
+
+/// The fragment of a shell page, being fetched.
+pub struct Fragment {
+    pending: PendingRequest,
+}
+
+impl Fragment {
+    /// Starts fetching the fragment for a shell page request, and removes the client's credentials
+    /// from the shell request. Returns `None` if the request is not for a shell page.
+    pub fn fetch(req: &mut Request, backend: &str, settings: &Settings) -> Option<Self> {
+        let path = req.get_path();
+        if !settings
+            .get_list("shell_path_prefixes")
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+        // HEAD requests get the headers of the shell alone, which need no fragment.
+        if req.get_method() == Method::HEAD {
+            for name in CREDENTIAL_HEADERS {
+                req.remove_header(&name);
+            }
+            return None;
+        }
+
+        let mut url = req.get_url().clone();
+        url.set_path(
+            &settings
+                .get("fragment_path")
+                .unwrap_or_else(|| DEFAULT_FRAGMENT_PATH.to_string()),
+        );
+        url.set_query(None);
+        let mut fragment_req = Request::get(url).with_header(header::ACCEPT, "application/json");
+        for name in CREDENTIAL_HEADERS {
+            for value in req.get_header_all(&name) {
+                fragment_req.append_header(&name, value.clone());
+            }
+            req.remove_header(&name);
+        }
+        fragment_req.set_pass(true);
+
+        match fragment_req.send_async(backend) {
+            Ok(pending) => Some(Self { pending }),
+            Err(e) => {
+                log::warn!("cannot fetch fragment: {e}");
+                None
+            }
+        }
+    }
+
+    /// Sends a shell page to the client, with the values of the fragment in its slots.
+    ///
+    /// Responses other than successful uncompressed HTML are sent as they are.
+    pub fn merge_and_send(self, mut shell: Response) -> Result<(), Error> {
+        if shell.get_status() != StatusCode::OK || !is_uncompressed_html(&shell) {
+            shell.send_to_client();
+            return Ok(());
+        }
+
+        let values = self.values();
+        let mut body = shell.take_body();
+        shell.remove_header(header::CONTENT_LENGTH);
+        shell.remove_header(header::ETAG);
+        shell.remove_header(header::LAST_MODIFIED);
+        shell.set_header(header::CACHE_CONTROL, "private, no-store");
+        let mut out = shell.stream_to_client();
+
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if body.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            out.write_all(&fill_slots(&line, &values))?;
+        }
+        out.finish()?;
+        Ok(())
+    }
+
+    /// Waits for the fragment, and returns its values. A fragment that cannot be fetched or parsed
+    /// has no values, leaving every slot empty.
+    fn values(self) -> Value {
+        let mut resp = match self.pending.wait() {
+            Ok(resp) => resp,
+            Err(e) => {
+                log::warn!("cannot fetch fragment: {e}");
+                return Value::Null;
+            }
+        };
+        if !resp.get_status().is_success() {
+            log::warn!("fragment request failed with {}", resp.get_status());
+            return Value::Null;
+        }
+        serde_json::from_slice(&resp.take_body_bytes()).unwrap_or_else(|e| {
+            log::warn!("invalid fragment: {e}");
+            Value::Null
+        })
+    }
+}
+
+/// Replaces the slot markers in a chunk of the shell with their values.
+fn fill_slots(chunk: &[u8], values: &Value) -> Vec<u8> {
+    let mut filled = Vec::with_capacity(chunk.len());
+    let mut rest = chunk;
+    while let Some(start) = find(rest, SLOT_START) {
+        let name_start = start + SLOT_START.len();
+        let Some(name_len) = find(&rest[name_start..], SLOT_END) else {
+            break;
+        };
+        filled.extend_from_slice(&rest[..start]);
+        let name = String::from_utf8_lossy(&rest[name_start..name_start + name_len]);
+        if let Some(value) = slot_value(values, name.trim()) {
+            filled.extend_from_slice(escape_html(&value).as_bytes());
+        }
+        rest = &rest[name_start + name_len + SLOT_END.len()..];
+    }
+    filled.extend_from_slice(rest);
+    filled
+}
+
+/// Returns the text of a slot, looking up dotted names in nested objects.
+fn slot_value(values: &Value, name: &str) -> Option<String> {
+    let value = name
+        .split('.')
+        .try_fold(values, |value, member| value.get(member))?;
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(flag) => Some(flag.to_string()),
+        _ => None,
+    }
+}
+
+/// Escapes text for HTML. Non-ASCII characters are written as character references, so that the
+/// text is correct whatever the charset of the shell.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c if c.is_ascii() => escaped.push(c),
+            c => escaped.push_str(&format!("&#{};", u32::from(c))),
+        }
+    }
+    escaped
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn is_uncompressed_html(resp: &Response) -> bool {
+    resp.get_content_type()
+        .is_some_and(|mime| mime.essence_str() == mime::TEXT_HTML.essence_str())
+        && !resp.contains_header(header::CONTENT_ENCODING)
+}
@@ -0,0 +1,308 @@
+//! Freshness lifetimes from the `Expires` and `Date` headers.
+//!
+//! Origins in the wild send HTTP dates in many shapes besides the standard IMF-fixdate: the
+//! obsolete RFC 850 and asctime formats, `UTC` or numeric offsets instead of `GMT`, missing
+//! weekdays or seconds, and values such as `0` or `-1` that mean "already expired". The parser
+//! here accepts all of these. The lifetime is measured against the origin's own `Date` header
+//! rather than the edge clock, so that a skewed origin clock does not distort it, and a lifetime
+//! that comes out negative is clamped to zero.
+//!
+//! Origins can also opt responses into being served stale while they are revalidated, or when a
+//! later fetch fails, with `stale-while-revalidate` and `stale-if-error` directives in
+//! `Surrogate-Control` or `Cache-Control`. The `Surrogate-Control` header is meant for the edge:
+//! its directives take precedence, and it is removed before responses are delivered.
+
+use fastly::http::{header, CandidateResponse};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The longest lifetime taken from an `Expires` header. RFC 9111 asks origins not to send dates
+/// more than a year ahead, so anything beyond that is treated as a mistake.
+const MAX_EXPIRES_TTL: Duration = Duration::from_secs(365 * 86_400);
+
+/// Returns the TTL implied by the `Expires` header of a response, or `None` if the response has
+/// no `Expires` header or its `Cache-Control` header takes precedence.
+pub fn expires_ttl(resp: &CandidateResponse) -> Option<Duration> {
+    ttl_from_expires(
+        resp.get_header_str(header::CACHE_CONTROL),
+        resp.get_header_str(header::EXPIRES),
+        resp.get_header_str(header::DATE),
+        SystemTime::now(),
+    )
+}
+
+/// The header carrying caching directives meant for the edge only.
+pub const SURROGATE_CONTROL: &str = "surrogate-control";
+
+/// Returns the `stale-while-revalidate` window a response asks for, or `None` if it asks for
+/// none.
+pub fn stale_while_revalidate(resp: &CandidateResponse) -> Option<Duration> {
+    directive_secs(
+        resp.get_header_str(SURROGATE_CONTROL),
+        resp.get_header_str(header::CACHE_CONTROL),
+        "stale-while-revalidate",
+    )
+}
+
+/// Returns the `stale-if-error` window a response asks for, or `None` if it asks for none.
+pub fn stale_if_error(resp: &CandidateResponse) -> Option<Duration> {
+    directive_secs(
+        resp.get_header_str(SURROGATE_CONTROL),
+        resp.get_header_str(header::CACHE_CONTROL),
+        "stale-if-error",
+    )
+}
+
+/// Returns the number of seconds given to a directive, taking it from `Surrogate-Control` if it
+/// is there and from `Cache-Control` otherwise. Directives with invalid values are ignored.
+fn directive_secs(
+    surrogate_control: Option<&str>,
+    cache_control: Option<&str>,
+    name: &str,
+) -> Option<Duration> {
+    let secs_in = |value: &str| {
+        value.split(',').find_map(|directive| {
+            let (directive_name, secs) = directive.split_once('=')?;
+            if !directive_name.trim().eq_ignore_ascii_case(name) {
+                return None;
+            }
+            secs.trim().trim_matches('"').parse::<u64>().ok()
+        })
+    };
+    surrogate_control
+        .and_then(secs_in)
+        .or_else(|| cache_control.and_then(secs_in))
+        .map(Duration::from_secs)
+}
+
+fn ttl_from_expires(
+    cache_control: Option<&str>,
+    expires: Option<&str>,
+    date: Option<&str>,
+    now: SystemTime,
+) -> Option<Duration> {
+    let expires = expires?;
+    let has_max_age = cache_control.is_some_and(|value| {
+        value.split(',').any(|directive| {
+            let name = directive.split('=').next().unwrap_or_default().trim();
+            name.eq_ignore_ascii_case("max-age") || name.eq_ignore_ascii_case("s-maxage")
+        })
+    });
+    if has_max_age {
+        return None;
+    }
+
+    // An `Expires` value that is not a valid date means the response is already stale.
+    let Some(expires) = parse_http_date(expires) else {
+        return Some(Duration::ZERO);
+    };
+    let date = date.and_then(parse_http_date).unwrap_or(now);
+    let ttl = expires.duration_since(date).unwrap_or(Duration::ZERO);
+    Some(ttl.min(MAX_EXPIRES_TTL))
+}
+
+/// Parses an HTTP date, tolerating the common deviations from the standard format.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+    let mut time = None;
+    let mut offset_secs = 0i64;
+
+    let tokens = value
+        .split([' ', ',', '\t'])
+        .filter(|token| !token.is_empty())
+        // RFC 850 dates join the day, month and year with dashes, as in `06-Nov-94`.
+        .flat_map(|token| {
+            let dashed_date = token.contains('-') && token.chars().any(|c| c.is_ascii_alphabetic());
+            token
+                .split(move |c| dashed_date && c == '-')
+                .filter(|part| !part.is_empty())
+        });
+
+    for token in tokens {
+        if token.contains(':') {
+            time = Some(parse_time(token)?);
+        } else if let Some(sign) = token.strip_prefix(['+', '-']).map(|_| &token[..1]) {
+            let digits = &token[1..];
+            if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let hours: i64 = digits[..2].parse().ok()?;
+            let minutes: i64 = digits[2..].parse().ok()?;
+            let offset = hours * 3600 + minutes * 60;
+            offset_secs = if sign == "+" { offset } else { -offset };
+        } else if token.bytes().all(|b| b.is_ascii_digit()) {
+            let number: i64 = token.parse().ok()?;
+            if token.len() <= 2 && day.is_none() {
+                day = Some(number);
+            } else if year.is_none() {
+                year = Some(match (token.len(), number) {
+                    (2, n) if n < 70 => 2000 + n,
+                    (2, n) => 1900 + n,
+                    (4, n) => n,
+                    _ => return None,
+                });
+            } else {
+                return None;
+            }
+        } else if let Some(index) = month_index(token) {
+            month = Some(index);
+        } else if !is_weekday(token) && !is_utc_zone(token) {
+            return None;
+        }
+    }
+
+    let (day, month, year, (hour, minute, second)) = (day?, month?, year?, time?);
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second
+        - offset_secs;
+    u64::try_from(secs)
+        .ok()
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Parses `hh:mm:ss`, or `hh:mm` without seconds.
+fn parse_time(token: &str) -> Option<(i64, i64, i64)> {
+    let mut parts = token.split(':').map(|part| part.parse::<i64>().ok());
+    let hour = parts.next()??;
+    let minute = parts.next()??;
+    let second = parts.next().unwrap_or(Some(0))?;
+    parts.next().is_none().then_some((hour, minute, second))
+}
+
+fn month_index(token: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let prefix = token.get(..3)?.to_ascii_lowercase();
+    MONTHS
+        .iter()
+        .position(|month| *month == prefix)
+        .map(|index| index as i64 + 1)
+}
+
+fn is_weekday(token: &str) -> bool {
+    const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+    token
+        .get(..3)
+        .is_some_and(|prefix| WEEKDAYS.contains(&prefix.to_ascii_lowercase().as_str()))
+}
+
+fn is_utc_zone(token: &str) -> bool {
+    ["GMT", "UTC", "UT", "Z"]
+        .iter()
+        .any(|zone| zone.eq_ignore_ascii_case(token))
+}
+
+/// Returns the number of days between 1970-01-01 and the given date in the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `Sun, 06 Nov 1994 08:49:37 GMT`, the example date of RFC 9110.
+    const EXAMPLE: u64 = 784_111_777;
+
+    fn at(secs: u64) -> Option<SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn dates_are_parsed_in_every_format() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            at(EXAMPLE)
+        );
+        assert_eq!(
+            parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            at(EXAMPLE)
+        );
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), at(EXAMPLE));
+        assert_eq!(parse_http_date("06 Nov 1994 08:49:37 UTC"), at(EXAMPLE));
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 09:49:37 +0100"),
+            at(EXAMPLE)
+        );
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49 GMT"),
+            at(EXAMPLE - 37)
+        );
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        for value in [
+            "",
+            "0",
+            "-1",
+            "tomorrow",
+            "Sun, 06 Nov 1994",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49:37 +01",
+            "Sun, 06 Nov 19945 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(value), None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn expires_is_measured_against_the_origin_date() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let now = UNIX_EPOCH;
+        let ttl =
+            |cache_control, expires| ttl_from_expires(cache_control, expires, Some(date), now);
+
+        assert_eq!(
+            ttl(None, Some("Sun, 06 Nov 1994 09:49:37 GMT")),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(
+            ttl(None, Some("Sat, 05 Nov 1994 08:49:37 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            ttl(None, Some("Sun, 06 Nov 2094 08:49:37 GMT")),
+            Some(MAX_EXPIRES_TTL)
+        );
+        assert_eq!(ttl(None, Some("0")), Some(Duration::ZERO));
+        assert_eq!(ttl(Some("public, s-maxage=60"), Some("0")), None);
+        assert_eq!(ttl(None, None), None);
+    }
+
+    #[test]
+    fn surrogate_control_directives_take_precedence() {
+        assert_eq!(
+            directive_secs(
+                Some("max-age=60, stale-if-error=\"600\""),
+                Some("stale-if-error=30"),
+                "stale-if-error"
+            ),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            directive_secs(
+                None,
+                Some("STALE-WHILE-REVALIDATE=30"),
+                "stale-while-revalidate"
+            ),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            directive_secs(None, Some("stale-if-error=soon"), "stale-if-error"),
+            None
+        );
+    }
+}
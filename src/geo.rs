@@ -0,0 +1,71 @@
+//! Region variants of cached responses, from the client's location.
+//!
+//! With the `geo_variants` setting on, every request is classified into a region from the
+//! geolocation of the client address, and the region is sent to the origin in an `X-Region`
+//! header that the cache varies on. Clients in the same region share one cached variant, however
+//! many cities or addresses they come from.
+//!
+//! Regions are lowercase continent codes, such as `eu` or `na`, unless the client's country is
+//! listed in the `geo_regions` setting, whose entries of the form `<region>=<country>|<country>`
+//! (such as `dach=DE|AT|CH`) group countries into custom regions. Clients that cannot be located
+//! are in the `unknown` region.
+//!
+//! As an example of a region-aware body transform, JSON objects from the `/api/` routes have the
+//! region added as a `region` member before they are cached.
+
+use crate::config::Settings;
+use crate::transform;
+use fastly::geo::geo_lookup;
+use fastly::http::HeaderName;
+use fastly::Request;
+use serde_json::Value;
+
+/// The request header carrying the client's region, which the cache varies on.
+pub const REGION_HEADER: HeaderName = HeaderName::from_static("x-region");
+
+/// The region of clients that cannot be located.
+const UNKNOWN_REGION: &str = "unknown";
+
+/// Returns the region of a request's client, or `None` if responses do not vary by region.
+pub fn region(req: &Request, settings: &Settings) -> Option<String> {
+    if !settings.get_bool("geo_variants", false) {
+        return None;
+    }
+    let Some(geo) = req.get_client_ip_addr().and_then(geo_lookup) else {
+        return Some(UNKNOWN_REGION.to_string());
+    };
+    let country = geo.country_code();
+    let custom_region = settings
+        .get_list("geo_regions")
+        .into_iter()
+        .find_map(|entry| {
+            let (region, countries) = entry.split_once('=')?;
+            countries
+                .split('|')
+                .any(|listed| listed.trim().eq_ignore_ascii_case(country))
+                .then(|| region.trim().to_ascii_lowercase())
+        });
+    Some(custom_region.unwrap_or_else(|| geo.continent().as_code().to_ascii_lowercase()))
+}
+
+/// Sets the region header of a request, replacing any sent by the client.
+pub fn set_region(req: &mut Request, region: &str) {
+    req.set_header(REGION_HEADER, region);
+}
+
+/// Returns a JSON body with the region added to it, if it is a JSON object. Other bodies are
+/// returned as they are.
+pub fn inject_region(body: &[u8], region: &str) -> Vec<u8> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(mut object)) => {
+            object.insert("region".to_string(), region.into());
+            serde_json::to_vec(&object).unwrap_or_else(|_| body.to_vec())
+        }
+        _ => body.to_vec(),
+    }
+}
+
+/// Returns whether the region can be added to a response body: uncompressed JSON only.
+pub fn is_injectable(content_type: Option<&str>, content_encoding: Option<&str>) -> bool {
+    transform::is_json(content_type) && transform::is_transformable_encoding(content_encoding)
+}
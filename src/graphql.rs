@@ -0,0 +1,368 @@
+//! Caching of GraphQL queries.
+//!
+//! GraphQL clients usually send every operation as a `POST`, which the readthrough cache never
+//! stores. Under the path prefixes of the `graphql_path_prefixes` setting, such as `/graphql`,
+//! operations are read from the JSON body of `POST` requests, or from the `query`, `variables` and
+//! `operationName` parameters of `GET` requests, and queries are converted to the cacheable form
+//! of the GraphQL over HTTP specification: a `GET` whose URL holds the query and its variables.
+//!
+//! The query is normalized first, without its comments and insignificant whitespace and commas,
+//! and the variables are written with their members sorted, so that the same query sent with
+//! different formatting shares one cached object: the cache key, hashed from the URL, is hashed
+//! from the normalized query and variables.
+//!
+//! Mutations and subscriptions always pass, as do documents that cannot be parsed, and queries too
+//! long to fit in a URL. The name of the operation can be matched by the `operation` condition of
+//! cache rules, to give each query its own TTL (see the [`cache_rules`](crate::cache_rules)
+//! module).
+
+use crate::config::Settings;
+use fastly::http::{header, Method};
+use fastly::Request;
+use serde_json::{Map, Value};
+
+/// The longest URL a query is sent in. Longer queries are passed as they were sent.
+const MAX_URL_LEN: usize = 8 * 1024;
+
+/// A GraphQL operation sent to one of the GraphQL endpoints.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Operation {
+    /// The name of the operation, if it has one.
+    pub name: Option<String>,
+    /// Whether the operation is a query that was converted to a cacheable `GET`. Other operations
+    /// must pass.
+    pub cacheable: bool,
+}
+
+impl Operation {
+    /// Reads the GraphQL operation of a request to a GraphQL endpoint, converting the request to
+    /// a cacheable `GET` if it is a query. Returns `None` for other requests.
+    pub fn take_from_request(req: &mut Request, settings: &Settings) -> Option<Self> {
+        let is_endpoint = settings
+            .get_list("graphql_path_prefixes")
+            .iter()
+            .any(|prefix| req.get_path().starts_with(prefix.as_str()));
+        if !is_endpoint {
+            return None;
+        }
+        let uncacheable = |name| {
+            Some(Self {
+                name,
+                cacheable: false,
+            })
+        };
+
+        let params = match *req.get_method() {
+            Method::GET => params_from_url(req),
+            Method::POST => {
+                let body = req.take_body_bytes();
+                let params = serde_json::from_slice::<Value>(&body)
+                    .ok()
+                    .and_then(|value| params_from_body(&value));
+                req.set_body(body);
+                params
+            }
+            _ => None,
+        };
+        let Some(params) = params else {
+            return uncacheable(None);
+        };
+        let query = normalize(&params.query);
+        let Some((kind, name)) = select(&query, params.operation_name.as_deref()) else {
+            return uncacheable(params.operation_name);
+        };
+        if kind != "query" {
+            return uncacheable(name);
+        }
+
+        let mut url = req.get_url().clone();
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs.clear().append_pair("query", &query);
+            if let Some(variables) = params.variables.filter(|variables| !variables.is_empty()) {
+                pairs.append_pair("variables", &Value::Object(variables).to_string());
+            }
+            if let Some(operation_name) = &params.operation_name {
+                pairs.append_pair("operationName", operation_name);
+            }
+        }
+        if url.as_str().len() > MAX_URL_LEN {
+            return uncacheable(name);
+        }
+        req.set_method(Method::GET);
+        req.set_url(url);
+        req.set_body(Vec::new());
+        req.remove_header(header::CONTENT_TYPE);
+        req.remove_header(header::CONTENT_LENGTH);
+        Some(Self {
+            name,
+            cacheable: true,
+        })
+    }
+}
+
+/// The parameters of a GraphQL request.
+struct Params {
+    query: String,
+    variables: Option<Map<String, Value>>,
+    operation_name: Option<String>,
+}
+
+/// Reads the parameters of a `POST` request from its JSON body.
+fn params_from_body(body: &Value) -> Option<Params> {
+    Some(Params {
+        query: body.get("query")?.as_str()?.to_string(),
+        variables: match body.get("variables") {
+            None | Some(Value::Null) => None,
+            Some(variables) => Some(variables.as_object()?.clone()),
+        },
+        operation_name: body
+            .get("operationName")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    })
+}
+
+/// Reads the parameters of a `GET` request from its query string.
+fn params_from_url(req: &Request) -> Option<Params> {
+    let mut query = None;
+    let mut variables = None;
+    let mut operation_name = None;
+    for (name, value) in req.get_url().query_pairs() {
+        match &*name {
+            "query" => query = Some(value.into_owned()),
+            "variables" => variables = Some(serde_json::from_str::<Value>(&value).ok()?),
+            "operationName" => operation_name = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    Some(Params {
+        query: query?,
+        variables: match variables {
+            None | Some(Value::Null) => None,
+            Some(Value::Object(variables)) => Some(variables),
+            Some(_) => return None,
+        },
+        operation_name,
+    })
+}
+
+/// Returns a GraphQL document without its comments, and with a single space between tokens only
+/// where one is needed to tell them apart. Strings are kept as they are.
+fn normalize(document: &str) -> String {
+    let mut out = String::with_capacity(document.len());
+    let mut chars = document.chars().peekable();
+    let mut separated = false;
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' || c == '\r' {
+                        break;
+                    }
+                }
+                separated = true;
+            }
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => separated = true,
+            '"' => {
+                separated = false;
+                out.push('"');
+                let block = chars.peek() == Some(&'"') && {
+                    let mut ahead = chars.clone();
+                    ahead.next();
+                    ahead.next() == Some('"')
+                };
+                if block {
+                    out.push_str("\"\"");
+                    chars.nth(1);
+                    let mut quotes = 0;
+                    for c in chars.by_ref() {
+                        out.push(c);
+                        quotes = if c == '"' { quotes + 1 } else { 0 };
+                        if quotes == 3 && !out.ends_with("\\\"\"\"") {
+                            break;
+                        }
+                    }
+                } else {
+                    let mut escaped = false;
+                    for c in chars.by_ref() {
+                        out.push(c);
+                        match c {
+                            '\\' if !escaped => escaped = true,
+                            '"' if !escaped => break,
+                            _ => escaped = false,
+                        }
+                    }
+                }
+            }
+            c => {
+                if separated && out.ends_with(is_name_char) && is_name_char(c) {
+                    out.push(' ');
+                }
+                separated = false;
+                out.push(c);
+            }
+        }
+    }
+    out
+}
+
+/// Returns the type (`query`, `mutation` or `subscription`) and name of the operation that a
+/// normalized document asks to execute: the one named `operation_name`, or else the only one.
+/// Returns `None` if the document holds no such operation.
+fn select(document: &str, operation_name: Option<&str>) -> Option<(String, Option<String>)> {
+    let mut operations: Vec<(String, Option<String>)> = Vec::new();
+    let mut depth = 0usize;
+    let mut definition: Option<(String, Option<String>)> = None;
+    let mut expects_name = false;
+    for token in tokens(document) {
+        match token {
+            "{" if depth == 0 => {
+                operations.push(
+                    definition
+                        .take()
+                        .unwrap_or_else(|| ("query".to_string(), None)),
+                );
+                depth += 1;
+            }
+            "{" | "(" | "[" => depth += 1,
+            "}" | ")" | "]" => depth = depth.checked_sub(1)?,
+            name if depth == 0 && token.starts_with(|c: char| c.is_alphabetic() || c == '_') => {
+                match &mut definition {
+                    None => {
+                        definition = Some((name.to_string(), None));
+                        expects_name = true;
+                        continue;
+                    }
+                    Some((_, operation @ None)) if expects_name => {
+                        *operation = Some(name.to_string());
+                    }
+                    Some(_) => {}
+                }
+            }
+            _ => {}
+        }
+        expects_name = false;
+    }
+    if depth != 0 {
+        return None;
+    }
+
+    let mut candidates = operations
+        .into_iter()
+        .filter(|(kind, _)| kind != "fragment");
+    let selected = match operation_name {
+        Some(operation_name) => {
+            candidates.find(|(_, name)| name.as_deref() == Some(operation_name))?
+        }
+        None => {
+            let only = candidates.next()?;
+            candidates.next().is_none().then_some(only)?
+        }
+    };
+    ["query", "mutation", "subscription"]
+        .contains(&selected.0.as_str())
+        .then_some(selected)
+}
+
+/// Splits a normalized document into names, punctuators and strings.
+fn tokens(document: &str) -> impl Iterator<Item = &str> {
+    let mut rest = document;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start();
+        let first = rest.chars().next()?;
+        let len = if first == '"' {
+            let block = rest.starts_with("\"\"\"");
+            let (open, close) = if block { (3, "\"\"\"") } else { (1, "\"") };
+            let mut end = open;
+            loop {
+                match rest[end..].find(close) {
+                    Some(at) if rest[..end + at].ends_with('\\') => end += at + 1,
+                    Some(at) => break end + at + close.len(),
+                    None => break rest.len(),
+                }
+            }
+        } else if rest.starts_with("...") {
+            3
+        } else if first.is_alphanumeric() || first == '_' || first == '-' {
+            rest.find(|c: char| !(c.is_alphanumeric() || "_.-+".contains(c)))
+                .unwrap_or(rest.len())
+                .max(first.len_utf8())
+        } else {
+            first.len_utf8()
+        };
+        let (token, remaining) = rest.split_at(len);
+        rest = remaining;
+        Some(token)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_are_normalized() {
+        let query = r#"
+            # The product page.
+            query Product($id: ID!, $first: Int = 10) {
+              product(id: $id) {
+                name,  description(format: "plain,  text")
+                ... on Book { author }
+              }
+            }
+        "#;
+        assert_eq!(
+            normalize(query),
+            r#"query Product($id:ID!$first:Int=10){product(id:$id){name description(format:"plain,  text")...on Book{author}}}"#
+        );
+        assert_eq!(
+            normalize("{ a(s: \"\"\" x \"y\" \"\"\") }"),
+            "{a(s:\"\"\" x \"y\" \"\"\")}"
+        );
+    }
+
+    #[test]
+    fn selected_operation_is_found() {
+        let select = |document: &str, name| select(&normalize(document), name);
+        assert_eq!(
+            select("{ products { name } }", None),
+            Some(("query".to_string(), None))
+        );
+        assert_eq!(
+            select("query Products { products { name } }", None),
+            Some(("query".to_string(), Some("Products".to_string())))
+        );
+        let document = "query A { a } mutation B($x: In = {a: 1}) { b(x: $x) { id } } \
+                        fragment F on T { f }";
+        assert_eq!(
+            select(document, Some("B")),
+            Some(("mutation".to_string(), Some("B".to_string())))
+        );
+        assert_eq!(select(document, None), None);
+        assert_eq!(select(document, Some("C")), None);
+        assert_eq!(
+            select("mutation { like(id: 1) }", None),
+            Some(("mutation".to_string(), None))
+        );
+        assert_eq!(select("{ a", None), None);
+    }
+
+    #[test]
+    fn params_are_read_from_the_body() {
+        let body = serde_json::json!({
+            "query": "{ a }",
+            "variables": { "b": 1, "a": [2] },
+            "operationName": null,
+        });
+        let params = params_from_body(&body).unwrap();
+        assert_eq!(params.query, "{ a }");
+        assert_eq!(
+            Value::Object(params.variables.unwrap()).to_string(),
+            r#"{"a":[2],"b":1}"#
+        );
+        assert_eq!(params.operation_name, None);
+        assert!(params_from_body(&serde_json::json!({ "variables": {} })).is_none());
+    }
+}
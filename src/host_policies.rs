@@ -0,0 +1,239 @@
+//! Per-host policy documents, for serving many sites from one service.
+//!
+//! With the `host_policies` setting on, every request is served under the policy of the host it
+//! is for. Policies are JSON documents in the `host_policies` KV Store, keyed by lowercase host
+//! name without the port:
+//!
+//! ```json
+//! {
+//!   "backend": "customer_a_origin",
+//!   "settings": { "compressed_origin_fetch": "on", "pass_path_prefixes": "/cart" },
+//!   "auth": { "mode": "bearer", "token_sha256": "<hex SHA-256 of the token>" }
+//! }
+//! ```
+//!
+//! * `backend` names the backend the host's requests are sent to (`origin` if absent).
+//! * `settings` overrides service settings for the host. This is also how rules and transforms
+//!   are switched on and off per host.
+//! * `auth` is `{"mode": "none"}` (the default), or `{"mode": "bearer", ...}` to require an
+//!   `Authorization: Bearer` token whose SHA-256 digest matches `token_sha256`. The token is
+//!   removed before the request is sent on.
+//!
+//! Documents are validated on every request: unknown members, unknown settings, non-scalar
+//! setting values, backends that do not exist and unknown auth modes make the policy invalid, and
+//! the request is answered with a 503 rather than served under a half-understood policy. Requests
+//! for hosts without a policy are answered with a 421. Each host's responses are cached under its
+//! own URLs, so hosts never share cached objects.
+
+use crate::config::{self, Settings};
+use crate::signing;
+use fastly::http::{header, StatusCode};
+use fastly::kv_store::KVStore;
+use fastly::{Backend, Request, Response};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// The name of the KV Store holding the host policy documents.
+const STORE_NAME: &str = "host_policies";
+
+/// The backend of hosts whose policy names none.
+const DEFAULT_BACKEND: &str = "origin";
+
+/// The members a policy document may have.
+const POLICY_MEMBERS: [&str; 3] = ["backend", "settings", "auth"];
+
+/// How requests for a host are authenticated at the edge.
+enum AuthMode {
+    None,
+    Bearer { token_sha256: String },
+}
+
+/// The policy of one host.
+pub struct HostPolicy {
+    pub backend: String,
+    pub settings: Settings,
+    auth: AuthMode,
+}
+
+impl HostPolicy {
+    /// Returns the policy of the host a request is for, with the service settings overridden by
+    /// it. Without host policies, the service settings and the `origin` backend apply.
+    ///
+    /// Returns the response to send instead if the request cannot be served under a policy.
+    pub fn resolve(req: &mut Request, settings: Settings) -> Result<Self, Box<Response>> {
+        if !settings.get_bool("host_policies", false) {
+            return Ok(Self {
+                backend: DEFAULT_BACKEND.to_string(),
+                settings,
+                auth: AuthMode::None,
+            });
+        }
+
+        let host = req
+            .get_header_str(header::HOST)
+            .or_else(|| req.get_url().host_str())
+            .map(|host| {
+                host.split(':')
+                    .next()
+                    .unwrap_or_default()
+                    .to_ascii_lowercase()
+            })
+            .unwrap_or_default();
+        let document = match load(&host) {
+            Ok(Some(document)) => document,
+            Ok(None) => {
+                log::warn!("no policy for host {host:?}");
+                return Err(Box::new(
+                    Response::from_status(StatusCode::MISDIRECTED_REQUEST)
+                        .with_body_text_plain("Unknown host\n"),
+                ));
+            }
+            Err(e) => {
+                log::warn!("cannot load policy of host {host:?}: {e}");
+                return Err(Box::new(unavailable()));
+            }
+        };
+
+        let policy = match Self::from_document(&document, settings) {
+            Ok(policy) => policy,
+            Err(e) => {
+                log::warn!("invalid policy for host {host:?}: {e}");
+                return Err(Box::new(unavailable()));
+            }
+        };
+        policy.authenticate(req)?;
+        Ok(policy)
+    }
+
+    /// Validates a policy document, and returns the policy it describes.
+    fn from_document(document: &[u8], settings: Settings) -> Result<Self, String> {
+        let document: Value =
+            serde_json::from_slice(document).map_err(|e| format!("not valid JSON: {e}"))?;
+        let document = document
+            .as_object()
+            .ok_or("the policy must be a JSON object")?;
+        if let Some(member) = document
+            .keys()
+            .find(|member| !POLICY_MEMBERS.contains(&member.as_str()))
+        {
+            return Err(format!("unknown member {member:?}"));
+        }
+
+        let backend = match document.get("backend") {
+            None => DEFAULT_BACKEND.to_string(),
+            Some(Value::String(backend)) => backend.clone(),
+            Some(_) => return Err("backend must be a string".into()),
+        };
+        if !Backend::from_name(&backend).is_ok_and(|backend| backend.exists()) {
+            return Err(format!("backend {backend:?} does not exist"));
+        }
+
+        let overrides = match document.get("settings") {
+            None => HashMap::new(),
+            Some(Value::Object(overrides)) => setting_overrides(overrides)?,
+            Some(_) => return Err("settings must be a JSON object".into()),
+        };
+
+        let auth = match document.get("auth") {
+            None => AuthMode::None,
+            Some(auth) => auth_mode(auth)?,
+        };
+
+        Ok(Self {
+            backend,
+            settings: settings.with_overrides(overrides),
+            auth,
+        })
+    }
+
+    /// Checks the credentials the host requires, and removes them from the request.
+    fn authenticate(&self, req: &mut Request) -> Result<(), Box<Response>> {
+        let AuthMode::Bearer { token_sha256 } = &self.auth else {
+            return Ok(());
+        };
+        let authorization = req.remove_header_str(header::AUTHORIZATION);
+        let digest = authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| hex_sha256(token.trim().as_bytes()));
+        match digest {
+            Some(digest)
+                if signing::constant_time_eq(digest.as_bytes(), token_sha256.as_bytes()) =>
+            {
+                Ok(())
+            }
+            _ => Err(Box::new(
+                Response::from_status(StatusCode::UNAUTHORIZED)
+                    .with_header(header::WWW_AUTHENTICATE, "Bearer"),
+            )),
+        }
+    }
+}
+
+fn load(host: &str) -> Result<Option<Vec<u8>>, fastly::Error> {
+    let Some(store) = KVStore::open(STORE_NAME)? else {
+        return Err(fastly::Error::msg(format!(
+            "KV Store {STORE_NAME} is not linked"
+        )));
+    };
+    match store.lookup(host) {
+        Ok(mut found) => Ok(Some(found.take_body_bytes())),
+        Err(fastly::kv_store::KVStoreError::ItemNotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Validates the setting overrides of a policy. Every key must be a known setting, and every
+/// value a string, number or boolean.
+fn setting_overrides(overrides: &Map<String, Value>) -> Result<HashMap<String, String>, String> {
+    overrides
+        .iter()
+        .map(|(key, value)| {
+            if !config::KEYS.contains(&key.as_str()) || key == "host_policies" {
+                return Err(format!("unknown setting {key:?}"));
+            }
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Number(value) => value.to_string(),
+                Value::Bool(value) => value.to_string(),
+                _ => {
+                    return Err(format!(
+                        "setting {key:?} must be a string, number or boolean"
+                    ))
+                }
+            };
+            Ok((key.clone(), value))
+        })
+        .collect()
+}
+
+fn auth_mode(auth: &Value) -> Result<AuthMode, String> {
+    match auth["mode"].as_str() {
+        Some("none") => Ok(AuthMode::None),
+        Some("bearer") => {
+            let token_sha256 = auth["token_sha256"]
+                .as_str()
+                .filter(|digest| {
+                    digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit())
+                })
+                .ok_or("bearer auth needs the hex SHA-256 digest of the token in token_sha256")?;
+            Ok(AuthMode::Bearer {
+                token_sha256: token_sha256.to_ascii_lowercase(),
+            })
+        }
+        _ => Err("auth mode must be \"none\" or \"bearer\"".into()),
+    }
+}
+
+fn unavailable() -> Response {
+    Response::from_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_body_text_plain("This site is not configured correctly\n")
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
@@ -0,0 +1,439 @@
+//! Streaming rewrites of cached HTML pages.
+//!
+//! HTML pages can be rewritten as they are stored, with element handlers registered per path
+//! prefix. Pages are parsed and rewritten chunk by chunk as their origin body is read, so the
+//! document is never held in memory as a whole. The rewritten page is what the cache stores.
+//!
+//! The `html_rewrites` setting registers the common rewrites, as entries of the form
+//! `<path prefix> <action> <arguments>`:
+//!
+//! * `/ links <from> <to>` rewrites `href` and `src` attributes starting with `<from>` to start
+//!   with `<to>` instead, such as `/ links http://origin.internal/ https://www.example.com/`;
+//! * `/ script <src>` injects a script at the end of the `<body>`;
+//! * `/ meta <name> <content>` injects a meta tag at the end of the `<head>`;
+//! * `/ strip <selector>` removes the elements matching a CSS selector, such as
+//!   `/blog/ strip div.ad-slot`.
+//!
+//! Since the setting is a comma-separated list, selectors and meta contents cannot contain
+//! commas. Entries that cannot be parsed are logged and ignored. Other rewrites can be registered
+//! in code with [`HtmlRewrites::builder`], by giving a [`Rewrites`] any element handler.
+//!
+//! The rewrites of every prefix a path starts with are applied, in the order they are
+//! registered. Gzip and Brotli pages are decompressed to be rewritten, and compressed again.
+
+use crate::compression::Coding;
+use crate::config::Settings;
+use crate::transform;
+use lol_html::html_content::{ContentType, Element};
+use lol_html::{ElementContentHandlers, HandlerResult, OutputSink, Selector};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// An element handler, called with every element matching its selector.
+type Handler = Arc<dyn Fn(&mut Element<'_, '_>) -> HandlerResult + Send + Sync>;
+
+/// The rewrites of HTML pages, registered per path prefix.
+#[derive(Default)]
+pub struct HtmlRewrites {
+    routes: Vec<(String, Rewrites)>,
+}
+
+impl HtmlRewrites {
+    /// Returns a builder of the rewrites of HTML pages.
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Returns the rewrites registered by the `html_rewrites` setting.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let mut builder = Self::builder();
+        for entry in settings.get_list("html_rewrites") {
+            match parse(&entry) {
+                Some((prefix, rewrites)) => builder = builder.route(prefix, rewrites),
+                None => log::warn!("ignoring invalid HTML rewrite {entry:?}"),
+            }
+        }
+        builder.build()
+    }
+
+    /// Returns the rewrites of the pages under a path, or `None` if no rewrites apply to it.
+    pub fn for_path(&self, path: &str) -> Option<Rewrites> {
+        let mut matching = self
+            .routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .peekable();
+        matching.peek()?;
+        let mut rewrites = Rewrites::default();
+        for (_, route_rewrites) in matching {
+            rewrites
+                .handlers
+                .extend(route_rewrites.handlers.iter().cloned());
+        }
+        Some(rewrites)
+    }
+}
+
+/// A builder of the rewrites of HTML pages.
+#[derive(Default)]
+pub struct Builder {
+    routes: Vec<(String, Rewrites)>,
+}
+
+impl Builder {
+    /// Registers rewrites for the pages under a path prefix.
+    pub fn route(mut self, prefix: impl Into<String>, rewrites: Rewrites) -> Self {
+        self.routes.push((prefix.into(), rewrites));
+        self
+    }
+
+    /// Returns the registered rewrites.
+    pub fn build(self) -> HtmlRewrites {
+        HtmlRewrites {
+            routes: self.routes,
+        }
+    }
+}
+
+/// The element handlers rewriting one HTML page.
+#[derive(Clone, Default)]
+pub struct Rewrites {
+    handlers: Vec<(Selector, Handler)>,
+}
+
+impl Rewrites {
+    /// Returns rewrites that leave pages as they are.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler called with every element matching a CSS selector. Invalid selectors
+    /// are logged and ignored.
+    pub fn on_element(
+        mut self,
+        selector: &str,
+        handler: impl Fn(&mut Element<'_, '_>) -> HandlerResult + Send + Sync + 'static,
+    ) -> Self {
+        match selector.parse() {
+            Ok(selector) => self.handlers.push((selector, Arc::new(handler))),
+            Err(e) => log::warn!("ignoring HTML rewrite of invalid selector {selector:?}: {e}"),
+        }
+        self
+    }
+
+    /// Adds the handlers of other rewrites, called after these.
+    pub fn merge(mut self, other: Rewrites) -> Self {
+        self.handlers.extend(other.handlers);
+        self
+    }
+
+    /// Rewrites the `href` and `src` attributes starting with `from` to start with `to` instead.
+    pub fn rewrite_links(self, from: &str, to: &str) -> Self {
+        let (from, to) = (from.to_string(), to.to_string());
+        self.on_element("[href], [src]", move |el| {
+            for name in ["href", "src"] {
+                let rewritten = el
+                    .get_attribute(name)
+                    .and_then(|value| Some(format!("{to}{}", value.strip_prefix(&from)?)));
+                if let Some(rewritten) = rewritten {
+                    el.set_attribute(name, &rewritten)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Injects a script at the end of the `<body>`.
+    pub fn inject_script(self, src: &str) -> Self {
+        let tag = format!(r#"<script src="{}"></script>"#, escape_attribute(src));
+        self.on_element("body", move |el| {
+            el.append(&tag, ContentType::Html);
+            Ok(())
+        })
+    }
+
+    /// Injects a meta tag at the end of the `<head>`.
+    pub fn inject_meta(self, name: &str, content: &str) -> Self {
+        let tag = format!(
+            r#"<meta name="{}" content="{}">"#,
+            escape_attribute(name),
+            escape_attribute(content)
+        );
+        self.on_element("head", move |el| {
+            el.append(&tag, ContentType::Html);
+            Ok(())
+        })
+    }
+
+    /// Removes the elements matching a CSS selector, along with their content.
+    pub fn strip(self, selector: &str) -> Self {
+        self.on_element(selector, |el| {
+            el.remove();
+            Ok(())
+        })
+    }
+
+    /// Returns whether a response can be rewritten: HTML that is not encoded, or that can be
+    /// decompressed.
+    pub fn applies(content_type: Option<&str>, content_encoding: Option<&str>) -> bool {
+        transform::is_html(content_type) && transform::is_transformable_encoding(content_encoding)
+    }
+
+    /// Returns a writer that rewrites the HTML written to it, and writes the result to `output` as
+    /// it goes. The writer must be finished once the whole page has been written.
+    pub fn writer<W: Write>(&self, output: W) -> RewritingWriter<W> {
+        let error = Rc::new(RefCell::new(None));
+        let settings = self.handlers.iter().fold(
+            lol_html::Settings::new(),
+            |settings, (selector, handler)| {
+                let handler = handler.clone();
+                settings.append_element_content_handler((
+                    Cow::Owned(selector.clone()),
+                    ElementContentHandlers::default()
+                        .element(move |el: &mut Element<'_, '_>| handler(el)),
+                ))
+            },
+        );
+        let rewriter = lol_html::HtmlRewriter::new(
+            settings,
+            Sink {
+                output,
+                error: error.clone(),
+            },
+        );
+        RewritingWriter { rewriter, error }
+    }
+}
+
+/// A writer that rewrites the HTML written to it with [`Rewrites`].
+pub struct RewritingWriter<W: Write> {
+    rewriter: lol_html::HtmlRewriter<'static, Sink<W>>,
+    error: Rc<RefCell<Option<io::Error>>>,
+}
+
+impl<W: Write> RewritingWriter<W> {
+    /// Rewrites the end of the page, and writes what the rewriter still held back.
+    pub fn finish(self) -> io::Result<()> {
+        self.rewriter.end().map_err(io::Error::other)?;
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+impl<W: Write> Write for RewritingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.rewriter.write(buf).map_err(io::Error::other)?;
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the output of the rewriter, keeping the first error for the writer to report.
+struct Sink<W> {
+    output: W,
+    error: Rc<RefCell<Option<io::Error>>>,
+}
+
+impl<W: Write> OutputSink for Sink<W> {
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        let mut error = self.error.borrow_mut();
+        if error.is_none() {
+            if let Err(e) = self.output.write_all(chunk) {
+                *error = Some(e);
+            }
+        }
+    }
+}
+
+/// Writes a page to `output` through the rewrites, if any, compressing the result with a coding,
+/// if any. The page itself is written by `write`.
+pub fn write_page(
+    rewrites: Option<&Rewrites>,
+    coding: Option<Coding>,
+    output: &mut impl Write,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<()> {
+    match coding {
+        Some(coding) => {
+            let mut encoder = coding.encoder(output);
+            write_rewritten(rewrites, &mut encoder, write)?;
+            encoder.finish()
+        }
+        None => write_rewritten(rewrites, output, write),
+    }
+}
+
+/// Writes a page to `output` through the rewrites, if any.
+fn write_rewritten(
+    rewrites: Option<&Rewrites>,
+    mut output: impl Write,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<()> {
+    match rewrites {
+        Some(rewrites) => {
+            let mut writer = rewrites.writer(output);
+            write(&mut writer)?;
+            writer.finish()
+        }
+        None => write(&mut output),
+    }
+}
+
+/// Parses an entry of the `html_rewrites` setting into its path prefix and rewrites.
+fn parse(entry: &str) -> Option<(String, Rewrites)> {
+    let (prefix, rest) = entry.trim().split_once(char::is_whitespace)?;
+    if !prefix.starts_with('/') {
+        return None;
+    }
+    let (action, arguments) = rest.trim().split_once(char::is_whitespace)?;
+    let arguments = arguments.trim();
+    let rewrites = Rewrites::new();
+    let rewrites = match action {
+        "links" => {
+            let (from, to) = arguments.split_once(char::is_whitespace)?;
+            rewrites.rewrite_links(from, to.trim())
+        }
+        "script" => rewrites.inject_script(arguments),
+        "meta" => {
+            let (name, content) = arguments.split_once(char::is_whitespace)?;
+            rewrites.inject_meta(name, content.trim())
+        }
+        "strip" => {
+            let rewrites = rewrites.strip(arguments);
+            if rewrites.handlers.is_empty() {
+                return None;
+            }
+            rewrites
+        }
+        _ => return None,
+    };
+    Some((prefix.to_string(), rewrites))
+}
+
+/// Escapes a value to be written as a double-quoted attribute.
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(rewrites: &Rewrites, page: &str) -> String {
+        let mut output = Vec::new();
+        let mut writer = rewrites.writer(&mut output);
+        // Pages arrive in arbitrary chunks, which may split tags.
+        for chunk in page.as_bytes().chunks(7) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn links_are_rewritten() {
+        let rewrites = Rewrites::new().rewrite_links("http://origin.internal/", "/");
+        assert_eq!(
+            rewrite(
+                &rewrites,
+                r#"<a href="http://origin.internal/shop">Shop</a><img src="http://cdn.example/a.png">"#
+            ),
+            r#"<a href="/shop">Shop</a><img src="http://cdn.example/a.png">"#
+        );
+    }
+
+    #[test]
+    fn scripts_and_meta_tags_are_injected() {
+        let rewrites = Rewrites::new()
+            .inject_script("/edge.js")
+            .inject_meta("robots", "noindex \"now\"");
+        assert_eq!(
+            rewrite(
+                &rewrites,
+                "<html><head><title>T</title></head><body><p>Hi</p></body></html>"
+            ),
+            "<html><head><title>T</title><meta name=\"robots\" content=\"noindex &quot;now&quot;\"></head>\
+             <body><p>Hi</p><script src=\"/edge.js\"></script></body></html>"
+        );
+    }
+
+    #[test]
+    fn elements_are_stripped() {
+        let rewrites = Rewrites::new().strip("div.ad-slot");
+        assert_eq!(
+            rewrite(
+                &rewrites,
+                r#"<p>A</p><div class="ad-slot"><img src="ad.png"></div><div>B</div>"#
+            ),
+            "<p>A</p><div>B</div>"
+        );
+    }
+
+    #[test]
+    fn rewrites_of_matching_prefixes_are_combined() {
+        let rewrites = HtmlRewrites::builder()
+            .route("/", Rewrites::new().strip("aside"))
+            .route("/blog/", Rewrites::new().strip("nav"))
+            .build();
+        let page = "<nav>N</nav><aside>A</aside><main>M</main>";
+        let blog = rewrites.for_path("/blog/post").unwrap();
+        assert_eq!(rewrite(&blog, page), "<main>M</main>");
+        let home = rewrites.for_path("/home").unwrap();
+        assert_eq!(rewrite(&home, page), "<nav>N</nav><main>M</main>");
+        assert!(HtmlRewrites::default().for_path("/").is_none());
+    }
+
+    #[test]
+    fn pages_rendered_from_json_get_the_rewrites_of_their_route() {
+        let html =
+            transform::render_json_to_html(&br#"{"firstName": "Ada", "lastName": "Lovelace"}"#[..])
+                .unwrap();
+        let rewrites = HtmlRewrites::builder()
+            .route(
+                "/people/",
+                Rewrites::new().on_element("div", |el| {
+                    el.set_attribute("data-experiment", "hero")?;
+                    Ok(())
+                }),
+            )
+            .build()
+            .for_path("/people/ada")
+            .unwrap()
+            .merge(Rewrites::new().on_element("div", |el| {
+                el.set_attribute("data-variant", "compact")?;
+                Ok(())
+            }));
+        let mut out = Vec::new();
+        write_page(Some(&rewrites), None, &mut out, |out| {
+            out.write_all(html.as_bytes())
+        })
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"<div data-experiment="hero" data-variant="compact">Ada Lovelace</div>"#
+        );
+    }
+
+    #[test]
+    fn setting_entries_are_parsed() {
+        assert!(parse("/ links http://a/ https://b/").is_some());
+        assert!(parse("/ meta robots noindex, nofollow").is_some());
+        assert!(parse("/blog/ strip div.ad-slot > img").is_some());
+        assert!(parse("/ strip ::invalid((").is_none());
+        assert!(parse("/ links http://a/").is_none());
+        assert!(parse("blog strip nav").is_none());
+        assert!(parse("/ unknown x").is_none());
+    }
+}
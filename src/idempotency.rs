@@ -0,0 +1,289 @@
+//! Idempotency keys for `POST` requests.
+//!
+//! Clients that retry a `POST`, for example after a timeout, can send the same `Idempotency-Key`
+//! header with every attempt. The first attempt is forwarded to the origin and its response is
+//! recorded in the `idempotency` KV Store. Later attempts with the same key are answered with the
+//! recorded response instead of reaching the origin again, so a double submission cannot, say,
+//! place an order twice.
+//!
+//! Keys are scoped to the path and to the caller, identified by its `Authorization` and `Cookie`
+//! headers, so that a client reusing the key of another one never gets that client's response.
+//!
+//! A key is reserved before the first attempt is forwarded, so a duplicate that arrives while the
+//! first attempt is still in flight is refused with a 409. A key reused with a different request
+//! body is refused with a 422. Server errors are not recorded, so that the client can retry them.
+//! The first attempt is prepared for the origin like any other request sent to it.
+
+use crate::config::Settings;
+use crate::origin_request::OriginRequest;
+use fastly::http::{header, HeaderName, Method, StatusCode};
+use fastly::kv_store::{InsertMode, KVStore, KVStoreError};
+use fastly::{Error, Request, Response};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// The name of the KV Store that records responses by idempotency key.
+const STORE_NAME: &str = "idempotency";
+
+/// The request header carrying the idempotency key.
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// The response header marking a response replayed from the KV Store.
+const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// How long responses are recorded, unless overridden by the `idempotency_window` setting (in
+/// seconds).
+const DEFAULT_WINDOW_SECS: u64 = 86_400;
+
+/// The longest idempotency key accepted.
+const MAX_KEY_LEN: usize = 255;
+
+/// The response headers that are recorded and replayed along with the status and body.
+const RECORDED_HEADERS: [HeaderName; 2] = [header::CONTENT_TYPE, header::LOCATION];
+
+/// Returns whether a request is a `POST` with an idempotency key, on one of the path prefixes in
+/// the `idempotency_path_prefixes` setting.
+pub fn applies(req: &Request, settings: &Settings) -> bool {
+    is_keyed_post(
+        req.get_method(),
+        req.contains_header(IDEMPOTENCY_KEY_HEADER),
+        req.get_path(),
+        &settings.get_list("idempotency_path_prefixes"),
+    )
+}
+
+fn is_keyed_post(method: &Method, has_key: bool, path: &str, prefixes: &[String]) -> bool {
+    *method == Method::POST
+        && has_key
+        && prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+}
+
+/// Sends a request with an idempotency key to the backend, or replays the response recorded for
+/// an earlier request with the same key.
+pub fn send(
+    mut req: Request,
+    backend: &str,
+    origin_request: &OriginRequest,
+    settings: &Settings,
+) -> Result<Response, Error> {
+    req.set_pass(true);
+
+    let key = req
+        .get_header_str(IDEMPOTENCY_KEY_HEADER)
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST));
+    }
+
+    // The caller is read before the request is prepared, which replaces its credentials.
+    let record_key = record_key(
+        req.get_path(),
+        &key,
+        req.get_header_str(header::AUTHORIZATION),
+        req.get_header_str(header::COOKIE),
+    );
+    origin_request.prepare(&mut req, backend);
+
+    let Some(store) = KVStore::open(STORE_NAME)? else {
+        log::warn!("KV Store {STORE_NAME} is not linked; forwarding without an idempotency check");
+        return Ok(req.send(backend)?);
+    };
+
+    let body = req.take_body_bytes();
+    let fingerprint = sha256_hex(&body);
+    req.set_body(body);
+
+    let window = Duration::from_secs(settings.get_u64("idempotency_window", DEFAULT_WINDOW_SECS));
+    let reservation = store
+        .build_insert()
+        .mode(InsertMode::Add)
+        .metadata(&json!({ "fingerprint": fingerprint }).to_string())
+        .time_to_live(window)
+        .execute(&record_key, "");
+    match reservation {
+        Ok(()) => {}
+        Err(KVStoreError::ItemPreconditionFailed) => {
+            return Ok(replay(&store, &record_key, &fingerprint));
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut resp = match req.send(backend) {
+        Ok(resp) => resp,
+        Err(e) => {
+            release(&store, &record_key);
+            return Err(e.into());
+        }
+    };
+    if resp.get_status().is_server_error() {
+        release(&store, &record_key);
+        return Ok(resp);
+    }
+
+    let body = resp.take_body_bytes();
+    let headers: Vec<(&str, &str)> = RECORDED_HEADERS
+        .iter()
+        .filter_map(|name| Some((name.as_str(), resp.get_header_str(name)?)))
+        .collect();
+    let record = json!({
+        "fingerprint": fingerprint,
+        "status": resp.get_status().as_u16(),
+        "headers": headers,
+    });
+    let result = store
+        .build_insert()
+        .metadata(&record.to_string())
+        .time_to_live(window)
+        .execute(&record_key, body.as_slice());
+    if let Err(e) = result {
+        log::warn!("cannot record response for idempotency key: {e}");
+    }
+    resp.set_body(body);
+    Ok(resp)
+}
+
+/// Builds the response to a duplicate request from the record of the original one.
+fn replay(store: &KVStore, record_key: &str, fingerprint: &str) -> Response {
+    let Ok(mut found) = store.lookup(record_key) else {
+        // The record expired between the reservation attempt and now; the client may retry.
+        return Response::from_status(StatusCode::CONFLICT);
+    };
+    let record: Value = found
+        .metadata()
+        .and_then(|metadata| serde_json::from_slice(&metadata).ok())
+        .unwrap_or_default();
+
+    match recorded_response(&record, fingerprint) {
+        Ok((status, headers)) => {
+            let mut resp = Response::from_status(status).with_body(found.take_body());
+            for (name, value) in headers {
+                resp.set_header(name, value);
+            }
+            resp.set_header(REPLAYED_HEADER, "true");
+            resp
+        }
+        Err(status) => Response::from_status(status),
+    }
+}
+
+/// The status and headers recorded for a request with an idempotency key.
+type Recorded<'a> = (StatusCode, Vec<(&'a str, &'a str)>);
+
+/// Returns the status and headers recorded for the original request, or the status refusing a
+/// duplicate: a 422 if its body differs, or a 409 if the original request is still in flight.
+fn recorded_response<'a>(record: &'a Value, fingerprint: &str) -> Result<Recorded<'a>, StatusCode> {
+    if record["fingerprint"] != fingerprint {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let status = record["status"]
+        .as_u64()
+        .and_then(|status| u16::try_from(status).ok())
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or(StatusCode::CONFLICT)?;
+    let headers = record["headers"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|pair| Some((pair[0].as_str()?, pair[1].as_str()?)))
+        .collect();
+    Ok((status, headers))
+}
+
+/// Returns the KV Store key recording the requests with an idempotency key, scoped to their path
+/// and caller. Each part is length-prefixed, so that different parts never make the same key.
+fn record_key(path: &str, key: &str, authorization: Option<&str>, cookie: Option<&str>) -> String {
+    let parts = [
+        Some(path),
+        Some(key),
+        authorization.map(str::trim),
+        cookie.map(str::trim),
+    ];
+    let scoped: String = parts
+        .iter()
+        .map(|part| match part {
+            Some(part) => format!("{}:{part}", part.len()),
+            None => "-".to_string(),
+        })
+        .collect();
+    sha256_hex(scoped.as_bytes())
+}
+
+/// Removes the reservation of a key whose request failed, so that it can be retried.
+fn release(store: &KVStore, record_key: &str) {
+    if let Err(e) = store.delete(record_key) {
+        log::warn!("cannot release idempotency key: {e}");
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_keyed_posts_under_a_prefix_apply() {
+        let prefixes = vec!["/orders".to_string()];
+        assert!(is_keyed_post(&Method::POST, true, "/orders/new", &prefixes));
+        assert!(!is_keyed_post(
+            &Method::POST,
+            false,
+            "/orders/new",
+            &prefixes
+        ));
+        assert!(!is_keyed_post(&Method::PUT, true, "/orders/new", &prefixes));
+        assert!(!is_keyed_post(&Method::POST, true, "/cart", &prefixes));
+        assert!(!is_keyed_post(&Method::POST, true, "/orders", &[]));
+    }
+
+    #[test]
+    fn keys_are_scoped_to_the_caller() {
+        let key = record_key("/orders", "k1", Some("Bearer a"), None);
+        assert_eq!(record_key("/orders", "k1", Some("Bearer a"), None), key);
+        assert_ne!(record_key("/orders", "k1", Some("Bearer b"), None), key);
+        assert_ne!(record_key("/orders", "k1", None, None), key);
+        assert_ne!(record_key("/orders", "k1", None, Some("Bearer a")), key);
+        assert_ne!(record_key("/orders", "k2", Some("Bearer a"), None), key);
+        assert_ne!(
+            record_key("/a:b", "c", None, None),
+            record_key("/a", "b:c", None, None)
+        );
+    }
+
+    #[test]
+    fn duplicates_are_replayed_only_with_the_same_body() {
+        let record = json!({
+            "fingerprint": "f1",
+            "status": 201,
+            "headers": [["content-type", "application/json"], ["location", "/orders/7"]],
+        });
+        assert_eq!(
+            recorded_response(&record, "f1"),
+            Ok((
+                StatusCode::CREATED,
+                vec![
+                    ("content-type", "application/json"),
+                    ("location", "/orders/7")
+                ]
+            ))
+        );
+        assert_eq!(
+            recorded_response(&record, "f2"),
+            Err(StatusCode::UNPROCESSABLE_ENTITY)
+        );
+        assert_eq!(
+            recorded_response(&json!({ "fingerprint": "f1" }), "f1"),
+            Err(StatusCode::CONFLICT)
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Verification of origin bodies against the digests the origin sends with them.
+//!
+//! When a response carries a `Repr-Digest`, `Content-Digest`, `Digest` or `Content-MD5` header,
+//! its body is hashed as it streams through the body transform and compared with the digest. On a
+//! mismatch, the transform fails without finishing the body, so the truncated or corrupted object
+//! is never committed to the cache, and the failure is logged.
+//!
+//! The strongest supported algorithm is checked: SHA-512, then SHA-256, then MD5. Transforms that
+//! rewrite the body check the digest against the body they read, and remove the digest headers,
+//! which no longer describe the body they store.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fastly::http::{header, CandidateResponse};
+use md5::Md5;
+use sha2::digest::DynDigest;
+use sha2::{Digest, Sha256, Sha512};
+use std::io::{self, Read, Write};
+
+/// The digest headers, in order of preference. The digest fields of RFC 9530 come first, then
+/// the obsolete `Digest` and `Content-MD5` headers.
+const DIGEST_HEADERS: [&str; 4] = ["repr-digest", "content-digest", "digest", "content-md5"];
+
+/// The size of the chunks a body is hashed in while streaming.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A hash algorithm used by origin digests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Algorithm {
+    Md5,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "sha-512" => Some(Algorithm::Sha512),
+            "sha-256" => Some(Algorithm::Sha256),
+            "md5" => Some(Algorithm::Md5),
+            _ => None,
+        }
+    }
+
+    fn hasher(self) -> Box<dyn DynDigest> {
+        match self {
+            Algorithm::Md5 => Box::new(Md5::new()),
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+            Algorithm::Sha512 => Box::new(Sha512::new()),
+        }
+    }
+}
+
+/// The digest an origin body is expected to have.
+#[derive(Clone, Debug)]
+pub struct ExpectedDigest {
+    header: &'static str,
+    algorithm: Algorithm,
+    digest: Vec<u8>,
+}
+
+impl ExpectedDigest {
+    /// Returns the digest announced by a response, or `None` if it announces none that can be
+    /// checked.
+    pub fn from_response(resp: &CandidateResponse) -> Option<Self> {
+        DIGEST_HEADERS.into_iter().find_map(|name| {
+            let value = resp.get_header_str(name)?;
+            let (algorithm, digest) = if name == "content-md5" {
+                (Algorithm::Md5, STANDARD.decode(value.trim()).ok()?)
+            } else {
+                strongest(value)?
+            };
+            Some(Self {
+                header: name,
+                algorithm,
+                digest,
+            })
+        })
+    }
+
+    /// Returns the digest announced by a response whose body is about to be rewritten, and
+    /// removes the digest headers from it.
+    pub fn take_from_response(resp: &mut CandidateResponse) -> Option<Self> {
+        let expected = Self::from_response(resp);
+        for name in DIGEST_HEADERS {
+            resp.remove_header(name);
+        }
+        expected
+    }
+
+    /// Installs a body transform that copies the body as it is, failing if it does not match the
+    /// digest.
+    ///
+    /// Responses with trailers are left alone, since copying the body in chunks would drop them.
+    pub fn install(self, resp: &mut CandidateResponse) {
+        if resp.contains_header(header::TRAILER) {
+            return;
+        }
+        resp.set_body_transform(move |mut body_in, body_out| {
+            let mut hasher = self.algorithm.hasher();
+            let mut chunk = vec![0; CHUNK_SIZE];
+            loop {
+                let len = body_in.read(&mut chunk)?;
+                if len == 0 {
+                    break;
+                }
+                hasher.update(&chunk[..len]);
+                body_out.write_all(&chunk[..len])?;
+            }
+            self.check(&hasher.finalize())?;
+            Ok(())
+        });
+    }
+
+    /// Checks a whole body against the digest.
+    pub fn verify(&self, body: &[u8]) -> io::Result<()> {
+        let mut hasher = self.algorithm.hasher();
+        hasher.update(body);
+        self.check(&hasher.finalize())
+    }
+
+    fn check(&self, actual: &[u8]) -> io::Result<()> {
+        if actual == self.digest.as_slice() {
+            return Ok(());
+        }
+        log::warn!(
+            "origin body does not match its {} {:?} digest; not caching it",
+            self.header,
+            self.algorithm
+        );
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("origin body does not match its {} header", self.header),
+        ))
+    }
+}
+
+/// A reader that hashes a body as it is read, for transforms that stream the body rather than
+/// reading it in full. The digest is checked by [`DigestReader::finish`], once the whole body has
+/// been read.
+pub struct DigestReader<R> {
+    inner: R,
+    expected: Option<(ExpectedDigest, Box<dyn DynDigest>)>,
+    bytes_read: usize,
+}
+
+impl<R: Read> DigestReader<R> {
+    /// Wraps a body reader, to check it against an optional digest.
+    pub fn new(inner: R, expected: Option<ExpectedDigest>) -> Self {
+        Self {
+            inner,
+            expected: expected.map(|expected| {
+                let hasher = expected.algorithm.hasher();
+                (expected, hasher)
+            }),
+            bytes_read: 0,
+        }
+    }
+
+    /// Returns the number of body bytes read so far.
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+
+    /// Checks the body read against the digest.
+    pub fn finish(self) -> io::Result<()> {
+        match self.expected {
+            Some((expected, hasher)) => expected.check(&hasher.finalize()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some((_, hasher)) = &mut self.expected {
+            hasher.update(&buf[..len]);
+        }
+        self.bytes_read += len;
+        Ok(len)
+    }
+}
+
+/// Verifies a whole body against an optional digest, for transforms that read the body in full.
+pub fn verify(expected: Option<&ExpectedDigest>, body: &[u8]) -> io::Result<()> {
+    expected.map_or(Ok(()), |expected| expected.verify(body))
+}
+
+/// Returns the strongest supported digest in a `Repr-Digest`, `Content-Digest` or `Digest` value.
+///
+/// The RFC 9530 fields wrap their base64 values in colons, as structured field byte sequences,
+/// while the older `Digest` header does not.
+fn strongest(value: &str) -> Option<(Algorithm, Vec<u8>)> {
+    value
+        .split(',')
+        .filter_map(|member| {
+            let (name, digest) = member.split_once('=')?;
+            let algorithm = Algorithm::from_name(name)?;
+            let digest = STANDARD.decode(digest.trim().trim_matches(':')).ok()?;
+            Some((algorithm, digest))
+        })
+        .max_by_key(|(algorithm, _)| *algorithm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+
+    fn expected(value: &str) -> Option<ExpectedDigest> {
+        let (algorithm, digest) = strongest(value)?;
+        Some(ExpectedDigest {
+            header: "repr-digest",
+            algorithm,
+            digest,
+        })
+    }
+
+    #[test]
+    fn matching_bodies_pass_and_others_fail() {
+        let digest = expected(&format!("sha-256=:{HELLO_SHA256}:")).unwrap();
+        assert!(digest.verify(b"hello").is_ok());
+        assert_eq!(
+            digest.verify(b"hellO").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut reader = DigestReader::new(&b"hello"[..], Some(digest.clone()));
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert!(reader.finish().is_ok());
+        let mut truncated = DigestReader::new(&b"hell"[..], Some(digest));
+        io::copy(&mut truncated, &mut io::sink()).unwrap();
+        assert!(truncated.finish().is_err());
+    }
+
+    #[test]
+    fn the_strongest_digest_is_checked() {
+        let digest = expected(&format!(
+            "md5=XUFAKrxLKna5cZ2REBfFkg==, sha-256={HELLO_SHA256}, unknown=abc"
+        ))
+        .unwrap();
+        assert_eq!(digest.algorithm, Algorithm::Sha256);
+        assert!(digest.verify(b"hello").is_ok());
+    }
+
+    #[test]
+    fn malformed_digests_are_ignored() {
+        assert!(expected("sha-256=:not base64!:").is_none());
+        assert!(expected("sha-256").is_none());
+        assert!(expected("crc32=:AAAAAA==:").is_none());
+        assert!(expected("").is_none());
+        let digest = expected(&format!("sha-256=:%%:, sha-512=:{HELLO_SHA256}:")).unwrap();
+        assert_eq!(digest.algorithm, Algorithm::Sha512);
+        assert!(digest.verify(b"hello").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! JSONP wrapping of cached JSON responses for legacy clients.
+//!
+//! The `callback` query parameter is removed from the request before it reaches the readthrough
+//! cache, so every caller shares a single cached JSON object. The callback is applied to the
+//! response body only at delivery time.
+//!
+//! Gzip and Brotli bodies, such as those compressed at the edge, are decompressed before they are
+//! wrapped and compressed again with the same coding. Bodies with other codings are delivered
+//! unwrapped.
+
+use crate::compression::{self, Coding};
+use crate::{query, transform};
+use fastly::http::header;
+use fastly::{mime, Body, Request, Response};
+use std::io;
+
+/// The query parameter carrying the JSONP callback name.
+const CALLBACK_PARAM: &str = "callback";
+
+/// The longest callback name that will be accepted.
+const MAX_CALLBACK_LEN: usize = 128;
+
+/// Removes the `callback` query parameter from the request, returning its value if present.
+pub fn take_callback(req: &mut Request) -> Option<String> {
+    query::take_param(req, CALLBACK_PARAM)
+}
+
+/// Returns whether `name` is safe to echo back as a JavaScript function reference.
+///
+/// Only dotted identifiers such as `handle` or `app.handlers.onData` are accepted, which rules out
+/// any attempt to inject script through the callback parameter.
+pub fn is_valid_callback(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_CALLBACK_LEN
+        && name.split('.').all(|part| {
+            let mut chars = part.chars();
+            matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        })
+}
+
+/// Wraps a JSON response body in a call to `callback`.
+///
+/// Responses that are not successful JSON responses, or whose body is encoded with a coding other
+/// than gzip or Brotli, are returned unchanged.
+pub fn wrap(mut resp: Response, callback: &str) -> Response {
+    let content_encoding = resp.get_header_str(header::CONTENT_ENCODING);
+    if !resp.get_status().is_success()
+        || !is_wrappable(resp.get_header_str(header::CONTENT_TYPE), content_encoding)
+    {
+        return resp;
+    }
+
+    match Coding::of(content_encoding) {
+        Some(coding) => {
+            let body = resp.take_body_bytes();
+            match wrap_body(callback, coding, body.clone()) {
+                Ok(wrapped) => resp.set_body(wrapped),
+                Err(e) => {
+                    log::warn!("cannot wrap a {} JSON body: {e}", coding.name());
+                    resp.set_body(body);
+                    return resp;
+                }
+            }
+        }
+        None => {
+            let mut body = Body::from(prefix(callback));
+            body.append(resp.take_body());
+            body.append(Body::from(SUFFIX));
+            resp.set_body(body);
+        }
+    }
+
+    resp.remove_header(header::CONTENT_LENGTH);
+    resp.set_content_type(mime::APPLICATION_JAVASCRIPT_UTF_8);
+    resp.set_header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    resp
+}
+
+/// Returns whether a response body can be wrapped: JSON, uncompressed or compressed with a coding
+/// that can be undone.
+fn is_wrappable(content_type: Option<&str>, content_encoding: Option<&str>) -> bool {
+    transform::is_json(content_type) && transform::is_transformable_encoding(content_encoding)
+}
+
+/// The end of a wrapped body.
+const SUFFIX: &str = ");";
+
+/// Returns the start of a body wrapped in a call to `callback`.
+///
+/// The leading comment guards against content-sniffing attacks that target the first bytes of
+/// the response.
+fn prefix(callback: &str) -> String {
+    format!("/**/ typeof {callback} === 'function' && {callback}(")
+}
+
+/// Wraps an encoded body in a call to `callback`, keeping its coding.
+fn wrap_body(callback: &str, coding: Coding, body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut wrapped = prefix(callback).into_bytes();
+    wrapped.extend(compression::decode(Some(coding), body)?);
+    wrapped.extend_from_slice(SUFFIX.as_bytes());
+    compression::encode(Some(coding), wrapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_dotted_identifiers_are_valid_callbacks() {
+        assert!(is_valid_callback("handle"));
+        assert!(is_valid_callback("app.handlers.on_Data$1"));
+        assert!(is_valid_callback(&"a".repeat(MAX_CALLBACK_LEN)));
+
+        assert!(!is_valid_callback(""));
+        assert!(!is_valid_callback(&"a".repeat(MAX_CALLBACK_LEN + 1)));
+        assert!(!is_valid_callback("1handle"));
+        assert!(!is_valid_callback("app..handle"));
+        assert!(!is_valid_callback("alert(1)"));
+        assert!(!is_valid_callback("<script>"));
+        assert!(!is_valid_callback("a;b"));
+    }
+
+    #[test]
+    fn json_bodies_are_wrappable_whatever_their_parameters() {
+        assert!(is_wrappable(Some("application/json"), None));
+        assert!(is_wrappable(Some("application/json; charset=utf-8"), None));
+        assert!(is_wrappable(Some("application/problem+json"), Some("gzip")));
+        assert!(is_wrappable(Some("application/json"), Some("br")));
+        assert!(!is_wrappable(Some("application/json"), Some("zstd")));
+        assert!(!is_wrappable(Some("text/html"), None));
+        assert!(!is_wrappable(None, None));
+    }
+
+    #[test]
+    fn compressed_bodies_are_wrapped_with_their_coding() {
+        for coding in [Coding::Gzip, Coding::Brotli] {
+            let body = compression::encode(Some(coding), br#"{"a":1}"#.to_vec()).unwrap();
+            let wrapped = wrap_body("cb", coding, body).unwrap();
+            assert_eq!(
+                compression::decode(Some(coding), wrapped).unwrap(),
+                br#"/**/ typeof cb === 'function' && cb({"a":1});"#
+            );
+        }
+        assert!(wrap_body("cb", Coding::Gzip, b"not gzip".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! The advanced caching policies of the service.
+//!
+//! Each module implements one policy, such as the choice of TTLs, a body transform or the routing
+//! of requests, as far as possible in functions of plain values that can be unit tested. The
+//! binary wires them into the before-send, after-send and body-transform callbacks of the
+//! readthrough cache.
+
+pub mod accept;
+pub mod adaptive_ttl;
+pub mod admin;
+pub mod after_send;
+pub mod auth;
+pub mod banner;
+pub mod bots;
+pub mod cache_key;
+pub mod cache_rules;
+pub mod cache_status;
+pub mod cacheability;
+pub mod capture;
+pub mod chaos;
+pub mod commerce;
+pub mod compression;
+pub mod config;
+pub mod cookies;
+pub mod core_cache;
+pub mod cors;
+pub mod debug;
+pub mod delivery;
+pub mod devices;
+pub mod early_hints;
+pub mod enrichment;
+pub mod error_pages;
+pub mod errors;
+pub mod esi;
+pub mod etags;
+pub mod event_mode;
+pub mod experiments;
+pub mod failover;
+pub mod fallback;
+pub mod features;
+pub mod formats;
+pub mod fragments;
+pub mod freshness;
+pub mod geo;
+pub mod graphql;
+pub mod host_policies;
+pub mod html_rewrite;
+pub mod idempotency;
+pub mod integrity;
+pub mod jsonp;
+pub mod load_shedding;
+pub mod locales;
+pub mod logging;
+pub mod media;
+pub mod memo;
+pub mod metrics;
+pub mod no_cache;
+pub mod origin_auth;
+pub mod origin_request;
+pub mod partial_content;
+pub mod personalization;
+pub mod policy;
+pub mod projection;
+pub mod purge_api;
+pub mod query;
+pub mod rate_limiting;
+pub mod redirects;
+pub mod request_filter;
+pub mod routing;
+pub mod scrubbing;
+pub mod secrets;
+pub mod security_headers;
+pub mod server_timing;
+pub mod signed_urls;
+pub mod signing;
+pub mod sigv4;
+pub mod snapshots;
+pub mod stale;
+pub mod surrogate_keys;
+pub mod tenants;
+pub mod trace;
+pub mod tracking_params;
+pub mod trailers;
+pub mod transform;
+pub mod url_normalization;
+pub mod warmup;
@@ -0,0 +1,131 @@
+//! Load shedding when the origin slows down.
+//!
+//! The latency of every origin fetch is added to a rolling window kept by the Compute instance.
+//! When the average of the window exceeds the `load_shed_latency_ms` setting, the whole POP starts
+//! shedding load for `load_shed_cooldown` seconds, through a marker in the Simple Cache:
+//!
+//! * Requests that cannot be served from the cache, such as `POST` requests and always-pass paths,
+//!   are answered with a 503 and a `Retry-After` header instead of adding to the origin's load.
+//! * Responses stored in the cache get a long stale-while-revalidate and stale-if-error window, so
+//!   that expired objects keep being served while the origin catches up.
+//!
+//! Shedding stops by itself when the marker expires, unless a slow fetch starts it again.
+
+use crate::config::Settings;
+use fastly::cache::simple::{self, CacheEntry};
+use fastly::http::{header, CandidateResponse, StatusCode};
+use fastly::Response;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The Simple Cache key of the marker that is present while load is shed.
+const MARKER: &str = "load-shedding";
+
+/// How long load is shed after the origin was found to be slow, unless overridden by the
+/// `load_shed_cooldown` setting (in seconds).
+const DEFAULT_COOLDOWN_SECS: u64 = 10;
+
+/// The stale windows given to responses stored while load is shed, unless overridden by the
+/// `load_shed_stale` setting (in seconds).
+const DEFAULT_STALE_SECS: u64 = 300;
+
+/// The number of origin fetches in the rolling latency window.
+const WINDOW_SIZE: usize = 20;
+
+/// The latencies of the most recent origin fetches made by this instance.
+static WINDOW: Mutex<VecDeque<Duration>> = Mutex::new(VecDeque::new());
+
+/// The load shedding policy.
+#[derive(Clone)]
+pub struct LoadShedder {
+    threshold: Duration,
+    cooldown: Duration,
+    stale: Duration,
+    fetch_started: Arc<Mutex<Option<Instant>>>,
+}
+
+impl LoadShedder {
+    /// Reads the load shedding policy from the settings, or returns `None` if no latency
+    /// threshold is set.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        let threshold_ms = settings.get_u64("load_shed_latency_ms", 0);
+        if threshold_ms == 0 {
+            return None;
+        }
+        Some(Self {
+            threshold: Duration::from_millis(threshold_ms),
+            cooldown: Duration::from_secs(
+                settings.get_u64("load_shed_cooldown", DEFAULT_COOLDOWN_SECS),
+            ),
+            stale: Duration::from_secs(settings.get_u64("load_shed_stale", DEFAULT_STALE_SECS)),
+            fetch_started: Arc::default(),
+        })
+    }
+
+    /// Returns whether load is being shed.
+    pub fn is_shedding(&self) -> bool {
+        matches!(simple::get(MARKER.to_string()), Ok(Some(_)))
+    }
+
+    /// The response to a request that is shed.
+    pub fn reject(&self) -> Response {
+        Response::from_status(StatusCode::SERVICE_UNAVAILABLE).with_header(
+            header::RETRY_AFTER,
+            self.cooldown.as_secs().max(1).to_string(),
+        )
+    }
+
+    /// Notes the start of an origin fetch.
+    pub fn origin_fetch_started(&self) {
+        if let Ok(mut started) = self.fetch_started.lock() {
+            *started = Some(Instant::now());
+        }
+    }
+
+    /// Records the latency of the origin fetch that produced a response, starts shedding if the
+    /// origin is slow, and lengthens the stale windows of the response while load is shed.
+    pub fn origin_fetch_finished(&self, resp: &mut CandidateResponse) {
+        let started = self.fetch_started.lock().ok().and_then(|mut s| s.take());
+        if let Some(started) = started {
+            if self.record_latency(started.elapsed()) > self.threshold {
+                self.start_shedding();
+            }
+        }
+
+        if self.is_shedding() && resp.is_cacheable() {
+            if resp.get_stale_while_revalidate() < self.stale {
+                resp.set_stale_while_revalidate(self.stale);
+            }
+            if resp.get_stale_if_error() < self.stale {
+                resp.set_stale_if_error(self.stale);
+            }
+        }
+    }
+
+    /// Adds a latency to the rolling window, and returns the average latency of the window.
+    fn record_latency(&self, latency: Duration) -> Duration {
+        let Ok(mut window) = WINDOW.lock() else {
+            return latency;
+        };
+        if window.len() == WINDOW_SIZE {
+            window.pop_front();
+        }
+        window.push_back(latency);
+        window.iter().sum::<Duration>() / window.len() as u32
+    }
+
+    fn start_shedding(&self) {
+        log::warn!("origin latency above {:?}; shedding load", self.threshold);
+        let cooldown = self.cooldown;
+        let result = simple::get_or_set_with(MARKER.to_string(), || {
+            Ok(CacheEntry {
+                value: "1".into(),
+                ttl: cooldown,
+            })
+        });
+        if let Err(e) = result {
+            log::warn!("cannot start shedding load: {e}");
+        }
+    }
+}
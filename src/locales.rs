@@ -0,0 +1,188 @@
+//! Locale variants of cached responses, from the client's `Accept-Language` header.
+//!
+//! Browsers send `Accept-Language` values in countless combinations of languages, regions and
+//! weights, so a response that varies on the header would otherwise be stored once for every one
+//! of them. With the `supported_locales` setting, such as `en,fr,de`, the header is replaced
+//! before the cache lookup by the supported locale the client prefers, or the first one if it
+//! prefers none, and responses vary on it: each object is stored at most once per supported
+//! locale.
+//!
+//! The header is normalized before the lookup rather than in the before-send callback, since the
+//! cache matches the header values of the lookup against those of stored objects: the origin is
+//! sent the same normalized value that the variant is stored under.
+//!
+//! As an example of a per-locale body transform, JSON objects from the `/api/` routes can carry
+//! translations as `{"$i18n": {"en": "Hello", "fr": "Bonjour"}}` objects, anywhere in the
+//! document. Each is replaced by the translation for the locale of the variant, or for the first
+//! supported locale if it has none, before the object is cached.
+
+use crate::accept;
+use crate::config::Settings;
+use crate::transform;
+use fastly::http::header;
+use fastly::Request;
+use serde_json::{Map, Value};
+
+/// The member of the objects carrying translations.
+const TRANSLATIONS_MEMBER: &str = "$i18n";
+
+/// The supported locales, and the one chosen for a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Locale {
+    /// The locale of the request.
+    pub chosen: String,
+    fallback: String,
+}
+
+impl Locale {
+    /// Replaces the `Accept-Language` header of a request with the supported locale it prefers,
+    /// returning it, or `None` if no locales are supported.
+    pub fn normalize(req: &mut Request, settings: &Settings) -> Option<Self> {
+        let supported = settings.get_list("supported_locales");
+        let fallback = supported.first()?.to_ascii_lowercase();
+        let chosen = negotiate(req.get_header_str(header::ACCEPT_LANGUAGE), &supported)
+            .unwrap_or_else(|| fallback.clone());
+        req.set_header(header::ACCEPT_LANGUAGE, &chosen);
+        Some(Self { chosen, fallback })
+    }
+
+    /// Returns a JSON body with its translations resolved for this locale. Other bodies are
+    /// returned as they are.
+    pub fn localize(&self, body: &[u8]) -> Vec<u8> {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(mut document) => {
+                self.resolve(&mut document);
+                serde_json::to_vec(&document).unwrap_or_else(|_| body.to_vec())
+            }
+            Err(_) => body.to_vec(),
+        }
+    }
+
+    /// Returns whether a response body can be localized: uncompressed JSON only.
+    pub fn is_localizable(content_type: Option<&str>, content_encoding: Option<&str>) -> bool {
+        transform::is_json(content_type) && transform::is_transformable_encoding(content_encoding)
+    }
+
+    fn resolve(&self, value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                if let Some(translation) = self.translation(object) {
+                    *value = translation;
+                    return;
+                }
+                object.values_mut().for_each(|member| self.resolve(member));
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.resolve(item)),
+            _ => {}
+        }
+    }
+
+    /// Returns the translation held by a translations object, or `None` if it is not one.
+    fn translation(&self, object: &Map<String, Value>) -> Option<Value> {
+        if object.len() != 1 {
+            return None;
+        }
+        let translations = object.get(TRANSLATIONS_MEMBER)?.as_object()?;
+        let find = |locale: &str| {
+            translations
+                .iter()
+                .find(|(tag, _)| tag.eq_ignore_ascii_case(locale))
+                .map(|(_, text)| text.clone())
+        };
+        Some(
+            find(&self.chosen)
+                .or_else(|| find(&self.fallback))
+                .unwrap_or(Value::Null),
+        )
+    }
+}
+
+/// Returns the supported locale that an `Accept-Language` header prefers, or `None` if it
+/// accepts none of them. A language range matches a supported locale with the same tag, or with
+/// the same primary language, such as `fr-CA` for `fr`, at a lower priority.
+fn negotiate(accept_language: Option<&str>, supported: &[String]) -> Option<String> {
+    let mut best: Option<(&String, f32, bool)> = None;
+    for (tag, quality) in accept::weighted(accept_language.unwrap_or_default()) {
+        if quality <= 0.0 {
+            continue;
+        }
+        let primary = |tag: &str| {
+            tag.split('-')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase()
+        };
+        let candidate = supported
+            .iter()
+            .find(|locale| locale.eq_ignore_ascii_case(tag))
+            .map(|locale| (locale, true))
+            .or_else(|| {
+                supported
+                    .iter()
+                    .find(|locale| primary(locale) == primary(tag))
+                    .map(|locale| (locale, false))
+            });
+        if let Some((locale, exact)) = candidate {
+            let better = best.is_none_or(|(_, best_quality, best_exact)| {
+                quality > best_quality || (quality == best_quality && exact && !best_exact)
+            });
+            if better {
+                best = Some((locale, quality, exact));
+            }
+        }
+    }
+    best.map(|(locale, _, _)| locale.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn supported() -> Vec<String> {
+        ["en", "fr", "de-CH"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn preferred_supported_locale_is_chosen() {
+        let negotiate = |value| negotiate(Some(value), &supported());
+        assert_eq!(negotiate("fr-CA,fr;q=0.9,en;q=0.8").as_deref(), Some("fr"));
+        assert_eq!(negotiate("es,de;q=0.5,en;q=0.4").as_deref(), Some("de-ch"));
+        assert_eq!(negotiate("en-US;q=0.5,fr;q=0.5").as_deref(), Some("fr"));
+        assert_eq!(negotiate("fr;q=0,en;q=0.1").as_deref(), Some("en"));
+        assert_eq!(negotiate("es, ja").as_deref(), None);
+        assert_eq!(super::negotiate(None, &supported()), None);
+    }
+
+    #[test]
+    fn translations_are_resolved_for_the_locale() {
+        let locale = Locale {
+            chosen: "fr".to_string(),
+            fallback: "en".to_string(),
+        };
+        let body = json!({
+            "title": { "$i18n": { "en": "Hello", "FR": "Bonjour" } },
+            "items": [{ "label": { "$i18n": { "en": "Cart" } } }],
+            "plain": { "en": "kept", "fr": "as is" },
+        });
+        let localized: Value =
+            serde_json::from_slice(&locale.localize(&serde_json::to_vec(&body).unwrap())).unwrap();
+        assert_eq!(
+            localized,
+            json!({
+                "title": "Bonjour",
+                "items": [{ "label": "Cart" }],
+                "plain": { "en": "kept", "fr": "as is" },
+            })
+        );
+    }
+
+    #[test]
+    fn other_bodies_are_kept() {
+        let locale = Locale {
+            chosen: "fr".to_string(),
+            fallback: "en".to_string(),
+        };
+        assert_eq!(locale.localize(b"not json"), b"not json");
+    }
+}
@@ -0,0 +1,141 @@
+//! Structured logging to a named log endpoint.
+//!
+//! Every log line is a JSON object, sent to the log endpoint named by the `log_endpoint` setting
+//! and echoed to standard output for log tailing. Each request is logged once, in an access log
+//! line with its method, path, status, cache state, origin latency and request ID. Diagnostic
+//! messages of the other modules go through the `log` macros, and are logged along with their
+//! level and module.
+
+use crate::cache_status::Disposition;
+use crate::config::Settings;
+use fastly::{Request, Response};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// The log endpoint, unless overridden by the `log_endpoint` setting.
+const DEFAULT_ENDPOINT: &str = "logs";
+
+/// The target of access log lines, which are already JSON.
+const ACCESS_TARGET: &str = "access";
+
+/// Wraps the log endpoint so that every line is a JSON object.
+struct JsonLogger {
+    endpoint: log_fastly::Logger,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.endpoint.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let line = if record.target() == ACCESS_TARGET {
+            record.args().to_string()
+        } else {
+            json!({
+                "type": "diagnostic",
+                "level": record.level().as_str().to_ascii_lowercase(),
+                "module": record.module_path(),
+                "message": record.args().to_string(),
+            })
+            .to_string()
+        };
+        self.endpoint.log(
+            &Record::builder()
+                .args(format_args!("{line}"))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Sends log lines to the log endpoint. Lines logged before this is called are dropped.
+pub fn init(settings: &Settings) {
+    let endpoint = settings
+        .get("log_endpoint")
+        .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string());
+    let result = log_fastly::Logger::builder()
+        .max_level(LevelFilter::Info)
+        .default_endpoint(endpoint.as_str())
+        .echo_stdout(true)
+        .build()
+        .map_err(|e| e.to_string())
+        .and_then(|endpoint| {
+            log::set_boxed_logger(Box::new(JsonLogger { endpoint })).map_err(|e| e.to_string())
+        });
+    match result {
+        Ok(()) => log::set_max_level(LevelFilter::Info),
+        Err(e) => println!("cannot log to endpoint {endpoint}: {e}"),
+    }
+}
+
+/// Returns the ID of a client request, as shown in logs and on error pages.
+pub fn request_id(req: &Request) -> String {
+    req.get_client_request_id()
+        .map(str::to_string)
+        .or_else(|| std::env::var("FASTLY_TRACE_ID").ok())
+        .unwrap_or_default()
+}
+
+/// The access log line of one request.
+pub struct AccessLog {
+    started: Instant,
+    method: String,
+    path: String,
+    request_id: String,
+    cache_state: Cell<Option<Disposition>>,
+    origin_latency: Cell<Option<Duration>>,
+}
+
+impl AccessLog {
+    /// Starts the access log line of a client request.
+    pub fn for_request(req: &Request) -> Self {
+        Self {
+            started: Instant::now(),
+            method: req.get_method_str().to_string(),
+            path: req.get_path().to_string(),
+            request_id: request_id(req),
+            cache_state: Cell::default(),
+            origin_latency: Cell::default(),
+        }
+    }
+
+    /// Notes what the cache did with the request.
+    pub fn note_cache_state(&self, disposition: Disposition) {
+        self.cache_state.set(Some(disposition));
+    }
+
+    /// Notes how long the origin took to answer.
+    pub fn note_origin_latency(&self, latency: Duration) {
+        self.origin_latency.set(Some(latency));
+    }
+
+    /// Logs the request along with the response delivered to the client.
+    pub fn emit(&self, resp: &Response) {
+        let cache_state = self.cache_state.get();
+        let origin_latency = match cache_state {
+            Some(Disposition::Hit) => None,
+            _ => self.origin_latency.get(),
+        };
+        log::log!(
+            target: ACCESS_TARGET,
+            Level::Info,
+            "{}",
+            json!({
+                "type": "access",
+                "method": self.method,
+                "path": self.path,
+                "status": resp.get_status().as_u16(),
+                "cache_state": cache_state.map(Disposition::as_str),
+                "origin_latency_ms": origin_latency.map(|latency| latency.as_millis() as u64),
+                "duration_ms": self.started.elapsed().as_millis() as u64,
+                "request_id": self.request_id,
+            })
+        );
+    }
+}
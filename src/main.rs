@@ -1,9 +1,66 @@
 //! Default Compute template program.
 
-use fastly::http::header;
-use fastly::{mime, Body, Error, Request, Response};
-use serde_json::Value;
-use std::time::Duration;
+// Every policy lives in a module of the library crate, and is wired into the cache here.
+use fastly_compute_project::*;
+
+use adaptive_ttl::AdaptiveTtl;
+use cache_rules::CacheRules;
+use cache_status::CacheStatus;
+use chaos::Chaos;
+use commerce::StockPolicy;
+use compression::Coding;
+use config::Settings;
+use cookies::CookieStash;
+use cors::Cors;
+use delivery::DeliveryHook;
+use early_hints::EarlyHints;
+use enrichment::Enrichment;
+use errors::SyntheticErrors;
+use esi::EsiProcessor;
+use etags::EtagWriter;
+use event_mode::EventMode;
+use experiments::Assignment;
+use failover::Failover;
+use fallback::FallbackCopy;
+use features::{Feature, Features};
+use formats::Format;
+use fragments::Fragment;
+use graphql::Operation;
+use host_policies::HostPolicy;
+use html_rewrite::{HtmlRewrites, Rewrites};
+use integrity::{DigestReader, ExpectedDigest};
+use load_shedding::LoadShedder;
+use locales::Locale;
+use logging::AccessLog;
+use media::{MediaKind, MediaPolicy};
+use memo::Memo;
+use metrics::{HitRatio, TransformMetrics};
+use origin_request::OriginRequest;
+use personalization::Personalization;
+use policy::{StatusTtls, Storage};
+use projection::Projection;
+use rate_limiting::RateLimiter;
+use redirects::RedirectPolicy;
+use request_filter::RequestFilter;
+use routing::Route;
+use scrubbing::HeaderScrubber;
+use security_headers::SecurityHeaders;
+use server_timing::ServerTiming;
+use snapshots::Snapshotter;
+use stale::StaleFallback;
+use trace::Tracer;
+use tracking_params::TrackingParams;
+use trailers::TrailerPolicy;
+use transform::{DecodingReader, HtmlNegotiation, JsonToHtml, Person};
+use url_normalization::UrlNormalization;
+
+use fastly::http::{header, Method, StatusCode};
+use fastly::{mime, Error, Request, Response};
+use serde_json::json;
+use std::io::{self, Write};
+use std::iter;
+use std::sync::Arc;
+use std::time::Instant;
 
 /// The entry point for your application.
 ///
@@ -11,15 +68,585 @@ use std::time::Duration;
 /// route based on the request properties (such as method or path), send the request to a backend,
 /// make completely new requests, and/or generate synthetic responses.
 ///
-/// If `main` returns an error, a 500 error response will be delivered to the client.
-#[fastly::main]
-fn main(mut req: Request) -> Result<Response, Error> {
+/// If handling the request fails, a 500 error response will be delivered to the client.
+fn main() -> Result<(), Error> {
+    fastly::init();
+    let mut req = Request::from_client();
+
+    // HEAD requests are answered with the headers of the GET response alone.
+    let is_head = req.get_method() == Method::HEAD;
+
+    let settings = Settings::open();
+    logging::init(&settings);
+
     // Log service version
-    println!(
+    log::info!(
         "FASTLY_SERVICE_VERSION: {}",
         std::env::var("FASTLY_SERVICE_VERSION").unwrap_or_else(|_| String::new())
     );
 
+    // Every request is logged once, with what the cache did with it.
+    let access_log = AccessLog::for_request(&req);
+
+    // With host policies, each host is served from its own backend, under its own settings. The
+    // admin API is not tied to any host.
+    let (settings, backend) = if admin::is_admin_request(&req) {
+        (settings, "origin".to_string())
+    } else {
+        match HostPolicy::resolve(&mut req, settings) {
+            Ok(policy) => (policy.settings, policy.backend),
+            Err(resp) => {
+                access_log.emit(&resp);
+                (*resp).send_to_client();
+                return Ok(());
+            }
+        }
+    };
+
+    // Requests breaking the deny rules are blocked before anything else handles them, and before
+    // any fragment is fetched for them.
+    if let Some(resp) =
+        RequestFilter::from_settings(&settings).and_then(|filter| filter.check(&req))
+    {
+        access_log.emit(&resp);
+        resp.send_to_client();
+        return Ok(());
+    }
+
+    // Shell pages are cached once for everyone, and the client's personalized fragment is fetched
+    // alongside them, to be merged into the shell while it is streamed to the client.
+    let fragment = Fragment::fetch(&mut req, &backend, &settings);
+
+    // Other personalized pages are cached anonymously too, and the client's session is injected
+    // into them as they are streamed to the client.
+    let personalization = Personalization::take_from_request(&mut req, &settings);
+
+    // Failures, whether of the origin or of the service itself, are delivered as error pages
+    // carrying the request ID.
+    let errors = SyntheticErrors::for_request(&req, &settings);
+
+    // Responses to allowed origins get their CORS headers after the cache, whatever they are.
+    let cors = Cors::from_settings(&settings);
+    let client_origin = req.get_header_str(header::ORIGIN).map(str::to_string);
+
+    // Security headers are added after the cache too, with the overrides of the request's route.
+    let security_headers = SecurityHeaders::for_request(&req, &settings)
+        .filter(|_| Features::from_settings(&settings).is_enabled(Feature::SecurityHeaders));
+
+    // Captures of the exchange, for debugging, are stored once the client has its response.
+    let pending_capture = capture::Pending::default();
+
+    let mut resp = handle(
+        req,
+        settings,
+        &backend,
+        &errors,
+        &access_log,
+        &pending_capture,
+    )
+    .unwrap_or_else(|e| errors.internal(&e));
+    if let Some(cors) = &cors {
+        cors.apply(client_origin.as_deref(), &mut resp);
+    }
+    if let Some(security_headers) = &security_headers {
+        security_headers.apply(&mut resp);
+    }
+    access_log.emit(&resp);
+    if is_head {
+        drop(resp.take_body());
+        resp.send_to_client();
+    } else {
+        match (fragment, personalization) {
+            (Some(fragment), _) => fragment.merge_and_send(resp)?,
+            (None, Some(personalization)) => personalization.inject_and_send(resp)?,
+            (None, None) => resp.send_to_client(),
+        }
+    }
+    pending_capture.store();
+    Ok(())
+}
+
+/// Handles a client request, returning the response to deliver.
+fn handle(
+    mut req: Request,
+    settings: Settings,
+    backend: &str,
+    errors: &SyntheticErrors,
+    access_log: &AccessLog,
+    pending_capture: &capture::Pending,
+) -> Result<Response, Error> {
+    // A new instance starts connecting to the backends while it handles its first request.
+    let _warmup_probes = warmup::probe_backends(&settings);
+
+    // The /_edge/ admin API is served at the edge, and never reaches the origin directly.
+    if admin::is_admin_request(&req) {
+        return Ok(admin::handle(req, &settings));
+    }
+
+    // Clients holding the purge token can purge URLs and surrogate keys through the Fastly API.
+    if purge_api::is_purge_request(&req, &settings) {
+        return Ok(purge_api::handle(req, &settings));
+    }
+
+    // CORS preflights are answered at the edge, from the allowed origins, methods and headers.
+    let cors = Cors::from_settings(&settings);
+    if let Some(cors) = cors.as_ref().filter(|_| Cors::is_preflight(&req)) {
+        return Ok(cors.preflight(&req));
+    }
+
+    // URLs are canonicalized before they are routed, looked up and fetched, or clients are
+    // redirected to the canonical URL.
+    if let Some(url_normalization) = UrlNormalization::from_settings(&settings) {
+        if let Some(redirect) = url_normalization.apply(&mut req) {
+            return Ok(redirect);
+        }
+    }
+
+    // Requests under protected paths must carry a valid bearer JWT, which is checked before the
+    // cache is reached. The identity it carries is sent to the origin in claim headers.
+    let identity = match auth::authenticate(&mut req, &settings) {
+        Ok(identity) => identity,
+        Err(resp) => return Ok(*resp),
+    };
+
+    // GraphQL queries are converted to GET requests holding the normalized query and variables in
+    // their URL, before they are routed, so that they are cached like any other GET. Mutations
+    // always pass.
+    let graphql_operation = Operation::take_from_request(&mut req, &settings);
+    let is_graphql_pass = graphql_operation
+        .as_ref()
+        .is_some_and(|operation| !operation.cacheable);
+
+    // Only GET and HEAD requests go through the caching pipeline. Other methods, such as POST,
+    // PUT, PATCH and DELETE, are forwarded to the origin with their bodies, never reaching the
+    // readthrough cache or its callbacks. GraphQL queries sent as a POST were converted to a GET
+    // above, so that they can still be cached.
+    let is_uncacheable_method = !matches!(*req.get_method(), Method::GET | Method::HEAD);
+
+    // Requests are dispatched to a route by method and path prefix. Each route sends them to its
+    // own backend, and can bypass the cache or choose the TTL of its responses.
+    let route = Route::for_request(&req, &settings, backend);
+    let backend = route.backend.as_str();
+    let route_ttl = route.ttl;
+    let route_stale_while_revalidate = route.stale_while_revalidate;
+    let route_stale_if_error = route.stale_if_error;
+    let route_hit_for_pass_ttl = route.hit_for_pass_ttl;
+    let status_ttls = StatusTtls::from_settings(&settings);
+
+    // Clients over the rate limit of their route are turned away before the cache or the origin
+    // is touched.
+    if let Some(limiter) = RateLimiter::for_request(&req, &settings, route.rate_limit.as_ref()) {
+        if let Some(resp) = limiter.check() {
+            return Ok(resp);
+        }
+    }
+
+    // The outcome of every request can be counted, to report the edge hit ratio.
+    let hit_ratio = HitRatio::from_settings(&settings);
+    let after_send_hit_ratio = hit_ratio.clone();
+
+    // The body transforms can record how much they shrink or grow bodies, and how long they take.
+    let transform_metrics = TransformMetrics::from_settings(&settings);
+
+    // The snippets rendered from JSON bodies are memoized, so that the same data is only rendered
+    // once in a while.
+    let json_to_html_memo = Memo::from_settings(transform::JSON_TO_HTML, &settings);
+    let after_send_json_to_html_memo = json_to_html_memo.clone();
+
+    // Personalized commerce flows, such as the cart and the checkout, bypass the cache and every
+    // transformation, as do pass routes, GraphQL mutations and methods other than GET and HEAD.
+    let is_uncacheable = is_uncacheable_method
+        || route.pass
+        || is_graphql_pass
+        || commerce::is_always_pass(&req, &settings);
+
+    // While the origin is slow, requests that cannot be served from the cache are turned away
+    // instead of adding to its load.
+    let load_shedder = LoadShedder::from_settings(&settings);
+    if let Some(shedder) = &load_shedder {
+        if is_uncacheable && shedder.is_shedding() {
+            return Ok(shedder.reject());
+        }
+    }
+
+    // Retried POST requests that carry an idempotency key are answered with the response to the
+    // first attempt, so that the origin only ever processes them once.
+    if idempotency::applies(&req, &settings) {
+        if let Some(hit_ratio) = &hit_ratio {
+            hit_ratio.record(true);
+        }
+        let origin_request = OriginRequest::new(&settings, identity, [backend]);
+        return idempotency::send(req, backend, &origin_request, &settings);
+    }
+
+    // Uncacheable requests are sent straight to the origin, prepared as they would be by the
+    // before-send callback below. This is checked before any caching rule, so that none can
+    // accidentally apply to them.
+    if is_uncacheable {
+        if let Some(hit_ratio) = &hit_ratio {
+            hit_ratio.record(true);
+        }
+        OriginRequest::new(&settings, identity, [backend]).prepare(&mut req, backend);
+        req.set_pass(true);
+        return Ok(req.send(backend)?);
+    }
+
+    // HEAD requests are looked up, and fetched on a miss, as the GET they stand for, so that they
+    // share its cached object instead of creating one of their own, and probes of cached pages
+    // never reach the origin. The body is dropped before the response is delivered.
+    if req.get_method() == Method::HEAD {
+        req.set_method(Method::GET);
+    }
+
+    // Outside those flows, request cookies can be removed before the cache lookup, except for an
+    // allowlist, so that responses personalized by them are never cached for everyone.
+    // Requests under an experiment are bucketed from their visitor ID cookie, before cookies are
+    // stripped, and cached in one variant per bucket.
+    let experiment = Assignment::for_request(&mut req, &settings);
+    let is_experiment = experiment.is_some();
+
+    cookies::strip_request_cookies(&mut req, &settings);
+
+    // ## Advanced Caching use case: Using the Core Cache API directly
+    //
+    // Requests under some paths can be cached with the lower-level Core Cache API instead, which
+    // makes the transactional lookup, the insertion and the revalidation that the readthrough
+    // cache performs behind the callbacks below explicit. Requests carrying an identity are
+    // personalized, and are never cached this way.
+    //
+    // For details on the Core Cache API, see
+    // https://www.fastly.com/documentation/guides/concepts/edge-state/cache/#core-cache
+    if identity.is_none() && core_cache::applies(&req, &settings) {
+        return core_cache::handle(req, backend);
+    }
+
+    // Operators identify themselves with a debug token, which lets them bypass the cache and
+    // override the TTL of the responses they fetch.
+    let is_operator = debug::take_debug_token(&mut req);
+    let ttl_override = debug::take_ttl_override(&mut req, is_operator);
+
+    // Operators can ask for a fresh copy from the origin with `Cache-Control: no-cache`, which is
+    // then stored in place of the cached one. The same directive from anonymous clients is
+    // ignored, so that they cannot bust the shared cache.
+    let is_fresh_copy_request = no_cache::take_fresh_copy_request(&mut req, is_operator);
+
+    // Caching rules can be declared in the `cache_rules` setting, without a redeploy. They are
+    // evaluated against the origin response in the after-send callback, except for pass rules,
+    // which send requests straight to the origin.
+    let graphql_operation_name = graphql_operation
+        .as_ref()
+        .and_then(|operation| operation.name.as_deref());
+    let cache_rules = CacheRules::for_request(&req, graphql_operation_name, &settings);
+    let is_rule_pass = cache_rules.as_ref().is_some_and(CacheRules::pass);
+    if is_rule_pass {
+        req.set_pass(true);
+    }
+
+    // Operators can also ask for a trace of every caching decision taken for their request.
+    let tracer = Tracer::for_request(&mut req, is_operator);
+    let after_send_tracer = tracer.clone();
+
+    // Every response delivered through the cache carries an RFC 9211 `Cache-Status` header.
+    let cache_status = CacheStatus::for_request(&settings, is_rule_pass);
+    let after_send_cache_status = cache_status.clone();
+
+    // Responses can also tell the browser where their latency came from.
+    let server_timing = ServerTiming::from_settings(&settings);
+    let before_send_server_timing = server_timing.clone();
+    let after_send_server_timing = server_timing.clone();
+
+    // HLS and DASH manifests and segments have their own cache lifetimes. When playback tokens
+    // are required, segment requests without a valid token are rejected, and the token is removed
+    // from the cache key of those with one.
+    let media_kind = MediaKind::from_path(req.get_path());
+    let media_policy = MediaPolicy::from_settings(&settings);
+    let media_path = req.get_path().to_string();
+    if media_kind == Some(MediaKind::Segment)
+        && settings.get_bool("media_token_auth", false)
+        && !media::authorize_segment(&mut req)
+    {
+        return Ok(Response::from_status(StatusCode::FORBIDDEN));
+    }
+
+    // Assets under the signed URL prefixes are only served to requests with a valid signature and
+    // expiry, which are then removed from the URL so that every signed URL shares one object.
+    if signed_urls::is_required(&req, &settings) && !signed_urls::authorize(&mut req) {
+        return Ok(Response::from_status(StatusCode::FORBIDDEN));
+    }
+
+    // Requests under /api/ are JSON API routes. Their responses are cached as JSON, rather than
+    // being transformed to HTML like other JSON content below.
+    let is_api_route = req.get_path().starts_with("/api/");
+
+    // Legacy clients may ask for a JSONP response using `?callback=fn`. The parameter is removed
+    // before the request reaches the cache so that every caller shares one cached JSON object,
+    // and the callback is applied only when the response is delivered.
+    let jsonp_callback = if is_api_route {
+        jsonp::take_callback(&mut req)
+    } else {
+        None
+    };
+    if let Some(callback) = &jsonp_callback {
+        if !jsonp::is_valid_callback(callback) {
+            return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+                .with_body_text_plain("Invalid JSONP callback\n"));
+        }
+    }
+
+    // API clients can ask for a lighter object with `?fields=a,b`. The parameter is removed before
+    // the request reaches the cache, and each field set is cached under its own namespace.
+    let projection = if is_api_route && settings.get_bool("json_projection", false) {
+        Projection::take_from_request(&mut req)
+    } else {
+        None
+    };
+
+    // Pages rendered from JSON can also be fetched as the untransformed origin JSON, with
+    // `?format=raw`. The raw variant is cached separately, under its own cache namespace.
+    let is_raw_variant = !is_api_route && transform::take_raw_variant(&mut req);
+
+    // On routes with the `html` option, clients whose Accept header prefers JSON are served the
+    // origin JSON rather than the rendered page.
+    let html_negotiation = route.html.filter(|_| !is_api_route && !is_raw_variant);
+    let wants_json = html_negotiation.is_some()
+        && accept::prefers(
+            req.get_header_str(header::ACCEPT),
+            "application/json",
+            "text/html",
+        );
+
+    // With tenant partitioning, each tenant of the API has its own cache namespace, derived from
+    // its API key, while public endpoints share one. Private endpoints require a valid API key.
+    let cache_namespace = if is_api_route && settings.get_bool("tenant_partitioning", false) {
+        match tenants::cache_namespace(&req, &settings) {
+            Some(namespace) => Some(namespace),
+            None => return Ok(Response::from_status(StatusCode::UNAUTHORIZED)),
+        }
+    } else {
+        None
+    };
+    let cache_namespace = match &projection {
+        Some(projection) => Some(projection.cache_namespace(cache_namespace.as_deref())),
+        None => cache_namespace,
+    };
+
+    // Behaviors can be turned off with feature flags. Objects stored while a feature that shapes
+    // them is off are cached under their own namespace.
+    let features = Features::from_settings(&settings);
+    let json_to_html = features.is_enabled(Feature::JsonToHtml);
+    let cache_namespace = features.cache_namespace(cache_namespace.as_deref());
+
+    // When redirect following is enabled, globally or for the route, internal origin redirects
+    // are followed at the edge.
+    // Every hop shares the cache key of the original request, so that the final response is
+    // cached under it. Cache keys can also be normalized, so that URLs that only differ in the
+    // case of their host, in the order of their query parameters or in ignored parameters share
+    // one cached object.
+    // GraphQL queries are keyed on their URL, which holds the normalized query and variables.
+    // Marketing tracking parameters are left out of the key, and removed from the request before
+    // it is sent to the origin.
+    let redirect_policy = RedirectPolicy::for_request(&req, &settings, route.redirect_hops);
+    let cache_namespace = cache_namespace
+        .as_deref()
+        .or(is_raw_variant.then_some(transform::RAW_VARIANT));
+    let cache_key_normalization = cache_key::Normalization::from_settings(&settings);
+    let tracking_params = TrackingParams::from_settings(&settings);
+    let mut key_url = req.get_url().clone();
+    if let Some(tracking_params) = &tracking_params {
+        tracking_params.strip(&mut key_url);
+    }
+    let explicit_cache_key =
+        cache_key::derive(&key_url, cache_namespace, cache_key_normalization.as_ref());
+    if redirect_policy.is_some()
+        || graphql_operation.is_some()
+        || cache_namespace.is_some()
+        || cache_key_normalization.is_some()
+        || tracking_params.is_some()
+    {
+        req.set_cache_key(explicit_cache_key);
+    }
+    let redirect_base = req.get_url().clone();
+    let after_send_redirect_policy = redirect_policy.clone();
+
+    // JSON API routes can also be delivered as YAML or CSV, as chosen by the client's Accept
+    // header. The origin is always asked for JSON, so the cache holds a single JSON object that
+    // is converted when it is delivered. JSONP responses are always JSON.
+    let api_format = if is_api_route && jsonp_callback.is_none() {
+        Some(Format::negotiate(&req))
+    } else {
+        None
+    };
+    if is_api_route || html_negotiation == Some(HtmlNegotiation::Delivery) {
+        formats::request_canonical_json(&mut req);
+    } else if html_negotiation == Some(HtmlNegotiation::Vary) {
+        // Each representation is cached as its own variant, under a canonical Accept value.
+        let accept = if wants_json {
+            mime::APPLICATION_JSON
+        } else {
+            mime::TEXT_HTML
+        };
+        req.set_header(header::ACCEPT, accept.as_ref());
+    } else if settings.get_bool("normalize_accept", false) {
+        // Elsewhere, the Accept header can be reduced to a few canonical values, so that responses
+        // that vary on it are not stored once for every Accept string in use.
+        accept::normalize(&mut req);
+    }
+
+    // Scrapers are served a variant of product pages without prices. The variant is selected by
+    // a request header that the cache varies on, so both variants are cached separately.
+    let is_product_page = commerce::is_product_page(&req, &settings);
+    if is_product_page {
+        commerce::set_price_variant(&mut req);
+    }
+    let stock_policy = StockPolicy::from_settings(&settings);
+
+    // Pages can be cached in one variant per device class: desktop, mobile or tablet.
+    let is_device_variant = settings.get_bool("device_variants", false);
+    if is_device_variant {
+        devices::set_device_class(&mut req);
+    }
+
+    // Responses can also be cached in one variant per region of the world, shared by every client
+    // in the region.
+    let region = geo::region(&req, &settings);
+    if let Some(region) = &region {
+        geo::set_region(&mut req, region);
+    }
+
+    // Responses can be cached in one variant per supported locale, whatever the client's
+    // Accept-Language header lists.
+    let locale = Locale::normalize(&mut req, &settings);
+
+    // JSON objects can be enriched with edge data from a KV Store before they are cached.
+    let enrichment = Enrichment::for_request(&req, &settings);
+
+    // Responses vary on the Origin header, which is normalized to the allowed origin it comes
+    // from, so that the CORS headers of an origin response are never served to another origin.
+    if let Some(cors) = &cors {
+        cors.normalize_origin(&mut req);
+    }
+    let is_cors = cors.is_some();
+
+    // Origin trailers are carried through body transforms on some routes, and stripped on others.
+    let trailer_policy = TrailerPolicy::for_request(&req, &settings);
+
+    // Internal origin headers can be kept out of the cache, and out of client responses.
+    let header_scrubber = HeaderScrubber::from_settings(&settings);
+    let after_send_header_scrubber = header_scrubber.clone();
+
+    // Transformed bodies can get strong ETags of their own, computed as they are stored. The
+    // client's If-None-Match is then answered at the edge, when the object is delivered.
+    let edge_etags = settings.get_bool("edge_etags", false);
+    let if_none_match = if edge_etags {
+        etags::take_if_none_match(&mut req)
+    } else {
+        None
+    };
+
+    // During breaking events, operators can make every cached object expire sooner.
+    let event_mode = EventMode::from_settings(&settings);
+
+    // Cache lifetimes can be stretched or shrunk by how often each object actually changes.
+    let adaptive_ttl = AdaptiveTtl::for_request(&req, &settings);
+
+    // Responses can be tagged with surrogate keys generated from their path, host and content
+    // type, and the surrogate keys of popular entities are split into shards, by page.
+    let surrogate_key_generator = surrogate_keys::Generator::for_request(&req, &settings);
+    let surrogate_key_sharding = surrogate_keys::Sharding::for_request(&req, &settings);
+
+    // Whether the client request itself rules out storing the response, under RFC 9111.
+    let request_facts = cacheability::RequestFacts::from_request(&req);
+
+    // Responses that only set harmless cookies, such as load-balancer affinity cookies, are cached
+    // without them. The cookies are only delivered to the client whose request fetched the
+    // response from the origin.
+    let cookie_stash = CookieStash::from_settings(&settings);
+    let after_send_cookie_stash = cookie_stash.clone();
+
+    // Headers specific to this client, such as its cookies and CORS headers, are set on the
+    // response as it is delivered, and never on the object stored in the shared cache.
+    let delivery_hook = DeliveryHook::from_settings(&settings, cookie_stash);
+
+    // On the capture path prefixes, the exchange is recorded for debugging, along with the cache
+    // decision taken when the response came from the origin.
+    let recorder = capture::Recorder::for_request(&mut req, &settings);
+    let after_send_recorder = recorder.clone();
+
+    // The origin can be asked for compressed bodies only, which are decompressed at delivery time
+    // for the clients that do not accept them.
+    let compressed_origin_fetch = settings.get_bool("compressed_origin_fetch", false);
+    let client_accepts_gzip = compression::accepts_gzip(&req);
+
+    // Uncompressed text can be compressed at the edge instead, with the best coding the client
+    // accepts. The `Accept-Encoding` of the lookup is normalized to that coding, so that the cache
+    // holds one variant per coding rather than one per client header.
+    let edge_compression = features.is_enabled(Feature::EdgeCompression)
+        && settings.get_bool("edge_compression", false);
+    let edge_coding = if edge_compression {
+        compression::normalize_accept_encoding(&mut req)
+    } else {
+        None
+    };
+
+    // On non-production hosts, chaos mode injects origin latency, origin errors and after-send
+    // failures, so that failure handling can be exercised.
+    let chaos = Chaos::for_request(&req, &settings);
+
+    // Critical pages are snapshotted to a KV Store, to be served if the origin goes down entirely
+    // while disaster mode is on.
+    let snapshotter = Snapshotter::for_request(&req, &settings);
+    let after_send_snapshotter = snapshotter.clone();
+
+    // Objects fetched from the origin can also be copied to the Core Cache, to be served stale
+    // when the origin fails.
+    let stale_fallback = StaleFallback::for_request(&req, &settings, &explicit_cache_key);
+    let after_send_stale_fallback = stale_fallback.clone();
+
+    // As a last resort, a copy uploaded ahead of time to a KV Store is served instead.
+    let fallback_copy = FallbackCopy::for_request(&req, &settings);
+
+    // The preload links of pages are remembered when they are fetched, and sent to later clients
+    // in a `103 Early Hints` response before the page itself.
+    let early_hints = EarlyHints::for_request(&req, &settings);
+    let after_send_early_hints = early_hints.clone();
+
+    // HTML pages can be assembled from the fragments of their Edge Side Includes before they are
+    // cached.
+    let esi_processor = EsiProcessor::for_request(&req, backend, &settings);
+
+    // HTML pages can also be rewritten as they are cached, by the element handlers registered for
+    // their path.
+    // The pages of an experiment bucket are tagged with it.
+    let html_rewrites = HtmlRewrites::from_settings(&settings).for_path(req.get_path());
+    let html_rewrites = match (html_rewrites, &experiment) {
+        (rewrites, Some(experiment)) => {
+            Some(rewrites.unwrap_or_default().merge(experiment.rewrites()))
+        }
+        (rewrites, None) => rewrites,
+    };
+    let html_rewrites = html_rewrites.filter(|_| features.is_enabled(Feature::HtmlRewrites));
+
+    // Requests can fail over to secondary backends when the origin fails.
+    let failover = Failover::for_request(&req, &settings);
+    let after_send_failover = failover.is_some();
+
+    // Requests are prepared for the origin they are forwarded to, the primary backend or a
+    // secondary one.
+    let origin_request = OriginRequest::new(
+        &settings,
+        identity,
+        iter::once(backend).chain(
+            failover
+                .iter()
+                .flat_map(|failover| failover.backends())
+                .map(String::as_str),
+        ),
+    );
+
+    // The latency of origin fetches is measured between the two callbacks below.
+    let before_send_load_shedder = load_shedder.clone();
+    let after_send_load_shedder = load_shedder;
+
     // ## Advanced Caching use case: Modifying a request as it is forwarded to a backend
 
     // Sometimes it is useful to perform modifications to the incoming Request before invoking the
@@ -29,20 +656,57 @@ fn main(mut req: Request) -> Result<Response, Error> {
     //
     // For details on the before-send callback function, see
     // https://www.fastly.com/documentation/guides/concepts/edge-state/cache/#modifying-a-request-as-it-is-forwarded-to-a-backend
+    //
+    // The callback is told which backend the request is sent to, so that failover attempts to
+    // secondary backends can each install it with their own backend.
+
+    let before_send_tracer = tracer.clone();
+    let before_send: failover::BeforeSend = Arc::new(move |req: &mut Request, backend: &str| {
+        log::info!("in before-send callback function");
+        let before_send_started = Instant::now();
+        let snapshot = before_send_tracer
+            .as_ref()
+            .map(|_| trace::Snapshot::of(req));
+
+        if let Some(shedder) = &before_send_load_shedder {
+            shedder.origin_fetch_started();
+        }
+        if let Some(chaos) = &chaos {
+            chaos.delay_origin();
+        }
 
-    req.set_before_send(|req| {
-        println!("in before-send callback function");
+        if compressed_origin_fetch {
+            compression::request_gzip(req);
+        }
 
         // Example: Inject headers before sending
         //
         // In this example, we use the before-send callback function to add an authorization header.
-        // If building the header is an expensive operation, then it makes sense to add this
-        // header only if the request would make it to the backend.
-        let auth_header = "Foo".to_string();
-        req.set_header(header::AUTHORIZATION, auth_header);
+        // Since the credential is read from a Secret Store, it makes sense to add this header only
+        // if the request would make it to the backend. Requests to S3 buckets are signed instead,
+        // last, so that the signature covers the request exactly as it is sent.
+        origin_request.prepare(req, backend);
+
+        if let (Some(tracer), Some(snapshot)) = (&before_send_tracer, &snapshot) {
+            tracer.record_before_send(backend, snapshot, req);
+        }
+
+        if let Some(server_timing) = &before_send_server_timing {
+            server_timing.before_send(before_send_started);
+        }
 
         Ok(())
     });
+    let primary_before_send = before_send.clone();
+    let primary_backend = backend.to_string();
+    req.set_before_send(move |req| primary_before_send(req, &primary_backend));
+
+    // Cached objects are tagged with their URL, so that an operator's request for a fresh copy
+    // can purge them before it is sent.
+    let url_key = no_cache::url_key(&req);
+    if is_fresh_copy_request {
+        no_cache::purge(&url_key);
+    }
 
     // ## Advanced Caching use case: Controlling cache behavior based on backend response
 
@@ -58,80 +722,773 @@ fn main(mut req: Request) -> Result<Response, Error> {
     // For details on the after-send callback function, see
     // https://www.fastly.com/documentation/guides/concepts/edge-state/cache/#controlling-cache-behavior-based-on-backend-response
 
-    req.set_after_send(|resp| {
-        println!("in after-send callback function");
+    req.set_after_send(move |resp| {
+        log::info!("in after-send callback function");
+        let after_send_started = Instant::now();
 
-        // Example: Customize caching based on content type
-        //
-        // This example shows usages that utilize some members of CandidateResponse.
-        //
-        // * CandidateResponse::set_ttl() - override the Time to Live (TTL) of the object in the cache
-        // * CandidateResponse::set_uncacheable(false) - specify that this object is not to be stored in the cache
-        //
-        // For details on CandidateResponse, see
-        // https://www.fastly.com/documentation/guides/concepts/edge-state/cache/#the-candidateresponse-object
-        match resp.get_header_str("Content-Type") {
-            Some("image") => resp.set_ttl(Duration::from_secs(67)),
-            Some("text/html") => resp.set_ttl(Duration::from_secs(321)),
-            Some("application/xml") => resp.set_uncacheable(false),
-            _ => resp.set_ttl(Duration::from_secs(30)),
+        // If the policy below fails, the response is passed through uncached rather than failing
+        // the request.
+        after_send::run_or_pass(resp, |resp| {
+            if let Some(chaos) = &chaos {
+                if chaos.inject_origin_error(resp) {
+                    return Ok(());
+                }
+            }
+
+            // Partial responses are never stored as if they were the complete object.
+            if partial_content::guard(resp) {
+                return Ok(());
+            }
+
+            if let Some(stash) = &after_send_cookie_stash {
+                stash.strip(resp);
+            }
+
+            // Responses that RFC 9111 forbids a shared cache to store are never stored, whatever
+            // the policies below decide. They are still transformed for delivery.
+            if let Some(reason) = cacheability::check(request_facts, resp) {
+                log::info!("not caching response: {reason}");
+                if let Some(tracer) = &after_send_tracer {
+                    tracer.record("uncacheable", json!({ "reason": reason.to_string() }));
+                }
+                resp.set_uncacheable(false);
+            }
+
+            // Internal redirects that will be followed at the edge are never stored in the cache,
+            // leaving the cache key free for the final response.
+            if let Some(policy) = &after_send_redirect_policy {
+                let is_internal_redirect = RedirectPolicy::is_followable_status(resp.get_status())
+                    && resp
+                        .get_header_str(header::LOCATION)
+                        .and_then(|location| policy.internal_target(&redirect_base, location))
+                        .is_some();
+                if is_internal_redirect {
+                    if let Some(tracer) = &after_send_tracer {
+                        tracer.record(
+                            "uncacheable",
+                            json!({ "reason": "internal redirect followed at the edge" }),
+                        );
+                    }
+                    resp.set_uncacheable(false);
+                    return Ok(());
+                }
+            }
+
+            if is_product_page {
+                resp.push_vary(&commerce::PRICE_VARIANT_HEADER);
+            }
+            if is_device_variant {
+                resp.push_vary(&devices::DEVICE_CLASS_HEADER);
+            }
+            if is_experiment {
+                resp.push_vary(&experiments::EXPERIMENT_HEADER);
+            }
+            if region.is_some() {
+                resp.push_vary(&geo::REGION_HEADER);
+            }
+            if locale.is_some() {
+                resp.push_vary(&header::ACCEPT_LANGUAGE);
+            }
+            if is_cors {
+                resp.push_vary(&header::ORIGIN);
+            }
+            if html_negotiation == Some(HtmlNegotiation::Vary) {
+                resp.push_vary(&header::ACCEPT);
+            }
+
+            // Origin bodies are checked against the digest sent with them as they are stored, so
+            // that a truncated or corrupted body never makes it into the cache. Transforms
+            // installed below replace this one, and check the digest themselves.
+            if let Some(digest) = ExpectedDigest::from_response(resp) {
+                digest.install(resp);
+            }
+
+            // Trailers are stripped from responses on routes that do not preserve them. This comes
+            // before the other transforms, which replace this one and handle trailers themselves.
+            trailer_policy.apply(resp);
+
+            // Uncompressed text is compressed with the coding of the lookup's normalized
+            // `Accept-Encoding`, and each coding is cached as its own variant. Media manifests are
+            // rewritten below and left uncompressed, and partial content is never recompressed.
+            let edge_compressible = edge_compression
+                && media_kind.is_none()
+                && resp.get_status() == StatusCode::OK
+                && compression::is_compressible(
+                    resp.get_header_str(header::CONTENT_TYPE),
+                    resp.get_header_str(header::CONTENT_ENCODING),
+                );
+            if edge_compressible {
+                resp.push_vary(&header::ACCEPT_ENCODING);
+            }
+            let edge_coding = edge_coding.filter(|_| edge_compressible);
+            let mut transformed = false;
+
+            // Example: Customize caching based on content type
+            //
+            // This example shows usages that utilize some members of CandidateResponse.
+            //
+            // * CandidateResponse::set_ttl() - override the Time to Live (TTL) of the object in the
+            //   cache
+            // * CandidateResponse::set_uncacheable(false) - specify that this object is not to be
+            //   stored in the cache
+            //
+            // For details on CandidateResponse, see
+            // https://www.fastly.com/documentation/guides/concepts/edge-state/cache/#the-candidateresponse-object
+            //
+            // The TTL of each content type is chosen in the `policy` module. Routes and cache rules
+            // with their own TTL use it for every content type instead, and so do negative
+            // responses and permanent redirects otherwise.
+            let cache_rule = cache_rules.as_ref().and_then(|rules| {
+                rules.for_response(resp.get_status(), resp.get_header_str(header::CONTENT_TYPE))
+            });
+            match policy::storage(
+                route_ttl
+                    .or(cache_rule.and_then(|rule| rule.ttl))
+                    .or(status_ttls.for_status(resp.get_status().as_u16())),
+                resp.get_header_str(header::CONTENT_TYPE),
+                freshness::expires_ttl(resp),
+            ) {
+                Storage::Ttl(ttl) => resp.set_ttl(ttl),
+                Storage::Uncacheable => resp.set_uncacheable(false),
+            }
+
+            // Origins can ask for a response to be served stale while it is revalidated, or if
+            // fetching it again fails. Routes with their own stale windows replace those.
+            if let Some(stale_while_revalidate) =
+                route_stale_while_revalidate.or_else(|| freshness::stale_while_revalidate(resp))
+            {
+                resp.set_stale_while_revalidate(stale_while_revalidate);
+            }
+            if let Some(stale_if_error) =
+                route_stale_if_error.or_else(|| freshness::stale_if_error(resp))
+            {
+                resp.set_stale_if_error(stale_if_error);
+            }
+
+            if let Some(rule) = cache_rule {
+                rule.apply(resp);
+            }
+
+            // Product pages of items that are almost sold out are kept for a much shorter time, so
+            // that they are updated quickly.
+            if is_product_page {
+                stock_policy.apply(resp);
+            }
+
+            // Streaming media manifests are cached briefly and have their segment URLs rewritten to
+            // edge paths, while segments are cached for a long time.
+            if let Some(kind) = media_kind {
+                media_policy.apply(kind, &media_path, trailer_policy, resp);
+            }
+
+            // Example: Creating a hit-for-pass object
+            //
+            // By specifying true when calling CandidateResponse::set_uncacheable(), you mark the
+            // request as "hit-for-pass", which is a marker in the cache to disable request
+            // collapsing for this object until a cacheable response is returned. The reason is
+            // kept, so that the marker can be logged with its lifetime once that is settled below.
+            let hit_for_pass_reason =
+                policy::hit_for_pass_reason(resp.get_header_names().map(|name| name.as_str()));
+            if hit_for_pass_reason.is_some() {
+                resp.set_uncacheable(true);
+            }
+
+            // Internal origin headers are removed before the object is stored.
+            if let Some(scrubber) = &after_send_header_scrubber {
+                let scrubbed = scrubber.scrub_stored(resp);
+                if !scrubbed.is_empty() {
+                    if let Some(tracer) = &after_send_tracer {
+                        tracer.record("scrub", json!({ "headers": scrubbed }));
+                    }
+                }
+            }
+
+            // Example: Manipulating the response body that is stored to the cache
+            //
+            // In an after-send callback, optionally use the CandidateResponse::set_body_transform()
+            // method to set a body-transform callback. When the cache interface receives the
+            // response body from the backend, it invokes the body-transform callback, passing in
+            // the Body that contains the response received from the backend and a StreamingBody for
+            // your callback to use to write out the transformed body. This transformed body is
+            // stored into the cache and returned to the client from the send operation.
+            //
+            // The transformation is declared in this way rather than directly working with the body
+            // during the after-send callback function, because not every response contains a fresh
+            // body. Specifically, 304 Not Modified responses, which are used to revalidate a stale
+            // cached response, are valuable precisely because they do not retransmit the body; in
+            // this case, the backend and (if specified) your after-send callback function update
+            // the headers and cache policy of the existing response object "in-place", without
+            // applying the body-transform or changing the cached response body.
+            //
+            // This design enables the readthrough cache to internally manage the complexities of
+            // revalidation, allowing the developer to provide a single code path without needing to
+            // think about revalidation at all.
+            //
+            // In this example, a transformation is made from JSON content to an HTML snippet and
+            // saved to the cache. Gzip and Brotli JSON is decompressed to be parsed, and the HTML is
+            // compressed again with the same coding. JSON with other codings is cached as it is,
+            // since the transform cannot parse it, and so is the raw variant of the page.
+            //
+            // Revalidations are handled explicitly: a 304 Not Modified carries no body, so the
+            // transform does not run, and the cached body stays as it is. Rendered objects carry a
+            // marker header, so that their content type stays HTML even if the 304 from the origin
+            // says the content is JSON.
+            //
+            // For details on the body-transform callback function, see
+            // https://www.fastly.com/documentation/guides/concepts/edge-state/cache/#modifying-the-body-that-is-saved-to-the-cache
+
+            let plan = if !json_to_html
+                || is_api_route
+                || is_raw_variant
+                || wants_json
+                || html_negotiation == Some(HtmlNegotiation::Delivery)
+            {
+                JsonToHtml::Skip
+            } else {
+                transform::plan_json_to_html(
+                    resp.get_header_str(header::CONTENT_TYPE),
+                    resp.get_header_str(header::CONTENT_ENCODING),
+                    resp.get_header_str(transform::TRANSFORMED_HEADER),
+                )
+            };
+            match plan {
+                JsonToHtml::Render => {
+                    // The origin body is decoded from its declared charset, and the HTML is always
+                    // stored as UTF-8.
+                    let origin_content_type = resp
+                        .get_header_str(header::CONTENT_TYPE)
+                        .map(str::to_string);
+                    let charset = transform::charset(origin_content_type.as_deref());
+                    let coding = Coding::of(resp.get_header_str(header::CONTENT_ENCODING));
+                    let output_coding = coding.or(edge_coding);
+                    resp.set_content_type(mime::TEXT_HTML_UTF_8);
+                    resp.set_header(transform::TRANSFORMED_HEADER, transform::JSON_TO_HTML);
+                    let digest = ExpectedDigest::take_from_response(resp);
+                    let render_memo = after_send_json_to_html_memo.clone();
+                    let render_esi = esi_processor.clone();
+                    let render_rewrites = html_rewrites.clone();
+                    resp.set_body_transform(move |mut body_in, body_out| {
+                        log::info!("in body-transform callback function");
+
+                        // The body is checked against its digest, decompressed, decoded and parsed
+                        // as it is read, chunk by chunk, rather than being read into memory first.
+                        let started = Instant::now();
+                        let mut reader = DigestReader::new(&mut body_in, digest);
+                        let person = match coding {
+                            Some(coding) => Person::read(DecodingReader::new(
+                                coding.decoder(&mut reader),
+                                charset,
+                            ))?,
+                            None => Person::read(DecodingReader::new(&mut reader, charset))?,
+                        };
+
+                        // A snippet already rendered from the same data is reused from the
+                        // Simple Cache.
+                        let html = match &render_memo {
+                            Some(memo) => memo.get_or_compute(&person.memo_input(), || {
+                                person.render().into_bytes()
+                            }),
+                            None => person.render().into_bytes(),
+                        };
+                        let bytes_read = reader.bytes_read();
+                        reader.finish()?;
+                        trailer_policy.forward_trailers(&mut body_in, body_out);
+
+                        if let Some(metrics) = transform_metrics {
+                            metrics.record(
+                                "json-to-html",
+                                origin_content_type.as_deref(),
+                                bytes_read,
+                                html.len(),
+                                started.elapsed(),
+                            );
+                        }
+
+                        // The rendered page is assembled and rewritten like an origin page of
+                        // the route would be.
+                        let mut out = EtagWriter::new(&mut *body_out, edge_etags);
+                        html_rewrite::write_page(
+                            render_rewrites.as_ref(),
+                            output_coding,
+                            &mut out,
+                            |mut out| match &render_esi {
+                                Some(processor) => processor.assemble(&html, &mut out),
+                                None => out.write_all(&html),
+                            },
+                        )?;
+                        out.append_trailer();
+
+                        Ok(())
+                    });
+                    if let Some(tracer) = &after_send_tracer {
+                        tracer.record(
+                            "transform",
+                            json!({
+                                "transform": "json-to-html",
+                                "esi": esi_processor.is_some(),
+                                "rewrites": html_rewrites.is_some(),
+                            }),
+                        );
+                    }
+                    transformed = true;
+                }
+                JsonToHtml::AlreadyRendered => resp.set_content_type(mime::TEXT_HTML_UTF_8),
+                JsonToHtml::Skip => {}
+            }
+
+            // ESI pages are assembled, and HTML pages rewritten, as their origin body is stored, so
+            // that the cached page already holds its fragments and rewrites. Pages rendered from
+            // JSON above already had them applied by the render transform, which installing
+            // another transform would replace.
+            let esi_processor = esi_processor.clone().filter(|_| {
+                !transformed
+                    && EsiProcessor::applies(
+                        resp.get_header_str(header::CONTENT_TYPE),
+                        resp.get_header_str(header::CONTENT_ENCODING),
+                    )
+            });
+            let html_rewrites = html_rewrites.clone().filter(|_| {
+                !transformed
+                    && Rewrites::applies(
+                        resp.get_header_str(header::CONTENT_TYPE),
+                        resp.get_header_str(header::CONTENT_ENCODING),
+                    )
+            });
+            if esi_processor.is_some() || html_rewrites.is_some() {
+                if let Some(tracer) = &after_send_tracer {
+                    tracer.record(
+                        "transform",
+                        json!({
+                            "transform": "html",
+                            "esi": esi_processor.is_some(),
+                            "rewrites": html_rewrites.is_some(),
+                        }),
+                    );
+                }
+                let coding = Coding::of(resp.get_header_str(header::CONTENT_ENCODING));
+                let output_coding = coding.or(edge_coding);
+                let digest = ExpectedDigest::take_from_response(resp);
+                resp.set_body_transform(move |mut body_in, body_out| {
+                    match &esi_processor {
+                        // Includes are found in the whole page, which is read first.
+                        Some(processor) => {
+                            let body = trailer_policy.read_body(body_in, body_out);
+                            integrity::verify(digest.as_ref(), &body)?;
+                            let page = compression::decode(coding, body)?;
+                            let mut out = EtagWriter::new(&mut *body_out, edge_etags);
+                            html_rewrite::write_page(
+                                html_rewrites.as_ref(),
+                                output_coding,
+                                &mut out,
+                                |mut out| processor.assemble(&page, &mut out),
+                            )?;
+                            out.append_trailer();
+                        }
+                        // Pages that are only rewritten are streamed through the rewriter.
+                        None => {
+                            let mut reader = DigestReader::new(&mut body_in, digest);
+                            let mut out = EtagWriter::new(&mut *body_out, edge_etags);
+                            html_rewrite::write_page(
+                                html_rewrites.as_ref(),
+                                output_coding,
+                                &mut out,
+                                |out| {
+                                    match coding {
+                                        Some(coding) => {
+                                            io::copy(&mut coding.decoder(&mut reader), out)
+                                        }
+                                        None => io::copy(&mut reader, out),
+                                    }
+                                    .map(drop)
+                                },
+                            )?;
+                            out.append_trailer();
+                            reader.finish()?;
+                            trailer_policy.forward_trailers(&mut body_in, body_out);
+                        }
+                    }
+
+                    Ok(())
+                });
+                transformed = true;
+            }
+
+            // In this example, JSON objects are first enriched with edge data. The locale variant of
+            // JSON API objects then has its translations resolved for the locale, and the region
+            // variant is told which region it is for, by adding the region to the object before it
+            // is cached. Projected variants then keep only the requested fields of the object.
+            let enrichment = enrichment.clone().filter(|_| {
+                Enrichment::applies(
+                    resp.get_header_str(header::CONTENT_TYPE),
+                    resp.get_header_str(header::CONTENT_ENCODING),
+                )
+            });
+            let localized_locale = locale.clone().filter(|_| {
+                is_api_route
+                    && Locale::is_localizable(
+                        resp.get_header_str(header::CONTENT_TYPE),
+                        resp.get_header_str(header::CONTENT_ENCODING),
+                    )
+            });
+            let injected_region = region.clone().filter(|_| {
+                is_api_route
+                    && geo::is_injectable(
+                        resp.get_header_str(header::CONTENT_TYPE),
+                        resp.get_header_str(header::CONTENT_ENCODING),
+                    )
+            });
+            let projection = projection.clone().filter(|_| {
+                Projection::applies(
+                    resp.get_header_str(header::CONTENT_TYPE),
+                    resp.get_header_str(header::CONTENT_ENCODING),
+                )
+            });
+            if enrichment.is_some()
+                || localized_locale.is_some()
+                || injected_region.is_some()
+                || projection.is_some()
+            {
+                if let Some(tracer) = &after_send_tracer {
+                    tracer.record(
+                        "transform",
+                        json!({
+                            "transform": "json",
+                            "enrichment": enrichment.is_some(),
+                            "locale": localized_locale.as_ref().map(|locale| &locale.chosen),
+                            "region": injected_region,
+                            "projection": projection.is_some(),
+                        }),
+                    );
+                }
+                let content_type = resp
+                    .get_header_str(header::CONTENT_TYPE)
+                    .map(str::to_string);
+                let coding = Coding::of(resp.get_header_str(header::CONTENT_ENCODING));
+                let output_coding = coding.or(edge_coding);
+                let digest = ExpectedDigest::take_from_response(resp);
+                resp.set_body_transform(move |body_in, body_out| {
+                    let record = |stage: &str, bytes_in, bytes_out, started: Instant| {
+                        if let Some(metrics) = transform_metrics {
+                            metrics.record(
+                                stage,
+                                content_type.as_deref(),
+                                bytes_in,
+                                bytes_out,
+                                started.elapsed(),
+                            );
+                        }
+                    };
+
+                    let body = trailer_policy.read_body(body_in, body_out);
+                    integrity::verify(digest.as_ref(), &body)?;
+                    let mut object = compression::decode(coding, body)?;
+                    if let Some(enrichment) = &enrichment {
+                        let started = Instant::now();
+                        let enriched = enrichment.apply(&object);
+                        record("kv-enrichment", object.len(), enriched.len(), started);
+                        object = enriched;
+                    }
+                    if let Some(locale) = &localized_locale {
+                        let started = Instant::now();
+                        let localized = locale.localize(&object);
+                        record("localization", object.len(), localized.len(), started);
+                        object = localized;
+                    }
+                    if let Some(region) = &injected_region {
+                        let started = Instant::now();
+                        let injected = geo::inject_region(&object, region);
+                        record("region-injection", object.len(), injected.len(), started);
+                        object = injected;
+                    }
+                    if let Some(projection) = &projection {
+                        let started = Instant::now();
+                        let projected = projection.apply(&object);
+                        record("json-projection", object.len(), projected.len(), started);
+                        object = projected;
+                    }
+
+                    let mut out = EtagWriter::new(&mut *body_out, edge_etags);
+                    out.write_all(&compression::encode(output_coding, object)?)?;
+                    out.append_trailer();
+
+                    Ok(())
+                });
+                transformed = true;
+            }
+
+            // Transformed objects carry the ETag of their body in a trailer.
+            if transformed && edge_etags {
+                etags::mark(resp);
+            }
+
+            // The transforms above compress their output themselves. Bodies that none of them
+            // rewrote are compressed as they are.
+            if let Some(coding) = edge_coding {
+                if let Some(tracer) = &after_send_tracer {
+                    tracer.record(
+                        "transform",
+                        json!({ "transform": "edge-compression", "coding": coding.name() }),
+                    );
+                }
+                if !transformed {
+                    compression::install_edge_compression(
+                        coding,
+                        trailer_policy,
+                        transform_metrics,
+                        resp,
+                    );
+                } else {
+                    compression::mark_encoded(coding, resp);
+                }
+            }
+
+            if let Some(generator) = &surrogate_key_generator {
+                generator.apply(resp);
+            }
+
+            if let Some(sharding) = &surrogate_key_sharding {
+                sharding.apply(resp);
+            }
+
+            no_cache::tag(&url_key, resp);
+
+            if let Some(adaptive_ttl) = &adaptive_ttl {
+                adaptive_ttl.apply(resp);
+            }
+
+            // Live-event mode scales down whatever cache lifetimes were chosen above.
+            if let Some(event_mode) = &event_mode {
+                event_mode.apply(resp);
+            }
+
+            // An operator's TTL override replaces whatever lifetime was chosen above.
+            if let Some(ttl) = ttl_override {
+                resp.set_ttl(ttl);
+            }
+
+            // Hit-for-pass markers last for the TTL of the response, unless the route sets their
+            // lifetime. Each is logged with its reason, so that operators can tell why requests
+            // for an object stopped being collapsed.
+            if let Some(reason) = &hit_for_pass_reason {
+                if let Some(ttl) = route_hit_for_pass_ttl {
+                    resp.set_ttl(ttl);
+                }
+                let ttl = resp.get_ttl().as_secs();
+                log::info!("hit-for-pass for {ttl} seconds: {reason}");
+                if let Some(tracer) = &after_send_tracer {
+                    tracer.record("hit-for-pass", json!({ "reason": reason, "ttl": ttl }));
+                }
+            }
+
+            // While failover is on, failures are never stored, so that the attempt on the next
+            // backend does not find them in the cache.
+            if after_send_failover && failover::is_failure(resp.get_status()) {
+                if let Some(tracer) = &after_send_tracer {
+                    tracer.record(
+                        "uncacheable",
+                        json!({ "reason": "origin failure while failover is on" }),
+                    );
+                }
+                resp.set_uncacheable(false);
+            }
+
+            if let Some(chaos) = &chaos {
+                chaos.fail_transform()?;
+            }
+
+            Ok(())
+        });
+
+        if let Some(shedder) = &after_send_load_shedder {
+            shedder.origin_fetch_finished(resp);
         }
 
-        // Example: Creating a hit-for-pass object
-        //
-        // By specifying true when calling CandidateResponse::set_uncacheable(), you mark the
-        // request as "hit-for-pass", which is a marker in the cache to disable request collapsing
-        // for this object until a cacheable response is returned.
-        if resp.contains_header("my-private-header") {
-            resp.set_uncacheable(true);
+        if let Some(snapshotter) = &after_send_snapshotter {
+            snapshotter.note_fetch(resp);
         }
 
-        // Example: Manipulating the response body that is stored to the cache
-        //
-        // In an after-send callback, optionally use the CandidateResponse::set_body_transform()
-        // method to set a body-transform callback. When the cache interface receives the response
-        // body from the backend, it invokes the body-transform callback, passing in the Body that
-        // contains the response received from the backend and a StreamingBody for your callback
-        // to use to write out the transformed body. This transformed body is stored into the cache
-        // and returned to the client from the send operation.
-        //
-        // The transformation is declared in this way rather than directly working with the body
-        // during the after-send callback function, because not every response contains a fresh
-        // body. Specifically, 304 Not Modified responses, which are used to revalidate a stale
-        // cached response, are valuable precisely because they do not retransmit the body; in
-        // this case, the backend and (if specified) your after-send callback function update
-        // the headers and cache policy of the existing response object "in-place", without
-        // applying the body-transform or changing the cached response body.
-        //
-        // This design enables the readthrough cache to internally manage the complexities of
-        // revalidation, allowing the developer to provide a single code path without needing
-        // to think about revalidation at all.
-        //
-        // In this example, a transformation is made from JSON content to an HTML snippet
-        // and saved to the cache.
-        //
-        // For details on the body-transform callback function, see
-        // https://www.fastly.com/documentation/guides/concepts/edge-state/cache/#modifying-the-body-that-is-saved-to-the-cache
+        if let Some(stale_fallback) = &after_send_stale_fallback {
+            stale_fallback.note_fetch(resp);
+        }
 
-        if Some(mime::APPLICATION_JSON) == resp.get_content_type() {
-            resp.set_content_type(mime::TEXT_HTML);
-            resp.set_body_transform(|body_in, body_out| {
-                println!("in body-transform callback function");
+        if let Some(recorder) = &after_send_recorder {
+            recorder.record_decision(resp);
+        }
 
-                let json: Value = serde_json::from_str(&body_in.into_string()).unwrap();
+        if let Some(hit_ratio) = &after_send_hit_ratio {
+            hit_ratio.note_fetch();
+        }
 
-                let first_name = json["firstName"].as_str().unwrap_or_default();
-                let last_name = json["lastName"].as_str().unwrap_or_default();
-                let html = format!("<div>{} {}</div>", first_name, last_name);
+        after_send_cache_status.note_fetch(resp);
 
-                body_out.append(Body::from(html.as_bytes()));
+        if let Some(tracer) = &after_send_tracer {
+            tracer.record_decision(resp);
+        }
 
-                Ok(())
-            });
+        if let Some(server_timing) = &after_send_server_timing {
+            server_timing.after_send(after_send_started);
+        }
+
+        if let Some(early_hints) = &after_send_early_hints {
+            early_hints.remember(resp);
         }
 
         Ok(())
     });
 
-    Ok(req.send("origin")?)
+    // Origin error pages are replaced with custom error documents in the client's language.
+    let locale = error_pages::locale(&req);
+
+    // Article pages are cached without the breaking-news banner, which is added on delivery.
+    let is_article_page = banner::is_article_page(&req, &settings);
+
+    let template = redirect_policy.as_ref().map(|_| req.clone_without_body());
+    let failover_template = failover.as_ref().map(|_| req.clone_without_body());
+    let refetch_template = settings
+        .get_bool("refetch_partial_content", false)
+        .then(|| req.clone_without_body());
+
+    let disaster_snapshotter = snapshotter
+        .as_ref()
+        .filter(|_| settings.get_bool("disaster_mode", false));
+
+    if let Some(early_hints) = &early_hints {
+        early_hints.send();
+    }
+
+    let origin_fetch_started = Instant::now();
+    let result = req.send(backend);
+    let (result, origin_backend) = match (&failover, &failover_template) {
+        (Some(failover), Some(template)) => failover.retry(template, result, backend, &before_send),
+        _ => (result, backend.to_string()),
+    };
+    let mut resp = match result {
+        Ok(resp) => resp,
+        Err(e) => match stale_fallback.as_ref().and_then(StaleFallback::serve) {
+            Some(stale) => {
+                log::warn!("origin unreachable, serving stale copy: {e}");
+                stale
+            }
+            None => match disaster_snapshotter.and_then(Snapshotter::serve) {
+                Some(snapshot) => {
+                    log::warn!("origin unreachable, serving snapshot: {e}");
+                    snapshot
+                }
+                None => match fallback_copy.as_ref().and_then(FallbackCopy::serve) {
+                    Some(copy) => {
+                        log::warn!("origin unreachable, serving fallback copy: {e}");
+                        copy
+                    }
+                    None if stale_fallback.is_some() => {
+                        log::warn!("origin unreachable, serving outage page: {e}");
+                        errors.outage()
+                    }
+                    None => return Err(e.into()),
+                },
+            },
+        },
+    };
+    access_log.note_origin_latency(origin_fetch_started.elapsed());
+    if let Some(server_timing) = &server_timing {
+        server_timing.send_finished();
+    }
+    if failover.is_some() {
+        resp.set_header(failover::ORIGIN_HEADER, &origin_backend);
+    }
+    if let (Some(policy), Some(template)) = (&redirect_policy, &template) {
+        resp = redirects::follow(policy, template, resp, backend)?;
+    }
+    if resp.get_status() == StatusCode::PARTIAL_CONTENT {
+        if let Some(template) = &refetch_template {
+            resp = partial_content::refetch(template, backend)?;
+        }
+    }
+    if resp.get_status().is_server_error() {
+        if let Some(stale) = stale_fallback.as_ref().and_then(StaleFallback::serve) {
+            log::warn!(
+                "origin failed with {}, serving stale copy",
+                resp.get_status()
+            );
+            resp = stale;
+        }
+    } else if let Some(stale_fallback) = &stale_fallback {
+        stale_fallback.save(&mut resp);
+    }
+    if snapshots::is_origin_failure(resp.get_status()) {
+        if let Some(snapshot) = disaster_snapshotter.and_then(Snapshotter::serve) {
+            log::warn!("origin failed with {}, serving snapshot", resp.get_status());
+            resp = snapshot;
+        } else if let Some(copy) = fallback_copy.as_ref().and_then(FallbackCopy::serve) {
+            log::warn!(
+                "origin failed with {}, serving fallback copy",
+                resp.get_status()
+            );
+            resp = copy;
+        }
+    } else if let Some(snapshotter) = &snapshotter {
+        snapshotter.save(&mut resp);
+    }
+    let disposition = cache_status.apply(&mut resp);
+    access_log.note_cache_state(disposition);
+    if let Some(server_timing) = &server_timing {
+        server_timing.apply(&mut resp, disposition);
+    }
+    if compressed_origin_fetch {
+        resp = compression::deliver(resp, client_accepts_gzip, transform_metrics);
+    }
+    // Custom error documents take precedence over synthetic error pages, and are filled in with
+    // the request ID.
+    let resp = errors.replace(resp);
+    let mut resp = error_pages::apply(resp, &locale, errors.request_id(), &settings);
+
+    if is_article_page {
+        resp = banner::inject(resp, &settings);
+    }
+
+    if let Some(format) = api_format {
+        resp = formats::convert(resp, format, transform_metrics);
+    }
+
+    // Routes negotiated at delivery keep only the JSON object in the cache, and render the page
+    // from it for clients that prefer HTML.
+    if html_negotiation == Some(HtmlNegotiation::Delivery) {
+        resp = if wants_json || !json_to_html {
+            resp.with_header(header::VARY, "Accept")
+        } else {
+            transform::render_on_delivery(resp, json_to_html_memo.as_ref(), transform_metrics)
+        };
+    }
+
+    let mut resp = match &jsonp_callback {
+        Some(callback) => jsonp::wrap(resp, callback),
+        None => resp,
+    };
+
+    if edge_etags {
+        resp = etags::apply(resp, if_none_match.as_deref());
+    }
+
+    if let Some(scrubber) = &header_scrubber {
+        scrubber.scrub_delivered(&mut resp);
+    }
+
+    delivery_hook.apply(&mut resp);
+
+    if let Some(experiment) = &experiment {
+        experiment.apply(&mut resp);
+    }
+
+    if let Some(recorder) = &recorder {
+        recorder.finish(&mut resp, pending_capture);
+    }
+
+    if let Some(tracer) = &tracer {
+        tracer.finish(&mut resp);
+    }
+
+    if let Some(hit_ratio) = &hit_ratio {
+        hit_ratio.record(is_rule_pass);
+    }
+
+    Ok(resp)
 }
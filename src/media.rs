@@ -0,0 +1,384 @@
+//! Caching of HLS and DASH video streams.
+//!
+//! Segments never change once published, so they are cached for a long time. Manifests are cached
+//! briefly, because live manifests are updated every few seconds. A manifest that turns out to be
+//! video on demand (VOD) is remembered in the Simple Cache, so that it is cached for longer the
+//! next time it is fetched from the origin.
+//!
+//! Absolute segment URLs in manifests are rewritten to paths on the edge, so that players fetch
+//! segments through the cache rather than directly from the origin.
+//!
+//! Segment requests can be required to carry a short-lived playback token, in a `token` query
+//! parameter of the form `exp=<unix time>~stream=<stream ID>~hmac=<signature>`. The signature is
+//! the hex-encoded HMAC-SHA256 of everything before `~hmac=`, and the stream ID must match the
+//! first segment of the request path.
+
+use crate::compression::{self, Coding};
+use crate::config::Settings;
+use crate::integrity::{self, ExpectedDigest};
+use crate::metrics::TransformMetrics;
+use crate::trailers::TrailerPolicy;
+use crate::{query, secrets, signing, transform};
+use fastly::cache::simple::{self, CacheEntry};
+use fastly::http::{header, CandidateResponse, Url};
+use fastly::{Body, Request};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long live manifests are cached, unless overridden by the `media_live_manifest_ttl` setting
+/// (in seconds).
+const DEFAULT_LIVE_MANIFEST_TTL_SECS: u64 = 2;
+
+/// How long VOD manifests are cached, unless overridden by the `media_vod_manifest_ttl` setting
+/// (in seconds).
+const DEFAULT_VOD_MANIFEST_TTL_SECS: u64 = 300;
+
+/// How long segments are cached, unless overridden by the `media_segment_ttl` setting (in
+/// seconds).
+const DEFAULT_SEGMENT_TTL_SECS: u64 = 86_400;
+
+/// How long a manifest is remembered as VOD.
+const VOD_MARKER_TTL: Duration = Duration::from_secs(86_400);
+
+/// The query parameter carrying a playback token.
+const TOKEN_PARAM: &str = "token";
+
+/// The name of the secret used to sign playback tokens.
+const TOKEN_KEY_SECRET: &str = "playback_token_key";
+
+/// The kinds of streaming media resources.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKind {
+    HlsManifest,
+    DashManifest,
+    Segment,
+}
+
+impl MediaKind {
+    /// Classifies a request path by its file extension.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+        match extension.as_str() {
+            "m3u8" => Some(MediaKind::HlsManifest),
+            "mpd" => Some(MediaKind::DashManifest),
+            "ts" | "m4s" | "mp4" | "m4a" | "m4v" | "aac" | "cmfv" | "cmfa" | "vtt" => {
+                Some(MediaKind::Segment)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Checks the playback token of a segment request, removing it from the request URL.
+///
+/// Removing the token keeps it out of the cache key, so that every authorized viewer shares the
+/// same cached segments.
+pub fn authorize_segment(req: &mut Request) -> bool {
+    let Some(token) = query::take_param(req, TOKEN_PARAM) else {
+        return false;
+    };
+    let Some(key) = secrets::get(TOKEN_KEY_SECRET) else {
+        return false;
+    };
+    let stream_id = req
+        .get_path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    verify_playback_token(&token, stream_id, &key, now)
+}
+
+fn verify_playback_token(token: &str, stream_id: &str, key: &[u8], now: u64) -> bool {
+    let Some((payload, signature)) = token.rsplit_once("~hmac=") else {
+        return false;
+    };
+
+    let mut expiry = None;
+    let mut stream = None;
+    for field in payload.split('~') {
+        match field.split_once('=') {
+            Some(("exp", value)) => expiry = value.parse::<u64>().ok(),
+            Some(("stream", value)) => stream = Some(value),
+            _ => return false,
+        }
+    }
+
+    expiry.is_some_and(|expiry| expiry > now)
+        && stream == Some(stream_id)
+        && signing::verify_hex(key, payload.as_bytes(), signature)
+}
+
+/// Cache lifetimes of streaming media resources.
+#[derive(Clone, Copy)]
+pub struct MediaPolicy {
+    live_manifest_ttl: Duration,
+    vod_manifest_ttl: Duration,
+    segment_ttl: Duration,
+    transform_metrics: Option<TransformMetrics>,
+}
+
+impl MediaPolicy {
+    /// Reads the media cache lifetimes from the settings.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let secs = |key, default| Duration::from_secs(settings.get_u64(key, default));
+        Self {
+            live_manifest_ttl: secs("media_live_manifest_ttl", DEFAULT_LIVE_MANIFEST_TTL_SECS),
+            vod_manifest_ttl: secs("media_vod_manifest_ttl", DEFAULT_VOD_MANIFEST_TTL_SECS),
+            segment_ttl: secs("media_segment_ttl", DEFAULT_SEGMENT_TTL_SECS),
+            transform_metrics: TransformMetrics::from_settings(settings),
+        }
+    }
+
+    /// Sets the TTL of a media response, and rewrites the segment URLs of manifests.
+    pub fn apply(
+        &self,
+        kind: MediaKind,
+        path: &str,
+        trailer_policy: TrailerPolicy,
+        resp: &mut CandidateResponse,
+    ) {
+        if !resp.get_status().is_success() {
+            return;
+        }
+
+        if kind == MediaKind::Segment {
+            resp.set_ttl(self.segment_ttl);
+            return;
+        }
+
+        let vod_marker = format!("media-vod{path}");
+        if is_known_vod(&vod_marker) {
+            resp.set_ttl(self.vod_manifest_ttl);
+        } else {
+            resp.set_ttl(self.live_manifest_ttl);
+        }
+
+        if !transform::is_transformable_encoding(resp.get_header_str(header::CONTENT_ENCODING)) {
+            return;
+        }
+
+        // Manifests are decoded from their declared charset and stored as UTF-8.
+        let content_type = resp
+            .get_header_str(header::CONTENT_TYPE)
+            .map(str::to_string);
+        let charset = transform::charset(content_type.as_deref());
+        if let Some(content_type) = &content_type {
+            resp.set_header(
+                header::CONTENT_TYPE,
+                transform::with_utf8_charset(content_type),
+            );
+        }
+
+        let coding = Coding::of(resp.get_header_str(header::CONTENT_ENCODING));
+        let digest = ExpectedDigest::take_from_response(resp);
+        let transform_metrics = self.transform_metrics;
+        resp.set_body_transform(move |body_in, body_out| {
+            log::info!("in media manifest body-transform callback function");
+
+            let started = Instant::now();
+            let body = trailer_policy.read_body(body_in, body_out);
+            integrity::verify(digest.as_ref(), &body)?;
+            let body = compression::decode(coding, body)?;
+            let manifest = transform::decode_text(&body, charset);
+            let is_vod = match kind {
+                MediaKind::HlsManifest => is_hls_vod(&manifest),
+                _ => is_dash_vod(&manifest),
+            };
+            if is_vod {
+                remember_vod(vod_marker);
+            }
+
+            let rewritten = rewrite_manifest(kind, &manifest);
+            if let Some(metrics) = transform_metrics {
+                metrics.record(
+                    "manifest-rewrite",
+                    content_type.as_deref(),
+                    body.len(),
+                    rewritten.len(),
+                    started.elapsed(),
+                );
+            }
+            body_out.append(Body::from(compression::encode(
+                coding,
+                rewritten.into_bytes(),
+            )?));
+            Ok(())
+        });
+    }
+}
+
+/// Returns whether an HLS playlist is complete, rather than a live playlist that is still growing.
+fn is_hls_vod(manifest: &str) -> bool {
+    manifest.lines().map(str::trim).any(|line| {
+        line == "#EXT-X-ENDLIST" || line.eq_ignore_ascii_case("#EXT-X-PLAYLIST-TYPE:VOD")
+    })
+}
+
+/// Returns whether a DASH manifest is static, rather than a dynamic live manifest.
+fn is_dash_vod(manifest: &str) -> bool {
+    !manifest.contains(r#"type="dynamic""#)
+}
+
+fn is_known_vod(marker: &str) -> bool {
+    matches!(simple::get(marker.to_string()), Ok(Some(_)))
+}
+
+fn remember_vod(marker: String) {
+    let result = simple::get_or_set_with(marker, || {
+        Ok(CacheEntry {
+            value: "1".into(),
+            ttl: VOD_MARKER_TTL,
+        })
+    });
+    if let Err(e) = result {
+        log::warn!("failed to remember VOD manifest: {e}");
+    }
+}
+
+/// Rewrites the absolute URLs in a manifest to paths on the edge.
+fn rewrite_manifest(kind: MediaKind, manifest: &str) -> String {
+    match kind {
+        MediaKind::HlsManifest => manifest
+            .lines()
+            .map(|line| {
+                let line = if line.starts_with('#') {
+                    // Tags such as #EXT-X-KEY and #EXT-X-MAP reference URLs in quoted attributes.
+                    rewrite_embedded_urls(line, '"', '"')
+                } else {
+                    to_edge_path(line.trim()).unwrap_or_else(|| line.to_string())
+                };
+                line + "\n"
+            })
+            .collect(),
+        // DASH manifests reference URLs in attributes and in <BaseURL> elements.
+        _ => rewrite_embedded_urls(&rewrite_embedded_urls(manifest, '"', '"'), '>', '<'),
+    }
+}
+
+/// Rewrites absolute URLs that appear directly after `open` and end at `close`.
+fn rewrite_embedded_urls(text: &str, open: char, close: char) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(open) {
+        let (before, after) = rest.split_at(start + open.len_utf8());
+        out.push_str(before);
+        let Some(end) = after.find(close) else {
+            rest = after;
+            break;
+        };
+        let value = &after[..end];
+        out.push_str(&to_edge_path(value).unwrap_or_else(|| value.to_string()));
+        out.push(close);
+        rest = &after[end + close.len_utf8()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Converts an absolute HTTP(S) URL to a root-relative path on the edge.
+fn to_edge_path(value: &str) -> Option<String> {
+    if !(value.starts_with("http://") || value.starts_with("https://")) {
+        return None;
+    }
+    let url = Url::parse(value).ok()?;
+    Some(match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resources_are_classified_by_extension() {
+        assert_eq!(
+            MediaKind::from_path("/live/master.M3U8"),
+            Some(MediaKind::HlsManifest)
+        );
+        assert_eq!(
+            MediaKind::from_path("/vod/manifest.mpd"),
+            Some(MediaKind::DashManifest)
+        );
+        assert_eq!(
+            MediaKind::from_path("/vod/seg-1.m4s"),
+            Some(MediaKind::Segment)
+        );
+        assert_eq!(MediaKind::from_path("/vod/index.html"), None);
+        assert_eq!(MediaKind::from_path("/vod/playlist"), None);
+    }
+
+    #[test]
+    fn manifests_point_at_the_edge() {
+        let hls = "#EXTM3U\n\
+                   #EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/k/1?v=2\"\n\
+                   #EXT-X-PLAYLIST-TYPE:VOD\n\
+                   https://cdn.example.com/vod/seg-1.ts\n\
+                   seg-2.ts\n\
+                   #EXT-X-ENDLIST";
+        assert_eq!(
+            rewrite_manifest(MediaKind::HlsManifest, hls),
+            "#EXTM3U\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"/k/1?v=2\"\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n\
+             /vod/seg-1.ts\n\
+             seg-2.ts\n\
+             #EXT-X-ENDLIST\n"
+        );
+        assert!(is_hls_vod(hls));
+        assert!(!is_hls_vod("#EXTM3U\n/live/seg-9.ts"));
+
+        let dash = r#"<MPD type="static"><BaseURL>https://cdn.example.com/vod/</BaseURL><SegmentTemplate media="https://cdn.example.com/vod/$Number$.m4s"/></MPD>"#;
+        assert_eq!(
+            rewrite_manifest(MediaKind::DashManifest, dash),
+            r#"<MPD type="static"><BaseURL>/vod/</BaseURL><SegmentTemplate media="/vod/$Number$.m4s"/></MPD>"#
+        );
+        assert!(is_dash_vod(dash));
+        assert!(!is_dash_vod(r#"<MPD type="dynamic">"#));
+    }
+    #[test]
+    fn playback_tokens_are_bound_to_a_stream_and_expire() {
+        let key = b"playback key";
+        let token = |payload: &str| {
+            format!(
+                "{payload}~hmac={}",
+                signing::sign_hex(key, payload.as_bytes())
+            )
+        };
+        let valid = token("exp=2000~stream=live1");
+
+        assert!(verify_playback_token(&valid, "live1", key, 1999));
+        assert!(!verify_playback_token(&valid, "live1", key, 2000));
+        assert!(!verify_playback_token(&valid, "live2", key, 1999));
+        assert!(!verify_playback_token(&valid, "live1", b"other key", 1999));
+        assert!(!verify_playback_token(
+            &valid.replace("exp=2000", "exp=9000"),
+            "live1",
+            key,
+            1999
+        ));
+        assert!(!verify_playback_token(
+            &token("exp=2000~stream=live1~user=ada"),
+            "live1",
+            key,
+            1999
+        ));
+        assert!(!verify_playback_token(
+            &token("stream=live1"),
+            "live1",
+            key,
+            0
+        ));
+        assert!(!verify_playback_token(
+            "exp=2000~stream=live1",
+            "live1",
+            key,
+            1999
+        ));
+    }
+}
@@ -0,0 +1,91 @@
+//! Memoization of expensive computations in the Simple Cache.
+//!
+//! Some results are expensive to compute but the same for many requests, such as an HTML fragment
+//! rendered from the same data, or the result of introspecting the same token. A [`Memo`] keeps
+//! them in the Simple Cache, under a key hashed from the input they were computed from, for the
+//! number of seconds of the `memoization_ttl` setting. Each instance of the service then computes
+//! a result once, and every instance in a POP shares it.
+//!
+//! Results are only ever a cache: if the Simple Cache fails, they are computed again.
+
+use crate::config::Settings;
+use fastly::cache::simple::{self, CacheEntry};
+use fastly::Error;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::time::Duration;
+
+/// How long results are kept, unless overridden by the `memoization_ttl` setting (in seconds).
+const DEFAULT_TTL_SECS: u64 = 300;
+
+/// The memoized results of one computation.
+#[derive(Clone)]
+pub struct Memo {
+    namespace: &'static str,
+    ttl: Duration,
+}
+
+impl Memo {
+    /// Returns the memo of the computation named `namespace`, or `None` if the `memoization_ttl`
+    /// setting is `0`.
+    pub fn from_settings(namespace: &'static str, settings: &Settings) -> Option<Self> {
+        let ttl = settings.get_u64("memoization_ttl", DEFAULT_TTL_SECS);
+        (ttl > 0).then(|| Self {
+            namespace,
+            ttl: Duration::from_secs(ttl),
+        })
+    }
+
+    /// Returns the result computed from `input`, computing it with `compute` unless it is cached.
+    pub fn get_or_compute(&self, input: &[u8], compute: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        let mut compute = Some(compute);
+        let mut computed = None;
+        let result = simple::get_or_set_with(key(self.namespace, input), || {
+            let value = compute.take().map(|compute| compute()).unwrap_or_default();
+            computed = Some(value.clone());
+            Ok(CacheEntry {
+                value: value.into(),
+                ttl: self.ttl,
+            })
+        });
+        let cached = match result {
+            Ok(Some(mut body)) if computed.is_none() => {
+                let mut cached = Vec::new();
+                body.read_to_end(&mut cached)
+                    .map(|_| cached)
+                    .map_err(Error::from)
+            }
+            Ok(_) => Ok(computed.take().unwrap_or_default()),
+            Err(e) => Err(Error::from(e)),
+        };
+        cached.unwrap_or_else(|e| {
+            log::warn!("cannot memoize {}: {e}", self.namespace);
+            computed
+                .or_else(|| compute.map(|compute| compute()))
+                .unwrap_or_default()
+        })
+    }
+}
+
+/// Returns the Simple Cache key of the result computed from an input.
+fn key(namespace: &str, input: &[u8]) -> String {
+    let digest: String = Sha256::digest(input)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("memo/{namespace}/{digest}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_hashed_per_namespace() {
+        assert_eq!(
+            key("fragment", b"abc"),
+            "memo/fragment/ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_ne!(key("fragment", b"abc"), key("introspection", b"abc"));
+    }
+}
@@ -0,0 +1,263 @@
+//! Rolling cache hit-ratio metrics, kept at the edge.
+//!
+//! With the `hit_ratio_metrics` setting on, the outcome of every request is counted in per-minute
+//! buckets in the `metrics` KV Store: a hit was served from the cache, a miss was fetched from the
+//! origin through the cache, and a pass bypassed the cache. The counts are read back through the
+//! `/_edge/metrics` admin route, and each request logs the running counts of its minute.
+//!
+//! With the `transform_metrics` setting on, the body transforms also record how many bytes they
+//! read and wrote, and how long they took, by stage and content type, in per-minute buckets of the
+//! same store. The route reports them alongside the cache outcomes, so that the value of each
+//! transform can be judged from real traffic.
+//!
+//! Counting is best-effort: concurrent requests may overwrite each other's increments, so the
+//! counts are a sample rather than an exact tally.
+
+use crate::config::Settings;
+use fastly::kv_store::KVStore;
+use serde_json::Map;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the KV Store holding the per-minute counts.
+const STORE_NAME: &str = "metrics";
+
+/// How long each per-minute bucket is kept.
+const RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The outcome of a request, as far as the cache is concerned.
+#[derive(Clone, Copy)]
+enum Outcome {
+    Hit,
+    Miss,
+    Pass,
+}
+
+impl Outcome {
+    fn name(self) -> &'static str {
+        match self {
+            Outcome::Hit => "hit",
+            Outcome::Miss => "miss",
+            Outcome::Pass => "pass",
+        }
+    }
+}
+
+/// Counts the outcome of one request.
+#[derive(Clone)]
+pub struct HitRatio {
+    fetched: Arc<AtomicBool>,
+}
+
+impl HitRatio {
+    /// Returns the counter of a request, or `None` if the `hit_ratio_metrics` setting is off.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        settings.get_bool("hit_ratio_metrics", false).then(|| Self {
+            fetched: Arc::default(),
+        })
+    }
+
+    /// Notes that the response was fetched from the origin through the cache.
+    pub fn note_fetch(&self) {
+        self.fetched.store(true, Ordering::Relaxed);
+    }
+
+    /// Counts the request as a pass if it bypassed the cache, and otherwise as a hit or a miss.
+    pub fn record(&self, passed: bool) {
+        let outcome = if passed {
+            Outcome::Pass
+        } else if self.fetched.load(Ordering::Relaxed) {
+            Outcome::Miss
+        } else {
+            Outcome::Hit
+        };
+        if let Err(e) = increment(outcome) {
+            log::warn!("cannot count cache {}: {e}", outcome.name());
+        }
+    }
+}
+
+/// Records the efficiency of body transforms.
+#[derive(Clone, Copy)]
+pub struct TransformMetrics;
+
+impl TransformMetrics {
+    /// Returns the transform recorder, or `None` if the `transform_metrics` setting is off.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        settings
+            .get_bool("transform_metrics", false)
+            .then_some(Self)
+    }
+
+    /// Records one run of a transform stage on a body of the given content type.
+    pub fn record(
+        self,
+        stage: &str,
+        content_type: Option<&str>,
+        bytes_in: usize,
+        bytes_out: usize,
+        elapsed: Duration,
+    ) {
+        let content_type = content_type
+            .and_then(|value| value.split(';').next())
+            .map(|essence| essence.trim().to_ascii_lowercase())
+            .unwrap_or_else(|| "unknown".to_string());
+        let result = add_transform_run(
+            &format!("{stage} {content_type}"),
+            bytes_in as u64,
+            bytes_out as u64,
+            elapsed.as_micros() as u64,
+        );
+        if let Err(e) = result {
+            log::warn!("cannot record {stage} transform metrics: {e}");
+        }
+    }
+}
+
+/// Returns the counts of the last `minutes` minutes, oldest first, along with their totals, and
+/// the totals of the body transforms over the same minutes.
+pub fn recent(minutes: u64) -> Result<Value, fastly::Error> {
+    let Some(store) = KVStore::open(STORE_NAME)? else {
+        return Ok(json!({ "minutes": [], "totals": summary(0, 0, 0), "transforms": {} }));
+    };
+    let now = current_minute();
+    let mut buckets = Vec::new();
+    let (mut hits, mut misses, mut passes) = (0, 0, 0);
+    for minute in now.saturating_sub(minutes.saturating_sub(1))..=now {
+        let Ok(mut found) = store.lookup(&bucket_key(minute)) else {
+            continue;
+        };
+        let counts: Value = serde_json::from_slice(&found.take_body_bytes()).unwrap_or_default();
+        let (hit, miss, pass) = counts_of(&counts);
+        hits += hit;
+        misses += miss;
+        passes += pass;
+        let mut bucket = summary(hit, miss, pass);
+        bucket["minute_start"] = (minute * 60).into();
+        buckets.push(bucket);
+    }
+
+    let mut transforms: Map<String, Value> = Map::new();
+    for minute in now.saturating_sub(minutes.saturating_sub(1))..=now {
+        let Ok(mut found) = store.lookup(&transform_bucket_key(minute)) else {
+            continue;
+        };
+        let runs: Value = serde_json::from_slice(&found.take_body_bytes()).unwrap_or_default();
+        for (name, run) in runs.as_object().into_iter().flatten() {
+            let total = transforms.entry(name.clone()).or_insert_with(|| json!({}));
+            for field in TRANSFORM_FIELDS {
+                let sum = total[field].as_u64().unwrap_or(0) + run[field].as_u64().unwrap_or(0);
+                total[field] = sum.into();
+            }
+        }
+    }
+    for total in transforms.values_mut() {
+        let bytes_in = total["bytes_in"].as_u64().unwrap_or(0);
+        let runs = total["runs"].as_u64().unwrap_or(0);
+        total["output_ratio"] = (bytes_in > 0)
+            .then(|| total["bytes_out"].as_u64().unwrap_or(0) as f64 / bytes_in as f64)
+            .into();
+        total["average_micros"] = (runs > 0)
+            .then(|| total["micros"].as_u64().unwrap_or(0) / runs)
+            .into();
+    }
+
+    Ok(json!({
+        "minutes": buckets,
+        "totals": summary(hits, misses, passes),
+        "transforms": transforms,
+    }))
+}
+
+/// The fields summed over the runs of a transform stage.
+const TRANSFORM_FIELDS: [&str; 4] = ["runs", "bytes_in", "bytes_out", "micros"];
+
+fn add_transform_run(
+    name: &str,
+    bytes_in: u64,
+    bytes_out: u64,
+    micros: u64,
+) -> Result<(), fastly::Error> {
+    let store = KVStore::open(STORE_NAME)?
+        .ok_or_else(|| fastly::Error::msg(format!("KV Store {STORE_NAME} is not linked")))?;
+    let key = transform_bucket_key(current_minute());
+    let mut runs: Value = store
+        .lookup(&key)
+        .ok()
+        .and_then(|mut found| serde_json::from_slice(&found.take_body_bytes()).ok())
+        .unwrap_or_else(|| json!({}));
+    let run = &mut runs[name];
+    for (field, value) in TRANSFORM_FIELDS
+        .into_iter()
+        .zip([1, bytes_in, bytes_out, micros])
+    {
+        run[field] = (run[field].as_u64().unwrap_or(0) + value).into();
+    }
+    store
+        .build_insert()
+        .time_to_live(RETENTION)
+        .execute(&key, runs.to_string())?;
+    Ok(())
+}
+
+fn increment(outcome: Outcome) -> Result<(), fastly::Error> {
+    let store = KVStore::open(STORE_NAME)?
+        .ok_or_else(|| fastly::Error::msg(format!("KV Store {STORE_NAME} is not linked")))?;
+    let minute = current_minute();
+    let key = bucket_key(minute);
+    let mut counts: Value = store
+        .lookup(&key)
+        .ok()
+        .and_then(|mut found| serde_json::from_slice(&found.take_body_bytes()).ok())
+        .unwrap_or_else(|| json!({}));
+    let count = counts[outcome.name()].as_u64().unwrap_or(0);
+    counts[outcome.name()] = (count + 1).into();
+    store
+        .build_insert()
+        .time_to_live(RETENTION)
+        .execute(&key, counts.to_string())?;
+
+    let (hit, miss, pass) = counts_of(&counts);
+    let ratio = summary(hit, miss, pass)["hit_ratio"].clone();
+    log::info!(
+        "cache outcomes for minute {}: {hit} hits, {miss} misses, {pass} passes, hit ratio {ratio}",
+        minute * 60
+    );
+    Ok(())
+}
+
+/// Returns a bucket's counts and hit ratio. The hit ratio only considers requests that could have
+/// been served from the cache, and is `null` when there were none.
+fn summary(hits: u64, misses: u64, passes: u64) -> Value {
+    let cacheable = hits + misses;
+    let hit_ratio = (cacheable > 0).then(|| hits as f64 / cacheable as f64);
+    json!({ "hit": hits, "miss": misses, "pass": passes, "hit_ratio": hit_ratio })
+}
+
+fn counts_of(counts: &Value) -> (u64, u64, u64) {
+    let count = |outcome: Outcome| counts[outcome.name()].as_u64().unwrap_or(0);
+    (
+        count(Outcome::Hit),
+        count(Outcome::Miss),
+        count(Outcome::Pass),
+    )
+}
+
+fn bucket_key(minute: u64) -> String {
+    format!("minute/{minute}")
+}
+
+fn transform_bucket_key(minute: u64) -> String {
+    format!("transforms/{minute}")
+}
+
+/// Returns the number of minutes since the Unix epoch.
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
@@ -0,0 +1,120 @@
+//! Client requests for a fresh copy, with `Cache-Control: no-cache` or `Pragma: no-cache`.
+//!
+//! Honoring these directives from anyone would let any client bust the shared cache and send load
+//! straight to the origin. They are therefore only honored on requests from operators, who carry
+//! the debug token (see the `debug` module). From other clients, the directives are removed and
+//! the request is served as usual.
+//!
+//! An operator's request refreshes the cache rather than bypassing it: every object cached through
+//! the readthrough cache is tagged with a surrogate key derived from its URL, which is purged
+//! before the request is sent, so that the request fetches a fresh copy from the origin and stores
+//! it in place of the old one, for every client after it.
+
+use fastly::http::{header, purge, CandidateResponse};
+use fastly::Request;
+use sha2::{Digest, Sha256};
+
+/// Returns whether a request asks for a fresh copy and is allowed to, removing its no-cache
+/// directives if it is not allowed to.
+pub fn take_fresh_copy_request(req: &mut Request, is_operator: bool) -> bool {
+    let cache_control = req
+        .get_header_str(header::CACHE_CONTROL)
+        .unwrap_or_default()
+        .to_string();
+    let has_no_cache = directives(&cache_control).any(|d| d.eq_ignore_ascii_case("no-cache"))
+        || req
+            .get_header_str(header::PRAGMA)
+            .is_some_and(|pragma| directives(pragma).any(|d| d.eq_ignore_ascii_case("no-cache")));
+    if !has_no_cache {
+        return false;
+    }
+
+    if is_operator {
+        log::info!("honoring no-cache from an operator");
+        return true;
+    }
+
+    let remaining: Vec<&str> = directives(&cache_control)
+        .filter(|d| !d.eq_ignore_ascii_case("no-cache"))
+        .collect();
+    let remaining = remaining.join(", ");
+    if remaining.is_empty() {
+        req.remove_header(header::CACHE_CONTROL);
+    } else {
+        req.set_header(header::CACHE_CONTROL, remaining);
+    }
+    req.remove_header(header::PRAGMA);
+    false
+}
+
+/// Returns the surrogate key tagging the cached objects of a request's URL, in all their
+/// variants.
+pub fn url_key(req: &Request) -> String {
+    let host = req
+        .get_header_str(header::HOST)
+        .or_else(|| req.get_url().host_str())
+        .unwrap_or_default();
+    let mut target = req.get_path().to_string();
+    if let Some(query) = req.get_query_str() {
+        target.push('?');
+        target.push_str(query);
+    }
+    url_key_of(host, &target)
+}
+
+/// Adds a URL key to the surrogate keys of a response about to be cached.
+pub fn tag(url_key: &str, resp: &mut CandidateResponse) {
+    let mut keys: Vec<String> = resp
+        .get_surrogate_keys()
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+        .collect();
+    if !keys.iter().any(|key| key == url_key) {
+        keys.push(url_key.to_string());
+    }
+    resp.set_surrogate_keys(keys.iter().map(String::as_str));
+}
+
+/// Purges the cached objects of a URL key, so that the next request for the URL fetches a fresh
+/// copy and stores it. Should the purge fail, the cached copy is served.
+pub fn purge(url_key: &str) {
+    match purge::purge_surrogate_key(url_key) {
+        Ok(()) => log::info!("purged {url_key} for a fresh copy"),
+        Err(e) => log::warn!("cannot purge {url_key} for a fresh copy: {e}"),
+    }
+}
+
+fn url_key_of(host: &str, target: &str) -> String {
+    let digest: String = Sha256::digest(format!("{}{target}", host.to_ascii_lowercase()))[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("url:{digest}")
+}
+
+fn directives(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|d| !d.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_keys_identify_a_host_path_and_query() {
+        let key = url_key_of("Example.com", "/a?b=1");
+        assert!(key.starts_with("url:"));
+        assert_eq!(key.len(), 4 + 32);
+        assert_eq!(url_key_of("example.com", "/a?b=1"), key);
+        assert_ne!(url_key_of("example.com", "/a?b=2"), key);
+        assert_ne!(url_key_of("example.org", "/a?b=1"), key);
+    }
+
+    #[test]
+    fn directives_are_trimmed_and_empty_ones_skipped() {
+        assert_eq!(
+            directives(" no-cache,, max-age=0 ,").collect::<Vec<_>>(),
+            ["no-cache", "max-age=0"]
+        );
+    }
+}
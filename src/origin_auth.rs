@@ -0,0 +1,59 @@
+//! The credential the service presents to the origin.
+//!
+//! Requests forwarded to the origin carry an `Authorization` header holding the
+//! `origin_authorization` secret, such as `Bearer <token>` or `Basic <credentials>`. The header is
+//! set as requests are forwarded (see the `origin_request` module), in the before-send callback
+//! for requests fetched through the cache, so the secret is only read for requests that reach the
+//! origin.
+//!
+//! The credential is kept in memory by each instance for a few minutes, so that a busy instance
+//! does not read the Secret Store on every origin fetch, while a rotated secret is still picked up
+//! soon. If the secret is missing or is not a valid header value, requests are forwarded without
+//! credentials, and never with the client's own `Authorization` header, so that the origin answers
+//! with an error of its own rather than the fetch failing at the edge.
+
+use crate::secrets;
+use fastly::http::{header, HeaderValue};
+use fastly::Request;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The name of the secret holding the `Authorization` header value sent to the origin.
+const SECRET_NAME: &str = "origin_authorization";
+
+/// How long an instance keeps the credential it read, or the fact that it is missing.
+const CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// The credential last read by this instance, along with when it was read.
+static CREDENTIAL: Mutex<Option<(Instant, Option<HeaderValue>)>> = Mutex::new(None);
+
+/// Sets the origin credential on a request forwarded to the origin.
+pub fn apply(req: &mut Request) {
+    match credential() {
+        Some(value) => req.set_header(header::AUTHORIZATION, value),
+        None => {
+            req.remove_header(header::AUTHORIZATION);
+        }
+    }
+}
+
+/// Returns the origin credential, reading it from the Secret Store unless this instance read it
+/// recently.
+fn credential() -> Option<HeaderValue> {
+    let mut cached = CREDENTIAL.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((read_at, value)) = cached.as_ref() {
+        if read_at.elapsed() < CACHE_TTL {
+            return value.clone();
+        }
+    }
+    let value = secrets::get(SECRET_NAME).and_then(|secret| {
+        HeaderValue::from_bytes(secret.trim_ascii())
+            .inspect_err(|_| log::warn!("secret {SECRET_NAME} is not a valid header value"))
+            .ok()
+    });
+    if value.is_none() {
+        log::warn!("forwarding requests to the origin without credentials");
+    }
+    *cached = Some((Instant::now(), value.clone()));
+    value
+}
@@ -0,0 +1,59 @@
+//! Preparation of requests forwarded to the origin.
+//!
+//! Every request that reaches an origin is prepared the same way, whether it is fetched through
+//! the readthrough cache, on a miss, or sent straight to the origin, on a pass: marketing tracking
+//! parameters are removed, the identity of a client with a validated token is set in its claim
+//! headers, and the origin credential replaces any `Authorization` header sent by the client.
+//! Requests to private S3-compatible buckets are signed instead, last, so that the signature
+//! covers the request exactly as it is sent.
+
+use crate::auth::Identity;
+use crate::config::Settings;
+use crate::origin_auth;
+use crate::sigv4::Signer;
+use crate::tracking_params::TrackingParams;
+use fastly::Request;
+
+/// How the requests of one client request are prepared for the origin.
+#[derive(Clone)]
+pub struct OriginRequest {
+    tracking_params: Option<TrackingParams>,
+    identity: Option<Identity>,
+    signers: Vec<(String, Signer)>,
+}
+
+impl OriginRequest {
+    /// Returns the preparation of requests sent to any of `backends`, on behalf of a client with
+    /// the given identity.
+    pub fn new<'a>(
+        settings: &Settings,
+        identity: Option<Identity>,
+        backends: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let signers = backends
+            .into_iter()
+            .filter_map(|name| Some((name.to_string(), Signer::for_backend(name, settings)?)))
+            .collect();
+        Self {
+            tracking_params: TrackingParams::from_settings(settings),
+            identity,
+            signers,
+        }
+    }
+
+    /// Prepares a request about to be sent to `backend`.
+    pub fn prepare(&self, req: &mut Request, backend: &str) {
+        if let Some(tracking_params) = &self.tracking_params {
+            tracking_params.strip(&mut req.get_url_mut());
+        }
+
+        if let Some(identity) = &self.identity {
+            identity.apply(req);
+        }
+
+        match self.signers.iter().find(|(name, _)| name == backend) {
+            Some((_, signer)) => signer.sign(req),
+            None => origin_auth::apply(req),
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! Protection against caching partial responses.
+//!
+//! The readthrough cache requests whole objects from the origin and serves byte ranges from them
+//! itself. An origin that nevertheless answers with 206 Partial Content, for example because a
+//! client `Range` header reached it, must not have that partial body stored as if it were the
+//! complete object.
+
+use fastly::http::{header, CandidateResponse, StatusCode};
+use fastly::{Error, Request, Response};
+
+/// Prevents a 206 Partial Content response from being stored in the cache.
+///
+/// Returns `true` if the response was a partial response.
+pub fn guard(resp: &mut CandidateResponse) -> bool {
+    if resp.get_status() != StatusCode::PARTIAL_CONTENT {
+        return false;
+    }
+    log::info!("origin returned 206 Partial Content; not caching it");
+    resp.set_uncacheable(false);
+    true
+}
+
+/// Fetches the full object again after a partial response, so that the complete object can be
+/// stored in the cache.
+///
+/// `template` must be a copy of the original request, carrying its cache key and callbacks.
+pub fn refetch(template: &Request, backend: &str) -> Result<Response, Error> {
+    let mut req = template.clone_without_body();
+    req.remove_header(header::RANGE);
+    req.remove_header(header::IF_RANGE);
+    Ok(req.send(backend)?)
+}
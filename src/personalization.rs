@@ -0,0 +1,295 @@
+//! Per-user data injected into cached pages as they are delivered.
+//!
+//! Pages under the path prefixes of the `personalized_path_prefixes` setting are fetched and
+//! cached without the client's session cookie, named by the `session_cookie` setting, so that one
+//! anonymous copy is shared by every user. The session is read at the edge instead, and the data
+//! of the user is injected into the cached copy as it is streamed to the client:
+//!
+//! * in HTML pages, the content of elements with a `data-edge-session="<claim>"` attribute is
+//!   replaced with the HTML-escaped claim of the session, such as `<span
+//!   data-edge-session="name">Guest</span>`, the `content` of `<meta name="csrf-token">` is set to
+//!   the CSRF token of the session, and every `POST` form gets a hidden `csrf_token` input holding
+//!   it. Pages are rewritten by a streaming HTML rewriter, never held in memory as a whole;
+//! * in JSON bodies, objects of the form `{"$session": "<claim>"}` are replaced with the claim,
+//!   and `{"$session": "csrf_token"}` with the CSRF token.
+//!
+//! Session cookies are `<claims>.<signature>`, where `<claims>` is a base64url JSON object with at
+//! least an `sid` claim, the session ID, and `<signature>` the hex HMAC-SHA256 of `<claims>` under
+//! the `session_key` secret. The CSRF token of a session is the hex HMAC-SHA256 of `csrf:<sid>`
+//! under the same key, so the origin can check the tokens it is sent without any state. Requests without a
+//! valid session get the anonymous page, with its placeholders left as they are.
+//!
+//! Personalized responses are marked `private, no-store`, so that no downstream cache keeps them.
+//! Shell pages with personalized fragments (see the [`fragments`](crate::fragments) module) are
+//! merged with their fragment instead.
+
+use crate::config::Settings;
+use crate::html_rewrite::Rewrites;
+use crate::{secrets, signing};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use fastly::http::{header, StatusCode};
+use fastly::{mime, Error, Request, Response};
+use lol_html::html_content::ContentType;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+
+/// The name of the secret holding the key of session signatures and CSRF tokens.
+const KEY_SECRET: &str = "session_key";
+
+/// The name of the session cookie, unless overridden by the `session_cookie` setting.
+const DEFAULT_COOKIE: &str = "session";
+
+/// The attribute of HTML elements whose content is replaced with a session claim.
+const SESSION_ATTRIBUTE: &str = "data-edge-session";
+
+/// The member of JSON objects that are replaced with a session claim.
+const SESSION_MEMBER: &str = "$session";
+
+/// The name of the CSRF token, as a claim and as a form input.
+const CSRF_TOKEN: &str = "csrf_token";
+
+/// The session of a client, whose data is injected into the cached page delivered to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Personalization {
+    claims: Map<String, Value>,
+    csrf_token: String,
+}
+
+impl Personalization {
+    /// Removes the session cookie from a request for a personalized page, returning the session it
+    /// holds. Returns `None` for other requests, and for requests without a valid session.
+    pub fn take_from_request(req: &mut Request, settings: &Settings) -> Option<Self> {
+        let path = req.get_path();
+        if !settings
+            .get_list("personalized_path_prefixes")
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+
+        let cookie_name = settings
+            .get("session_cookie")
+            .unwrap_or_else(|| DEFAULT_COOKIE.to_string());
+        let mut session_cookie = None;
+        let kept: Vec<String> = req
+            .get_header_all_str(header::COOKIE)
+            .iter()
+            .flat_map(|value| value.split(';'))
+            .map(str::trim)
+            .filter(|cookie| match cookie.split_once('=') {
+                Some((name, value)) if name.trim() == cookie_name => {
+                    session_cookie = Some(value.trim().to_string());
+                    false
+                }
+                _ => !cookie.is_empty(),
+            })
+            .map(str::to_string)
+            .collect();
+        if kept.is_empty() {
+            req.remove_header(header::COOKIE);
+        } else {
+            req.set_header(header::COOKIE, kept.join("; "));
+        }
+
+        let session_cookie = session_cookie?;
+        let key = secrets::get(KEY_SECRET)?;
+        let session = Self::from_cookie(&session_cookie, &key);
+        if session.is_none() {
+            log::info!("ignoring invalid session cookie for {}", req.get_path());
+        }
+        session
+    }
+
+    /// Sends a cached response to the client, with the data of the session injected into it.
+    ///
+    /// Responses other than successful uncompressed HTML and JSON are sent as they are.
+    pub fn inject_and_send(self, mut resp: Response) -> Result<(), Error> {
+        let essence = resp
+            .get_content_type()
+            .map(|mime| mime.essence_str().to_string());
+        let is_html = essence.as_deref() == Some(mime::TEXT_HTML.essence_str());
+        let is_json = essence.as_deref() == Some(mime::APPLICATION_JSON.essence_str());
+        if resp.get_status() != StatusCode::OK
+            || resp.contains_header(header::CONTENT_ENCODING)
+            || !(is_html || is_json)
+        {
+            resp.send_to_client();
+            return Ok(());
+        }
+
+        let mut body = resp.take_body();
+        resp.remove_header(header::CONTENT_LENGTH);
+        resp.remove_header(header::ETAG);
+        resp.remove_header(header::LAST_MODIFIED);
+        resp.set_header(header::CACHE_CONTROL, "private, no-store");
+        if is_json {
+            let json = self.inject_json(&body.into_bytes());
+            resp.with_body(json).send_to_client();
+            return Ok(());
+        }
+
+        let mut out = resp.stream_to_client();
+        let mut writer = self.rewrites().writer(&mut out);
+        io::copy(&mut body, &mut writer)?;
+        writer.finish()?;
+        out.flush()?;
+        out.finish()?;
+        Ok(())
+    }
+
+    /// Reads a session cookie, returning `None` if its signature or claims are not valid.
+    fn from_cookie(cookie: &str, key: &[u8]) -> Option<Self> {
+        let (claims, signature) = cookie.rsplit_once('.')?;
+        if !signing::verify_hex(key, claims.as_bytes(), signature) {
+            return None;
+        }
+        let claims: Map<String, Value> =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+        let session_id = claims.get("sid")?.as_str()?;
+        let csrf_token = signing::sign_hex(key, format!("csrf:{session_id}").as_bytes());
+        Some(Self { claims, csrf_token })
+    }
+
+    /// Returns the text of a claim, or the CSRF token.
+    fn claim(&self, name: &str) -> Option<String> {
+        if name == CSRF_TOKEN {
+            return Some(self.csrf_token.clone());
+        }
+        match self.claims.get(name)? {
+            Value::String(text) => Some(text.clone()),
+            Value::Number(number) => Some(number.to_string()),
+            Value::Bool(flag) => Some(flag.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Returns the rewrites injecting the session into HTML pages.
+    fn rewrites(&self) -> Rewrites {
+        let session = self.clone();
+        let csrf_token = self.csrf_token.clone();
+        let csrf_input = format!(
+            r#"<input type="hidden" name="{CSRF_TOKEN}" value="{}">"#,
+            self.csrf_token
+        );
+        Rewrites::new()
+            .on_element(&format!("[{SESSION_ATTRIBUTE}]"), move |el| {
+                let value = el
+                    .get_attribute(SESSION_ATTRIBUTE)
+                    .and_then(|name| session.claim(name.trim()));
+                if let Some(value) = value {
+                    el.set_inner_content(&value, ContentType::Text);
+                }
+                Ok(())
+            })
+            .on_element(r#"meta[name="csrf-token"]"#, move |el| {
+                el.set_attribute("content", &csrf_token)?;
+                Ok(())
+            })
+            .on_element(r#"form[method="post" i]"#, move |el| {
+                el.prepend(&csrf_input, ContentType::Html);
+                Ok(())
+            })
+    }
+
+    /// Returns a JSON body with its session placeholders replaced. Other bodies are returned as
+    /// they are.
+    fn inject_json(&self, body: &[u8]) -> Vec<u8> {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(mut document) => {
+                self.resolve(&mut document);
+                serde_json::to_vec(&document).unwrap_or_else(|_| body.to_vec())
+            }
+            Err(_) => body.to_vec(),
+        }
+    }
+
+    fn resolve(&self, value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                let claim = (object.len() == 1)
+                    .then(|| object.get(SESSION_MEMBER)?.as_str())
+                    .flatten()
+                    .map(|name| self.claim(name).map_or(Value::Null, Value::String));
+                match claim {
+                    Some(claim) => *value = claim,
+                    None => object.values_mut().for_each(|member| self.resolve(member)),
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.resolve(item)),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const KEY: &[u8] = b"test key";
+
+    fn cookie(claims: Value) -> String {
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signature = signing::sign_hex(KEY, claims.as_bytes());
+        format!("{claims}.{signature}")
+    }
+
+    #[test]
+    fn sessions_are_read_from_signed_cookies() {
+        let session =
+            Personalization::from_cookie(&cookie(json!({ "sid": "s1", "name": "Ada" })), KEY)
+                .unwrap();
+        assert_eq!(session.claim("name").as_deref(), Some("Ada"));
+        assert_eq!(session.csrf_token.len(), 64);
+
+        let other = Personalization::from_cookie(&cookie(json!({ "sid": "s2" })), KEY).unwrap();
+        assert_ne!(other.csrf_token, session.csrf_token);
+
+        let tampered = cookie(json!({ "sid": "s1" })).replace('.', "x.");
+        assert!(Personalization::from_cookie(&tampered, KEY).is_none());
+        assert!(Personalization::from_cookie(&cookie(json!({ "name": "Ada" })), KEY).is_none());
+    }
+
+    #[test]
+    fn html_pages_are_personalized() {
+        let session =
+            Personalization::from_cookie(&cookie(json!({ "sid": "s1", "name": "<Ada>" })), KEY)
+                .unwrap();
+        let page = r#"<meta name="csrf-token" content=""><p>Hi <span data-edge-session="name">Guest</span></p><form method="POST"></form><form></form>"#;
+        let mut out = Vec::new();
+        let mut writer = session.rewrites().writer(&mut out);
+        writer.write_all(page.as_bytes()).unwrap();
+        writer.finish().unwrap();
+        let token = &session.csrf_token;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                r#"<meta name="csrf-token" content="{token}"><p>Hi <span data-edge-session="name">&lt;Ada&gt;</span></p><form method="POST"><input type="hidden" name="csrf_token" value="{token}"></form><form></form>"#
+            )
+        );
+    }
+
+    #[test]
+    fn json_placeholders_are_replaced() {
+        let session =
+            Personalization::from_cookie(&cookie(json!({ "sid": "s1", "name": "Ada" })), KEY)
+                .unwrap();
+        let body = json!({
+            "greeting": { "$session": "name" },
+            "forms": [{ "token": { "$session": "csrf_token" } }],
+            "missing": { "$session": "email" },
+        });
+        let injected: Value =
+            serde_json::from_slice(&session.inject_json(body.to_string().as_bytes())).unwrap();
+        assert_eq!(
+            injected,
+            json!({
+                "greeting": "Ada",
+                "forms": [{ "token": session.csrf_token }],
+                "missing": null,
+            })
+        );
+    }
+}
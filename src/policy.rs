@@ -0,0 +1,168 @@
+//! The caching policy applied to origin responses by content type.
+//!
+//! Responses are kept for a TTL chosen from their `Content-Type`: images for 67 seconds, HTML for
+//! 321 seconds, and anything else for the lifetime its `Expires` header asks for, or 30 seconds.
+//! XML responses are never stored. Routes and cache rules with their own TTL use it for every
+//! content type instead.
+//!
+//! Responses whose status says something about the URL rather than about its content are kept for
+//! a TTL of their own, whatever their content type: `404 Not Found` and `410 Gone` for the short
+//! TTL of the `negative_ttl` setting, so that a page that appears is found soon, and the permanent
+//! redirects `301` and `308` for the long TTL of the `permanent_redirect_ttl` setting.
+//!
+//! Responses carrying the `My-Private-Header` header are marked hit-for-pass: they are not stored,
+//! and requests for the same object are not collapsed until a cacheable response comes back. The
+//! marker lasts for the TTL of the response, or for the `hfp` option of its route, and the reason
+//! it was created is logged.
+//!
+//! The functions here only look at the values they are given, so they can be tested without a
+//! response.
+
+use crate::config::Settings;
+use std::time::Duration;
+
+/// The response header that makes a response hit-for-pass.
+pub const HIT_FOR_PASS_HEADER: &str = "my-private-header";
+
+/// The TTL of responses whose content type has no TTL of its own, and no `Expires` header.
+const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+/// How long negative responses are kept, unless overridden by the `negative_ttl` setting (in
+/// seconds).
+const DEFAULT_NEGATIVE_TTL_SECS: u64 = 10;
+
+/// How long permanent redirects are kept, unless overridden by the `permanent_redirect_ttl`
+/// setting (in seconds).
+const DEFAULT_PERMANENT_REDIRECT_TTL_SECS: u64 = 3600;
+
+/// The TTLs of responses with a status that has a TTL of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatusTtls {
+    /// The TTL of `404 Not Found` and `410 Gone` responses.
+    pub negative: Duration,
+    /// The TTL of `301 Moved Permanently` and `308 Permanent Redirect` responses.
+    pub permanent_redirect: Duration,
+}
+
+impl StatusTtls {
+    /// Returns the TTLs of the `negative_ttl` and `permanent_redirect_ttl` settings.
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            negative: Duration::from_secs(
+                settings.get_u64("negative_ttl", DEFAULT_NEGATIVE_TTL_SECS),
+            ),
+            permanent_redirect: Duration::from_secs(settings.get_u64(
+                "permanent_redirect_ttl",
+                DEFAULT_PERMANENT_REDIRECT_TTL_SECS,
+            )),
+        }
+    }
+
+    /// Returns the TTL of responses with a status, or `None` if it has no TTL of its own.
+    pub fn for_status(self, status: u16) -> Option<Duration> {
+        match status {
+            404 | 410 => Some(self.negative),
+            301 | 308 => Some(self.permanent_redirect),
+            _ => None,
+        }
+    }
+}
+
+/// Whether, and for how long, a response is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    /// The response is stored for this long.
+    Ttl(Duration),
+    /// The response is not stored.
+    Uncacheable,
+}
+
+/// Returns how a response is stored. `rule_ttl` is the TTL of the route or cache rule of the
+/// request, or of the status of the response, and `expires_ttl` the lifetime implied by the
+/// response's `Expires` header.
+pub fn storage(
+    rule_ttl: Option<Duration>,
+    content_type: Option<&str>,
+    expires_ttl: Option<Duration>,
+) -> Storage {
+    match (rule_ttl, content_type) {
+        (Some(ttl), _) => Storage::Ttl(ttl),
+        (None, Some("image")) => Storage::Ttl(Duration::from_secs(67)),
+        (None, Some("text/html")) => Storage::Ttl(Duration::from_secs(321)),
+        (None, Some("application/xml")) => Storage::Uncacheable,
+        // Other responses keep the lifetime their `Expires` header asks for, if any.
+        (None, _) => Storage::Ttl(expires_ttl.unwrap_or(DEFAULT_TTL)),
+    }
+}
+
+/// Returns why a response with these header names is hit-for-pass, or `None` if it is not.
+pub fn hit_for_pass_reason<'a>(mut header_names: impl Iterator<Item = &'a str>) -> Option<String> {
+    header_names
+        .find(|name| name.eq_ignore_ascii_case(HIT_FOR_PASS_HEADER))
+        .map(|name| format!("response header {name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ttl_is_chosen_by_content_type() {
+        assert_eq!(
+            storage(None, Some("image"), None),
+            Storage::Ttl(Duration::from_secs(67))
+        );
+        assert_eq!(
+            storage(None, Some("text/html"), Some(Duration::from_secs(5))),
+            Storage::Ttl(Duration::from_secs(321))
+        );
+        assert_eq!(
+            storage(None, Some("application/xml"), None),
+            Storage::Uncacheable
+        );
+        assert_eq!(
+            storage(None, Some("text/plain"), Some(Duration::from_secs(5))),
+            Storage::Ttl(Duration::from_secs(5))
+        );
+        assert_eq!(storage(None, None, None), Storage::Ttl(DEFAULT_TTL));
+    }
+
+    #[test]
+    fn rule_ttl_applies_to_every_content_type() {
+        let ttl = Duration::from_secs(600);
+        assert_eq!(
+            storage(Some(ttl), Some("application/xml"), None),
+            Storage::Ttl(ttl)
+        );
+        assert_eq!(storage(Some(ttl), Some("image"), None), Storage::Ttl(ttl));
+    }
+
+    #[test]
+    fn negative_responses_and_permanent_redirects_have_their_own_ttl() {
+        let ttls = StatusTtls {
+            negative: Duration::from_secs(10),
+            permanent_redirect: Duration::from_secs(3600),
+        };
+        let secs = |status| ttls.for_status(status).map(|ttl| ttl.as_secs());
+        assert_eq!(secs(404), Some(10));
+        assert_eq!(secs(410), Some(10));
+        assert_eq!(secs(301), Some(3600));
+        assert_eq!(secs(308), Some(3600));
+        assert_eq!(secs(302), None);
+        assert_eq!(secs(200), None);
+        assert_eq!(secs(500), None);
+    }
+
+    #[test]
+    fn private_header_makes_a_response_hit_for_pass() {
+        assert_eq!(
+            hit_for_pass_reason(["content-type", "My-Private-Header"].into_iter()).as_deref(),
+            Some("response header My-Private-Header")
+        );
+        assert_eq!(
+            hit_for_pass_reason(["content-type", "my-private-header"].into_iter()).as_deref(),
+            Some("response header my-private-header")
+        );
+        assert_eq!(hit_for_pass_reason(["content-type"].into_iter()), None);
+    }
+}
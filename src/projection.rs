@@ -0,0 +1,184 @@
+//! Projections of JSON API objects onto the fields a client asks for.
+//!
+//! With the `json_projection` setting on, clients of the `/api/` routes can ask for a lighter
+//! payload with `?fields=firstName,lastName`. The origin is always asked for the whole object,
+//! and the cached object keeps only the requested members. Each field set is cached as its own
+//! variant, under a cache namespace derived from the sorted set, so `?fields=b,a` and
+//! `?fields=a,b` share one cached object.
+//!
+//! Fields name top-level members, or nested members with dotted paths such as `address.city`.
+//! Arrays are projected element by element, so a list of objects keeps the requested members of
+//! each. Bodies that are not JSON are cached as they are.
+
+use crate::{query, transform};
+use fastly::Request;
+use serde_json::{Map, Value};
+
+/// The query parameter listing the requested fields.
+const FIELDS_PARAM: &str = "fields";
+
+/// The most fields a projection can request. Requests listing more are not projected.
+const MAX_FIELDS: usize = 64;
+
+/// The fields a client asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Projection {
+    /// The requested field paths, sorted and without duplicates.
+    fields: Vec<String>,
+}
+
+impl Projection {
+    /// Removes the fields parameter from a request, returning the projection it asks for, if any.
+    ///
+    /// The parameter is never sent to the origin. Callers key the projected variant on
+    /// [`Projection::cache_namespace`] instead.
+    pub fn take_from_request(req: &mut Request) -> Option<Self> {
+        Self::parse(&query::take_param(req, FIELDS_PARAM)?)
+    }
+
+    /// Parses the value of the fields parameter.
+    fn parse(value: &str) -> Option<Self> {
+        let mut fields: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(str::to_string)
+            .collect();
+        fields.sort();
+        fields.dedup();
+        if fields.is_empty() || fields.len() > MAX_FIELDS {
+            return None;
+        }
+        Some(Self { fields })
+    }
+
+    /// Returns the cache namespace of the projected variant, within the namespace the whole
+    /// object is cached in, if any.
+    pub fn cache_namespace(&self, within: Option<&str>) -> String {
+        let namespace = format!("{FIELDS_PARAM}={}", self.fields.join(","));
+        match within {
+            Some(within) => format!("{within} {namespace}"),
+            None => namespace,
+        }
+    }
+
+    /// Returns whether a response can be projected: JSON that is not encoded, or that can be
+    /// decompressed.
+    pub fn applies(content_type: Option<&str>, content_encoding: Option<&str>) -> bool {
+        transform::is_json(content_type) && transform::is_transformable_encoding(content_encoding)
+    }
+
+    /// Returns a JSON body with only the requested fields. Bodies that are not JSON are returned
+    /// as they are.
+    pub fn apply(&self, body: &[u8]) -> Vec<u8> {
+        let Ok(value) = serde_json::from_slice::<Value>(body) else {
+            return body.to_vec();
+        };
+        let paths: Vec<Vec<&str>> = self
+            .fields
+            .iter()
+            .map(|field| field.split('.').collect())
+            .collect();
+        serde_json::to_vec(&project(value, &paths)).unwrap_or_else(|_| body.to_vec())
+    }
+}
+
+/// Keeps the members of a value at the given paths. An empty path keeps the whole value.
+fn project(value: Value, paths: &[Vec<&str>]) -> Value {
+    if paths.iter().any(|path| path.is_empty()) {
+        return value;
+    }
+    match value {
+        Value::Object(mut object) => {
+            let mut projected = Map::new();
+            for path in paths {
+                let name = path[0];
+                if projected.contains_key(name) {
+                    continue;
+                }
+                let Some(member) = object.remove(name) else {
+                    continue;
+                };
+                let rest: Vec<Vec<&str>> = paths
+                    .iter()
+                    .filter(|path| path[0] == name)
+                    .map(|path| path[1..].to_vec())
+                    .collect();
+                projected.insert(name.to_string(), project(member, &rest));
+            }
+            Value::Object(projected)
+        }
+        Value::Array(elements) => Value::Array(
+            elements
+                .into_iter()
+                .map(|element| project(element, paths))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply(fields: &str, body: Value) -> Value {
+        let projection = Projection::parse(fields).unwrap();
+        serde_json::from_slice(&projection.apply(body.to_string().as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn only_requested_fields_are_kept() {
+        assert_eq!(
+            apply(
+                "lastName,firstName",
+                json!({ "firstName": "Ada", "lastName": "Lovelace", "bio": "A long text" })
+            ),
+            json!({ "firstName": "Ada", "lastName": "Lovelace" })
+        );
+    }
+
+    #[test]
+    fn nested_fields_and_arrays_are_projected() {
+        assert_eq!(
+            apply(
+                "name,address.city",
+                json!([
+                    { "name": "A", "age": 1, "address": { "city": "Paris", "zip": "75001" } },
+                    { "name": "B", "address": "unknown" }
+                ])
+            ),
+            json!([
+                { "name": "A", "address": { "city": "Paris" } },
+                { "name": "B", "address": "unknown" }
+            ])
+        );
+        assert_eq!(
+            apply(
+                "address,address.city",
+                json!({ "address": { "city": "Paris", "zip": "75001" } })
+            ),
+            json!({ "address": { "city": "Paris", "zip": "75001" } })
+        );
+    }
+
+    #[test]
+    fn field_sets_share_one_namespace_whatever_their_order() {
+        let a = Projection::parse("lastName, firstName,lastName").unwrap();
+        let b = Projection::parse("firstName,lastName").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.cache_namespace(None), "fields=firstName,lastName");
+        assert_eq!(
+            a.cache_namespace(Some("tenant")),
+            "tenant fields=firstName,lastName"
+        );
+        assert_eq!(Projection::parse(" , "), None);
+    }
+
+    #[test]
+    fn other_bodies_are_left_alone() {
+        let projection = Projection::parse("a").unwrap();
+        assert_eq!(projection.apply(b"not json"), b"not json");
+    }
+}
@@ -0,0 +1,241 @@
+//! Purging through the Fastly API, on behalf of clients.
+//!
+//! With the `purge_api` setting on, two kinds of purge requests are answered at the edge:
+//!
+//! * `PURGE /some/path` purges the URL it is sent to;
+//! * `POST /purge` with `{"surrogate_keys": ["...", ...]}` purges surrogate keys, and all of the
+//!   shards of sharded keys.
+//!
+//! Both require the `purge_token` secret as a bearer token, and are soft purges if they carry a
+//! `Fastly-Soft-Purge: 1` header. The purge itself is a call to the Fastly API, authenticated
+//! with the `fastly_api_token` secret, through a backend to `api.fastly.com` that is registered
+//! on first use. Unlike the purge of the `/_edge/` admin API, this also reaches services that
+//! share the cached objects through other entry points.
+//!
+//! Responses are JSON objects with an `ok` member, like those of the admin API.
+
+use crate::config::Settings;
+use crate::{secrets, signing, surrogate_keys};
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Backend, Request, Response};
+use serde_json::{json, Value};
+
+/// The path of surrogate key purges.
+const KEY_PURGE_PATH: &str = "/purge";
+
+/// The name of the secret holding the bearer token of purge requests.
+const TOKEN_SECRET: &str = "purge_token";
+
+/// The name of the secret holding the Fastly API token used to purge.
+const API_TOKEN_SECRET: &str = "fastly_api_token";
+
+/// The host of the Fastly API.
+const API_HOST: &str = "api.fastly.com";
+
+/// The name of the dynamic backend to the Fastly API.
+const API_BACKEND: &str = "fastly_api";
+
+/// The header asking for a soft purge, both from clients and to the Fastly API.
+const SOFT_PURGE_HEADER: &str = "fastly-soft-purge";
+
+/// The largest number of surrogate keys the Fastly API purges in one call.
+const MAX_KEYS: usize = 256;
+
+/// Returns whether a request is a purge request to be answered at the edge.
+pub fn is_purge_request(req: &Request, settings: &Settings) -> bool {
+    settings.get_bool("purge_api", false)
+        && (req.get_method_str().eq_ignore_ascii_case("PURGE")
+            || (req.get_method() == Method::POST && req.get_path() == KEY_PURGE_PATH))
+}
+
+/// Authenticates a purge request, purges through the Fastly API, and reports the result.
+pub fn handle(mut req: Request, settings: &Settings) -> Response {
+    let soft = req.get_header_str(SOFT_PURGE_HEADER) == Some("1");
+    let result = authorize(&req).and_then(|()| {
+        if req.get_method() == Method::POST {
+            let keys = requested_keys(&mut req, settings)?;
+            purge_keys(&keys, soft)
+        } else {
+            purge_url(&req, soft)
+        }
+    });
+
+    let (status, body) = match result {
+        Ok(Value::Object(mut data)) => {
+            data.insert("ok".into(), true.into());
+            data.insert("soft".into(), soft.into());
+            (StatusCode::OK, Value::Object(data))
+        }
+        Ok(data) => (StatusCode::OK, json!({ "ok": true, "data": data })),
+        Err((status, message)) => (status, json!({ "ok": false, "message": message })),
+    };
+    log::info!(
+        "{}",
+        json!({
+            "audit": "purge",
+            "method": req.get_method_str(),
+            "path": req.get_path(),
+            "client_ip": req.get_client_ip_addr().map(|ip| ip.to_string()),
+            "status": status.as_u16(),
+        })
+    );
+
+    Response::from_status(status)
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_body_json(&body)
+        .unwrap_or_else(|_| Response::from_status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// A failed purge, with the status and message to report.
+type Failure = (StatusCode, String);
+
+/// Checks the bearer token of a purge request.
+fn authorize(req: &Request) -> Result<(), Failure> {
+    let Some(expected) = secrets::get(TOKEN_SECRET) else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the purge token is not configured".to_string(),
+        ));
+    };
+    let token = req
+        .get_header_str(header::AUTHORIZATION)
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .trim();
+    if token.is_empty() || !signing::constant_time_eq(token.as_bytes(), &expected) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "a valid bearer token is required".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the surrogate keys to purge for the keys listed in a request body, including the
+/// shards of sharded keys.
+fn requested_keys(req: &mut Request, settings: &Settings) -> Result<Vec<String>, Failure> {
+    let body: Value = req.take_body_json().map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("the request body must be a JSON object: {e}"),
+        )
+    })?;
+    let requested: Vec<&str> = body["surrogate_keys"]
+        .as_array()
+        .map(|keys| keys.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let is_valid = |key: &&str| !key.is_empty() && !key.contains(char::is_whitespace);
+    if requested.is_empty() || !requested.iter().all(is_valid) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "surrogate_keys must list one or more keys without whitespace".to_string(),
+        ));
+    }
+    let mut keys = Vec::new();
+    for key in requested
+        .into_iter()
+        .flat_map(|key| surrogate_keys::purge_keys(key, settings))
+    {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    if keys.len() > MAX_KEYS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("at most {MAX_KEYS} surrogate keys can be purged at once, shards included"),
+        ));
+    }
+    Ok(keys)
+}
+
+/// Purges surrogate keys of this service with one Fastly API call.
+fn purge_keys(keys: &[String], soft: bool) -> Result<Value, Failure> {
+    let service_id = std::env::var("FASTLY_SERVICE_ID").map_err(|_| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the service ID is not known".to_string(),
+        )
+    })?;
+    let req = Request::post(format!("https://{API_HOST}/service/{service_id}/purge"))
+        .with_header("surrogate-key", keys.join(" "));
+    let result = call_api(req, soft)?;
+    Ok(json!({ "surrogate_keys": keys, "result": result }))
+}
+
+/// Purges the URL a `PURGE` request was sent to.
+fn purge_url(req: &Request, soft: bool) -> Result<Value, Failure> {
+    let host = req
+        .get_header_str(header::HOST)
+        .or_else(|| req.get_url().host_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let mut cached_url = format!("{host}{}", req.get_path());
+    if let Some(query) = req.get_query_str() {
+        cached_url.push('?');
+        cached_url.push_str(query);
+    }
+    let result = call_api(
+        Request::post(format!("https://{API_HOST}/purge/{cached_url}")),
+        soft,
+    )?;
+    Ok(json!({ "url": cached_url, "result": result }))
+}
+
+/// Sends an authenticated call to the Fastly API, and returns its JSON response.
+fn call_api(mut req: Request, soft: bool) -> Result<Value, Failure> {
+    let Some(api_token) = secrets::get(API_TOKEN_SECRET) else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the Fastly API token is not configured".to_string(),
+        ));
+    };
+    let api_token = String::from_utf8(api_token).map_err(|_| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the Fastly API token is not valid UTF-8".to_string(),
+        )
+    })?;
+    req.set_header("fastly-key", api_token.trim());
+    req.set_header(header::ACCEPT, "application/json");
+    if soft {
+        req.set_header(SOFT_PURGE_HEADER, "1");
+    }
+    req.set_pass(true);
+
+    let mut resp = req.send(api_backend()?).map_err(|e| {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("cannot reach the Fastly API: {e}"),
+        )
+    })?;
+    let status = resp.get_status();
+    let body: Value = resp.take_body_json().unwrap_or(Value::Null);
+    if !status.is_success() {
+        log::warn!("Fastly API purge failed with {status}: {body}");
+        return Err((
+            StatusCode::BAD_GATEWAY,
+            format!("the Fastly API answered with {}", status.as_u16()),
+        ));
+    }
+    Ok(body)
+}
+
+/// Returns the backend to the Fastly API, registering it if this instance has not yet.
+fn api_backend() -> Result<Backend, Failure> {
+    if let Ok(backend) = Backend::from_name(API_BACKEND) {
+        return Ok(backend);
+    }
+    Backend::builder(API_BACKEND, format!("{API_HOST}:443"))
+        .override_host(API_HOST)
+        .enable_ssl()
+        .sni_hostname(API_HOST)
+        .check_certificate(API_HOST)
+        .finish()
+        .map_err(|e| {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("cannot register the Fastly API backend: {e}"),
+            )
+        })
+}
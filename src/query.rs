@@ -0,0 +1,100 @@
+//! Helpers for reading and rewriting the query string of a request.
+
+use fastly::http::Url;
+use fastly::Request;
+
+/// Removes a query parameter from the request URL, returning its value if present.
+///
+/// The remaining query parameters are left in their original order. Because the readthrough cache
+/// keys objects on the URL, removing a parameter before the request is sent also removes it from
+/// the cache key.
+pub fn take_param(req: &mut Request, param: &str) -> Option<String> {
+    let mut taken = None;
+    let mut remaining = Vec::new();
+    for (name, value) in req.get_url().query_pairs() {
+        if name == param {
+            taken = Some(value.into_owned());
+        } else {
+            remaining.push((name.into_owned(), value.into_owned()));
+        }
+    }
+
+    taken.as_ref()?;
+
+    if remaining.is_empty() {
+        req.remove_query();
+    } else {
+        req.get_url_mut()
+            .query_pairs_mut()
+            .clear()
+            .extend_pairs(remaining);
+    }
+    taken
+}
+
+/// Returns whether a query parameter name is one of a list of names. Names ending with `*` match
+/// any parameter starting with the rest of the name.
+pub fn is_listed(name: &str, names: &[String]) -> bool {
+    names.iter().any(|listed| match listed.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == listed,
+    })
+}
+
+/// Removes the listed query parameters from a URL, leaving the others in their original order.
+/// Returns whether any parameter was removed.
+pub fn remove_listed(url: &mut Url, names: &[String]) -> bool {
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    let remaining: Vec<&(String, String)> = pairs
+        .iter()
+        .filter(|(name, _)| !is_listed(name, names))
+        .collect();
+    if remaining.len() == pairs.len() {
+        return false;
+    }
+    if remaining.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(remaining.iter().map(|(name, value)| (name, value)));
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn names_ending_with_a_star_are_prefixes() {
+        let listed = names(&["utm_*", "gclid"]);
+        assert!(is_listed("utm_source", &listed));
+        assert!(is_listed("utm_", &listed));
+        assert!(is_listed("gclid", &listed));
+        assert!(!is_listed("gclid2", &listed));
+        assert!(!is_listed("UTM_source", &listed));
+    }
+
+    #[test]
+    fn listed_params_are_removed_in_order() {
+        let listed = names(&["utm_*", "fbclid"]);
+        let mut url = Url::parse("https://example.com/p?b=2&utm_source=x&a=1&fbclid=y").unwrap();
+        assert!(remove_listed(&mut url, &listed));
+        assert_eq!(url.as_str(), "https://example.com/p?b=2&a=1");
+
+        assert!(!remove_listed(&mut url, &listed));
+        assert_eq!(url.as_str(), "https://example.com/p?b=2&a=1");
+
+        let mut url = Url::parse("https://example.com/p?utm_medium=email").unwrap();
+        assert!(remove_listed(&mut url, &listed));
+        assert_eq!(url.as_str(), "https://example.com/p");
+    }
+}
@@ -0,0 +1,181 @@
+//! Per-client rate limiting with the edge rate limiter.
+//!
+//! Clients sending more than `rate_limit` requests per second, on average over the last
+//! `rate_limit_window` seconds (1, 10 or 60), are put in a penalty box for `rate_limit_penalty`
+//! seconds, during which every request of theirs is answered with a `429 Too Many Requests`
+//! before the cache or the origin is touched. The penalty is truncated to whole minutes, between
+//! one minute and one hour.
+//!
+//! Clients are identified by their IP address, or with the `rate_limit_by` setting set to
+//! `api-key`, by the API key in their `X-API-Key` header, falling back to their address for
+//! requests without one. Routes can set a limit of their own with their `rate=<requests per
+//! second>` option, where `rate=0` turns limiting off. The requests of a client on such a route
+//! are counted separately from their other requests, so that each limit applies to the traffic it
+//! was chosen for.
+//!
+//! The counters live in the `clients` rate counter and the `clients` penalty box of the service.
+//! Should they fail, requests are let through.
+
+use crate::config::Settings;
+use fastly::erl::{Penaltybox, RateCounter, RateWindow, ERL};
+use fastly::http::{header, StatusCode};
+use fastly::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// The name of the rate counter and of the penalty box counting the requests of clients.
+const COUNTER_NAME: &str = "clients";
+
+/// The request header carrying API keys.
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// The averaging window of rates, unless overridden by the `rate_limit_window` setting (in
+/// seconds).
+const DEFAULT_WINDOW_SECS: u64 = 10;
+
+/// How long clients over the limit are penalized, unless overridden by the `rate_limit_penalty`
+/// setting (in seconds).
+const DEFAULT_PENALTY_SECS: u64 = 300;
+
+/// The limit set by a route.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Limit {
+    /// The path prefix of the route, whose requests are counted apart from the others.
+    pub scope: String,
+    /// The limit, in requests per second. `0` turns limiting off on the route.
+    pub requests_per_sec: u32,
+}
+
+/// The rate limit that applies to one request.
+pub struct RateLimiter {
+    entry: String,
+    limit: u32,
+    window: u64,
+    penalty: Duration,
+}
+
+impl RateLimiter {
+    /// Returns the rate limit of a request, given the limit of its route, or `None` if it is not
+    /// limited.
+    pub fn for_request(
+        req: &Request,
+        settings: &Settings,
+        route_limit: Option<&Limit>,
+    ) -> Option<Self> {
+        let (scope, limit) = match route_limit {
+            Some(limit) => (limit.scope.as_str(), limit.requests_per_sec),
+            None => (
+                "*",
+                u32::try_from(settings.get_u64("rate_limit", 0)).unwrap_or(u32::MAX),
+            ),
+        };
+        if limit == 0 {
+            return None;
+        }
+        let client = match settings.get("rate_limit_by").as_deref().map(str::trim) {
+            Some("api-key") => api_key_client(req).or_else(|| ip_client(req)),
+            None | Some("ip") => ip_client(req),
+            Some(other) => {
+                log::warn!("ignoring invalid rate_limit_by setting {other:?}");
+                ip_client(req)
+            }
+        }?;
+        let window = match settings.get_u64("rate_limit_window", DEFAULT_WINDOW_SECS) {
+            window @ (1 | 10 | 60) => window,
+            other => {
+                log::warn!("ignoring invalid rate_limit_window setting {other}");
+                DEFAULT_WINDOW_SECS
+            }
+        };
+        Some(Self {
+            entry: format!("{scope} {client}"),
+            limit,
+            window,
+            penalty: penalty(settings.get_u64("rate_limit_penalty", DEFAULT_PENALTY_SECS)),
+        })
+    }
+
+    /// Counts the request, returning the `429 Too Many Requests` to answer it with if its client
+    /// is over the limit or still penalized.
+    pub fn check(&self) -> Option<Response> {
+        let penalty_box = Penaltybox::open(COUNTER_NAME);
+        let limited = match penalty_box.has(&self.entry) {
+            Ok(true) => true,
+            Ok(false) => {
+                let window = match self.window {
+                    1 => RateWindow::OneSec,
+                    60 => RateWindow::SixtySecs,
+                    _ => RateWindow::TenSecs,
+                };
+                ERL::open(RateCounter::open(COUNTER_NAME), penalty_box)
+                    .check_rate(&self.entry, 1, window, self.limit, self.penalty)
+                    .inspect_err(|e| log::warn!("cannot check the rate of {}: {e:?}", self.entry))
+                    .unwrap_or(false)
+            }
+            Err(e) => {
+                log::warn!("cannot check the penalty box for {}: {e:?}", self.entry);
+                false
+            }
+        };
+        if !limited {
+            return None;
+        }
+        log::info!("rate limiting {}", self.entry);
+        Some(
+            Response::from_status(StatusCode::TOO_MANY_REQUESTS)
+                .with_header(header::RETRY_AFTER, self.penalty.as_secs().to_string()),
+        )
+    }
+}
+
+/// Returns the client ID of a request from its IP address.
+fn ip_client(req: &Request) -> Option<String> {
+    Some(format!("ip:{}", req.get_client_ip_addr()?))
+}
+
+/// Returns the client ID of a request from its API key. Keys are hashed, so that they are never
+/// kept by the rate limiter.
+fn api_key_client(req: &Request) -> Option<String> {
+    key_client(req.get_header_str(API_KEY_HEADER)?)
+}
+
+fn key_client(api_key: &str) -> Option<String> {
+    let api_key = api_key.trim();
+    if api_key.is_empty() {
+        return None;
+    }
+    let digest: String = Sha256::digest(api_key.as_bytes())[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Some(format!("key:{digest}"))
+}
+
+/// Returns the penalty for a number of seconds, as the edge rate limiter applies it: truncated
+/// to whole minutes, between one minute and one hour.
+fn penalty(secs: u64) -> Duration {
+    Duration::from_secs((secs / 60).clamp(1, 60) * 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn penalties_are_whole_minutes_within_an_hour() {
+        assert_eq!(penalty(300), Duration::from_secs(300));
+        assert_eq!(penalty(330), Duration::from_secs(300));
+        assert_eq!(penalty(10), Duration::from_secs(60));
+        assert_eq!(penalty(7200), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn api_keys_are_hashed() {
+        let client = key_client(" k1 ").unwrap();
+        assert!(client.starts_with("key:"));
+        assert_eq!(client.len(), 4 + 32);
+        assert_eq!(key_client("k1"), Some(client));
+        assert_ne!(key_client("k2"), key_client("k1"));
+        assert_eq!(key_client(" "), None);
+    }
+}
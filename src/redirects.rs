@@ -0,0 +1,166 @@
+//! Following internal origin redirects at the edge.
+//!
+//! When the origin answers with a 301, 302 or 307 that points to another internal URL, the edge
+//! follows the redirect itself instead of bouncing the client. Every hop is sent to the backend of
+//! the original request, with its cache key, so the final response is cached where the next client
+//! will look for it, and the intermediate redirects are never stored or delivered.
+//!
+//! Redirects are followed everywhere with the `follow_redirects` setting on, or on the routes
+//! whose `redirects=<hops>` option opts them in (see the [`routing`](crate::routing) module).
+
+use crate::config::Settings;
+use fastly::http::{header, Method, StatusCode, Url};
+use fastly::{Error, Request, Response};
+
+/// The number of redirects followed when the `redirect_max_hops` setting is absent.
+const DEFAULT_MAX_HOPS: u64 = 3;
+
+/// Which redirects are followed at the edge, read from the `follow_redirects`,
+/// `redirect_allowed_hosts` and `redirect_max_hops` settings.
+#[derive(Clone)]
+pub struct RedirectPolicy {
+    allowed_hosts: Vec<String>,
+    max_hops: u64,
+}
+
+impl RedirectPolicy {
+    /// Returns the redirect policy for a request, or `None` if redirects are not followed.
+    /// `route_hops`, the `redirects` option of the request's route, replaces the
+    /// `follow_redirects` and `redirect_max_hops` settings.
+    ///
+    /// Only `GET` and `HEAD` requests have their redirects followed.
+    pub fn for_request(
+        req: &Request,
+        settings: &Settings,
+        route_hops: Option<u64>,
+    ) -> Option<Self> {
+        if !matches!(*req.get_method(), Method::GET | Method::HEAD) {
+            return None;
+        }
+        let max_hops = match route_hops {
+            Some(hops) => hops,
+            None if settings.get_bool("follow_redirects", false) => {
+                settings.get_u64("redirect_max_hops", DEFAULT_MAX_HOPS)
+            }
+            None => 0,
+        };
+        (max_hops > 0).then(|| Self {
+            allowed_hosts: settings.get_list("redirect_allowed_hosts"),
+            max_hops,
+        })
+    }
+
+    /// Returns whether a response status is a redirect that this policy may follow.
+    pub fn is_followable_status(status: StatusCode) -> bool {
+        matches!(
+            status,
+            StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::TEMPORARY_REDIRECT
+        )
+    }
+
+    /// Resolves a `Location` header against the URL that produced it, returning the target only
+    /// if it is an internal URL: on the same host, or on one of the allow-listed hosts.
+    pub fn internal_target(&self, base: &Url, location: &str) -> Option<Url> {
+        let target = base.join(location).ok()?;
+        let host = target.host_str()?;
+        let internal = matches!(target.scheme(), "http" | "https")
+            && (Some(host) == base.host_str()
+                || self
+                    .allowed_hosts
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(host)));
+        internal.then_some(target)
+    }
+}
+
+/// Follows internal redirects in `resp`, re-sending `template` to each new location.
+///
+/// `template` must be a copy of the original request, carrying its cache key and callbacks. If the
+/// chain is longer than the policy allows, a 502 is returned rather than the intermediate redirect.
+pub fn follow(
+    policy: &RedirectPolicy,
+    template: &Request,
+    mut resp: Response,
+    backend: &str,
+) -> Result<Response, Error> {
+    let mut url = template.get_url().clone();
+    let mut hops = 0;
+
+    while RedirectPolicy::is_followable_status(resp.get_status()) {
+        let Some(target) = resp
+            .get_header_str(header::LOCATION)
+            .and_then(|location| policy.internal_target(&url, location))
+        else {
+            // External redirects are delivered to the client as usual.
+            return Ok(resp);
+        };
+
+        if hops == policy.max_hops {
+            log::warn!(
+                "redirect chain from {} exceeded {hops} hops",
+                template.get_url_str()
+            );
+            return Ok(Response::from_status(StatusCode::BAD_GATEWAY));
+        }
+        hops += 1;
+
+        let mut hop = template.clone_without_body();
+        if let Some(host) = target.host_str() {
+            hop.set_header(header::HOST, host);
+        }
+        hop.set_url(target.clone());
+        resp = hop.send(backend)?;
+        url = target;
+    }
+
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(location: &str) -> Option<String> {
+        let policy = RedirectPolicy {
+            allowed_hosts: vec!["www.example.com".to_string()],
+            max_hops: 3,
+        };
+        let base = Url::parse("https://example.com/old/page?id=7").unwrap();
+        policy
+            .internal_target(&base, location)
+            .map(|url| url.to_string())
+    }
+
+    #[test]
+    fn internal_targets_are_followed() {
+        assert_eq!(
+            target("/new/page").as_deref(),
+            Some("https://example.com/new/page")
+        );
+        assert_eq!(
+            target("sibling").as_deref(),
+            Some("https://example.com/old/sibling")
+        );
+        assert_eq!(
+            target("https://EXAMPLE.com/new").as_deref(),
+            Some("https://example.com/new")
+        );
+        assert_eq!(
+            target("https://WWW.example.com/new").as_deref(),
+            Some("https://www.example.com/new")
+        );
+        assert_eq!(
+            target("/new/page?id=7&tab=2").as_deref(),
+            Some("https://example.com/new/page?id=7&tab=2")
+        );
+    }
+
+    #[test]
+    fn foreign_targets_are_not_followed() {
+        assert_eq!(target("https://evil.example.net/new"), None);
+        assert_eq!(target("//evil.example.net/new"), None);
+        assert_eq!(target("https://example.com.evil.net/"), None);
+        assert_eq!(target("ftp://example.com/file"), None);
+        assert_eq!(target("javascript:alert(1)"), None);
+    }
+}
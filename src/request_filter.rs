@@ -0,0 +1,163 @@
+//! Filtering of unwanted requests, before they reach the cache or the origin.
+//!
+//! With the `request_filtering` setting on, every request is checked against the deny rules of
+//! the `request_filter` Config Store before anything else handles it:
+//!
+//! * `denied_methods`, comma-separated methods answered with a `405 Method Not Allowed`.
+//!   `TRACE,TRACK,CONNECT` by default;
+//! * `denied_paths`, comma-separated patterns answered with a `403 Forbidden`, such as
+//!   `*/.git/*,*.php,*<script*`. Patterns are matched against the percent-decoded and
+//!   lowercased path and query of the request, `*` matching any run of characters, so a pattern
+//!   must start with `*` to match anywhere;
+//! * `max_header_bytes`, the largest header, name and value together, that a request may carry,
+//!   or be answered with a `403 Forbidden`. `8192` by default.
+//!
+//! Rules are read for every request, so that they can be tightened during an attack without a
+//! redeploy. Blocked requests are logged with the rule they broke.
+
+use crate::config::Settings;
+use crate::{cache_rules, sigv4};
+use fastly::http::StatusCode;
+use fastly::{ConfigStore, Request, Response};
+
+/// The name of the Config Store holding the deny rules.
+const STORE_NAME: &str = "request_filter";
+
+/// The methods denied unless overridden by the `denied_methods` rule.
+const DEFAULT_DENIED_METHODS: &str = "TRACE,TRACK,CONNECT";
+
+/// The largest header allowed unless overridden by the `max_header_bytes` rule.
+const DEFAULT_MAX_HEADER_BYTES: usize = 8192;
+
+/// The deny rules of the service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestFilter {
+    denied_methods: Vec<String>,
+    denied_paths: Vec<String>,
+    max_header_bytes: usize,
+}
+
+impl RequestFilter {
+    /// Reads the deny rules, or returns `None` if requests are not filtered.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if !settings.get_bool("request_filtering", false) {
+            return None;
+        }
+        let store = ConfigStore::try_open(STORE_NAME)
+            .inspect_err(|e| log::warn!("cannot open Config Store {STORE_NAME}: {e}"))
+            .ok();
+        Some(Self::from_rules(|key| {
+            store.as_ref()?.try_get(key).ok().flatten()
+        }))
+    }
+
+    /// Returns the response blocking a request, or `None` if it is allowed.
+    pub fn check(&self, req: &Request) -> Option<Response> {
+        let method = req.get_method_str();
+        if self.denies_method(method) {
+            log::info!("blocking {method} {}: method denied", req.get_path());
+            return Some(Response::from_status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+        let target = match req.get_query_str() {
+            Some(query) => format!("{}?{query}", req.get_path()),
+            None => req.get_path().to_string(),
+        };
+        if let Some(pattern) = self.denied_path(&target) {
+            log::info!("blocking {method} {target}: matches {pattern:?}");
+            return Some(Response::from_status(StatusCode::FORBIDDEN));
+        }
+        let oversized = req
+            .get_headers()
+            .find(|(name, value)| name.as_str().len() + value.len() > self.max_header_bytes);
+        if let Some((name, _)) = oversized {
+            log::info!("blocking {method} {target}: oversized {name} header");
+            return Some(Response::from_status(StatusCode::FORBIDDEN));
+        }
+        None
+    }
+
+    fn from_rules(rule: impl Fn(&str) -> Option<String>) -> Self {
+        let list = |value: String| -> Vec<String> {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let max_header_bytes = match rule("max_header_bytes") {
+            Some(value) => value.trim().parse().unwrap_or_else(|_| {
+                log::warn!("ignoring invalid max_header_bytes rule {value:?}");
+                DEFAULT_MAX_HEADER_BYTES
+            }),
+            None => DEFAULT_MAX_HEADER_BYTES,
+        };
+        Self {
+            denied_methods: list(
+                rule("denied_methods").unwrap_or_else(|| DEFAULT_DENIED_METHODS.to_string()),
+            ),
+            denied_paths: rule("denied_paths")
+                .map(list)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|pattern| pattern.to_lowercase())
+                .collect(),
+            max_header_bytes,
+        }
+    }
+
+    fn denies_method(&self, method: &str) -> bool {
+        self.denied_methods
+            .iter()
+            .any(|denied| denied.eq_ignore_ascii_case(method))
+    }
+
+    /// Returns the pattern denying a request target, if any.
+    fn denied_path(&self, target: &str) -> Option<&str> {
+        let target = sigv4::percent_decode(target).to_lowercase();
+        self.denied_paths
+            .iter()
+            .find(|pattern| cache_rules::matches(pattern.as_bytes(), target.as_bytes()))
+            .map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn with_rules(rules: &[(&str, &str)]) -> RequestFilter {
+        let rules: HashMap<&str, &str> = rules.iter().copied().collect();
+        RequestFilter::from_rules(|key| rules.get(key).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn rules_have_defaults() {
+        let filter = with_rules(&[]);
+        assert!(filter.denies_method("trace"));
+        assert!(!filter.denies_method("GET"));
+        assert_eq!(filter.denied_path("/.git/config"), None);
+        assert_eq!(filter.max_header_bytes, DEFAULT_MAX_HEADER_BYTES);
+
+        let filter = with_rules(&[("denied_methods", ""), ("max_header_bytes", "lots")]);
+        assert!(!filter.denies_method("TRACE"));
+        assert_eq!(filter.max_header_bytes, DEFAULT_MAX_HEADER_BYTES);
+    }
+
+    #[test]
+    fn paths_are_matched_decoded_and_case_insensitively() {
+        let filter = with_rules(&[
+            ("denied_methods", "PUT, DELETE"),
+            ("denied_paths", "*/.git/*, *.PHP ,*<script*"),
+        ]);
+        assert!(filter.denies_method("DELETE"));
+        assert_eq!(filter.denied_path("/repo/.git/HEAD"), Some("*/.git/*"));
+        assert_eq!(filter.denied_path("/wp-login.php"), Some("*.php"));
+        assert_eq!(
+            filter.denied_path("/search?q=%3CSCRIPT%3Ealert(1)"),
+            Some("*<script*")
+        );
+        assert_eq!(filter.denied_path("/php/index.html"), None);
+    }
+}
@@ -0,0 +1,195 @@
+//! Dispatch of requests to routes, by method and path prefix.
+//!
+//! The `routes` setting lists routes as comma-separated entries of the form
+//! `<methods> <path prefix> <backend> [options]`, such as `GET|HEAD /assets/ assets ttl=86400`
+//! or `* /api/ api pass`. Methods are separated by `|`, and `*` matches any method. The first
+//! entry matching a request decides its route; requests matching none take the default route,
+//! which sends them to the host's backend under the usual caching policies.
+//!
+//! Options change the policies of a route:
+//!
+//! * `pass` sends requests straight to the backend, bypassing the cache and every transform;
+//! * `ttl=<seconds>` replaces the TTL chosen for responses by content type;
+//! * `swr=<seconds>` and `sie=<seconds>` set the stale-while-revalidate and stale-if-error windows
+//!   of responses, replacing those the origin asks for. Stale objects are then served while they
+//!   are revalidated in the background, and while the origin fails;
+//! * `hfp=<seconds>` sets how long hit-for-pass markers last, during which requests for an object
+//!   whose last response could not be cached are not collapsed;
+//! * `redirects=<hops>` follows internal origin redirects at the edge, up to that many hops, and
+//!   caches the final response under the original URL, whether or not the `follow_redirects`
+//!   setting is on. `redirects=0` never follows them on the route (see the
+//!   [`redirects`](crate::redirects) module);
+//! * `rate=<requests per second>` limits the rate of requests of each client on the route,
+//!   replacing the `rate_limit` setting. `rate=0` never limits them (see the
+//!   [`rate_limiting`](crate::rate_limiting) module);
+//! * `html=vary` and `html=delivery` serve pages rendered from JSON as JSON to clients whose
+//!   `Accept` header prefers it, either from a second cached variant or from the JSON object
+//!   cached alone and rendered to HTML as it is delivered to the others (see
+//!   [`HtmlNegotiation`]).
+//!
+//! Entries that cannot be parsed are logged and ignored.
+
+use crate::config::Settings;
+use crate::rate_limiting::Limit;
+use crate::transform::HtmlNegotiation;
+use fastly::Request;
+use std::time::Duration;
+
+/// The route a request is dispatched to.
+pub struct Route {
+    /// The backend the request is sent to.
+    pub backend: String,
+    /// Whether the request bypasses the cache.
+    pub pass: bool,
+    /// The TTL of responses on this route, replacing the one chosen by content type.
+    pub ttl: Option<Duration>,
+    /// The stale-while-revalidate window of responses on this route.
+    pub stale_while_revalidate: Option<Duration>,
+    /// The stale-if-error window of responses on this route.
+    pub stale_if_error: Option<Duration>,
+    /// How long hit-for-pass markers created on this route last.
+    pub hit_for_pass_ttl: Option<Duration>,
+    /// How pages rendered from JSON are negotiated with the client on this route.
+    pub html: Option<HtmlNegotiation>,
+    /// How many internal redirects are followed at the edge on this route, replacing the
+    /// `follow_redirects` and `redirect_max_hops` settings.
+    pub redirect_hops: Option<u64>,
+    /// The rate limit of clients on this route, replacing the `rate_limit` setting.
+    pub rate_limit: Option<Limit>,
+}
+
+impl Route {
+    /// Returns the route sending requests to `backend`, without any option.
+    pub fn new(backend: &str) -> Self {
+        Self {
+            backend: backend.to_string(),
+            pass: false,
+            ttl: None,
+            stale_while_revalidate: None,
+            stale_if_error: None,
+            hit_for_pass_ttl: None,
+            html: None,
+            redirect_hops: None,
+            rate_limit: None,
+        }
+    }
+
+    /// Returns the route of a request. The default route sends it to `default_backend`.
+    pub fn for_request(req: &Request, settings: &Settings, default_backend: &str) -> Self {
+        let method = req.get_method_str();
+        let path = req.get_path();
+        settings
+            .get_list("routes")
+            .iter()
+            .filter_map(|entry| {
+                let route = parse(entry);
+                if route.is_none() {
+                    log::warn!("ignoring invalid route {entry:?}");
+                }
+                route
+            })
+            .find(|(methods, prefix, _)| matches(methods, prefix, method, path))
+            .map(|(_, _, route)| route)
+            .unwrap_or_else(|| Self::new(default_backend))
+    }
+}
+
+/// Parses a route entry into its methods, its path prefix and the route itself.
+fn parse(entry: &str) -> Option<(String, String, Route)> {
+    let mut fields = entry.split_whitespace();
+    let methods = fields.next()?.to_string();
+    let prefix = fields.next().filter(|prefix| prefix.starts_with('/'))?;
+    let mut route = Route::new(fields.next()?);
+    for option in fields {
+        match option.split_once('=') {
+            None if option == "pass" => route.pass = true,
+            Some(("ttl", secs)) => route.ttl = Some(parse_secs(secs)?),
+            Some(("swr", secs)) => route.stale_while_revalidate = Some(parse_secs(secs)?),
+            Some(("sie", secs)) => route.stale_if_error = Some(parse_secs(secs)?),
+            Some(("hfp", secs)) => route.hit_for_pass_ttl = Some(parse_secs(secs)?),
+            Some(("html", mode)) => route.html = Some(HtmlNegotiation::parse(mode)?),
+            Some(("redirects", hops)) => route.redirect_hops = Some(hops.parse().ok()?),
+            Some(("rate", rate)) => {
+                route.rate_limit = Some(Limit {
+                    scope: prefix.to_string(),
+                    requests_per_sec: rate.parse().ok()?,
+                })
+            }
+            _ => return None,
+        }
+    }
+    Some((methods, prefix.to_string(), route))
+}
+
+/// Returns whether a request with `method` and `path` matches the methods and path prefix of a
+/// route entry.
+fn matches(methods: &str, prefix: &str, method: &str, path: &str) -> bool {
+    path.starts_with(prefix)
+        && (methods == "*"
+            || methods
+                .split('|')
+                .any(|allowed| allowed.eq_ignore_ascii_case(method)))
+}
+
+fn parse_secs(secs: &str) -> Option<Duration> {
+    secs.parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_parsed_with_their_options() {
+        let (methods, prefix, route) = parse("GET|HEAD /assets/ assets ttl=86400 swr=60").unwrap();
+        assert_eq!(
+            (methods.as_str(), prefix.as_str()),
+            ("GET|HEAD", "/assets/")
+        );
+        assert_eq!(route.backend, "assets");
+        assert!(!route.pass);
+        assert_eq!(route.ttl, Some(Duration::from_secs(86400)));
+        assert_eq!(route.stale_while_revalidate, Some(Duration::from_secs(60)));
+        assert_eq!(route.stale_if_error, None);
+
+        let (_, _, route) = parse("* /api/ api pass").unwrap();
+        assert!(route.pass);
+        assert_eq!(route.html, None);
+
+        let (_, _, route) = parse("GET /people/ origin html=delivery hfp=5").unwrap();
+        assert_eq!(route.html, Some(HtmlNegotiation::Delivery));
+        assert_eq!(route.hit_for_pass_ttl, Some(Duration::from_secs(5)));
+        assert_eq!(route.redirect_hops, None);
+
+        let (_, _, route) = parse("GET /docs/ docs redirects=2").unwrap();
+        assert_eq!(route.redirect_hops, Some(2));
+        assert_eq!(route.rate_limit, None);
+
+        let (_, _, route) = parse("* /api/ api pass rate=20").unwrap();
+        assert_eq!(
+            route.rate_limit,
+            Some(Limit {
+                scope: "/api/".to_string(),
+                requests_per_sec: 20,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        assert!(parse("GET assets/ assets").is_none());
+        assert!(parse("GET /assets/").is_none());
+        assert!(parse("GET /assets/ assets ttl=soon").is_none());
+        assert!(parse("GET /assets/ assets cache").is_none());
+        assert!(parse("GET /people/ origin html=json").is_none());
+        assert!(parse("GET /docs/ docs redirects=many").is_none());
+    }
+
+    #[test]
+    fn entries_match_by_method_and_prefix() {
+        assert!(matches("GET|HEAD", "/assets/", "head", "/assets/app.js"));
+        assert!(matches("*", "/api/", "DELETE", "/api/items/1"));
+        assert!(!matches("GET|HEAD", "/assets/", "POST", "/assets/app.js"));
+        assert!(!matches("*", "/api/", "GET", "/apiv2/"));
+    }
+}
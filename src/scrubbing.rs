@@ -0,0 +1,112 @@
+//! Removal of internal origin headers from cached objects and client responses.
+//!
+//! Origins often add headers meant for their own operators, such as `X-Backend-Node` or debugging
+//! headers, that should be neither stored in the shared cache nor shown to clients. Headers whose
+//! names match the `scrubbed_headers` setting are removed in the after-send callback, before the
+//! object is stored, and headers matching the `scrubbed_delivery_headers` setting are removed from
+//! every response as it is delivered, which also covers objects stored before the setting
+//! changed. Headers matching the `scrubbed_headers_allowlist` setting are never removed.
+//!
+//! Every list is comma-separated, and names ending with `*` match any header starting with the
+//! rest of the name, so that `x-*` and an allowlist of `x-edge-*` keep only the headers added at
+//! the edge.
+
+use crate::config::Settings;
+use crate::query;
+use fastly::http::CandidateResponse;
+use fastly::Response;
+
+/// The header names removed before caching and at delivery.
+#[derive(Clone, Debug)]
+pub struct HeaderScrubber {
+    stored: Vec<String>,
+    delivered: Vec<String>,
+    allowed: Vec<String>,
+}
+
+impl HeaderScrubber {
+    /// Returns the scrubber of the scrubbing settings, or `None` if no header is scrubbed.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        let list = |key| {
+            settings
+                .get_list(key)
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect::<Vec<_>>()
+        };
+        let scrubber = Self {
+            stored: list("scrubbed_headers"),
+            delivered: list("scrubbed_delivery_headers"),
+            allowed: list("scrubbed_headers_allowlist"),
+        };
+        (!scrubber.stored.is_empty() || !scrubber.delivered.is_empty()).then_some(scrubber)
+    }
+
+    /// Removes the scrubbed headers from a response before it is stored, returning their names.
+    pub fn scrub_stored(&self, resp: &mut CandidateResponse) -> Vec<String> {
+        let names = self.scrubbed(
+            &self.stored,
+            resp.get_header_names().map(|name| name.as_str()),
+        );
+        for name in &names {
+            resp.remove_header(name.as_str());
+        }
+        names
+    }
+
+    /// Removes the scrubbed headers from a response as it is delivered.
+    pub fn scrub_delivered(&self, resp: &mut Response) {
+        let names = self.scrubbed(
+            &self.delivered,
+            resp.get_header_names().map(|name| name.as_str()),
+        );
+        for name in &names {
+            resp.remove_header(name.as_str());
+        }
+    }
+
+    /// Returns the header names, among `names`, that a deny list removes.
+    fn scrubbed<'a>(&self, denied: &[String], names: impl Iterator<Item = &'a str>) -> Vec<String> {
+        let mut scrubbed: Vec<String> = names
+            .filter(|name| query::is_listed(name, denied) && !query::is_listed(name, &self.allowed))
+            .map(str::to_string)
+            .collect();
+        scrubbed.dedup();
+        scrubbed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(names: &str) -> Vec<String> {
+        names.split(',').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn denied_headers_are_scrubbed_unless_allowed() {
+        let scrubber = HeaderScrubber {
+            stored: list("x-backend-node,x-debug-*,x-*"),
+            delivered: list("server"),
+            allowed: list("x-edge-*,x-region"),
+        };
+        let names = [
+            "content-type",
+            "x-backend-node",
+            "x-debug-query-time",
+            "x-cache-tag",
+            "x-edge-transformed",
+            "x-region",
+            "server",
+        ];
+        assert_eq!(
+            scrubber.scrubbed(&scrubber.stored, names.into_iter()),
+            ["x-backend-node", "x-debug-query-time", "x-cache-tag"]
+        );
+        assert_eq!(
+            scrubber.scrubbed(&scrubber.delivered, names.into_iter()),
+            ["server"]
+        );
+    }
+}
@@ -0,0 +1,24 @@
+//! Access to credentials kept in a Secret Store.
+
+use fastly::SecretStore;
+
+/// The name of the Secret Store that holds the service credentials.
+const STORE_NAME: &str = "secrets";
+
+/// Returns the plaintext of a secret, or `None` if the Secret Store or the secret is missing.
+pub fn get(name: &str) -> Option<Vec<u8>> {
+    let store = SecretStore::open(STORE_NAME)
+        .inspect_err(|e| log::warn!("cannot open Secret Store {STORE_NAME}: {e}"))
+        .ok()?;
+    match store.try_get(name) {
+        Ok(Some(secret)) => Some(secret.plaintext().to_vec()),
+        Ok(None) => {
+            log::warn!("secret {name} is missing from Secret Store {STORE_NAME}");
+            None
+        }
+        Err(e) => {
+            log::warn!("cannot read secret {name}: {e}");
+            None
+        }
+    }
+}
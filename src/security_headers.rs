@@ -0,0 +1,205 @@
+//! Security headers, added to every response as it is delivered.
+//!
+//! With the `security_headers` setting on, every response gets a `Strict-Transport-Security`,
+//! `X-Content-Type-Options` and `Referrer-Policy` header, and a `Content-Security-Policy` from the
+//! `content_security_policy` setting if it has one. They are added after the cache, to cached and
+//! synthetic responses alike, so that a policy change applies at once rather than as objects
+//! expire, and the headers are never stored with each cached variant. Values sent by the origin
+//! are replaced.
+//!
+//! Routes can override the headers with the JSON array of the `security_header_overrides`
+//! setting, whose first entry matching the request path applies:
+//!
+//! ```json
+//! [
+//!   { "path": "/embed/*", "content-security-policy": "frame-ancestors *" },
+//!   { "path": "/legacy/*", "strict-transport-security": null }
+//! ]
+//! ```
+//!
+//! A string replaces the value of a header, and `null` leaves it out. Paths are patterns in which
+//! `*` matches any run of characters, as in cache rules. A document that cannot be parsed is
+//! logged and ignored as a whole.
+
+use crate::cache_rules;
+use crate::config::Settings;
+use fastly::{Request, Response};
+use serde_json::Value;
+
+/// The `Strict-Transport-Security` value, unless overridden: one year, for every subdomain too.
+const DEFAULT_HSTS: &str = "max-age=31536000; includeSubDomains";
+
+/// The `Referrer-Policy` value, unless overridden.
+const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+
+/// The headers that can be overridden per route.
+const HEADER_NAMES: [&str; 4] = [
+    "content-security-policy",
+    "strict-transport-security",
+    "x-content-type-options",
+    "referrer-policy",
+];
+
+/// The security headers of the responses to one request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SecurityHeaders {
+    /// Each header name with its value, or `None` if it is left out.
+    headers: Vec<(&'static str, Option<String>)>,
+}
+
+impl SecurityHeaders {
+    /// Returns the security headers of the responses to a request, or `None` if the
+    /// `security_headers` setting is off.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        if !settings.get_bool("security_headers", false) {
+            return None;
+        }
+        let mut headers = Self::defaults(settings.get("content_security_policy"));
+        if let Some(document) = settings.get("security_header_overrides") {
+            match parse(&document) {
+                Ok(overrides) => headers.apply_overrides(&overrides, req.get_path()),
+                Err(e) => log::warn!("ignoring invalid security header overrides: {e}"),
+            }
+        }
+        Some(headers)
+    }
+
+    /// Sets the security headers of a delivered response.
+    pub fn apply(&self, resp: &mut Response) {
+        for (name, value) in &self.headers {
+            match value {
+                Some(value) => resp.set_header(*name, value),
+                None => {
+                    resp.remove_header(*name);
+                }
+            }
+        }
+    }
+
+    fn defaults(content_security_policy: Option<String>) -> Self {
+        Self {
+            headers: vec![
+                (
+                    HEADER_NAMES[0],
+                    content_security_policy.filter(|policy| !policy.trim().is_empty()),
+                ),
+                (HEADER_NAMES[1], Some(DEFAULT_HSTS.to_string())),
+                (HEADER_NAMES[2], Some("nosniff".to_string())),
+                (HEADER_NAMES[3], Some(DEFAULT_REFERRER_POLICY.to_string())),
+            ],
+        }
+    }
+
+    /// Applies the first override matching a request path.
+    fn apply_overrides(&mut self, overrides: &[Override], path: &str) {
+        let Some(matching) = overrides
+            .iter()
+            .find(|entry| cache_rules::matches(entry.path.as_bytes(), path.as_bytes()))
+        else {
+            return;
+        };
+        for (name, value) in &matching.headers {
+            if let Some((_, current)) = self.headers.iter_mut().find(|(known, _)| known == name) {
+                current.clone_from(value);
+            }
+        }
+    }
+}
+
+/// A per-route override of the security headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Override {
+    path: String,
+    headers: Vec<(String, Option<String>)>,
+}
+
+/// Parses an overrides document into its entries, in order.
+fn parse(document: &str) -> Result<Vec<Override>, String> {
+    let document: Value =
+        serde_json::from_str(document).map_err(|e| format!("not valid JSON: {e}"))?;
+    let entries = document
+        .as_array()
+        .ok_or("the overrides must be a JSON array")?;
+    entries
+        .iter()
+        .map(|entry| {
+            let entry = entry
+                .as_object()
+                .ok_or("every override must be a JSON object")?;
+            let path = entry
+                .get("path")
+                .and_then(Value::as_str)
+                .filter(|path| path.starts_with('/'))
+                .ok_or("every override must have a path pattern starting with /")?;
+            let headers = entry
+                .iter()
+                .filter(|(member, _)| member.as_str() != "path")
+                .map(|(member, value)| {
+                    let name = member.to_ascii_lowercase();
+                    if !HEADER_NAMES.contains(&name.as_str()) {
+                        return Err(format!("unknown header {member:?}"));
+                    }
+                    match value {
+                        Value::Null => Ok((name, None)),
+                        Value::String(value) => Ok((name, Some(value.clone()))),
+                        _ => Err(format!("{member} must be a string or null")),
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(Override {
+                path: path.to_string(),
+                headers,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value<'a>(headers: &'a SecurityHeaders, name: &str) -> Option<&'a str> {
+        headers
+            .headers
+            .iter()
+            .find(|(known, _)| *known == name)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    #[test]
+    fn first_matching_override_applies() {
+        let overrides = parse(
+            r#"[
+                { "path": "/embed/*", "Content-Security-Policy": "frame-ancestors *" },
+                { "path": "/embed/legacy/*", "strict-transport-security": null },
+                { "path": "/legacy/*", "strict-transport-security": null }
+            ]"#,
+        )
+        .unwrap();
+
+        let mut headers = SecurityHeaders::defaults(Some("default-src 'self'".to_string()));
+        headers.apply_overrides(&overrides, "/embed/legacy/widget");
+        assert_eq!(
+            value(&headers, "content-security-policy"),
+            Some("frame-ancestors *")
+        );
+        assert_eq!(
+            value(&headers, "strict-transport-security"),
+            Some(DEFAULT_HSTS)
+        );
+
+        let mut headers = SecurityHeaders::defaults(None);
+        headers.apply_overrides(&overrides, "/legacy/page");
+        assert_eq!(value(&headers, "content-security-policy"), None);
+        assert_eq!(value(&headers, "strict-transport-security"), None);
+        assert_eq!(value(&headers, "x-content-type-options"), Some("nosniff"));
+    }
+
+    #[test]
+    fn invalid_overrides_are_rejected() {
+        assert!(parse("{}").is_err());
+        assert!(parse(r#"[{ "content-security-policy": "x" }]"#).is_err());
+        assert!(parse(r#"[{ "path": "/a", "x-frame-options": "DENY" }]"#).is_err());
+        assert!(parse(r#"[{ "path": "/a", "referrer-policy": 1 }]"#).is_err());
+    }
+}
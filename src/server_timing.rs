@@ -0,0 +1,121 @@
+//! The `Server-Timing` response header.
+//!
+//! With the `server_timing` setting on, responses tell the browser where their latency came from,
+//! so that it shows up in the developer tools of frontend teams:
+//!
+//! * `before-send` is the time spent in the before-send callback.
+//! * `origin` is the time from the end of the before-send callback to the start of the after-send
+//!   callback, which is when the origin answered.
+//! * `transform` is the time from the end of the after-send callback until the response came
+//!   back from the cache, during which the body is transformed and stored.
+//! * `cache` describes what the cache did with the request, as in the `X-Cache` header.
+//!
+//! Responses served from the cache only carry the `cache` metric.
+
+use crate::cache_status::Disposition;
+use crate::config::Settings;
+use fastly::Response;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The response header carrying the timings.
+const SERVER_TIMING_HEADER: &str = "server-timing";
+
+/// The moments marked while a request goes through the cache.
+#[derive(Default)]
+struct Marks {
+    before_send: Option<(Instant, Instant)>,
+    after_send: Option<(Instant, Instant)>,
+    send_finished: Option<Instant>,
+}
+
+/// The timings of one request.
+#[derive(Clone, Default)]
+pub struct ServerTiming {
+    marks: Arc<Mutex<Marks>>,
+}
+
+impl ServerTiming {
+    /// Starts timing a request, if the `server_timing` setting is on.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        settings
+            .get_bool("server_timing", false)
+            .then(Self::default)
+    }
+
+    /// Marks the span of the before-send callback, from `started` until now.
+    pub fn before_send(&self, started: Instant) {
+        self.mark(|marks| marks.before_send = Some((started, Instant::now())));
+    }
+
+    /// Marks the span of the after-send callback, from `started` until now.
+    pub fn after_send(&self, started: Instant) {
+        self.mark(|marks| marks.after_send = Some((started, Instant::now())));
+    }
+
+    /// Marks the moment the response came back from the cache.
+    pub fn send_finished(&self) {
+        self.mark(|marks| marks.send_finished = Some(Instant::now()));
+    }
+
+    /// Adds the `Server-Timing` header to a response.
+    pub fn apply(&self, resp: &mut Response, disposition: Disposition) {
+        let mut metrics = Vec::new();
+        if let Ok(marks) = self.marks.lock() {
+            if let Some((started, finished)) = marks.before_send {
+                metrics.push(("before-send", finished - started));
+                if let Some((after_send_started, _)) = marks.after_send {
+                    metrics.push((
+                        "origin",
+                        after_send_started.saturating_duration_since(finished),
+                    ));
+                }
+            }
+            if let (Some((_, finished)), Some(send_finished)) =
+                (marks.after_send, marks.send_finished)
+            {
+                metrics.push((
+                    "transform",
+                    send_finished.saturating_duration_since(finished),
+                ));
+            }
+        }
+        resp.append_header(SERVER_TIMING_HEADER, header_value(&metrics, disposition));
+    }
+
+    fn mark(&self, f: impl FnOnce(&mut Marks)) {
+        if let Ok(mut marks) = self.marks.lock() {
+            f(&mut marks);
+        }
+    }
+}
+
+/// Returns the `Server-Timing` value of some durations, in milliseconds, and a cache disposition.
+fn header_value(metrics: &[(&str, Duration)], disposition: Disposition) -> String {
+    metrics
+        .iter()
+        .map(|(name, duration)| format!("{name};dur={:.1}", duration.as_secs_f64() * 1000.0))
+        .chain([format!("cache;desc={}", disposition.as_str())])
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_in_milliseconds() {
+        assert_eq!(
+            header_value(
+                &[
+                    ("origin", Duration::from_micros(123_456)),
+                    ("transform", Duration::from_millis(2)),
+                ],
+                Disposition::Miss
+            ),
+            "origin;dur=123.5, transform;dur=2.0, cache;desc=MISS"
+        );
+        assert_eq!(header_value(&[], Disposition::Hit), "cache;desc=HIT");
+    }
+}
@@ -0,0 +1,89 @@
+//! Signed URLs for cached assets.
+//!
+//! Requests under the path prefixes in the `signed_url_prefixes` setting must carry a signature
+//! and an expiry in their query string, as `?expires=<unix time>&signature=<hex signature>`. The
+//! signature is the hex-encoded HMAC-SHA256 of `<path>:<expires>` under the `signed_url_key`
+//! secret, so a signed URL grants access to one path until it expires. Requests without a valid
+//! signature are answered with a 403 before the cache is reached.
+//!
+//! Both parameters are removed from the request once checked, so they are left out of the cache
+//! key, and every holder of a valid URL shares the same cached object.
+
+use crate::config::Settings;
+use crate::{query, secrets, signing};
+use fastly::Request;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The query parameter carrying the expiry of a signed URL.
+const EXPIRES_PARAM: &str = "expires";
+
+/// The query parameter carrying the signature of a signed URL.
+const SIGNATURE_PARAM: &str = "signature";
+
+/// The name of the secret used to sign URLs.
+const KEY_SECRET: &str = "signed_url_key";
+
+/// Returns whether a request must carry a signed URL.
+pub fn is_required(req: &Request, settings: &Settings) -> bool {
+    let path = req.get_path();
+    settings
+        .get_list("signed_url_prefixes")
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+}
+
+/// Checks the signature of a request URL, removing the signature parameters from it.
+pub fn authorize(req: &mut Request) -> bool {
+    let expires = query::take_param(req, EXPIRES_PARAM);
+    let signature = query::take_param(req, SIGNATURE_PARAM);
+    let (Some(expires), Some(signature)) = (expires, signature) else {
+        return false;
+    };
+    let Some(key) = secrets::get(KEY_SECRET) else {
+        return false;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    verify(req.get_path(), &expires, &signature, &key, now)
+}
+
+fn verify(path: &str, expires: &str, signature: &str, key: &[u8], now: u64) -> bool {
+    expires.parse::<u64>().is_ok_and(|expires| expires > now)
+        && signing::verify_hex(key, format!("{path}:{expires}").as_bytes(), signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    const KEY: &[u8] = b"secret";
+
+    fn sign(path: &str, expires: u64) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(KEY).unwrap();
+        mac.update(format!("{path}:{expires}").as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    #[test]
+    fn valid_signatures_are_accepted() {
+        let signature = sign("/assets/report.pdf", 2000);
+        assert!(verify("/assets/report.pdf", "2000", &signature, KEY, 1000));
+    }
+
+    #[test]
+    fn expired_or_foreign_signatures_are_rejected() {
+        let signature = sign("/assets/report.pdf", 2000);
+        assert!(!verify("/assets/report.pdf", "2000", &signature, KEY, 2000));
+        assert!(!verify("/assets/other.pdf", "2000", &signature, KEY, 1000));
+        assert!(!verify("/assets/report.pdf", "3000", &signature, KEY, 1000));
+        assert!(!verify("/assets/report.pdf", "soon", &signature, KEY, 1000));
+    }
+}
@@ -0,0 +1,47 @@
+//! HMAC-SHA256 signatures for tokens and signed URLs.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Returns whether `signature_hex` is the hex-encoded HMAC-SHA256 of `message` under `key`.
+///
+/// The comparison is performed in constant time.
+pub fn verify_hex(key: &[u8], message: &[u8], signature_hex: &str) -> bool {
+    let Some(signature) = decode_hex(signature_hex) else {
+        return false;
+    };
+    let Ok(mut mac) = HmacSha256::new_from_slice(key) else {
+        return false;
+    };
+    mac.update(message);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Returns the hex-encoded HMAC-SHA256 of `message` under `key`.
+pub fn sign_hex(key: &[u8], message: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Returns whether two byte strings are equal, taking the same time wherever they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Decodes a hex string, accepting either case.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,382 @@
+//! AWS Signature Version 4 signing of requests to S3-compatible origins.
+//!
+//! Private buckets on S3, or on S3-compatible storage such as Google Cloud Storage in
+//! interoperability mode, only answer signed requests. Requests sent to the backends listed in
+//! the `s3_backends` setting are signed in the before-send callback, so that the bucket can be
+//! the cacheable origin: only requests that reach the origin are signed, and the signature never
+//! takes part in the cache lookup.
+//!
+//! The bucket host is set by the `s3_host` setting, such as `my-bucket.s3.eu-west-1.amazonaws.com`
+//! or `storage.googleapis.com`, and sent as the `Host` of signed requests. The region and service
+//! of the signature are set by the `s3_region` and `s3_service` settings, `us-east-1` and `s3` by
+//! default. The access key is read from the `aws_access_key_id` and `aws_secret_access_key`
+//! secrets, along with `aws_session_token` for temporary credentials, if present.
+//!
+//! Request bodies are not hashed: requests are signed with `UNSIGNED-PAYLOAD`, which S3 accepts
+//! over HTTPS. Requests are forwarded unsigned if the credentials are missing, and the bucket
+//! answers them with an error of its own.
+
+use crate::config::Settings;
+use crate::secrets;
+use fastly::http::header;
+use fastly::Request;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The payload hash of requests whose body is not signed.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// The signing algorithm, as named in the `Authorization` header.
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// The signer of the requests sent to an S3-compatible backend.
+#[derive(Clone)]
+pub struct Signer {
+    host: String,
+    region: String,
+    service: String,
+}
+
+/// The credentials requests are signed with.
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Signer {
+    /// Returns the signer of requests sent to a backend, or `None` if the backend is not listed
+    /// in the `s3_backends` setting, or no bucket host is set.
+    pub fn for_backend(backend: &str, settings: &Settings) -> Option<Self> {
+        if !settings
+            .get_list("s3_backends")
+            .iter()
+            .any(|listed| listed == backend)
+        {
+            return None;
+        }
+        let Some(host) = settings.get("s3_host") else {
+            log::warn!("not signing requests to {backend}: s3_host is not set");
+            return None;
+        };
+        Some(Self {
+            host,
+            region: settings
+                .get("s3_region")
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            service: settings
+                .get("s3_service")
+                .unwrap_or_else(|| "s3".to_string()),
+        })
+    }
+
+    /// Signs a request forwarded to the bucket, replacing its `Authorization` header.
+    pub fn sign(&self, req: &mut Request) {
+        req.set_header(header::HOST, &self.host);
+        let Some(credentials) = Credentials::from_secrets() else {
+            log::warn!("forwarding an unsigned request to {}", self.host);
+            req.remove_header(header::AUTHORIZATION);
+            return;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let amz_date = amz_date(now);
+
+        let mut headers = vec![
+            ("host".to_string(), self.host.clone()),
+            (
+                "x-amz-content-sha256".to_string(),
+                UNSIGNED_PAYLOAD.to_string(),
+            ),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        let query: Vec<(String, String)> = req
+            .get_url()
+            .query_pairs()
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        let canonical_request = canonical_request(
+            req.get_method_str(),
+            req.get_path(),
+            &query,
+            &headers,
+            UNSIGNED_PAYLOAD,
+        );
+        let scope = format!(
+            "{}/{}/{}/aws4_request",
+            &amz_date[..8],
+            self.region,
+            self.service
+        );
+        let signature = signature(
+            &credentials.secret_access_key,
+            &amz_date,
+            &self.region,
+            &self.service,
+            &canonical_request,
+        );
+
+        for (name, value) in &headers {
+            req.set_header(name, value);
+        }
+        req.set_header(
+            header::AUTHORIZATION,
+            format!(
+                "{ALGORITHM} Credential={}/{scope}, SignedHeaders={}, Signature={signature}",
+                credentials.access_key_id,
+                signed_headers(&headers)
+            ),
+        );
+    }
+}
+
+impl Credentials {
+    /// Reads the credentials from the Secret Store.
+    fn from_secrets() -> Option<Self> {
+        let read = |name| {
+            secrets::get(name)
+                .and_then(|secret| String::from_utf8(secret).ok())
+                .map(|secret| secret.trim().to_string())
+        };
+        Some(Self {
+            access_key_id: read("aws_access_key_id")?,
+            secret_access_key: read("aws_secret_access_key")?,
+            session_token: read("aws_session_token"),
+        })
+    }
+}
+
+/// Returns the canonical form of a request, which is what the signature covers.
+///
+/// Header names must be lowercase. The path is given as it appears in the URL, and the query
+/// parameters decoded.
+fn canonical_request(
+    method: &str,
+    path: &str,
+    query: &[(String, String)],
+    headers: &[(String, String)],
+    payload_hash: &str,
+) -> String {
+    let mut query: Vec<(String, String)> = query
+        .iter()
+        .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+        .collect();
+    query.sort();
+    let query: Vec<String> = query
+        .into_iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+
+    let mut headers: Vec<&(String, String)> = headers.iter().collect();
+    headers.sort();
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+        .collect();
+
+    format!(
+        "{method}\n{}\n{}\n{canonical_headers}\n{}\n{payload_hash}",
+        uri_encode(&percent_decode(path), false),
+        query.join("&"),
+        signed_headers(&headers.into_iter().cloned().collect::<Vec<_>>()),
+    )
+}
+
+/// Returns the sorted, semicolon-separated names of the signed headers.
+fn signed_headers(headers: &[(String, String)]) -> String {
+    let mut names: Vec<&str> = headers.iter().map(|(name, _)| name.as_str()).collect();
+    names.sort_unstable();
+    names.join(";")
+}
+
+/// Returns the hex-encoded signature of a canonical request, made at `amz_date`.
+fn signature(
+    secret_access_key: &str,
+    amz_date: &str,
+    region: &str,
+    service: &str,
+    canonical_request: &str,
+) -> String {
+    let date = &amz_date[..8];
+    let string_to_sign = format!(
+        "{ALGORITHM}\n{amz_date}\n{date}/{region}/{service}/aws4_request\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = [date, region, service, "aws4_request"].iter().fold(
+        format!("AWS4{secret_access_key}").into_bytes(),
+        |key, part| hmac(&key, part.as_bytes()),
+    );
+    hex(&hmac(&key, string_to_sign.as_bytes()))
+}
+
+/// Returns the HMAC-SHA256 of a message.
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encodes bytes as lowercase hex.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Percent-encodes every byte of a string but the unreserved characters, and `/` unless
+/// `encode_slash` is set.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Decodes the percent-encoded bytes of a URL path.
+pub fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Formats a Unix time as an ISO 8601 basic timestamp, such as `20130524T000000Z`.
+fn amz_date(unix_secs: u64) -> String {
+    let days = (unix_secs / 86_400) as i64;
+    let secs = unix_secs % 86_400;
+    // Converts days since 1970-01-01 to a civil date in the proleptic Gregorian calendar.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_PAYLOAD_HASH: &str =
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // The GET Object example of the Amazon S3 Signature Version 4 documentation.
+    #[test]
+    fn canonical_request_and_signature_match_the_aws_example() {
+        let canonical_request = canonical_request(
+            "GET",
+            "/test.txt",
+            &[],
+            &headers(&[
+                ("x-amz-date", "20130524T000000Z"),
+                ("host", "examplebucket.s3.amazonaws.com"),
+                ("range", "bytes=0-9"),
+                ("x-amz-content-sha256", EMPTY_PAYLOAD_HASH),
+            ]),
+            EMPTY_PAYLOAD_HASH,
+        );
+        assert_eq!(
+            canonical_request,
+            "GET\n/test.txt\n\n\
+             host:examplebucket.s3.amazonaws.com\n\
+             range:bytes=0-9\n\
+             x-amz-content-sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n\
+             x-amz-date:20130524T000000Z\n\n\
+             host;range;x-amz-content-sha256;x-amz-date\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            signature(
+                "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+                "20130524T000000Z",
+                "us-east-1",
+                "s3",
+                &canonical_request
+            ),
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[test]
+    fn query_parameters_are_encoded_and_sorted() {
+        let canonical_request = canonical_request(
+            "GET",
+            "/",
+            &[
+                ("prefix".to_string(), "photos/2024 summer".to_string()),
+                ("list-type".to_string(), "2".to_string()),
+                ("acl".to_string(), String::new()),
+            ],
+            &headers(&[("host", "bucket.example")]),
+            UNSIGNED_PAYLOAD,
+        );
+        assert!(canonical_request
+            .starts_with("GET\n/\nacl=&list-type=2&prefix=photos%2F2024%20summer\nhost:"));
+    }
+
+    #[test]
+    fn paths_are_encoded_once() {
+        let canonical_request = canonical_request(
+            "GET",
+            "/photos/summer%20beach(1).jpg",
+            &[],
+            &headers(&[("host", "bucket.example")]),
+            UNSIGNED_PAYLOAD,
+        );
+        assert!(canonical_request.starts_with("GET\n/photos/summer%20beach%281%29.jpg\n"));
+    }
+
+    #[test]
+    fn dates_are_formatted() {
+        assert_eq!(amz_date(1_369_353_600), "20130524T000000Z");
+        assert_eq!(amz_date(951_827_696), "20000229T123456Z");
+        assert_eq!(amz_date(0), "19700101T000000Z");
+    }
+}
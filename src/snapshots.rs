@@ -0,0 +1,165 @@
+//! Disaster recovery from page snapshots kept in a KV Store.
+//!
+//! Pages under the path prefixes in the `snapshot_path_prefixes` setting are copied to the
+//! `snapshots` KV Store when they are fetched from the origin and cacheable, at most once every
+//! `snapshot_interval` seconds per page. While the `disaster_mode` setting is on, a request for
+//! one of these pages that the origin cannot answer is served from its snapshot, with a banner
+//! telling readers that they are looking at a saved copy.
+
+use crate::banner;
+use crate::config::Settings;
+use fastly::cache::simple::{self, CacheEntry};
+use fastly::http::{header, CandidateResponse, Method, StatusCode};
+use fastly::kv_store::KVStore;
+use fastly::{Request, Response};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The name of the KV Store holding the page snapshots.
+const STORE_NAME: &str = "snapshots";
+
+/// The minimum time between two snapshots of a page, unless overridden by the
+/// `snapshot_interval` setting (in seconds).
+const DEFAULT_INTERVAL_SECS: u64 = 300;
+
+/// The response header carrying the time a served snapshot was taken.
+const SNAPSHOT_HEADER: &str = "x-snapshot-date";
+
+/// Snapshots of one page.
+#[derive(Clone)]
+pub struct Snapshotter {
+    key: String,
+    interval: Duration,
+    cacheable_fetch: Arc<AtomicBool>,
+}
+
+impl Snapshotter {
+    /// Returns the snapshotter of a page, or `None` if the page is not snapshotted.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        let path = req.get_path();
+        if req.get_method() != Method::GET
+            || !settings
+                .get_list("snapshot_path_prefixes")
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+        let page = match req.get_query_str() {
+            Some(query) => format!("{path}?{query}"),
+            None => path.to_string(),
+        };
+        let key = Sha256::digest(page.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        Some(Self {
+            key,
+            interval: Duration::from_secs(
+                settings.get_u64("snapshot_interval", DEFAULT_INTERVAL_SECS),
+            ),
+            cacheable_fetch: Arc::default(),
+        })
+    }
+
+    /// Notes whether a response received from the origin is a cacheable copy of the page.
+    pub fn note_fetch(&self, resp: &CandidateResponse) {
+        let cacheable = resp.get_status() == StatusCode::OK && resp.is_cacheable();
+        self.cacheable_fetch.store(cacheable, Ordering::Relaxed);
+    }
+
+    /// Saves a response as the snapshot of the page, if it was freshly fetched from the origin and
+    /// cacheable, and the last snapshot is old enough.
+    pub fn save(&self, resp: &mut Response) {
+        if !self.cacheable_fetch.load(Ordering::Relaxed) || !self.is_due() {
+            return;
+        }
+
+        let metadata = json!({
+            "content_type": resp.get_header_str(header::CONTENT_TYPE),
+            "content_encoding": resp.get_header_str(header::CONTENT_ENCODING),
+            "date": resp.get_header_str(header::DATE),
+        });
+        let body = resp.take_body_bytes();
+        let result = KVStore::open(STORE_NAME).and_then(|store| match store {
+            Some(store) => store
+                .build_insert()
+                .metadata(&metadata.to_string())
+                .execute(&self.key, body.as_slice()),
+            None => {
+                log::warn!("KV Store {STORE_NAME} is not linked; not saving snapshot");
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            log::warn!("cannot save snapshot: {e}");
+        }
+        resp.set_body(body);
+    }
+
+    /// Returns the snapshot of the page, with a banner saying that it is a saved copy.
+    pub fn serve(&self) -> Option<Response> {
+        let store = KVStore::open(STORE_NAME).ok().flatten()?;
+        let mut found = store.lookup(&self.key).ok()?;
+        let metadata: Value = found
+            .metadata()
+            .and_then(|metadata| serde_json::from_slice(&metadata).ok())
+            .unwrap_or_default();
+
+        let mut resp =
+            Response::from_body(found.take_body()).with_header(header::CACHE_CONTROL, "no-store");
+        if let Some(content_type) = metadata["content_type"].as_str() {
+            resp.set_header(header::CONTENT_TYPE, content_type);
+        }
+        if let Some(content_encoding) = metadata["content_encoding"].as_str() {
+            resp.set_header(header::CONTENT_ENCODING, content_encoding);
+        }
+        let date = metadata["date"].as_str();
+        if let Some(date) = date {
+            resp.set_header(SNAPSHOT_HEADER, date);
+        }
+
+        let saved = match date {
+            Some(date) => format!(" from {}", escape_html(date)),
+            None => String::new(),
+        };
+        let notice = format!(
+            "<div class=\"edge-snapshot-notice\" role=\"status\">This is a saved copy of this \
+             page{saved}, shown because the site is temporarily unavailable. It may be out of \
+             date.</div>"
+        );
+        Some(banner::insert_at_body_start(resp, &notice))
+    }
+
+    /// Returns whether a new snapshot should be taken, claiming the slot for it if so.
+    fn is_due(&self) -> bool {
+        let mut due = false;
+        let interval = self.interval;
+        let result = simple::get_or_set_with(format!("snapshot/{}", self.key), || {
+            due = true;
+            Ok(CacheEntry {
+                value: "1".into(),
+                ttl: interval,
+            })
+        });
+        result.is_ok() && due
+    }
+}
+
+/// Returns whether a response means that the origin could not serve the page.
+pub fn is_origin_failure(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,197 @@
+//! Stale copies of cached objects, served when the origin fails.
+//!
+//! With the `serve_stale_on_error` setting on, responses cached from the origin get a
+//! stale-if-error window of at least `stale_on_error_window` seconds, and a copy of each is kept
+//! in the Core Cache for as long as it could be served stale. When the origin cannot be reached,
+//! or answers with a 5xx, that copy is looked up explicitly and served instead, with an
+//! `X-Served-Stale: 1` header, so the fallback does not depend on the readthrough cache still
+//! holding the object. Copies vary on the request headers named in the `Vary` header of their
+//! response, like the objects they are copied from.
+//!
+//! When no stale copy exists, requests the origin could not be reached for are answered with the
+//! outage page of the [`errors`](crate::errors) module, while 5xx responses from the origin are
+//! delivered as they are.
+
+use crate::config::Settings;
+use fastly::cache::core::{self, CacheKey};
+use fastly::http::{header, CandidateResponse, HeaderName, HeaderValue, Method, StatusCode};
+use fastly::{Request, Response};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The response header marking a stale copy.
+pub const STALE_HEADER: HeaderName = HeaderName::from_static("x-served-stale");
+
+/// The shortest stale-if-error window of cached responses, unless overridden by the
+/// `stale_on_error_window` setting (in seconds).
+const DEFAULT_WINDOW_SECS: u64 = 86400;
+
+/// The prefix of the Core Cache keys of stale copies, keeping them apart from other objects.
+const KEY_PREFIX: &[u8] = b"stale/";
+
+/// Response headers that are not kept with a stale copy.
+const DROPPED_HEADERS: [HeaderName; 3] = [
+    header::CONTENT_LENGTH,
+    header::SET_COOKIE,
+    header::TRANSFER_ENCODING,
+];
+
+/// The stale copy of one object.
+#[derive(Clone)]
+pub struct StaleFallback {
+    key: CacheKey,
+    request_headers: Vec<(HeaderName, HeaderValue)>,
+    window: Duration,
+    /// How long the copy of the response just fetched from the origin should be kept, if it
+    /// should be kept at all.
+    fetched: Arc<Mutex<Option<Duration>>>,
+}
+
+impl StaleFallback {
+    /// Returns the stale fallback of a request cached under `cache_key`, or `None` if it is not a
+    /// `GET` or `HEAD` request, or stale copies are not served.
+    pub fn for_request(req: &Request, settings: &Settings, cache_key: &[u8]) -> Option<Self> {
+        if !settings.get_bool("serve_stale_on_error", false)
+            || !matches!(*req.get_method(), Method::GET | Method::HEAD)
+        {
+            return None;
+        }
+        Some(Self {
+            key: [KEY_PREFIX, cache_key].concat().into(),
+            request_headers: req
+                .get_headers()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            window: Duration::from_secs(
+                settings.get_u64("stale_on_error_window", DEFAULT_WINDOW_SECS),
+            ),
+            fetched: Arc::default(),
+        })
+    }
+
+    /// Lengthens the stale-if-error window of a response received from the origin, and notes
+    /// how long its copy should be kept if it is cacheable.
+    pub fn note_fetch(&self, resp: &mut CandidateResponse) {
+        let kept = (resp.get_status() == StatusCode::OK && resp.is_cacheable()).then(|| {
+            if resp.get_stale_if_error() < self.window {
+                resp.set_stale_if_error(self.window);
+            }
+            resp.get_ttl().saturating_sub(resp.get_age()) + resp.get_stale_if_error()
+        });
+        if let Ok(mut fetched) = self.fetched.lock() {
+            *fetched = kept;
+        }
+    }
+
+    /// Saves a copy of a response, if it was freshly fetched from the origin and cacheable.
+    pub fn save(&self, resp: &mut Response) {
+        let Some(lifetime) = self
+            .fetched
+            .lock()
+            .ok()
+            .and_then(|mut fetched| fetched.take())
+        else {
+            return;
+        };
+        let Some(vary) = vary_names(resp.get_header_all_str(header::VARY)) else {
+            return;
+        };
+
+        let headers: Vec<(&str, &str)> = resp
+            .get_headers()
+            .filter(|(name, _)| !DROPPED_HEADERS.contains(name))
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+            .collect();
+        let metadata = json!({ "status": resp.get_status().as_u16(), "headers": headers });
+        let body = resp.take_body_bytes();
+        let result = self
+            .request_headers
+            .iter()
+            .fold(
+                core::insert(self.key.clone(), lifetime),
+                |insert, (name, value)| insert.header(name, value),
+            )
+            .vary_by(&vary)
+            .known_length(body.len() as u64)
+            .user_metadata(metadata.to_string().into())
+            .execute()
+            .map_err(|e| e.to_string())
+            .and_then(|mut stream| {
+                stream.write_all(&body).map_err(|e| e.to_string())?;
+                stream.finish().map_err(|e| e.to_string())
+            });
+        if let Err(e) = result {
+            log::warn!("cannot save stale copy: {e}");
+        }
+        resp.set_body(body);
+    }
+
+    /// Returns the stale copy of the object, if one was kept.
+    pub fn serve(&self) -> Option<Response> {
+        let found = self
+            .request_headers
+            .iter()
+            .fold(core::lookup(self.key.clone()), |lookup, (name, value)| {
+                lookup.header(name, value)
+            })
+            .execute()
+            .ok()
+            .flatten()?;
+        let metadata: Value = serde_json::from_slice(&found.user_metadata()).ok()?;
+        let status = metadata["status"]
+            .as_u64()
+            .and_then(|status| StatusCode::from_u16(u16::try_from(status).ok()?).ok())?;
+
+        let mut resp = Response::from_body(found.to_stream().ok()?).with_status(status);
+        for header in metadata["headers"].as_array().into_iter().flatten() {
+            if let (Some(name), Some(value)) = (header[0].as_str(), header[1].as_str()) {
+                resp.append_header(name, value);
+            }
+        }
+        resp.set_header(header::AGE, found.age().as_secs().to_string());
+        resp.set_header(STALE_HEADER, "1");
+        Some(resp)
+    }
+}
+
+/// Returns the request headers named in the `Vary` values of a response, or `None` if it varies
+/// on `*` and cannot be copied.
+fn vary_names<'a>(values: impl IntoIterator<Item = &'a str>) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for name in values.into_iter().flat_map(|value| value.split(',')) {
+        let name = name.trim();
+        if name == "*" {
+            return None;
+        }
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    Some(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vary_names_are_collected_once() {
+        assert_eq!(
+            vary_names(["Accept-Encoding, X-Device-Class", "accept-encoding"]),
+            Some(vec![
+                header::ACCEPT_ENCODING,
+                HeaderName::from_static("x-device-class")
+            ])
+        );
+        assert_eq!(vary_names([]), Some(vec![]));
+    }
+
+    #[test]
+    fn responses_varying_on_everything_are_not_copied() {
+        assert_eq!(vary_names(["Accept-Encoding, *"]), None);
+    }
+}
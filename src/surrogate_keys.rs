@@ -0,0 +1,214 @@
+//! Generation and sharding of surrogate keys.
+//!
+//! With the `surrogate_key_generation` setting on, responses are tagged with surrogate keys
+//! derived from the request and the response, besides the keys sent by the origin in its
+//! `Surrogate-Key` header:
+//!
+//! * `path:<prefix>` for each of the first few path prefixes, such as `path:/products` and
+//!   `path:/products/123` for `/products/123`;
+//! * `type:<media type>` for the content type, such as `type:text/html`;
+//! * `host:<host>` for the request host.
+//!
+//! Keys are deduplicated, so that every object can be purged by any of them.
+//!
+//! A surrogate key such as `product-123` can end up attached to a huge number of pages, which
+//! makes every purge of it a large operation. Keys starting with one of the prefixes in the
+//! `sharded_surrogate_key_prefixes` setting are therefore split into `surrogate_key_shards`
+//! shards: each page gets the key with a shard suffix chosen by hashing its URL, such as
+//! `product-123-5`, and purging the entity fans out over all of its shards. Keys too long to take
+//! a shard suffix are left whole.
+
+use crate::config::Settings;
+use fastly::http::{header, CandidateResponse};
+use fastly::Request;
+use sha2::{Digest, Sha256};
+
+/// The number of path segments for which prefix keys are generated.
+const MAX_PATH_DEPTH: usize = 4;
+
+/// The longest surrogate key generated. Longer ones are skipped.
+const MAX_KEY_LEN: usize = 1024;
+
+/// The number of shards of a sharded key, unless overridden by the `surrogate_key_shards`
+/// setting.
+const DEFAULT_SHARDS: u64 = 8;
+
+/// The surrogate keys generated from the request for one page.
+#[derive(Clone)]
+pub struct Generator {
+    request_keys: Vec<String>,
+}
+
+impl Generator {
+    /// Returns the generator of a page's keys, or `None` if surrogate keys are not generated.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        if !settings.get_bool("surrogate_key_generation", false) {
+            return None;
+        }
+        let mut request_keys = Vec::new();
+        let mut prefix = String::new();
+        for segment in req
+            .get_path()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .take(MAX_PATH_DEPTH)
+        {
+            prefix.push('/');
+            prefix.push_str(segment);
+            request_keys.push(format!("path:{prefix}"));
+        }
+        if let Some(host) = req
+            .get_header_str(header::HOST)
+            .or_else(|| req.get_url().host_str())
+        {
+            request_keys.push(format!("host:{}", host.to_ascii_lowercase()));
+        }
+        Some(Self { request_keys })
+    }
+
+    /// Adds the generated keys to the surrogate keys of a response, after the origin's own, and
+    /// removes duplicates.
+    pub fn apply(&self, resp: &mut CandidateResponse) {
+        let type_key = resp
+            .get_header_str(header::CONTENT_TYPE)
+            .map(|content_type| {
+                let essence = content_type.split(';').next().unwrap_or_default();
+                format!("type:{}", essence.trim().to_ascii_lowercase())
+            });
+        let mut keys: Vec<String> = Vec::new();
+        for key in resp
+            .get_surrogate_keys()
+            .map(str::to_string)
+            .chain(self.request_keys.iter().cloned())
+            .chain(type_key)
+        {
+            let is_valid = !key.is_empty()
+                && key.len() <= MAX_KEY_LEN
+                && !key.chars().any(|c| c.is_whitespace() || c.is_control());
+            if is_valid && !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        resp.set_surrogate_keys(keys.iter().map(String::as_str));
+    }
+}
+
+/// The surrogate key sharding of one page.
+#[derive(Clone)]
+pub struct Sharding {
+    prefixes: Vec<String>,
+    shard: u64,
+}
+
+impl Sharding {
+    /// Returns the sharding of a page, or `None` if no surrogate key is sharded.
+    pub fn for_request(req: &Request, settings: &Settings) -> Option<Self> {
+        let prefixes = settings.get_list("sharded_surrogate_key_prefixes");
+        if prefixes.is_empty() {
+            return None;
+        }
+        Some(Self {
+            prefixes,
+            shard: shard_of(req.get_url_str(), shard_count(settings)),
+        })
+    }
+
+    /// Replaces the sharded surrogate keys of a response with the shard of this page.
+    pub fn apply(&self, resp: &mut CandidateResponse) {
+        let keys = self.shard(resp.get_surrogate_keys());
+        resp.set_surrogate_keys(keys.iter().map(String::as_str));
+    }
+
+    /// Returns surrogate keys with the sharded ones replaced by the shard of this page.
+    fn shard<'a>(&self, keys: impl Iterator<Item = &'a str>) -> Vec<String> {
+        keys.filter(|key| !key.is_empty())
+            .map(|key| {
+                let sharded = format!("{key}-{}", self.shard);
+                if self.is_sharded(key) && sharded.len() <= MAX_KEY_LEN {
+                    sharded
+                } else {
+                    key.to_string()
+                }
+            })
+            .collect()
+    }
+
+    fn is_sharded(&self, key: &str) -> bool {
+        self.prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+}
+
+/// Returns the surrogate keys to purge in order to purge `key`: the key itself, and all of its
+/// shards if it is sharded.
+pub fn purge_keys(key: &str, settings: &Settings) -> Vec<String> {
+    let mut keys = vec![key.to_string()];
+    if settings
+        .get_list("sharded_surrogate_key_prefixes")
+        .iter()
+        .any(|prefix| key.starts_with(prefix.as_str()))
+    {
+        keys.extend((0..shard_count(settings)).map(|shard| format!("{key}-{shard}")));
+    }
+    keys
+}
+
+/// Returns the shard of the page at a URL, out of `shards`.
+fn shard_of(url: &str, shards: u64) -> u64 {
+    let digest = Sha256::digest(url.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap_or_default()) % shards
+}
+
+fn shard_count(settings: &Settings) -> u64 {
+    settings
+        .get_u64("surrogate_key_shards", DEFAULT_SHARDS)
+        .max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sharding(shard: u64) -> Sharding {
+        Sharding {
+            prefixes: vec!["product-".to_string()],
+            shard,
+        }
+    }
+
+    #[test]
+    fn sharded_keys_stay_whole() {
+        assert_eq!(
+            sharding(5).shard(["product-123", "category-9", "", "product-"].into_iter()),
+            ["product-123-5", "category-9", "product--5"]
+        );
+        for url in ["https://example.com/a", "https://example.com/b?page=2"] {
+            let shard = shard_of(url, 8);
+            assert!(shard < 8);
+            assert_eq!(shard_of(url, 8), shard);
+            let keys = sharding(shard).shard(["product-123"].into_iter());
+            assert_eq!(keys, [format!("product-123-{shard}")]);
+            assert_eq!(keys[0].rsplit_once('-').unwrap().0, "product-123");
+        }
+    }
+
+    #[test]
+    fn keys_too_long_for_a_shard_suffix_are_left_whole() {
+        let fits = format!("product-{}", "x".repeat(MAX_KEY_LEN - "product-".len() - 2));
+        let too_long = format!("{fits}x");
+        assert_eq!(
+            sharding(5).shard([fits.as_str()].into_iter()),
+            [format!("{fits}-5")]
+        );
+        assert_eq!(
+            sharding(5).shard([fits.as_str()].into_iter())[0].len(),
+            MAX_KEY_LEN
+        );
+        assert_eq!(
+            sharding(5).shard([too_long.as_str()].into_iter()),
+            [too_long]
+        );
+        assert_eq!(sharding(12).shard([fits.as_str()].into_iter()), [fits]);
+    }
+}
@@ -0,0 +1,52 @@
+//! Per-tenant partitioning of the cache for multi-tenant APIs.
+//!
+//! Clients authenticate with an API key in the `X-API-Key` header. The tenant owning the key is
+//! looked up in the `api_keys` Config Store, whose keys are the hex-encoded SHA-256 digests of the
+//! API keys and whose values are tenant IDs, so that the API keys themselves are never stored.
+//!
+//! The tenant ID becomes the namespace of the cache key, so tenants can never observe each other's
+//! cached responses. Endpoints listed as public share a single namespace and need no API key.
+
+use crate::config::Settings;
+use fastly::{ConfigStore, Request};
+use sha2::{Digest, Sha256};
+
+/// The name of the Config Store mapping API key digests to tenant IDs.
+const API_KEYS_STORE_NAME: &str = "api_keys";
+
+/// The request header carrying the API key.
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// The cache namespace shared by public endpoints.
+const PUBLIC_NAMESPACE: &str = "public";
+
+/// Returns the cache namespace of an API request, or `None` if the request is for a private
+/// endpoint and carries no valid API key.
+///
+/// Public endpoints are those under one of the path prefixes in the `public_api_prefixes`
+/// setting.
+pub fn cache_namespace(req: &Request, settings: &Settings) -> Option<String> {
+    let path = req.get_path();
+    if settings
+        .get_list("public_api_prefixes")
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+    {
+        return Some(PUBLIC_NAMESPACE.to_string());
+    }
+
+    let api_key = req.get_header_str(API_KEY_HEADER)?.trim();
+    if api_key.is_empty() {
+        return None;
+    }
+    let digest: String = Sha256::digest(api_key.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    let store = ConfigStore::try_open(API_KEYS_STORE_NAME)
+        .inspect_err(|e| log::warn!("cannot open Config Store {API_KEYS_STORE_NAME}: {e}"))
+        .ok()?;
+    let tenant = store.try_get(&digest).ok().flatten()?;
+    Some(format!("tenant:{tenant}"))
+}
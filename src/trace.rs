@@ -0,0 +1,233 @@
+//! Debug traces of caching decisions, for operators.
+//!
+//! Operators holding the debug token (see the `debug` module) can send an `X-Debug-Trace` header
+//! to have every caching decision taken for their request collected in a structured trace: the
+//! changes the before-send callback made to the request sent to the origin, the transforms
+//! installed on the body, the TTL and stale windows chosen, and why a response was not stored.
+//!
+//! With `X-Debug-Trace: json`, the trace is appended to the response body as a line of JSON.
+//! Otherwise it is returned in an `X-Debug-Trace` response header. Traced responses are marked
+//! `no-store`, so that they are never kept by a downstream cache. The header is removed from
+//! every request, and ignored on requests from anyone else, so normal traffic is unaffected.
+
+use fastly::http::{header, CandidateResponse, HeaderName};
+use fastly::{Request, Response};
+use serde_json::{json, Map, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The request and response header of traces.
+const TRACE_HEADER: &str = "x-debug-trace";
+
+/// Headers whose values are never traced.
+const REDACTED_HEADERS: [HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::PROXY_AUTHORIZATION,
+    HeaderName::from_static("x-amz-security-token"),
+];
+
+/// Where a trace is returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+    Header,
+    Body,
+}
+
+/// The request as it was before the before-send callback changed it.
+pub struct Snapshot {
+    url: String,
+    headers: Map<String, Value>,
+}
+
+/// Collects the trace of one request.
+#[derive(Clone)]
+pub struct Tracer {
+    output: Output,
+    started: Instant,
+    events: Arc<Mutex<Vec<Value>>>,
+}
+
+impl Tracer {
+    /// Removes the trace header from a request, returning a tracer if it asks for one and is from
+    /// an operator.
+    pub fn for_request(req: &mut Request, is_operator: bool) -> Option<Self> {
+        let value = req.remove_header_str(TRACE_HEADER)?;
+        if !is_operator {
+            log::info!("ignoring debug trace request from a client without a debug token");
+            return None;
+        }
+        let output = if value.trim().eq_ignore_ascii_case("json") {
+            Output::Body
+        } else {
+            Output::Header
+        };
+        Some(Self {
+            output,
+            started: Instant::now(),
+            events: Arc::default(),
+        })
+    }
+
+    /// Adds an event to the trace.
+    pub fn record(&self, stage: &str, detail: Value) {
+        let mut event = json!({
+            "stage": stage,
+            "at_ms": self.started.elapsed().as_millis() as u64,
+        });
+        if let (Value::Object(event), Value::Object(detail)) = (&mut event, detail) {
+            event.extend(detail);
+        }
+        if let Ok(mut events) = self.events.lock() {
+            events.push(event);
+        }
+    }
+
+    /// Traces the changes the before-send callback made to a request sent to `backend`.
+    pub fn record_before_send(&self, backend: &str, before: &Snapshot, req: &Request) {
+        let after = Snapshot::of(req);
+        let url = (after.url != before.url).then_some(after.url);
+        self.record(
+            "before-send",
+            json!({
+                "backend": backend,
+                "url": url,
+                "headers": diff(&before.headers, &after.headers),
+            }),
+        );
+    }
+
+    /// Traces the cache decision taken for a response received from the origin.
+    pub fn record_decision(&self, resp: &CandidateResponse) {
+        let uncacheable_reason = self.events.lock().ok().and_then(|events| {
+            events
+                .iter()
+                .rev()
+                .find(|event| event["stage"] == "uncacheable")
+                .map(|event| event["reason"].clone())
+        });
+        let cacheable = resp.is_cacheable();
+        self.record(
+            "after-send",
+            json!({
+                "status": resp.get_status().as_u16(),
+                "cacheable": cacheable,
+                "ttl_secs": resp.get_ttl().as_secs(),
+                "stale_while_revalidate_secs": resp.get_stale_while_revalidate().as_secs(),
+                "stale_if_error_secs": resp.get_stale_if_error().as_secs(),
+                "vary": resp.get_vary().collect::<Vec<_>>(),
+                "uncacheable_reason": if cacheable {
+                    Value::Null
+                } else {
+                    uncacheable_reason.unwrap_or_else(|| "the origin response".into())
+                },
+            }),
+        );
+    }
+
+    /// Returns the trace along with the response delivered to the client.
+    pub fn finish(&self, resp: &mut Response) {
+        self.record("delivery", json!({ "status": resp.get_status().as_u16() }));
+        let events = self
+            .events
+            .lock()
+            .map(|events| events.clone())
+            .unwrap_or_default();
+        let trace = json!({ "trace": events });
+
+        resp.set_header(header::CACHE_CONTROL, "private, no-store");
+        match self.output {
+            Output::Header => resp.set_header(TRACE_HEADER, ascii_json(&trace)),
+            Output::Body => {
+                let mut body = resp.take_body_bytes();
+                body.push(b'\n');
+                body.extend_from_slice(trace.to_string().as_bytes());
+                body.push(b'\n');
+                resp.remove_header(header::CONTENT_LENGTH);
+                resp.set_body(body);
+            }
+        }
+    }
+}
+
+impl Snapshot {
+    /// Returns the URL and headers of a request, with sensitive header values redacted.
+    pub fn of(req: &Request) -> Self {
+        let mut headers = Map::new();
+        for (name, value) in req.get_headers() {
+            let value = if REDACTED_HEADERS.contains(name) {
+                "[redacted]".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            match headers.get_mut(name.as_str()) {
+                Some(Value::String(existing)) => {
+                    existing.push_str(", ");
+                    existing.push_str(&value);
+                }
+                _ => {
+                    headers.insert(name.to_string(), value.into());
+                }
+            }
+        }
+        Self {
+            url: req.get_url_str().to_string(),
+            headers,
+        }
+    }
+}
+
+/// Returns the headers added, changed and removed between two snapshots of a request.
+fn diff(before: &Map<String, Value>, after: &Map<String, Value>) -> Value {
+    let mut set = Map::new();
+    for (name, value) in after {
+        if before.get(name) != Some(value) {
+            set.insert(name.clone(), value.clone());
+        }
+    }
+    let removed: Vec<&String> = before
+        .keys()
+        .filter(|name| !after.contains_key(*name))
+        .collect();
+    json!({ "set": set, "removed": removed })
+}
+
+/// Serializes a value as JSON with only ASCII characters, so that it can be a header value.
+fn ascii_json(value: &Value) -> String {
+    let mut out = String::new();
+    for c in value.to_string().chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                out.push_str(&format!("\\u{unit:04x}"));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_changes_are_listed() {
+        let before = json!({ "accept": "*/*", "cookie": "[redacted]", "x-a": "1" });
+        let after = json!({ "accept": "*/*", "x-a": "2", "x-b": "3" });
+        assert_eq!(
+            diff(before.as_object().unwrap(), after.as_object().unwrap()),
+            json!({ "set": { "x-a": "2", "x-b": "3" }, "removed": ["cookie"] })
+        );
+    }
+
+    #[test]
+    fn header_traces_are_ascii() {
+        let trace = ascii_json(&json!({ "path": "/café/🎉" }));
+        assert_eq!(trace, r#"{"path":"/caf\u00e9/\ud83c\udf89"}"#);
+        assert_eq!(
+            serde_json::from_str::<Value>(&trace).unwrap(),
+            json!({ "path": "/café/🎉" })
+        );
+    }
+}
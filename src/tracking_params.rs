@@ -0,0 +1,42 @@
+//! Stripping of marketing tracking parameters.
+//!
+//! Links shared in campaigns and ads carry parameters such as `utm_source`, `fbclid` or `gclid`
+//! that only matter to analytics running in the browser. With the `strip_tracking_params` setting
+//! on, the parameters listed in `tracking_params` are left out of the cache key, so that every
+//! visitor from a campaign shares the cached page, and removed from the request in the before-send
+//! callback, so that the origin never sees them either. The list is read from the settings, so
+//! that parameters can be added without a redeploy.
+
+use crate::config::Settings;
+use crate::query;
+use fastly::http::Url;
+
+/// The tracking parameters stripped unless overridden by the `tracking_params` setting.
+const DEFAULT_PARAMS: [&str; 5] = ["utm_*", "fbclid", "gclid", "msclkid", "mc_eid"];
+
+/// The tracking parameters stripped from requests.
+#[derive(Clone)]
+pub struct TrackingParams {
+    names: Vec<String>,
+}
+
+impl TrackingParams {
+    /// Returns the tracking parameters to strip, or `None` if they are kept.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if !settings.get_bool("strip_tracking_params", false) {
+            return None;
+        }
+        let mut names = settings.get_list("tracking_params");
+        if names.is_empty() {
+            names = DEFAULT_PARAMS.iter().map(|name| name.to_string()).collect();
+        }
+        Some(Self { names })
+    }
+
+    /// Removes the tracking parameters from a URL.
+    pub fn strip(&self, url: &mut Url) {
+        if query::remove_listed(url, &self.names) {
+            log::info!("stripped tracking parameters from {}", url.path());
+        }
+    }
+}
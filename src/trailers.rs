@@ -0,0 +1,101 @@
+//! Handling of HTTP trailers through the body transforms.
+//!
+//! Some origins send metadata after the body, in trailers: gRPC-web status codes, or
+//! `Server-Timing` values computed while the body was generated. Body transforms replace the body
+//! that the trailers were attached to, so each transform must decide what happens to them.
+//!
+//! Routes listed in the `preserve_trailers_prefixes` setting carry the origin trailers over to the
+//! transformed body. Everywhere else, trailers are stripped, and a log entry records which ones
+//! were dropped.
+
+use crate::config::Settings;
+use crate::integrity::{self, ExpectedDigest};
+use fastly::experimental::{BodyExt, StreamingBodyExt};
+use fastly::http::body::StreamingBody;
+use fastly::http::{header, CandidateResponse};
+use fastly::{Body, Request};
+use std::io::Read;
+
+/// What happens to origin trailers when a body is transformed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailerPolicy {
+    Preserve,
+    Strip,
+}
+
+impl TrailerPolicy {
+    /// Returns the trailer policy of the route the request is for.
+    pub fn for_request(req: &Request, settings: &Settings) -> Self {
+        let path = req.get_path();
+        if settings
+            .get_list("preserve_trailers_prefixes")
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            TrailerPolicy::Preserve
+        } else {
+            TrailerPolicy::Strip
+        }
+    }
+
+    /// Makes sure that the trailers of a response are stripped when the policy says so, even if
+    /// no other transform applies to its body.
+    ///
+    /// Responses announce their trailers with a `Trailer` header. For such responses, a
+    /// transform that copies the body without its trailers is installed. Other transforms
+    /// installed later replace it, and apply the policy themselves. The body is checked against the
+    /// digest the origin sent with it, if any.
+    pub fn apply(self, resp: &mut CandidateResponse) {
+        if self == TrailerPolicy::Preserve || !resp.contains_header(header::TRAILER) {
+            return;
+        }
+        resp.remove_header(header::TRAILER);
+        let digest = ExpectedDigest::from_response(resp);
+        resp.set_body_transform(move |body_in, body_out| {
+            let body = self.read_body(body_in, body_out);
+            integrity::verify(digest.as_ref(), &body)?;
+            body_out.append(Body::from(body));
+            Ok(())
+        });
+    }
+
+    /// Reads a body to the end, then forwards its trailers to `body_out` or drops them.
+    ///
+    /// Trailers are only available once the whole body has been read, so transforms must use
+    /// this function rather than consuming the body directly.
+    pub fn read_body(self, mut body_in: Body, body_out: &mut StreamingBody) -> Vec<u8> {
+        let mut body = Vec::new();
+        if let Err(e) = body_in.read_to_end(&mut body) {
+            log::warn!("failed to read origin body: {e}");
+        }
+        self.forward_trailers(&mut body_in, body_out);
+        body
+    }
+
+    /// Forwards the trailers of a body that has been read to the end to `body_out`, or drops
+    /// them. Transforms that stream the body call this once they have read all of it.
+    pub fn forward_trailers(self, body_in: &mut Body, body_out: &mut StreamingBody) {
+        let trailers = match body_in.get_trailers() {
+            Ok(trailers) => trailers,
+            Err(e) => {
+                log::warn!("failed to read origin trailers: {e}");
+                return;
+            }
+        };
+        if trailers.is_empty() {
+            return;
+        }
+
+        match self {
+            TrailerPolicy::Preserve => {
+                for (name, value) in &trailers {
+                    body_out.append_trailer(name, value);
+                }
+            }
+            TrailerPolicy::Strip => {
+                let names: Vec<&str> = trailers.keys().map(|name| name.as_str()).collect();
+                log::info!("stripped origin trailers: {}", names.join(", "));
+            }
+        }
+    }
+}
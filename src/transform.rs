@@ -0,0 +1,510 @@
+//! Decisions about transforming response bodies before they are cached.
+//!
+//! A body transform only runs when the backend sends a fresh body. When a stale object is
+//! revalidated with a 304 Not Modified, the after-send callback still runs, but the cached body is
+//! kept as it is. Header changes made in after-send must therefore match the body that is already
+//! in the cache, rather than assume that the transform is about to run.
+//!
+//! To make that possible, objects whose body has been transformed carry a marker header. During a
+//! revalidation, the marker comes back with the cached headers, even if the 304 response carries
+//! the original `Content-Type` of the origin.
+//!
+//! Transforms parse the body as text, so gzip and Brotli bodies are decompressed before they are
+//! transformed, and the result is compressed again with the same coding. Bodies with other
+//! codings are cached as they are, rather than being handed to a parser as raw compressed bytes.
+//!
+//! Text bodies are decoded from the charset declared in their `Content-Type` before being
+//! transformed, and the transformed body is always UTF-8, so that Latin-1 or Shift_JIS content is
+//! not corrupted by string-based transforms.
+//!
+//! The JSON to HTML example streams the origin body rather than reading it into memory first: the
+//! body is decoded and parsed in chunks as it is read, and only the members the page is rendered
+//! from are kept, so that large JSON documents do not exhaust the memory of the instance.
+//!
+//! Routes with the `html` option serve the same origin JSON as JSON or HTML, as the client's
+//! `Accept` header prefers: either as two cached variants, or by caching the JSON only and
+//! rendering the page as it is delivered (see [`HtmlNegotiation`]).
+
+use crate::compression::Coding;
+use crate::memo::Memo;
+use crate::metrics::TransformMetrics;
+use crate::query;
+use encoding_rs::{CoderResult, Decoder, Encoding, UTF_8};
+use fastly::http::header;
+use fastly::{mime, Request, Response};
+use serde::Deserialize;
+use std::io::{self, BufReader, Read};
+use std::time::Instant;
+
+/// The header that records which transform produced the cached body.
+pub const TRANSFORMED_HEADER: &str = "x-edge-transformed";
+
+/// The marker value for bodies rendered from JSON to HTML.
+pub const JSON_TO_HTML: &str = "json-to-html";
+
+/// The size of the chunks a body is decoded in while streaming.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The query parameter that selects a variant of a rendered page.
+const FORMAT_PARAM: &str = "format";
+
+/// The value of the format parameter that selects the untransformed origin body.
+const RAW_FORMAT: &str = "raw";
+
+/// The cache namespace of the untransformed variant of rendered pages.
+pub const RAW_VARIANT: &str = "format=raw";
+
+/// Removes the format parameter from a request, returning whether it asks for the untransformed
+/// origin body.
+///
+/// The parameter is never sent to the origin. Callers key the raw variant on [`RAW_VARIANT`], so
+/// that other values of the parameter share the cached transformed page.
+pub fn take_raw_variant(req: &mut Request) -> bool {
+    query::take_param(req, FORMAT_PARAM).is_some_and(|format| format == RAW_FORMAT)
+}
+
+/// Returns the character encoding declared by the `charset` parameter of a `Content-Type`,
+/// defaulting to UTF-8.
+pub fn charset(content_type: Option<&str>) -> &'static Encoding {
+    content_type
+        .into_iter()
+        .flat_map(|value| value.split(';').skip(1))
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| Encoding::for_label(label.trim().trim_matches('"').as_bytes()))
+        .unwrap_or(UTF_8)
+}
+
+/// Decodes a text body to UTF-8. A byte order mark, if present, takes precedence over `encoding`.
+pub fn decode_text(body: &[u8], encoding: &'static Encoding) -> String {
+    let (text, actual, had_errors) = encoding.decode(body);
+    if had_errors {
+        log::warn!("body contained invalid {} sequences", actual.name());
+    }
+    text.into_owned()
+}
+
+/// A reader that decodes a text body to UTF-8 as it is read. A byte order mark, if present, takes
+/// precedence over the declared encoding.
+pub struct DecodingReader<R> {
+    inner: R,
+    decoder: Decoder,
+    decoded: Vec<u8>,
+    position: usize,
+    finished: bool,
+    had_errors: bool,
+}
+
+impl<R: Read> DecodingReader<R> {
+    pub fn new(inner: R, encoding: &'static Encoding) -> Self {
+        Self {
+            inner,
+            decoder: encoding.new_decoder(),
+            decoded: Vec::new(),
+            position: 0,
+            finished: false,
+            had_errors: false,
+        }
+    }
+
+    /// Decodes the next chunk of the body. Returns `false` at the end of the body.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.finished {
+            return Ok(false);
+        }
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let len = self.inner.read(&mut chunk)?;
+        let last = len == 0;
+        let capacity = self
+            .decoder
+            .max_utf8_buffer_length(len)
+            .unwrap_or(len * 3 + 16);
+        self.decoded.resize(capacity, 0);
+        let (result, _, written, had_errors) =
+            self.decoder
+                .decode_to_utf8(&chunk[..len], &mut self.decoded, last);
+        debug_assert!(matches!(result, CoderResult::InputEmpty));
+        self.decoded.truncate(written);
+        self.position = 0;
+        if had_errors && !self.had_errors {
+            log::warn!(
+                "body contained invalid {} sequences",
+                self.decoder.encoding().name()
+            );
+            self.had_errors = true;
+        }
+        self.finished = last;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.decoded.len() {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.decoded.len() - self.position);
+        buf[..len].copy_from_slice(&self.decoded[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// The members of the origin JSON that the example page is rendered from. Other members are
+/// skipped as they are parsed, without being kept in memory.
+#[derive(Deserialize)]
+pub struct Person {
+    #[serde(rename = "firstName", default)]
+    first_name: String,
+    #[serde(rename = "lastName", default)]
+    last_name: String,
+}
+
+impl Person {
+    /// Parses the members of a JSON body as it is read.
+    pub fn read(body: impl Read) -> io::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::with_capacity(
+            CHUNK_SIZE, body,
+        ))?)
+    }
+
+    /// Renders the example HTML snippet.
+    ///
+    /// Rendering stands in for an expensive computation here, such as a template filled from
+    /// other lookups, which is worth memoizing (see the [`memo`](crate::memo) module).
+    pub fn render(&self) -> String {
+        format!("<div>{} {}</div>", self.first_name, self.last_name)
+    }
+
+    /// Returns the input the snippet is rendered from, as a key for memoizing it. Each member is
+    /// prefixed with its length, so that no two persons share an input, whatever their names hold.
+    pub fn memo_input(&self) -> Vec<u8> {
+        let mut input = Vec::new();
+        for member in [&self.first_name, &self.last_name] {
+            input.extend_from_slice(&(member.len() as u64).to_be_bytes());
+            input.extend_from_slice(member.as_bytes());
+        }
+        input
+    }
+}
+
+/// Renders the example HTML snippet from a JSON body, parsing it as it is read.
+pub fn render_json_to_html(body: impl Read) -> io::Result<String> {
+    Ok(Person::read(body)?.render())
+}
+
+/// Renders the example HTML snippet from a cached JSON response as it is delivered, for routes
+/// negotiated with [`HtmlNegotiation::Delivery`]. The snippet is memoized like the one rendered
+/// before caching, and is delivered uncompressed.
+///
+/// Responses that are not successful JSON responses, or whose body cannot be parsed, are
+/// returned unchanged.
+pub fn render_on_delivery(
+    mut resp: Response,
+    memo: Option<&Memo>,
+    transform_metrics: Option<TransformMetrics>,
+) -> Response {
+    resp.append_header(header::VARY, "Accept");
+    let content_type = resp
+        .get_header_str(header::CONTENT_TYPE)
+        .map(str::to_string);
+    let content_encoding = resp.get_header_str(header::CONTENT_ENCODING);
+    if !resp.get_status().is_success()
+        || plan_json_to_html(content_type.as_deref(), content_encoding, None) != JsonToHtml::Render
+    {
+        return resp;
+    }
+
+    let started = Instant::now();
+    let coding = Coding::of(content_encoding);
+    let charset = charset(content_type.as_deref());
+    let body = resp.take_body_bytes();
+    let person = match coding {
+        Some(coding) => Person::read(DecodingReader::new(coding.decoder(&body[..]), charset)),
+        None => Person::read(DecodingReader::new(&body[..], charset)),
+    };
+    let person = match person {
+        Ok(person) => person,
+        Err(e) => {
+            log::warn!("cannot render invalid JSON response: {e}");
+            resp.set_body(body);
+            return resp;
+        }
+    };
+    let html = match memo {
+        Some(memo) => memo.get_or_compute(&person.memo_input(), || person.render().into_bytes()),
+        None => person.render().into_bytes(),
+    };
+    if let Some(metrics) = transform_metrics {
+        metrics.record(
+            JSON_TO_HTML,
+            content_type.as_deref(),
+            body.len(),
+            html.len(),
+            started.elapsed(),
+        );
+    }
+    resp.set_body(html);
+    resp.remove_header(header::CONTENT_LENGTH);
+    resp.remove_header(header::CONTENT_ENCODING);
+    resp.set_content_type(mime::TEXT_HTML_UTF_8);
+    resp
+}
+
+/// Returns a `Content-Type` value with its `charset` parameter set to UTF-8.
+pub fn with_utf8_charset(content_type: &str) -> String {
+    let mut parts = content_type.split(';');
+    let essence = parts.next().unwrap_or_default().trim();
+    let mut value = essence.to_string();
+    for param in parts.map(str::trim) {
+        let is_charset = param
+            .split_once('=')
+            .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("charset"));
+        if !param.is_empty() && !is_charset {
+            value.push_str("; ");
+            value.push_str(param);
+        }
+    }
+    value.push_str("; charset=utf-8");
+    value
+}
+
+/// Returns whether a `Content-Type` is JSON, including JSON-based types such as
+/// `application/ld+json`.
+pub fn is_json(content_type: Option<&str>) -> bool {
+    let essence = content_type
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+/// Returns whether a `Content-Type` is HTML.
+pub fn is_html(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case("text/html"))
+}
+
+/// Returns whether a body with the given `Content-Encoding` can be handed to a text transform,
+/// decompressed if needed.
+pub fn is_transformable_encoding(content_encoding: Option<&str>) -> bool {
+    Coding::of(content_encoding).is_some()
+        || content_encoding.is_none_or(|encoding| {
+            let encoding = encoding.trim();
+            encoding.is_empty() || encoding.eq_ignore_ascii_case("identity")
+        })
+}
+
+/// How pages rendered from JSON are negotiated with the client's `Accept` header, on routes with
+/// the `html` option (see the [`routing`](crate::routing) module).
+///
+/// Without it, every client is served the rendered HTML page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HtmlNegotiation {
+    /// The JSON and HTML representations are cached as two variants, varying on a normalized
+    /// `Accept` header: the page is rendered once, as it is cached.
+    Vary,
+    /// Only the JSON object is cached, and the page is rendered from it on every delivery to a
+    /// client that prefers HTML.
+    Delivery,
+}
+
+impl HtmlNegotiation {
+    /// Parses the value of the `html` route option: `vary` or `delivery`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "vary" => Some(Self::Vary),
+            "delivery" => Some(Self::Delivery),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with a candidate response for the JSON to HTML example transform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JsonToHtml {
+    /// A fresh JSON body: switch the content type to HTML and install the transform.
+    Render,
+    /// The cached body has already been rendered to HTML: keep the content type as HTML, without
+    /// installing the transform again.
+    AlreadyRendered,
+    /// Not a JSON body: leave the response alone.
+    Skip,
+}
+
+/// Decides how the JSON to HTML transform applies to a candidate response, given its
+/// `Content-Type`, its `Content-Encoding` and the value of its [`TRANSFORMED_HEADER`].
+pub fn plan_json_to_html(
+    content_type: Option<&str>,
+    content_encoding: Option<&str>,
+    transformed: Option<&str>,
+) -> JsonToHtml {
+    if transformed == Some(JSON_TO_HTML) {
+        return JsonToHtml::AlreadyRendered;
+    }
+    if !is_transformable_encoding(content_encoding) {
+        return JsonToHtml::Skip;
+    }
+    let is_json = content_type
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case("application/json"));
+    if is_json {
+        JsonToHtml::Render
+    } else {
+        JsonToHtml::Skip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_json_is_rendered() {
+        assert_eq!(
+            plan_json_to_html(Some("application/json; charset=utf-8"), None, None),
+            JsonToHtml::Render
+        );
+    }
+
+    #[test]
+    fn other_content_is_skipped() {
+        assert_eq!(
+            plan_json_to_html(Some("text/html"), None, None),
+            JsonToHtml::Skip
+        );
+        assert_eq!(plan_json_to_html(None, None, None), JsonToHtml::Skip);
+    }
+
+    #[test]
+    fn compressed_json_is_rendered_if_it_can_be_decompressed() {
+        assert_eq!(
+            plan_json_to_html(Some("application/json"), Some("gzip"), None),
+            JsonToHtml::Render
+        );
+        assert_eq!(
+            plan_json_to_html(Some("application/json"), Some("br"), None),
+            JsonToHtml::Render
+        );
+        assert_eq!(
+            plan_json_to_html(Some("application/json"), Some("zstd"), None),
+            JsonToHtml::Skip
+        );
+        assert_eq!(
+            plan_json_to_html(Some("application/json"), Some("identity"), None),
+            JsonToHtml::Render
+        );
+    }
+
+    #[test]
+    fn html_negotiation_is_parsed() {
+        assert_eq!(HtmlNegotiation::parse("vary"), Some(HtmlNegotiation::Vary));
+        assert_eq!(HtmlNegotiation::parse("always"), None);
+    }
+
+    #[test]
+    fn charset_is_read_from_content_type() {
+        assert_eq!(
+            charset(Some("text/html; charset=ISO-8859-1")).name(),
+            "windows-1252"
+        );
+        assert_eq!(
+            charset(Some("text/plain; Charset=\"Shift_JIS\"")).name(),
+            "Shift_JIS"
+        );
+        assert_eq!(charset(Some("application/json")), UTF_8);
+        assert_eq!(charset(None), UTF_8);
+    }
+
+    #[test]
+    fn latin1_is_decoded() {
+        let body = b"{\"firstName\":\"Ren\xe9\"}";
+        let text = decode_text(body, charset(Some("application/json; charset=latin1")));
+        assert_eq!(text, "{\"firstName\":\"Ren\u{e9}\"}");
+    }
+
+    #[test]
+    fn json_is_rendered_while_streaming() {
+        let body = br#"{"firstName":"Ada","items":[1,2,3],"lastName":"Lovelace"}"#;
+        let html = render_json_to_html(DecodingReader::new(&body[..], UTF_8)).unwrap();
+        assert_eq!(html, "<div>Ada Lovelace</div>");
+    }
+
+    #[test]
+    fn latin1_is_decoded_while_streaming() {
+        let body = b"{\"firstName\":\"Ren\xe9\"}";
+        let reader =
+            DecodingReader::new(&body[..], charset(Some("application/json; charset=latin1")));
+        assert_eq!(
+            render_json_to_html(reader).unwrap(),
+            "<div>Ren\u{e9} </div>"
+        );
+    }
+
+    #[test]
+    fn decoding_spans_chunks() {
+        // A multi-byte sequence split across reads must be decoded as one character.
+        let text = "\u{e9}".repeat(CHUNK_SIZE);
+        let mut decoded = String::new();
+        DecodingReader::new(text.as_bytes(), UTF_8)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn memo_inputs_keep_members_apart() {
+        let person = |first_name: &str, last_name: &str| Person {
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+        };
+        assert_ne!(
+            person("Ada L", "ovelace").memo_input(),
+            person("Ada", "Lovelace").memo_input()
+        );
+        assert_ne!(
+            person("Ada\0", "Lovelace").memo_input(),
+            person("Ada", "\0Lovelace").memo_input()
+        );
+    }
+
+    #[test]
+    fn invalid_json_fails_to_render() {
+        assert!(render_json_to_html(DecodingReader::new(&b"{not json"[..], UTF_8)).is_err());
+    }
+
+    #[test]
+    fn charset_parameter_is_replaced() {
+        assert_eq!(
+            with_utf8_charset("text/html; charset=shift_jis"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            with_utf8_charset("application/dash+xml; profile=x"),
+            "application/dash+xml; profile=x; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn revalidation_with_json_content_type_does_not_render_again() {
+        // A 304 from the origin may carry `Content-Type: application/json`, which replaces the
+        // `text/html` stored with the rendered object. No body is present, so the transform
+        // cannot run, and the object must not be treated as fresh JSON.
+        assert_eq!(
+            plan_json_to_html(Some("application/json"), None, Some(JSON_TO_HTML)),
+            JsonToHtml::AlreadyRendered
+        );
+    }
+
+    #[test]
+    fn revalidation_of_rendered_object_keeps_html() {
+        assert_eq!(
+            plan_json_to_html(Some("text/html"), None, Some(JSON_TO_HTML)),
+            JsonToHtml::AlreadyRendered
+        );
+    }
+}
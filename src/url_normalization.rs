@@ -0,0 +1,154 @@
+//! Canonical request URLs, before the cache lookup and the origin fetch.
+//!
+//! Clients and links reach the same page through many spellings of its URL: `/shop//shoes`,
+//! `/Shop/Shoes`, `/shop/shoes/`. With the `url_normalization` setting on, the request URL is
+//! rewritten to its canonical form before anything else looks at it, so that every spelling is
+//! routed, cached and fetched as the same URL:
+//!
+//! * runs of slashes in the path are collapsed into one;
+//! * the `Host` header is lowercased;
+//! * the path is lowercased with the `lowercase_paths` setting on;
+//! * a trailing slash is removed with the `trailing_slash` setting set to `strip`, or added to
+//!   paths whose last segment has no file extension with it set to `add`. It is left as it is
+//!   with `keep`, the default.
+//!
+//! With the `canonical_redirects` setting on, `GET` and `HEAD` requests for a URL that is not
+//! canonical are answered with a `301 Moved Permanently` to the canonical URL instead, so that
+//! clients and crawlers learn it. Other requests are always rewritten in place, since browsers
+//! would follow the redirect with a `GET`.
+
+use crate::config::Settings;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Request, Response};
+
+/// What happens to the trailing slash of paths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailingSlash {
+    Keep,
+    Strip,
+    Add,
+}
+
+/// The URL normalization of the service.
+#[derive(Clone, Debug)]
+pub struct UrlNormalization {
+    trailing_slash: TrailingSlash,
+    lowercase_paths: bool,
+    redirect: bool,
+}
+
+impl UrlNormalization {
+    /// Returns the URL normalization of the settings, or `None` if URLs are not normalized.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if !settings.get_bool("url_normalization", false) {
+            return None;
+        }
+        let trailing_slash = match settings.get("trailing_slash").as_deref().map(str::trim) {
+            Some("strip") => TrailingSlash::Strip,
+            Some("add") => TrailingSlash::Add,
+            None | Some("keep") => TrailingSlash::Keep,
+            Some(other) => {
+                log::warn!("ignoring invalid trailing_slash setting {other:?}");
+                TrailingSlash::Keep
+            }
+        };
+        Some(Self {
+            trailing_slash,
+            lowercase_paths: settings.get_bool("lowercase_paths", false),
+            redirect: settings.get_bool("canonical_redirects", false),
+        })
+    }
+
+    /// Rewrites the URL of a request to its canonical form, or returns the redirect to it that the
+    /// client should get instead.
+    pub fn apply(&self, req: &mut Request) -> Option<Response> {
+        if let Some(host) = req.get_header_str(header::HOST) {
+            let host = host.to_ascii_lowercase();
+            req.set_header(header::HOST, host);
+        }
+        let path = req.get_path();
+        let canonical = self.canonical_path(path);
+        if canonical == path {
+            return None;
+        }
+
+        let mut url = req.get_url().clone();
+        url.set_path(&canonical);
+        if self.redirect && matches!(*req.get_method(), Method::GET | Method::HEAD) {
+            let location = match url.query() {
+                Some(query) => format!("{canonical}?{query}"),
+                None => canonical,
+            };
+            return Some(
+                Response::from_status(StatusCode::MOVED_PERMANENTLY)
+                    .with_header(header::LOCATION, location),
+            );
+        }
+        req.set_url(url);
+        None
+    }
+
+    /// Returns the canonical form of a path.
+    fn canonical_path(&self, path: &str) -> String {
+        let mut canonical = String::with_capacity(path.len() + 1);
+        for c in path.chars() {
+            if !(c == '/' && canonical.ends_with('/')) {
+                canonical.push(c);
+            }
+        }
+        if self.lowercase_paths {
+            canonical = canonical.to_lowercase();
+        }
+        match self.trailing_slash {
+            TrailingSlash::Keep => {}
+            TrailingSlash::Strip => {
+                while canonical.len() > 1 && canonical.ends_with('/') {
+                    canonical.pop();
+                }
+            }
+            TrailingSlash::Add => {
+                let last_segment = canonical.rsplit('/').next().unwrap_or_default();
+                if !canonical.ends_with('/') && !last_segment.contains('.') {
+                    canonical.push('/');
+                }
+            }
+        }
+        canonical
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalization(trailing_slash: TrailingSlash, lowercase_paths: bool) -> UrlNormalization {
+        UrlNormalization {
+            trailing_slash,
+            lowercase_paths,
+            redirect: false,
+        }
+    }
+
+    #[test]
+    fn duplicate_slashes_are_collapsed() {
+        let keep = normalization(TrailingSlash::Keep, false);
+        assert_eq!(keep.canonical_path("//shop///Shoes/"), "/shop/Shoes/");
+        assert_eq!(keep.canonical_path("/shop/shoes"), "/shop/shoes");
+        assert_eq!(
+            normalization(TrailingSlash::Keep, true).canonical_path("/Shop//Shoes"),
+            "/shop/shoes"
+        );
+    }
+
+    #[test]
+    fn trailing_slash_follows_the_policy() {
+        let strip = normalization(TrailingSlash::Strip, false);
+        assert_eq!(strip.canonical_path("/shop/shoes//"), "/shop/shoes");
+        assert_eq!(strip.canonical_path("/"), "/");
+
+        let add = normalization(TrailingSlash::Add, false);
+        assert_eq!(add.canonical_path("/shop/shoes"), "/shop/shoes/");
+        assert_eq!(add.canonical_path("/shop/shoes/"), "/shop/shoes/");
+        assert_eq!(add.canonical_path("/assets/app.js"), "/assets/app.js");
+    }
+}
@@ -0,0 +1,53 @@
+//! Connection warm-up on cold instances.
+//!
+//! The first origin fetch made by a new Compute instance pays for the TCP and TLS handshakes with
+//! the backend. With the `origin_warmup` setting on, the first request handled by an instance
+//! sends a `HEAD /` to each of the backends in the `warmup_backends` setting in the background,
+//! so that the connections are being set up while the request is processed. The probes are never
+//! waited for, and their responses are ignored.
+//!
+//! A fresh instance is normally used for every request, so this is mostly useful with reusable
+//! sandboxes, where later requests handled by the same instance skip the probe.
+
+use crate::config::Settings;
+use fastly::http::request::PendingRequest;
+use fastly::{Backend, Request};
+use std::sync::OnceLock;
+
+/// The backends probed when the `warmup_backends` setting is absent.
+const DEFAULT_BACKENDS: [&str; 1] = ["origin"];
+
+/// Set once the instance has sent its warm-up probes.
+static WARMED_UP: OnceLock<()> = OnceLock::new();
+
+/// Sends the warm-up probes, if this is the first request handled by the instance and warm-up is
+/// on.
+///
+/// The returned handles must be kept until the end of the request, so that the probes are not
+/// abandoned early; they never need to be waited for.
+pub fn probe_backends(settings: &Settings) -> Vec<PendingRequest> {
+    if !settings.get_bool("origin_warmup", false) || WARMED_UP.set(()).is_err() {
+        return Vec::new();
+    }
+
+    let mut backends = settings.get_list("warmup_backends");
+    if backends.is_empty() {
+        backends = DEFAULT_BACKENDS.map(str::to_string).to_vec();
+    }
+
+    backends
+        .iter()
+        .filter_map(|name| {
+            let backend = Backend::from_name(name)
+                .inspect_err(|e| log::warn!("cannot warm up backend {name}: {e}"))
+                .ok()?;
+            let scheme = if backend.is_ssl() { "https" } else { "http" };
+            let mut probe = Request::head(format!("{scheme}://{}/", backend.get_host()));
+            probe.set_pass(true);
+            probe
+                .send_async(backend)
+                .inspect_err(|e| log::warn!("cannot warm up backend {name}: {e}"))
+                .ok()
+        })
+        .collect()
+}